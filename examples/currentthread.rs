@@ -0,0 +1,35 @@
+use rumqtt::{MqttClient, MqttOptions, QoS, ReconnectOptions};
+use std::{thread, time::Duration};
+use tokio::runtime::current_thread::Runtime;
+
+fn main() {
+    pretty_env_logger::init();
+    let broker = "test.mosquitto.org";
+    let port = 1883;
+
+    let reconnection_options = ReconnectOptions::Always(10);
+    let mqtt_options = MqttOptions::new("test-currentthread", broker, port)
+                                    .set_keep_alive(10)
+                                    .set_reconnect_opts(reconnection_options);
+
+    // eventloop runs on the runtime of this thread instead of a thread of its own
+    let (mut mqtt_client, notifications, eventloop) = MqttClient::new(mqtt_options);
+    mqtt_client.subscribe("hello/world", QoS::AtLeastOnce).unwrap();
+
+    thread::spawn(move || {
+        for i in 0..100 {
+            let payload = format!("publish {}", i);
+            thread::sleep(Duration::from_millis(1000));
+            mqtt_client.publish("hello/world", QoS::AtLeastOnce, false, payload).unwrap();
+        }
+    });
+
+    thread::spawn(move || {
+        for notification in notifications {
+            println!("{:?}", notification)
+        }
+    });
+
+    let mut runtime = Runtime::new().unwrap();
+    runtime.block_on(eventloop).unwrap();
+}
@@ -0,0 +1,30 @@
+//! Publishes every second and serves the metrics of the client at http://localhost:9000/metrics
+//!
+//! cargo run -p prometheus-example
+use metrics_exporter_prometheus::PrometheusBuilder;
+use rumqtt::{MqttClient, MqttOptions, QoS};
+use std::thread;
+use std::time::Duration;
+
+fn main() {
+    pretty_env_logger::init();
+    PrometheusBuilder::new()
+        .listen_address(([127, 0, 0, 1], 9000))
+        .install()
+        .expect("Failed to install the prometheus exporter");
+
+    let mqtt_options = MqttOptions::new("metrics-example", "localhost", 1883).set_keep_alive(10);
+    let (mut mqtt_client, notifications) = MqttClient::start(mqtt_options).unwrap();
+
+    thread::spawn(move || {
+        for i in 0..1000 {
+            let payload = format!("publish {}", i);
+            mqtt_client.publish("hello/world", QoS::AtLeastOnce, false, payload).unwrap();
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+
+    for notification in notifications {
+        println!("{:?}", notification)
+    }
+}
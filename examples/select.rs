@@ -27,7 +27,8 @@ fn main() {
 
     loop {
         select! {
-            recv(notifications) -> notification => {
+            recv(notifications.receiver()) -> notification => {
+                notifications.notify_received();
                 println!("{:?}", notification)
             }
             recv(done_rx) -> _done => break
@@ -0,0 +1,41 @@
+//! Time to publish 100k QoS0 messages to a local mock broker with and without
+//! the write buffer
+//!
+//! cargo run --release --example throughput --features test-helpers
+use rumqtt::testutils::MockBroker;
+use rumqtt::{MqttClient, MqttOptions, Packet, QoS};
+use std::time::{Duration, Instant};
+
+const COUNT: usize = 100_000;
+
+fn run(id: &str, write_buffer_size: usize) -> Duration {
+    let broker = MockBroker::start().unwrap();
+    let mqtt_options = MqttOptions::new(id, "127.0.0.1", broker.port())
+        .set_request_channel_capacity(1000)
+        .set_write_buffer_size(write_buffer_size);
+    let (mut mqtt_client, _notifications) = MqttClient::start(mqtt_options).unwrap();
+
+    let start = Instant::now();
+    for i in 0..COUNT {
+        mqtt_client.publish("hello/world", QoS::AtMostOnce, false, format!("publish {}", i)).unwrap();
+    }
+
+    let publishes = |packets: &[Packet]| {
+        packets.iter().filter(|packet| match packet {
+            Packet::Publish(_) => true,
+            _ => false,
+        }).count()
+    };
+    assert!(broker.wait_until(Duration::from_secs(60), |packets| publishes(packets) == COUNT));
+    start.elapsed()
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let unbuffered = run("throughput-unbuffered", 0);
+    println!("unbuffered = {:?}", unbuffered);
+
+    let buffered = run("throughput-buffered", 64 * 1024);
+    println!("64KB write buffer = {:?}", buffered);
+}
@@ -0,0 +1,94 @@
+//! Tls certificates which are loaded afresh for every connection attempt. Lets
+//! short lived certificates be rotated without restarting the client
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Certificate authority and client certificate & key (pem or der encoded)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Certificates {
+    pub ca: Option<Vec<u8>>,
+    /// (certificate, private key)
+    pub client_auth: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Source of the tls certificates. Called before every connection attempt (reconnections
+/// included). Errors fail the attempt with `ConnectError::CertificateProvider`
+pub trait CertProvider: Send + Sync + fmt::Debug {
+    fn certificates(&self) -> io::Result<Certificates>;
+}
+
+/// Reads the certificates from files on every connection
+#[derive(Debug, Clone)]
+pub struct FileCertProvider {
+    ca: Option<PathBuf>,
+    client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+impl FileCertProvider {
+    pub fn new() -> FileCertProvider {
+        FileCertProvider { ca: None, client_auth: None }
+    }
+
+    pub fn set_ca<P: AsRef<Path>>(mut self, path: P) -> FileCertProvider {
+        self.ca = Some(path.as_ref().to_owned());
+        self
+    }
+
+    pub fn set_client_auth<P: AsRef<Path>, Q: AsRef<Path>>(mut self, cert_path: P, key_path: Q) -> FileCertProvider {
+        self.client_auth = Some((cert_path.as_ref().to_owned(), key_path.as_ref().to_owned()));
+        self
+    }
+}
+
+impl Default for FileCertProvider {
+    fn default() -> Self {
+        FileCertProvider::new()
+    }
+}
+
+impl CertProvider for FileCertProvider {
+    fn certificates(&self) -> io::Result<Certificates> {
+        let ca = match self.ca.as_ref() {
+            Some(path) => Some(fs::read(path)?),
+            None => None,
+        };
+
+        let client_auth = match self.client_auth.as_ref() {
+            Some((cert, key)) => Some((fs::read(cert)?, fs::read(key)?)),
+            None => None,
+        };
+
+        Ok(Certificates { ca, client_auth })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CertProvider, Certificates, FileCertProvider};
+    use std::fs;
+    use uuid::Uuid;
+
+    #[test]
+    fn rotated_files_should_be_read_again() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-certs-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("client.pem"), dir.join("client.key"));
+        let provider = FileCertProvider::new().set_client_auth(&cert, &key);
+
+        assert!(provider.certificates().is_err());
+
+        fs::write(&cert, b"cert1").unwrap();
+        fs::write(&key, b"key1").unwrap();
+        let certificates = Certificates { ca: None, client_auth: Some((b"cert1".to_vec(), b"key1".to_vec())) };
+        assert_eq!(provider.certificates().unwrap(), certificates);
+
+        fs::write(&cert, b"cert2").unwrap();
+        fs::write(&key, b"key2").unwrap();
+        let certificates = Certificates { ca: None, client_auth: Some((b"cert2".to_vec(), b"key2".to_vec())) };
+        assert_eq!(provider.certificates().unwrap(), certificates);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,229 @@
+use crate::codec;
+use crate::error::NetworkError;
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+use mqtt311::Packet;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+/// Longest wait of buffered publishes for more to coalesce with
+pub const WRITE_LINGER: Duration = Duration::from_millis(1);
+
+pub trait BufferedWrite: Sink<SinkItem = Packet, SinkError = NetworkError> {
+    /// Holds packets till `capacity` bytes are buffered or the sending stream is idle.
+    /// Publishes of an idle stream wait up to `linger` for more to coalesce with. Any
+    /// other packet (acks, pings ..) is written as soon as the stream is idle
+    fn write_buffered(self, capacity: usize, linger: Duration) -> WriteBuffer<Self>
+    where
+        Self: Sized,
+    {
+        WriteBuffer {
+            sink: self,
+            packets: VecDeque::new(),
+            buffered: 0,
+            capacity,
+            linger,
+            delay: None,
+            urgent: false,
+        }
+    }
+}
+
+impl<T: ?Sized> BufferedWrite for T where T: Sink<SinkItem = Packet, SinkError = NetworkError> {}
+
+#[must_use = "sinks do nothing unless polled"]
+pub struct WriteBuffer<S> {
+    sink: S,
+    packets: VecDeque<Packet>,
+    // upper bound of the encoded size of the buffered packets
+    buffered: usize,
+    capacity: usize,
+    linger: Duration,
+    delay: Option<Delay>,
+    // a packet which shouldn't linger is buffered
+    urgent: bool,
+}
+
+impl<S> WriteBuffer<S>
+where
+    S: Sink<SinkItem = Packet, SinkError = NetworkError>,
+{
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+
+    /// Moves the buffered packets to the inner sink till it pushes back
+    fn write_out(&mut self) -> Result<(), NetworkError> {
+        while let Some(packet) = self.packets.pop_front() {
+            let len = codec::max_encoded_len(&packet);
+            match self.sink.start_send(packet)? {
+                AsyncSink::Ready => self.buffered -= len,
+                AsyncSink::NotReady(packet) => {
+                    self.packets.push_front(packet);
+                    break;
+                }
+            }
+        }
+
+        if self.packets.is_empty() {
+            self.delay = None;
+            self.urgent = false;
+        }
+
+        Ok(())
+    }
+}
+
+impl<S> Sink for WriteBuffer<S>
+where
+    S: Sink<SinkItem = Packet, SinkError = NetworkError>,
+{
+    type SinkItem = Packet;
+    type SinkError = NetworkError;
+
+    fn start_send(&mut self, packet: Packet) -> StartSend<Packet, NetworkError> {
+        // full buffer is written out before taking more
+        if self.buffered >= self.capacity {
+            self.write_out()?;
+            self.sink.poll_complete()?;
+            if self.buffered >= self.capacity {
+                return Ok(AsyncSink::NotReady(packet));
+            }
+        }
+
+        match packet {
+            Packet::Publish(_) => (),
+            _ => self.urgent = true,
+        }
+
+        self.buffered += codec::max_encoded_len(&packet);
+        self.packets.push_back(packet);
+        Ok(AsyncSink::Ready)
+    }
+
+    /// Called when the sending stream is idle
+    fn poll_complete(&mut self) -> Poll<(), NetworkError> {
+        if !self.packets.is_empty() && !self.urgent && self.buffered < self.capacity {
+            let linger = self.linger;
+            let delay = self.delay.get_or_insert_with(|| Delay::new(Instant::now() + linger));
+            if delay.poll().map_err(NetworkError::Timer)?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        self.write_out()?;
+        let flushed = self.sink.poll_complete()?;
+        if !self.packets.is_empty() {
+            return Ok(Async::NotReady);
+        }
+
+        Ok(flushed)
+    }
+
+    fn close(&mut self) -> Poll<(), NetworkError> {
+        self.write_out()?;
+        if !self.packets.is_empty() {
+            self.sink.poll_complete()?;
+            return Ok(Async::NotReady);
+        }
+
+        self.sink.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferedWrite;
+    use crate::error::NetworkError;
+    use futures::{future, Async, AsyncSink, Poll, Sink, StartSend};
+    use mqtt311::{Packet, Publish, QoS};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Records the number of packets written by every flush
+    #[derive(Default)]
+    struct Recorder {
+        pending: usize,
+        flushes: Vec<usize>,
+    }
+
+    impl Sink for Recorder {
+        type SinkItem = Packet;
+        type SinkError = NetworkError;
+
+        fn start_send(&mut self, _packet: Packet) -> StartSend<Packet, NetworkError> {
+            self.pending += 1;
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), NetworkError> {
+            if self.pending > 0 {
+                self.flushes.push(self.pending);
+                self.pending = 0;
+            }
+
+            Ok(Async::Ready(()))
+        }
+    }
+
+    fn publish(len: usize) -> Packet {
+        Packet::Publish(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(vec![1; len]),
+        })
+    }
+
+    #[test]
+    fn publishes_of_an_idle_stream_should_linger_before_the_flush() {
+        let mut runtime = Runtime::new().unwrap();
+        let sink = runtime
+            .block_on(future::lazy(|| {
+                let mut sink = Recorder::default().write_buffered(1024, Duration::from_millis(10));
+                for _ in 0..3 {
+                    assert!(sink.start_send(publish(10)).unwrap().is_ready());
+                }
+
+                assert!(sink.poll_complete().unwrap().is_not_ready());
+                assert!(sink.get_ref().flushes.is_empty());
+                Ok::<_, NetworkError>(sink)
+            }))
+            .unwrap();
+
+        let sink = runtime.block_on(sink.flush()).unwrap();
+        assert_eq!(sink.get_ref().flushes, vec![3]);
+    }
+
+    #[test]
+    fn pings_and_acks_should_be_flushed_without_lingering() {
+        let mut runtime = Runtime::new().unwrap();
+        let sink = runtime
+            .block_on(future::lazy(|| {
+                let mut sink = Recorder::default().write_buffered(1024, Duration::from_secs(10));
+                sink.start_send(publish(10)).unwrap();
+                sink.start_send(Packet::Pingreq).unwrap();
+                assert!(sink.poll_complete().unwrap().is_ready());
+                Ok::<_, NetworkError>(sink)
+            }))
+            .unwrap();
+
+        assert_eq!(sink.get_ref().flushes, vec![2]);
+    }
+
+    #[test]
+    fn full_buffer_should_be_written_out_before_taking_more() {
+        let mut sink = Recorder::default().write_buffered(100, Duration::from_secs(10));
+        sink.start_send(publish(40)).unwrap();
+        sink.start_send(publish(40)).unwrap();
+        assert!(sink.get_ref().flushes.is_empty());
+
+        sink.start_send(publish(40)).unwrap();
+        assert_eq!(sink.get_ref().flushes, vec![2]);
+    }
+}
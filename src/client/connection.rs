@@ -1,211 +1,517 @@
 use crate::client::{
     mqttstate::MqttState,
-    network::stream::NetworkStream,
+    network::{env_proxy, stream::NetworkStream, DnsResolver},
+    buffer::{BufferedWrite, WRITE_LINGER},
     prepend::Prepend,
-    Command, Notification, Request, UserHandle,
+    priority::Prioritize,
+    throttle::TopicThrottle,
+    Command, ConnectionInfo, ConnectionStatus, Notification, TlsInfo, NotificationReceiver, Request, StatusSignal, UserHandle,
 };
-use crate::codec::MqttCodec;
-use crate::error::{ConnectError, NetworkError};
-use crate::mqttoptions::{MqttOptions, Proxy, ReconnectOptions};
-use crossbeam_channel::{self, Sender};
+use crate::codec::{self, MqttCodec, PacketTooLarge};
+use crate::error::{ConnectError, ErrorSummary, NetworkError};
+use crate::hooks;
+use crate::session::SessionState;
+use crate::stats::Stats;
+use crate::mqttoptions::{InflightOverflow, MqttOptions, NotificationOverflow, Proxy, ProxyTlsConfig, RetryPolicy, SecurityOptions};
+use crossbeam_channel::{self, Sender, TrySendError};
 use futures::{
-    future::{self, Either},
+    future::{self, Either, Loop},
     stream::{self, poll_fn},
     sync::mpsc::{self, Receiver},
+    task::AtomicTask,
     Async, Future, Poll, Sink, Stream,
 };
-use mqtt311::Packet;
-use std::{cell::RefCell, rc::Rc, thread, time::Duration, io};
-use tokio::codec::Framed;
+use mqtt311::{Packet, Publish, QoS};
+use std::{
+    any::Any,
+    cell::RefCell,
+    io,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::codec::{Decoder, Framed};
 use tokio::prelude::StreamExt;
 use tokio::runtime::current_thread::Runtime;
-use tokio::timer::{timeout, Timeout};
+use tokio::timer::{timeout, Delay, Interval, Timeout};
 
 //  NOTES: Don't use `wait` in eventloop thread even if you
 //         are ok with blocking code. It might cause deadlocks
 //  https://github.com/tokio-rs/tokio-core/issues/182
 
+// clones share the state and the channels of the connection. see `spawn`
+#[derive(Clone)]
 pub struct Connection {
     mqtt_state: Rc<RefCell<MqttState>>,
     notification_tx: Sender<Notification>,
+    // receiver to drop the oldest notification from a full channel
+    notification_overflow_rx: Option<crossbeam_channel::Receiver<Notification>>,
+    notification_stream_tx: Option<mpsc::Sender<Notification>>,
+    notifications_dropped: Arc<AtomicUsize>,
+    // woken by the notification receiver while `NotificationOverflow::Block` waits for space
+    notifications_blocked: Arc<AtomicTask>,
+    notification_listeners: Arc<Mutex<Vec<Sender<Notification>>>>,
     connection_tx: Option<Sender<Result<(), ConnectError>>>,
-    connection_count: u32,
+    // set once the broker accepts a connection (connack). reconnections follow the
+    // reconnect policy from then on
+    ever_connected: bool,
+    reconnect_attempt: u32,
+    backoff_attempt: u32,
+    connected_at: Option<Instant>,
     mqttoptions: MqttOptions,
     is_network_enabled: bool,
+    connection_status: Arc<Mutex<ConnectionStatus>>,
+    status_signal: Arc<StatusSignal>,
+    // broker of the current connection shared with the client
+    connected_broker: Arc<Mutex<Option<(String, u16)>>>,
+    // error of the last failed connection shared with the client
+    last_error: Arc<Mutex<Option<ErrorSummary>>>,
+    // position of the broker to connect to in the brokers of the options
+    broker_index: usize,
+    session_present: Arc<AtomicBool>,
+    // tls parameters of the current connection shared with the client
+    tls_info: Arc<Mutex<Option<TlsInfo>>>,
+    // session of the stopped eventloop shared with the client
+    final_session: Arc<Mutex<Option<SessionState>>>,
+    // outlives the connections to reuse addresses as per the dns cache ttl
+    resolver: DnsResolver,
 }
 
 impl Connection {
     /// Takes mqtt options and tries to create initial connection on current thread and handles
     /// connection events in a new thread if the initial connection is successful
     pub fn run(mqttoptions: MqttOptions) -> Result<UserHandle, ConnectError> {
+        let (connection_tx, connection_rx) = crossbeam_channel::bounded(1);
+        let initial_connect = mqttoptions.initial_connect_policy();
+        let user_handle = Connection::spawn(mqttoptions, Some(connection_tx))?;
+
+        match initial_connect {
+            // We need to wait for a successful connection in all cases except for when the
+            // initial connection is retried
+            RetryPolicy::Never => connection_rx.recv()??,
+            RetryPolicy::Interval(_) | RetryPolicy::ExponentialBackoff { .. } => {
+                // read the result but ignore it
+                let _ = connection_rx.recv()?;
+            }
+        }
+
+        // return user handle to client to send requests and handle notifications
+        Ok(user_handle)
+    }
+
+    /// Same as `run` but returns right after starting the eventloop thread. Outcome of the
+    /// first connection attempt is notified (`Connected` or `ConnectFailed`)
+    pub fn run_async(mqttoptions: MqttOptions) -> Result<UserHandle, ConnectError> {
+        Connection::spawn(mqttoptions, None)
+    }
+
+    /// Starts the eventloop on a new thread
+    fn spawn(mqttoptions: MqttOptions, connection_tx: Option<Sender<Result<(), ConnectError>>>) -> Result<UserHandle, ConnectError> {
+        // named after the client to tell the eventloops of a process apart
+        let mut builder = thread::Builder::new().name(format!("mqtt-{}", mqttoptions.client_id()));
+        if let Some(size) = mqttoptions.eventloop_stack_size() {
+            builder = builder.stack_size(size);
+        }
+
+        let (new_connection, eventloop_done_tx, mut user_handle) = Connection::prepare(mqttoptions, connection_tx);
+
+        // start the network thread to handle all mqtt network io
+        let eventloop = builder.spawn(move || {
+            let (mut connection, request_rx, command_rx) = new_connection();
+            let mut runtime = match Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Runtime error = {:?}", e);
+                    connection.handle_connection_error(timeout::Error::inner(ConnectError::Runtime(e)));
+                    connection.eventloop_done(&eventloop_done_tx);
+                    return;
+                }
+            };
+
+            // eventloop owns the connection. panic is reported with a clone which shares its state
+            let mut reporter = connection.clone();
+            let eventloop = connection.mqtt_eventloop_future(request_rx, command_rx);
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(|| runtime.block_on(eventloop))) {
+                reporter.handle_panic(panic);
+            }
+
+            reporter.eventloop_done(&eventloop_done_tx);
+        });
+
+        let eventloop = eventloop.map_err(ConnectError::Spawn)?;
+
+        user_handle.eventloop = Some(eventloop);
+        Ok(user_handle)
+    }
+
+    /// Same as `run` but returns the eventloop as a future instead of running it on a new
+    /// thread. The future never blocks the thread (reconnections wait on timers), completes
+    /// when the eventloop stops and isn't `Send`. Spawn it on a current thread executor.
+    /// Initial connection errors are handled as per the reconnection options
+    pub fn new(mqttoptions: MqttOptions) -> (impl Future<Item = (), Error = ()>, UserHandle) {
+        let (new_connection, eventloop_done_tx, user_handle) = Connection::prepare(mqttoptions, None);
+        let (connection, request_rx, command_rx) = new_connection();
+        let eventloop = connection
+            .mqtt_eventloop_future(request_rx, command_rx)
+            .map(move |connection| connection.eventloop_done(&eventloop_done_tx));
+
+        (eventloop, user_handle)
+    }
+
+    /// Creates the channels between the client and the eventloop. Returns a constructor of the
+    /// connection (the connection isn't `Send`) along with the user handle
+    #[allow(clippy::type_complexity)]
+    fn prepare(mqttoptions: MqttOptions, connection_tx: Option<Sender<Result<(), ConnectError>>>)
+        -> (impl FnOnce() -> (Connection, Receiver<Request>, Receiver<Command>) + Send, Sender<usize>, UserHandle) {
         let (notification_tx, notification_rx) = crossbeam_channel::bounded(mqttoptions.notification_channel_capacity());
+        let notifications_dropped = Arc::new(AtomicUsize::new(0));
+        let notification_overflow_rx = match mqttoptions.notification_overflow() {
+            NotificationOverflow::DropOldest => Some(notification_rx.clone()),
+            _ => None,
+        };
+        let notifications_blocked = Arc::new(AtomicTask::new());
+        let notification_rx = NotificationReceiver::new(notification_rx, notifications_dropped.clone(), notifications_blocked.clone());
+        let notification_listeners = Arc::new(Mutex::new(Vec::new()));
+        let listeners = notification_listeners.clone();
+        let (notification_stream_tx, notification_stream_rx) = if mqttoptions.notification_stream() {
+            let (tx, rx) = mpsc::channel::<Notification>(mqttoptions.notification_channel_capacity());
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
         let (request_tx, request_rx) = mpsc::channel::<Request>(mqttoptions.request_channel_capacity());
         let (command_tx, command_rx) = mpsc::channel::<Command>(5);
 
-        let (connection_tx, connection_rx) = crossbeam_channel::bounded(1);
-        let reconnect_option = mqttoptions.reconnect_opts();
-
-        // start the network thread to handle all mqtt network io
-        thread::spawn(move || {
-            let mqtt_state = Rc::new(RefCell::new(MqttState::new(mqttoptions.clone())));
-            let mut connection = Connection {
+        let (eventloop_done_tx, eventloop_done_rx) = crossbeam_channel::bounded(1);
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::Disconnected { reconnecting: true }));
+        let status = connection_status.clone();
+        let status_signal = Arc::new(StatusSignal::default());
+        let signal = status_signal.clone();
+        let connected_broker = Arc::new(Mutex::new(None));
+        let broker = connected_broker.clone();
+        let stats = Arc::new(Stats::new(&mqttoptions.client_id()));
+        let state_stats = stats.clone();
+        #[cfg(feature = "mqtt5")]
+        let v5 = Arc::new(crate::v5::Context::default());
+        #[cfg(feature = "mqtt5")]
+        let state_v5 = v5.clone();
+        let last_error = Arc::new(Mutex::new(None));
+        let error = last_error.clone();
+        let session_present = Arc::new(AtomicBool::new(false));
+        let session = session_present.clone();
+        let tls_info = Arc::new(Mutex::new(None));
+        let tls = tls_info.clone();
+        let final_session = Arc::new(Mutex::new(None));
+        let exported_session = final_session.clone();
+
+        let new_connection = move || {
+            let mqtt_state = Rc::new(RefCell::new(MqttState::with_stats(mqttoptions.clone(), state_stats)));
+            #[cfg(feature = "mqtt5")]
+            mqtt_state.borrow_mut().set_v5_context(state_v5);
+            let resolver = DnsResolver::new(mqttoptions.dns_cache_ttl());
+            let connection = Connection {
                 mqtt_state,
                 notification_tx,
-                connection_tx: Some(connection_tx),
-                connection_count: 0,
+                notification_overflow_rx,
+                notification_stream_tx,
+                notifications_dropped,
+                notifications_blocked,
+                notification_listeners: listeners,
+                connection_tx,
+                ever_connected: false,
+                reconnect_attempt: 0,
+                backoff_attempt: 0,
+                connected_at: None,
                 mqttoptions,
                 is_network_enabled: true,
+                connection_status: status,
+                status_signal: signal,
+                connected_broker: broker,
+                last_error: error,
+                broker_index: 0,
+                session_present: session,
+                tls_info: tls,
+                final_session: exported_session,
+                resolver,
             };
 
-            connection.mqtt_eventloop(request_rx, command_rx)
-        });
+            (connection, request_rx, command_rx)
+        };
 
-        // return user handle to client to send requests and handle notifications
         let user_handle = UserHandle {
             request_tx,
             command_tx,
             notification_rx,
+            notification_stream_rx,
+            notification_listeners,
+            eventloop_done_rx,
+            eventloop: None,
+            connection_status,
+            status_signal,
+            connected_broker,
+            last_error,
+            session_present,
+            tls_info,
+            final_session,
+            stats,
+            #[cfg(feature = "mqtt5")]
+            v5,
         };
 
-        match reconnect_option {
-            // We need to wait for a successful connection in all cases except for when we always
-            // want to reconnect
-            ReconnectOptions::AfterFirstSuccess(_) => connection_rx.recv()??,
-            ReconnectOptions::Never => connection_rx.recv()??,
-            ReconnectOptions::Always(_) => {
-                // read the result but ignore it
-                let _ = connection_rx.recv()?;
-            }
-        }
+        (new_connection, eventloop_done_tx, user_handle)
+    }
 
-        Ok(user_handle)
+    /// Marks the eventloop as stopped and reports the messages which are not acked yet
+    /// to `shutdown`
+    fn eventloop_done(&self, eventloop_done_tx: &Sender<usize>) {
+        let session = self.mqtt_state.borrow().export_session();
+        *self.final_session.lock().unwrap_or_else(|e| e.into_inner()) = Some(session);
+        self.status_signal.stop();
+        self.set_connection_status(ConnectionStatus::Disconnected { reconnecting: false });
+        let unacked = self.mqtt_state.borrow().unacked_len();
+        let _ = eventloop_done_tx.try_send(unacked);
     }
 
-    /// Main mqtt event loop. Handles reconnection requests from `connect_or_not` and `mqtt_io`
-    fn mqtt_eventloop(&mut self, request_rx: Receiver<Request>, mut command_rx: Receiver<Command>) {
-        let network_request_stream = request_rx.map_err(|_| NetworkError::Blah);
-        let mut network_request_stream = network_request_stream.prependable();
-        let mut command_stream = self.command_stream(command_rx.by_ref());
+    /// Tells the user why the eventloop died. The channels of the eventloop are already
+    /// dropped by the unwind, so the client sees the eventloop as terminated
+    fn handle_panic(&mut self, panic: Box<dyn Any + Send>) {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => match panic.downcast::<&'static str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "Unknown panic".to_owned(),
+            },
+        };
 
-        'reconnection: loop {
-            let mqtt_connect_future = self.mqtt_connect();
-            let (runtime, framed) = match self.connect_or_not(mqtt_connect_future) {
-                Ok(f) => f,
-                Err(true) => continue 'reconnection,
-                Err(false) => break 'reconnection,
-            };
+        error!("Eventloop panicked. Error = {}", message);
+        // unblocks `run` when the panic happened before the initial connection
+        self.connection_tx.take();
+        self.notify(Notification::EventloopCrashed(message));
+    }
 
-            let network_request_stream = &mut network_request_stream;
-            // Insert previous session. If this is the first connect, the buffer in
-            // network_request_stream is empty.
-            network_request_stream.insert(self.mqtt_state.borrow_mut().handle_reconnection());
+    /// Main mqtt event loop. Reconnects as per the reconnection options and user commands.
+    /// Waits on timers instead of blocking the thread. Resolves to the connection once the
+    /// eventloop stops
+    fn mqtt_eventloop_future(self, request_rx: Receiver<Request>, command_rx: Receiver<Command>) -> impl Future<Item = Connection, Error = ()> {
+        // streams outlive every connection and are shared by the connection futures
+        let requests = Rc::new(RefCell::new(user_request_stream(request_rx).prependable()));
+        let commands = Rc::new(RefCell::new(command_stream(command_rx)));
+
+        future::loop_fn(self, move |mut connection| {
+            let requests = requests.clone();
+            let commands = commands.clone();
+
+            let connect = connection.connect_or_not(connection.mqtt_connect());
+            connect.then(move |o| {
+                let framed = match connection.handle_connect_result(o) {
+                    Ok(framed) => framed,
+                    Err(next) => return Either::A(connection.reconnect(next, commands)),
+                };
+
+                // Insert previous session before the user requests. Replays of the last
+                // reconnection which aren't sent yet are newer than the ones in the state
+                let replay = connection.mqtt_state.borrow_mut().handle_reconnection();
+                requests.borrow_mut().prepend(replay.into_iter().map(|request| Request::Replay(Box::new(request))));
+                connection.notify_expired();
+                let mqtt_future = connection.mqtt_future(SharedStream(commands.clone()), SharedStream(requests), framed);
+                Either::B(mqtt_future.then(move |o| {
+                    let next = connection.handle_eventloop_end(o);
+                    connection.reconnect(next, commands)
+                }))
+            })
+        })
+    }
 
-            let mqtt_future = self.mqtt_future(&mut command_stream, network_request_stream, framed);
+    /// Waits on a timer before the next iteration of `mqtt_eventloop_future` as per `next`.
+    /// Commands received while waiting end the wait
+    fn reconnect<S>(mut self, next: Reconnect, commands: Rc<RefCell<S>>) -> impl Future<Item = Loop<Connection, Connection>, Error = ()>
+    where
+        S: Stream<Item = Packet, Error = NetworkError>,
+    {
+        let delay = match next {
+            Reconnect::Now => return Either::A(future::ok(Loop::Continue(self))),
+            Reconnect::Never => return Either::A(future::ok(Loop::Break(self))),
+            Reconnect::Backoff => match self.next_reconnect_delay() {
+                Some(delay) => delay,
+                None => return Either::A(future::ok(Loop::Break(self))),
+            },
+        };
 
-            match self.mqtt_io(runtime, mqtt_future) {
-                Err(true) => continue 'reconnection,
-                Err(false) => break 'reconnection,
-                Ok(_v) => continue 'reconnection,
+        let wait = Delay::new(Instant::now() + delay).map_err(NetworkError::Timer);
+        // commands end in an error. a closed command channel leaves only the timer
+        let command = SharedStream(commands)
+            .into_future()
+            .map_err(|(e, _)| e)
+            .and_then(|_| future::empty());
+
+        let f = wait.select(command).then(move |o| -> Result<_, ()> {
+            match o {
+                Err((NetworkError::UserShutdown, _)) => return Ok(Loop::Break(self)),
+                Err((NetworkError::UserDisconnect, _)) => self.is_network_enabled = false,
+                Err((NetworkError::UserReconnect, _)) => {
+                    self.mqttoptions = self.mqtt_state.borrow().opts.clone();
+                    self.is_network_enabled = true;
+                }
+                Err((e, _)) => error!("Reconnection wait error = {:?}", e),
+                Ok(_) => (),
             }
-        }
-    }
 
+            Ok(Loop::Continue(self))
+        });
+
+        Either::B(f)
+    }
 
-    /// Makes a blocking mqtt connection an returns framed and reactor which created
-    /// the connection when `is_network_enabled` flag is set true
-    fn connect_or_not(&mut self, mqtt_connect_future: impl Future<Item = MqttFramed, Error = ConnectError>) -> Result<(Runtime, Option<MqttFramed>), bool> {
-        let mut rt = Runtime::new().unwrap();
-        let mqtt_connect_deadline = Timeout::new(mqtt_connect_future, self.mqttoptions.connection_timeout());
 
+    /// Makes an mqtt connection within the connection timeout when `is_network_enabled`
+    /// flag is set true. Resolves to `None` (without connecting) otherwise
+    fn connect_or_not(&self, mqtt_connect_future: impl Future<Item = MqttFramed, Error = ConnectError>)
+        -> impl Future<Item = Option<MqttFramed>, Error = timeout::Error<ConnectError>> {
         if !self.is_network_enabled {
-            return Ok((rt, None));
+            return Either::A(future::ok(None));
         }
 
-        let framed = match rt.block_on(mqtt_connect_deadline) {
-            Ok(framed) => {
+        let timeout = self.mqttoptions.connect_timeout();
+        Either::B(Timeout::new(mqtt_connect_future, timeout).map(Some))
+    }
+
+    /// Handles the outcome of `connect_or_not`. Returns the connection or the next step
+    /// of the eventloop after a failed connection
+    fn handle_connect_result(&mut self, o: Result<Option<MqttFramed>, timeout::Error<ConnectError>>) -> Result<Option<MqttFramed>, Reconnect> {
+        match o {
+            Ok(Some(framed)) => {
                 info!("Mqtt connection successful!!");
                 self.handle_connection_success();
-                framed
+                Ok(Some(framed))
+            }
+            Ok(None) => {
+                self.set_connection_status(ConnectionStatus::Disconnected { reconnecting: false });
+                Ok(None)
             }
             Err(e) => {
                 error!("Connection error = {:?}", e);
-                self.handle_connection_error(e);
-                return Err(self.should_reconnect_again());
-            }
-        };
-
-        Ok((rt, Some(framed)))
-    }
-
-    /// Tells whether eventloop should try to reconnect or not based
-    /// user reconnection configuration
-    fn should_reconnect_again(&self) -> bool {
-        let reconnect_options = self.mqttoptions.reconnect_opts();
-
-        match reconnect_options {
-            ReconnectOptions::Always(time) => {
-                let time = Duration::from_secs(time);
-                thread::sleep(time);
-                true
-            }
-            ReconnectOptions::AfterFirstSuccess(time) => {
-                // should reconnect only if initial connection was successful
-                let reconnect = self.connection_count > 0;
-                if reconnect {
-                    let time = Duration::from_secs(time);
-                    thread::sleep(time);
+                if self.handle_connection_error(e) {
+                    return Err(Reconnect::Never);
                 }
 
-                reconnect
+                Err(Reconnect::Backoff)
             }
-            ReconnectOptions::Never => false,
         }
     }
 
-    /// Ananlyses the eventloop return cases and decides if a reconnection is necessary
-    /// or not based on user commands like shutdown, disconnect and reconnect and reconnect
-    /// options.
-    /// Err(true) -> Reconnect
-    /// Err(false) -> Don't reconnect
-    fn mqtt_io(&mut self, mut runtime: Runtime, mqtt_future: impl Future<Item = (), Error = NetworkError>) -> Result<(), bool> {
-        let o = runtime.block_on(mqtt_future);
-        if let Err(e) = self.notification_tx.try_send(Notification::Disconnection) {
-            error!("Notification failure. Error = {:?}", e);
+    /// Time to wait before the next reconnection as per user reconnection configuration.
+    /// `None` when the eventloop shouldn't reconnect
+    fn next_reconnect_delay(&mut self) -> Option<Duration> {
+        let policy = match self.ever_connected {
+            true => self.mqttoptions.reconnect_policy(),
+            false => self.mqttoptions.initial_connect_policy(),
+        };
+
+        // failures of a connection which was up for a while start a fresh backoff
+        let threshold = self.mqttoptions.stable_connection_threshold();
+        if self.connected_at.take().map_or(false, |t| t.elapsed() >= threshold) {
+            self.backoff_attempt = 0;
         }
 
-        if let Err(e) = o {
-            debug!("Eventloop stopped with error. {:?}", e);
+        let time = match policy {
+            RetryPolicy::Interval(time) => time,
+            RetryPolicy::ExponentialBackoff { initial, max, multiplier, jitter } => {
+                let time = backoff_delay(initial, max, multiplier, jitter, self.backoff_attempt, rand::random());
+                self.backoff_attempt = self.backoff_attempt.saturating_add(1);
+                time
+            }
+            RetryPolicy::Never => return None,
+        };
 
-            return match e {
-                NetworkError::UserDisconnect => {
-                    self.is_network_enabled = false;
-                    Err(true)
-                }
-                NetworkError::UserReconnect => {
-                    self.is_network_enabled = true;
-                    Err(true)
-                }
-                NetworkError::NetworkStreamClosed if self.mqtt_state.borrow().is_disconnecting() => {
+        self.reconnect_attempt += 1;
+        self.mqtt_state.borrow().stats().reconnect_attempt();
+        let attempt = self.reconnect_attempt;
+        let error = self.last_error.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        self.notify(Notification::Reconnecting { attempt, delay: time, error });
+        hooks::dispatch(&self.mqttoptions, |handler| handler.on_reconnect_attempt(attempt, time));
+        Some(time)
+    }
+
+    /// Notifies the end of a connection and decides the next step based on user commands
+    /// like shutdown, disconnect and reconnect
+    fn handle_eventloop_end(&mut self, o: Result<(), NetworkError>) -> Reconnect {
+        self.set_connection_status(ConnectionStatus::Disconnected { reconnecting: true });
+        let disconnecting = self.mqtt_state.borrow().is_disconnecting();
+        let reason = match &o {
+            Ok(_) if disconnecting => "Disconnected".to_owned(),
+            Ok(_) => "Eventloop stopped".to_owned(),
+            Err(e) => e.to_string(),
+        };
+
+        // messages which weren't acked while draining are reported with the disconnection
+        let reason = if disconnecting {
+            format!("{}. Unacked messages = {}", reason, self.mqtt_state.borrow().unacked_len())
+        } else {
+            reason
+        };
+        // replays dropped by the ttl during the connection
+        self.notify_expired();
+        hooks::dispatch(&self.mqttoptions, |handler| handler.on_disconnect(&reason));
+        self.notify(Notification::Disconnected(reason));
+
+        let e = match o {
+            Ok(_) => {
+                debug!("Eventloop stopped without error");
+                if disconnecting {
                     self.is_network_enabled = false;
-                    Err(false)
-                }
-                NetworkError::NetworkStreamClosed => {
-                    self.is_network_enabled = true;
-                    Err(self.should_reconnect_again())
-                }
-                _ => {
-                    self.is_network_enabled = true;
-                    Err(self.should_reconnect_again())
+                    return Reconnect::Never;
                 }
+
+                self.set_last_error(ErrorSummary::from(&NetworkError::NetworkStreamClosed));
+                return Reconnect::Backoff;
             }
-        }
+            Err(e) => e,
+        };
 
-        if let Ok(_v) = o {
-            debug!("Eventloop stopped without error");
-            return Err(self.should_reconnect_again())
-        }
+        debug!("Eventloop stopped with error. {:?}", e);
 
-        Ok(())
+        match e {
+            NetworkError::UserDisconnect => {
+                self.is_network_enabled = false;
+                Reconnect::Now
+            }
+            NetworkError::UserReconnect => {
+                // pick options updated by a user reconnect request for the next connection
+                self.mqttoptions = self.mqtt_state.borrow().opts.clone();
+                self.is_network_enabled = true;
+                Reconnect::Now
+            }
+            // reconnect immediately. next connect packet carries a fresh jwt
+            NetworkError::JwtExpiring => Reconnect::Now,
+            NetworkError::UserShutdown => {
+                self.is_network_enabled = false;
+                Reconnect::Never
+            }
+            // all the clients are dropped. nobody can use the connection anymore
+            NetworkError::UserRequestChannelClosed => {
+                self.is_network_enabled = false;
+                Reconnect::Never
+            }
+            // connection broke while draining the messages before disconnect
+            _ if disconnecting => {
+                self.is_network_enabled = false;
+                Reconnect::Never
+            }
+            _ => {
+                self.is_network_enabled = true;
+                self.set_last_error(ErrorSummary::from(&e));
+                if !self.mqttoptions.sticky_broker() {
+                    self.next_broker();
+                }
+
+                Reconnect::Backoff
+            }
+        }
     }
 
     /// Applies throttling and inflight limiting based on user configuration and returns
@@ -223,7 +529,9 @@ impl Connection {
         let network_request_stream = self.inflight_limited_request_stream(network_request_stream);
         let network_request_stream = self.throttled_network_stream(network_request_stream);
         let network_request_stream = self.user_requests(network_request_stream);
+        let network_request_stream = self.drained_requests(network_request_stream);
         let network_request_stream = network_request_stream.and_then(move |packet| future::ok(packet.into()));
+        let command_stream = filter_commands(command_stream, framed.is_some());
 
         // check if the network is enabled and create a future
         let network = match framed {
@@ -231,6 +539,7 @@ impl Connection {
                 let (network_sink, network_stream) = f.split();
                 let network_sink = network_sink.sink_map_err(NetworkError::Io);
                 let network_reply_stream = self.network_reply_stream(network_stream);
+                let command_stream = command_stream.select(self.jwt_refresh_stream()).select(self.retransmit_stream());
                 Ok((network_reply_stream, network_sink, command_stream))
             }
             None => Err(command_stream),
@@ -240,9 +549,18 @@ impl Connection {
             Ok((network_reply_stream, network_sink, command_stream)) => {
                 // convert rquests to packets
                 let network_reply_stream = network_reply_stream.map(|r| r.into());
-                let network_stream = network_reply_stream.select(network_request_stream);
-                let stream = command_stream.select(network_stream);
-                let f = stream.forward(network_sink).map(|_| ());
+                // acks, pings and commands are protocol critical. they are written before
+                // the queued user requests so that a burst of publishes can't delay them
+                let priority_stream = command_stream.select(network_reply_stream);
+                let stream = priority_stream.prioritized(network_request_stream);
+                let stream = until_disconnect(stream);
+                let f = match self.mqttoptions.write_buffer_size() {
+                    0 => Either::A(stream.forward(network_sink).map(|_| ())),
+                    size => {
+                        let network_sink = network_sink.write_buffered(size, WRITE_LINGER);
+                        Either::B(stream.forward(network_sink).map(|_| ()))
+                    }
+                };
                 Either::A(f)
             }
             Err(command_stream) => {
@@ -253,49 +571,263 @@ impl Connection {
         }
     }
 
+    /// Updates the connection status shared with the client
+    fn set_connection_status(&self, status: ConnectionStatus) {
+        let (broker, tls) = match status {
+            ConnectionStatus::Connected => (Some(self.broker()), self.mqtt_state.borrow().tls_info()),
+            ConnectionStatus::Disconnected { .. } => (None, None),
+        };
+
+        match self.connected_broker.lock() {
+            Ok(mut connected_broker) => *connected_broker = broker,
+            Err(e) => *e.into_inner() = broker,
+        }
+
+        match self.tls_info.lock() {
+            Ok(mut tls_info) => *tls_info = tls,
+            Err(e) => *e.into_inner() = tls,
+        }
+
+        match self.connection_status.lock() {
+            Ok(mut connection_status) => *connection_status = status,
+            Err(e) => *e.into_inner() = status,
+        }
+
+        self.status_signal.notify();
+    }
+
+    /// Saves the reason of the last disconnection for the client
+    fn set_last_error(&self, error: ErrorSummary) {
+        match self.last_error.lock() {
+            Ok(mut last_error) => *last_error = Some(error),
+            Err(e) => *e.into_inner() = Some(error),
+        }
+    }
+
+    /// Broker to connect to
+    fn broker(&self) -> (String, u16) {
+        let brokers = self.mqttoptions.brokers();
+        brokers[self.broker_index % brokers.len()].clone()
+    }
+
+    /// Moves to the next broker of the list for the next connection attempt
+    fn next_broker(&mut self) {
+        self.broker_index = (self.broker_index + 1) % self.mqttoptions.brokers().len();
+    }
+
+    /// Notifies the publishes which were dropped by the queued message ttl
+    fn notify_expired(&mut self) {
+        let expired = self.mqtt_state.borrow_mut().take_expired();
+        for notification in expired {
+            self.notify(notification);
+        }
+    }
+
+    /// Sends a notification to the user. Notifications which don't fit in the
+    /// channel are dropped and counted
+    fn notify(&mut self, notification: Notification) {
+        broadcast(&self.notification_listeners, &notification);
+
+        // this is called outside the runtime. can't wait for space in the stream
+        if let Some(tx) = self.notification_stream_tx.as_mut() {
+            if let Err(e) = tx.try_send(notification) {
+                error!("Notification failure. Error = {:?}", e);
+                if e.is_full() {
+                    self.notifications_dropped.fetch_add(1, Ordering::SeqCst);
+                    self.mqtt_state.borrow().stats().notification_dropped();
+                }
+            }
+
+            return;
+        }
+
+        if let Err(e) = self.notification_tx.try_send(notification) {
+            error!("Notification failure. Error = {:?}", e);
+            if let TrySendError::Full(notification) = e {
+                self.notifications_dropped.fetch_add(1, Ordering::SeqCst);
+                self.mqtt_state.borrow().stats().notification_dropped();
+                if let Some(rx) = self.notification_overflow_rx.as_ref() {
+                    let _ = rx.try_recv();
+                    let _ = self.notification_tx.try_send(notification);
+                }
+            }
+        }
+    }
+
     /// Sends connection status on blocked connections status call in `run`
     fn handle_connection_success(&mut self) {
-        // send connection success notification only the first time
+        // session of the connection is visible to the clients which wait for it
+        let session_present = self.mqtt_state.borrow().session_present();
+        self.session_present.store(session_present, Ordering::SeqCst);
+        self.set_connection_status(ConnectionStatus::Connected);
+
+        // send connection success to `run` only the first time
         if let Some(connection_tx) = self.connection_tx.take() {
-            connection_tx.try_send(Ok(())).unwrap();
+            let _ = connection_tx.try_send(Ok(()));
+        }
+
+        let tls = self.mqtt_state.borrow().tls_info();
+        let info = ConnectionInfo { broker: self.broker(), session_present, tls };
+        hooks::dispatch(&self.mqttoptions, |handler| handler.on_connect(&info));
+        self.notify(Notification::Connected(info));
+        if self.ever_connected {
+            self.mqtt_state.borrow().stats().reconnected();
         } else {
-            let _ = self.notification_tx.try_send(Notification::Reconnection);
+            // backoff of the initial connection doesn't carry over to the reconnections
+            self.backoff_attempt = 0;
         }
 
-        self.connection_count += 1;
+        self.ever_connected = true;
+        self.reconnect_attempt = 0;
+        self.connected_at = Some(Instant::now());
     }
 
     /// Sends connection status on blocked connections status call in `run`
     /// TODO: Combine both
-    fn handle_connection_error(&mut self, error: timeout::Error<ConnectError>) {
+    /// Returns true when the broker (or proxy) refused the connection with a fatal code
+    fn handle_connection_error(&mut self, error: timeout::Error<ConnectError>) -> bool {
         let error = match error.into_inner() {
-            Some(e) => Err(e),
-            None => Err(ConnectError::Timeout),
+            Some(e) => e,
+            None => ConnectError::Timeout,
         };
 
-        // send connection error notification only the first time
-        if let Some(connection_tx) = self.connection_tx.take() {
-            connection_tx.try_send(error).unwrap();
+        // retrying credentials which will never work only gets the client rate limited
+        self.set_last_error(ErrorSummary::from(&error));
+
+        let fatal = match error {
+            ConnectError::MqttConnectionRefused(code) if self.mqttoptions.fatal_refusals().contains(&code) => {
+                error!("Connection refused. Not reconnecting. Code = {}", code);
+                self.notify(Notification::ConnectionRefused(code));
+                true
+            }
+            ConnectError::ProxyConnect { status, .. } if self.is_fatal_proxy_status(status) => {
+                error!("Proxy refused the tunnel. Not reconnecting. Status = {}", status);
+                self.notify(Notification::ProxyRefused(status));
+                true
+            }
+            _ => false,
+        };
+
+        self.set_connection_status(ConnectionStatus::Disconnected { reconnecting: !fatal });
+        self.next_broker();
+
+        // send connection error to `run` only the first time. nobody waits on the first
+        // attempt of the other eventloops, so its failure is notified instead
+        let first_attempt = !self.ever_connected && self.reconnect_attempt == 0;
+        match self.connection_tx.take() {
+            Some(connection_tx) => {
+                let _ = connection_tx.try_send(Err(error));
+            }
+            None if first_attempt => self.notify(Notification::ConnectFailed(ErrorSummary::from(&error))),
+            None => (),
         }
+
+        fatal
+    }
+
+    /// Proxy statuses which stop the reconnections. Credentials of the environment are
+    /// read again for every attempt. Authentication failures with them aren't fatal
+    fn is_fatal_proxy_status(&self, status: u16) -> bool {
+        let env_auth = match self.mqttoptions.proxy() {
+            Proxy::FromEnv => status == 407,
+            _ => false,
+        };
+
+        !env_auth && self.mqttoptions.fatal_proxy_statuses().contains(&status)
     }
 
-    /// Resolves dns with blocking API and composes a future which makes a new tcp
-    /// or tls connection to the broker. Note that this doesn't actual connect to the
-    /// broker
+    /// Composes a future which resolves dns (off the eventloop thread) and makes a
+    /// new tcp or tls connection to the broker. Note that this doesn't actual connect
+    /// to the broker
     fn tcp_connect_future(&self) -> impl Future<Item = MqttFramed, Error = ConnectError> {
-        let (host, port) = self.mqttoptions.broker_address();
+        let (host, port) = self.broker();
         let proxy = self.mqttoptions.proxy();
 
         let builder = NetworkStream::builder();
 
-        let builder = if let Some(ca) = self.mqttoptions.ca() {
-            let mut builder = builder.add_certificate_authority(&ca);
+        #[cfg(feature = "danger-insecure-tls")]
+        let builder = builder.danger_accept_invalid_certs(self.mqttoptions.accept_invalid_certs());
+
+        #[cfg(feature = "webpki-roots")]
+        let builder = match self.mqttoptions.webpki_roots() {
+            true => builder.add_webpki_roots(),
+            false => builder,
+        };
+
+        let builder = builder
+            .set_tcp_options(self.mqttoptions.tcp_options())
+            .set_address_preference(self.mqttoptions.address_preference())
+            .set_address_timeout(self.mqttoptions.address_timeout())
+            .set_dns_timeout(self.mqttoptions.dns_timeout())
+            .set_resolver(self.resolver.clone());
+
+        let builder = match self.mqttoptions.bind_address() {
+            Some(address) => builder.set_bind_address(address),
+            None => builder,
+        };
+
+        #[cfg(unix)]
+        let builder = match self.mqttoptions.unix_socket() {
+            Some(path) => builder.set_unix_socket(path),
+            None => builder,
+        };
+
+        let builder = match self.mqttoptions.custom_transport() {
+            Some(connector) => builder.set_custom_transport(connector),
+            None => builder,
+        };
+
+        #[cfg(feature = "websocket")]
+        let builder = match self.mqttoptions.websocket() {
+            Some(path) => builder.set_websocket(&path),
+            None => builder,
+        };
+
+        // certificates of the provider are loaded afresh to pick up rotations
+        let (ca, client_auth) = match self.mqttoptions.cert_provider() {
+            Some(provider) => match provider.certificates() {
+                Ok(certificates) => (certificates.ca, certificates.client_auth),
+                Err(e) => return Either::B(future::err(ConnectError::CertificateProvider(e))),
+            },
+            None => (self.mqttoptions.ca(), self.mqttoptions.client_auth()),
+        };
+
+        let verifiable = ca.is_some() || self.mqttoptions.accept_invalid_certs() || self.mqttoptions.webpki_roots();
+        let tls = self.mqttoptions.tls() || verifiable;
+        let builder = if tls {
+            let mut builder = match ca {
+                Some(ca) => builder.add_certificate_authority(&ca),
+                None => builder,
+            };
+
+            if self.mqttoptions.tls() {
+                builder = builder.require_tls();
+            }
+
+            if let Some(hostname) = self.mqttoptions.tls_hostname() {
+                builder = builder.set_tls_hostname(&hostname);
+            }
+
             if let Some(alpn) = self.mqttoptions.alpn() {
                 builder = builder.add_alpn_protocols(&alpn);
             }
 
-            if let Some((cert, key)) = self.mqttoptions.client_auth() {
+            if let Some((cert, key)) = client_auth {
                 builder = builder.add_client_auth(&cert, &key);
+
+                #[cfg(feature = "encrypted-keys")]
+                {
+                    if let Some(passphrase) = self.mqttoptions.client_key_passphrase() {
+                        builder = builder.add_client_auth_encrypted(&cert, &key, &passphrase);
+                    }
+                }
+            }
+
+            #[cfg(feature = "pkcs12")]
+            {
+                if let Some((identity, password)) = self.mqttoptions.client_identity_pkcs12() {
+                    builder = builder.add_client_identity_pkcs12(&identity, &password);
+                }
             }
 
             builder
@@ -304,34 +836,128 @@ impl Connection {
         };
 
 
+        let builder = match self.mqttoptions.proxy_tls() {
+            Some(config) => builder.set_proxy_tls(config),
+            None => builder,
+        };
+
         let builder = match proxy {
             Proxy::None => builder,
             Proxy::HttpConnect(proxy_host, proxy_port, key, expiry) => {
                 let id = self.mqttoptions.client_id();
                 builder.set_http_proxy(&id, &proxy_host, proxy_port, &key, expiry)
             }
+            Proxy::HttpConnectBasic { host: proxy_host, port: proxy_port, credentials } => {
+                builder.set_http_proxy_basic(&proxy_host, proxy_port, credentials)
+            }
+            // variables are read for every connection attempt
+            Proxy::FromEnv => match env_proxy(&host, port, tls) {
+                Some(proxy) => {
+                    let builder = builder.set_http_proxy_basic(&proxy.host, proxy.port, proxy.credentials);
+                    match proxy.tls && self.mqttoptions.proxy_tls().is_none() {
+                        true => builder.set_proxy_tls(ProxyTlsConfig::default()),
+                        false => builder,
+                    }
+                }
+                None => builder,
+            },
         };
 
-        builder.connect(&host, port)
+        Either::A(builder.connect(&host, port))
     }
 
     /// Composes a new future which is a combination of tcp connect + mqtt handshake
     fn mqtt_connect(&self) -> impl Future<Item = MqttFramed, Error = ConnectError> {
         let mqtt_state = self.mqtt_state.clone();
-        let tcp_connect_future = self.tcp_connect_future();
-        let connect_packet = self.mqtt_state.borrow_mut().handle_outgoing_connect().unwrap();
+        let stats = self.mqtt_state.borrow().stats();
+        let max_packet_size = self.mqttoptions.max_incoming_packet_size();
+        #[cfg(feature = "mqtt5")]
+        let v5 = match self.mqttoptions.protocol() {
+            crate::mqttoptions::Protocol::MQTT5 => Some(self.mqtt_state.borrow().v5_context()),
+            _ => None,
+        };
+        let state = self.mqtt_state.clone();
+        let tcp_connect_future = self.tcp_connect_future().map(move |mut framed| {
+            state.borrow_mut().set_tls_info(framed.get_ref().tls_info());
+            framed.codec_mut().set_stats(stats);
+            framed.codec_mut().set_max_packet_size(max_packet_size);
+            #[cfg(feature = "mqtt5")]
+            {
+                if let Some(context) = v5 {
+                    framed.codec_mut().set_v5(context);
+                }
+            }
+            framed
+        });
+        let connect_packet = match self.mqtt_state.borrow_mut().handle_outgoing_connect() {
+            Ok(packet) => packet,
+            Err(e) => return Either::B(future::err(e)),
+        };
 
-        tcp_connect_future
-            .and_then(move |framed| {
-                let packet = Packet::Connect(connect_packet);
-                framed.send(packet).map_err(ConnectError::Io)
+        let binary_password = match self.mqtt_state.borrow().opts.security_opts() {
+            SecurityOptions::UsernamePasswordBytes(_, password) => Some(password),
+            _ => None,
+        };
+
+        let connect = tcp_connect_future
+            .and_then(move |framed| match binary_password {
+                // mqtt311 can't encode a binary password. write the connect directly to the stream
+                Some(password) => {
+                    // codec (stats, max packet size) frames the stream again after the connect
+                    let parts = framed.into_parts();
+                    let (stream, codec) = (parts.io, parts.codec);
+                    let connect = future::result(codec.encode_connect(&connect_packet, &password))
+                        .and_then(move |connect| tokio::io::write_all(stream, connect))
+                        .and_then(|(stream, _)| tokio::io::flush(stream))
+                        .map(move |stream| codec.framed(stream))
+                        .map_err(ConnectError::Io);
+                    Either::A(connect)
+                }
+                None => {
+                    let packet = Packet::Connect(connect_packet);
+                    Either::B(framed.send(packet).map_err(ConnectError::Io))
+                }
             })
             .and_then(|framed| framed.into_future().map_err(|(err, _framed)| ConnectError::Io(err)))
             .and_then(move |(response, framed)| {
                 info!("Mqtt connect response = {:?}", response);
                 let mut mqtt_state = mqtt_state.borrow_mut();
                 check_and_validate_connack(response, framed, &mut mqtt_state)
+            });
+
+        Either::A(connect)
+    }
+
+    /// Periodically retransmits qos1 publishes which aren't acked within the ack timeout.
+    /// Empty when the ack timeout isn't set
+    fn retransmit_stream(&self) -> impl Stream<Item = Packet, Error = NetworkError> {
+        let timeout = match self.mqttoptions.ack_timeout() {
+            Some(timeout) if timeout > Duration::from_secs(0) => timeout,
+            _ => return Either::B(stream::empty()),
+        };
+
+        let mqtt_state = self.mqtt_state.clone();
+        let retransmits = Interval::new(Instant::now() + timeout, timeout)
+            .map_err(NetworkError::Timer)
+            .map(move |_| {
+                let publishes = mqtt_state.borrow_mut().handle_ack_timeout(timeout);
+                stream::iter_ok::<_, NetworkError>(publishes.into_iter().map(Packet::Publish))
             })
+            .flatten();
+
+        Either::A(retransmits)
+    }
+
+    /// Errors with `JwtExpiring` shortly before the jwt of the current connection
+    /// expires. Empty when the connection doesn't use a jwt
+    fn jwt_refresh_stream(&self) -> impl Stream<Item = Packet, Error = NetworkError> {
+        match self.mqtt_state.borrow().jwt_refresh_at() {
+            Some(deadline) => {
+                let refresh = Delay::new(deadline).then(|_| Err::<Packet, _>(NetworkError::JwtExpiring));
+                Either::A(refresh.into_stream())
+            }
+            None => Either::B(stream::empty()),
+        }
     }
 
     /// Handles all incoming network packets (including sending notifications to user over crossbeam
@@ -342,25 +968,102 @@ impl Connection {
 
         let keep_alive = self.mqttoptions.keep_alive();
         let notification_tx = self.notification_tx.clone();
+        let notification_overflow_rx = self.notification_overflow_rx.clone();
+        let notification_overflow = self.mqttoptions.notification_overflow();
+        let notification_stream_tx = self.notification_stream_tx.clone();
+        let notifications_dropped = self.notifications_dropped.clone();
+        let notifications_blocked = self.notifications_blocked.clone();
+        let notification_listeners = self.notification_listeners.clone();
+
+        // codec skips packets bigger than the limit. connection stays up
+        let network_stream = network_stream.then(|packet| match packet {
+            Ok(packet) => Ok(Ok(packet)),
+            Err(e) => PacketTooLarge::from_io_error(e).map(Err),
+        });
 
-        let network_stream = network_stream.timeout(keep_alive)
-            .or_else(move |e| {
+        // zero keep alive disables pings. network is never idle
+        let network_stream = if keep_alive == Duration::from_secs(0) {
+            Either::A(network_stream.map_err(NetworkError::Io))
+        } else {
+            let network_stream = network_stream.timeout(keep_alive).or_else(move |e| {
                 debug!("Idle network incoming timeout");
                 let mut mqtt_state = mqtt_state_ping.borrow_mut();
-                handle_incoming_stream_timeout_error(e, &mut mqtt_state)
-            })
+                handle_incoming_stream_timeout_error(e, &mut mqtt_state).map(Ok)
+            });
+
+            Either::B(network_stream)
+        };
+
+        let mqtt_state_rec = self.mqtt_state.clone();
+        let network_stream = network_stream
             .and_then(move |packet| {
-                debug!("Incoming packet = {:?}", packet_info(&packet));
-                let reply = mqtt_state.borrow_mut().handle_incoming_mqtt_packet(packet);
+                let reply = match packet {
+                    Ok(packet) => {
+                        debug!("Incoming packet = {:?}", packet_info(&packet));
+                        mqtt_state.borrow_mut().handle_incoming_mqtt_packet(packet)
+                    }
+                    Err(PacketTooLarge { topic, size }) => Ok((Notification::PayloadTooLarge { topic, size }, Request::None)),
+                };
+
                 future::result(reply)
             })
             .and_then(move |(notification, reply)| {
-                handle_notification_and_reply(&notification_tx, notification, reply)
+                broadcast(&notification_listeners, &notification);
+                let rec = match reply {
+                    Request::PubRec(pkid) => Some(pkid),
+                    _ => None,
+                };
+
+                let mqtt_state = mqtt_state_rec.clone();
+                let paused_state = mqtt_state_rec.clone();
+                // network isn't read while the notification waits for space. only the
+                // eventloop sends, so a full channel stays full until the send below
+                let paused = match notification {
+                    Notification::None => false,
+                    _ => {
+                        notification_overflow == NotificationOverflow::Block
+                            && notification_stream_tx.is_none()
+                            && notification_tx.is_full()
+                    }
+                };
+
+                if paused {
+                    paused_state.borrow_mut().set_inbound_paused(true);
+                }
+
+                handle_notification_and_reply(
+                    &notification_tx,
+                    notification_overflow_rx.as_ref(),
+                    notification_overflow,
+                    notification_stream_tx.as_ref(),
+                    &notifications_dropped,
+                    &notifications_blocked,
+                    notification,
+                    reply,
+                )
+                .then(move |o| {
+                    if paused {
+                        paused_state.borrow_mut().set_inbound_paused(false);
+                    }
+
+                    o
+                })
+                .map_err(move |e| {
+                    // undelivered qos2 publish should be delivered when the broker resends it
+                    if let (NetworkError::ReceiverCatchup, Some(pkid)) = (&e, rec) {
+                        mqtt_state.borrow_mut().forget_incoming_publish(pkid);
+                    }
+
+                    e
+                })
             })
             .filter(|reply| should_forward_packet(reply));
 
         let network_reply_stream = network_stream.chain(stream::once(Err(NetworkError::NetworkStreamClosed)));
         let mqtt_state = self.mqtt_state.clone();
+        if keep_alive == Duration::from_secs(0) {
+            return Either::A(network_reply_stream);
+        }
 
         // when there are no outgoing replies, timeout should check if a ping is
         // necessary. E.g If there are only qos0 incoming publishes,
@@ -370,13 +1073,15 @@ impl Connection {
         // and this timeout doesn't happen
         // When there are only qos0 incoming publishes, this timeout alone triggers
         let timeout = keep_alive + Duration::from_millis(500);
-        network_reply_stream.timeout(timeout)
+        let network_reply_stream = network_reply_stream.timeout(timeout)
             .or_else(move |e| {
                 debug!("Idle network reply timeout");
                 let mut mqtt_state = mqtt_state.borrow_mut();
                 handle_outgoing_stream_timeout_error(e, &mut mqtt_state)
             })
-            .filter(|reply| should_forward_packet(reply))
+            .filter(|reply| should_forward_packet(reply));
+
+        Either::B(network_reply_stream)
     }
 
     /// Handles all incoming user and session requests and creates a stream of packets to send
@@ -391,18 +1096,82 @@ impl Connection {
         let request_stream = request
             .map_err(|e| {
                 error!("User request error = {:?}", e);
-                NetworkError::Blah
+                e
             })
             .and_then(move |userrequest| {
                 let mut mqtt_state = mqtt_state.borrow_mut();
                 validate_userrequest(userrequest, &mut mqtt_state)
-            });
+            })
+            .filter_map(|packet| packet);
+
+        // publishes dropped by the ttl are summarized once the queued requests are drained
+        let mut request_stream = request_stream;
+        let mut notifier = self.clone();
+        let request_stream = poll_fn(move || -> Poll<Option<Request>, NetworkError> {
+            let request = request_stream.poll();
+            if let Ok(Async::NotReady) = request {
+                notifier.notify_expired();
+            }
+
+            request
+        });
+
+        let mqtt_state = self.mqtt_state.clone();
+        request_stream
+            .and_then(move |request: Request| {
+                let mut mqtt_state = mqtt_state.borrow_mut();
+                // publishes of a batch take their packet ids (and are saved for
+                // retransmission) together. nothing else is handled in between
+                let packets: Result<Vec<Request>, NetworkError> = expand_batch(request)
+                    .into_iter()
+                    .map(|request| handle_userrequest(request, &mut mqtt_state))
+                    .collect();
+
+                future::result(packets.map(stream::iter_ok::<_, NetworkError>))
+            })
+            .flatten()
+    }
 
+    /// Holds the disconnect of the user (or of the dropped clients) till the messages
+    /// written before it are acked or the drain timeout elapses. Requests after the
+    /// disconnect aren't read anymore
+    fn drained_requests(&self, requests: impl Stream<Item = Request, Error = NetworkError>) -> impl Stream<Item = Request, Error = NetworkError> {
         let mqtt_state = self.mqtt_state.clone();
-        request_stream.and_then(move |packet: Packet| {
-            let mut mqtt_state = mqtt_state.borrow_mut();
-            let o = mqtt_state.handle_outgoing_mqtt_packet(packet);
-            future::result(o)
+        let drain_timeout = self.mqttoptions.drain_timeout();
+        let mut requests = requests;
+        let mut drain: Option<Delay> = None;
+        let mut disconnected = false;
+
+        poll_fn(move || -> Poll<Option<Request>, NetworkError> {
+            if disconnected {
+                return Ok(Async::Ready(None));
+            }
+
+            if drain.is_none() {
+                match requests.poll() {
+                    Ok(Async::Ready(Some(Request::Disconnect))) => (),
+                    Err(NetworkError::UserRequestChannelClosed) => {
+                        mqtt_state.borrow_mut().handle_outgoing_disconnect()?;
+                    }
+                    o => return o,
+                }
+
+                drain = Some(Delay::new(Instant::now() + drain_timeout));
+            }
+
+            let unacked = mqtt_state.borrow().unacked_len();
+            if unacked > 0 {
+                if let Some(delay) = drain.as_mut() {
+                    if delay.poll().map_err(NetworkError::Timer)?.is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                }
+
+                warn!("Drain timeout elapsed. Disconnecting with {} unacked messages", unacked);
+            }
+
+            disconnected = true;
+            Ok(Async::Ready(Some(Request::Disconnect)))
         })
     }
 
@@ -410,7 +1179,7 @@ impl Connection {
     // by returning NotReady.
     fn inflight_limited_request_stream(&self, requests: impl Stream<Item = Request, Error = NetworkError>) -> impl Stream<Item = Request, Error = NetworkError> {
         let mqtt_state = self.mqtt_state.clone();
-        let in_flight = self.mqttoptions.inflight();
+        let drop_qos0 = self.mqttoptions.inflight_overflow() == InflightOverflow::DropQos0;
         let mut stream = requests.peekable();
 
         // don't read anything from the user request stream if current queue length
@@ -419,51 +1188,258 @@ impl Connection {
         // TODO: Understand poll_fn wakeups
         // https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=fcf42c86eb819053fe9eeaa1a2f457e6
         poll_fn(move || -> Poll<Option<Request>, NetworkError> {
-            let current_queue_len = mqtt_state.borrow().publish_queue_len();
-            if current_queue_len >= in_flight {
-                match stream.peek() {
-                    Err(_) => stream.poll(),
-                    _ => Ok(Async::NotReady),
+            loop {
+                let mqtt_state = mqtt_state.borrow();
+                // running out of packet ids also applies backpressure
+                if !mqtt_state.publish_queue_full() && !mqtt_state.pkids_exhausted() {
+                    return stream.poll();
+                }
+
+                let droppable = match stream.peek() {
+                    Err(_) => return stream.poll(),
+                    Ok(Async::Ready(Some(request))) => drop_qos0 && is_qos0_publish(request),
+                    _ => false,
+                };
+
+                if !droppable {
+                    return Ok(Async::NotReady);
+                }
+
+                // oldest pending qos0 publish makes space for the newer requests
+                if let Ok(Async::Ready(Some(_))) = stream.poll() {
+                    warn!("In flight limit reached. Dropping a pending qos0 publish");
+                    mqtt_state.stats().publish_dropped();
                 }
-            } else {
-                stream.poll()
             }
         })
     }
 
-    /// Apply throttling if configured
+    /// Apply throttling if configured. Topic throttles take the publishes of their topics
+    /// and the global throttle applies to the rest of the requests
     fn throttled_network_stream(&mut self, requests: impl Stream<Item = Request, Error = NetworkError>) -> impl Stream<Item = Request, Error = NetworkError> {
-        if let Some(rate) = self.mqttoptions.throttle() {
-            let duration = Duration::from_nanos((1_000_000_000.0 / rate) as u64);
-            let throttled = requests.throttle(duration).map_err(|_| NetworkError::Throttle);
-            Either::A(throttled)
-        } else {
-            Either::B(requests)
+        let topic_throttles = self.mqttoptions.topic_throttles();
+        match self.mqttoptions.throttle() {
+            rate if !topic_throttles.is_empty() => Either::A(TopicThrottle::new(requests, topic_throttles, rate)),
+            Some(rate) => {
+                let duration = Duration::from_nanos((1_000_000_000.0 / rate) as u64);
+                // keeps the error of the request stream or the timer
+                let throttled = requests.throttle(duration).map_err(|e| {
+                    if e.is_stream_error() {
+                        e.into_stream_error().unwrap_or(NetworkError::Throttle)
+                    } else {
+                        e.into_timer_error().map_or(NetworkError::Throttle, NetworkError::Timer)
+                    }
+                });
+                Either::B(Either::A(throttled))
+            }
+            None => Either::B(Either::B(requests)),
         }
     }
+}
 
-    /// Convert commands to errors
-    fn command_stream<'a>(&mut self, commands: &'a mut mpsc::Receiver<Command>) -> impl Stream<Item = Packet, Error = NetworkError> + 'a {
-        // process user commands and raise appropriate error to the event loop
-        commands
-            .or_else(|_err| Err(NetworkError::Blah))
-            .and_then(|usercommand| match usercommand {
-                Command::Pause => Err(NetworkError::UserDisconnect),
-                Command::Resume => Err(NetworkError::UserReconnect),
-            })
+/// Next step of the eventloop after a connection ends
+#[derive(Debug, PartialEq)]
+enum Reconnect {
+    /// Reconnect right away
+    Now,
+    /// Reconnect after the delay of the reconnection options
+    Backoff,
+    /// Stop the eventloop
+    Never,
+}
+
+/// Stream shared by futures which don't outlive each other. Lets every connection of
+/// `mqtt_eventloop_future` own the streams which are common to all the connections
+struct SharedStream<S>(Rc<RefCell<S>>);
+
+impl<S: Stream> Stream for SharedStream<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        self.0.borrow_mut().poll()
     }
 }
 
-fn handle_notification_and_reply(notification_tx: &Sender<Notification>, notification: Notification, reply: Request) -> impl Future<Item = Request, Error = NetworkError> {
-    match notification {
-        Notification::None => future::ok(reply),
-        _ => match notification_tx.try_send(notification) {
-            Ok(()) => {
-                future::ok(reply)
-            }
-            Err(e) => {
+/// Requests of the user. Ends with an error once all the clients (senders) are dropped
+fn user_request_stream(requests: Receiver<Request>) -> impl Stream<Item = Request, Error = NetworkError> {
+    requests
+        .map_err(|_| NetworkError::UserRequestChannelClosed)
+        .chain(stream::once(Err(NetworkError::UserRequestChannelClosed)))
+}
+
+/// Hands a validated request to the state and returns the packet to send
+fn handle_userrequest(request: Request, mqtt_state: &mut MqttState) -> Result<Request, NetworkError> {
+    let (request, replay) = match request {
+        Request::Replay(request) => (*request, true),
+        request => (request, false),
+    };
+
+    // age of a publish counts from the time the client queued it
+    let (request, queued_at) = match request {
+        Request::Queued(request, queued_at) => (*request, Some(queued_at)),
+        request => (request, None),
+    };
+
+    let o = match request {
+        // raw packets bypass the state
+        #[cfg(feature = "danger-raw-packets")]
+        Request::Raw(packet) => Ok(Request::Raw(packet)),
+        // routes of the channel are added on the suback
+        Request::SubscribeWithChannel(subscribe, tx) => mqtt_state.handle_outgoing_subscribe_with_channel(subscribe, tx),
+        request => mqtt_state.handle_outgoing_mqtt_packet(request.into()),
+    };
+
+    if let (Ok(Request::Publish(publish)), Some(queued_at)) = (&o, queued_at) {
+        mqtt_state.set_queued_at(publish, queued_at);
+    }
+
+    // failures of the previous session are told apart from the ones of the user
+    o.map_err(|e| match replay {
+        true => NetworkError::ReplayFailed(e.to_string()),
+        false => e,
+    })
+}
+
+/// Publishes of a batch as separate requests. Publishes of a queued batch keep the
+/// time the batch was queued
+fn expand_batch(request: Request) -> Vec<Request> {
+    match request {
+        Request::PublishBatch(batch) => batch.into_iter().map(Request::Publish).collect(),
+        Request::Queued(request, queued_at) => {
+            expand_batch(*request).into_iter().map(|request| Request::Queued(Box::new(request), queued_at)).collect()
+        }
+        request => vec![request],
+    }
+}
+
+/// Convert commands to errors
+fn command_stream(commands: impl Stream<Item = Command, Error = ()>) -> impl Stream<Item = Packet, Error = NetworkError> {
+    // process user commands and raise appropriate error to the event loop
+    commands
+        .or_else(|_err| Err(NetworkError::UserCommandChannelClosed))
+        .and_then(|usercommand| match usercommand {
+            Command::Pause => Err(NetworkError::UserDisconnect),
+            Command::Resume => Err(NetworkError::UserReconnect),
+            Command::Shutdown => Err(NetworkError::UserShutdown),
+        })
+}
+
+/// Drops commands which don't change the current network state. Pausing a paused
+/// network or resuming an active network is a no-op
+fn filter_commands(commands: impl Stream<Item = Packet, Error = NetworkError>, is_network_enabled: bool) -> impl Stream<Item = Packet, Error = NetworkError> {
+    commands
+        .then(move |command| match command {
+            Err(NetworkError::UserReconnect) if is_network_enabled => Ok(None),
+            Err(NetworkError::UserDisconnect) if !is_network_enabled => Ok(None),
+            command => command.map(Some),
+        })
+        .filter_map(|packet| packet)
+}
+
+/// Ends the outgoing packet stream after forwarding a disconnect packet. `forward` then
+/// flushes and closes the network sink which closes the connection to the broker
+fn until_disconnect(mut stream: impl Stream<Item = Packet, Error = NetworkError>) -> impl Stream<Item = Packet, Error = NetworkError> {
+    let mut disconnected = false;
+
+    poll_fn(move || -> Poll<Option<Packet>, NetworkError> {
+        if disconnected {
+            return Ok(Async::Ready(None));
+        }
+
+        match stream.poll()? {
+            Async::Ready(Some(Packet::Disconnect)) => {
+                disconnected = true;
+                Ok(Async::Ready(Some(Packet::Disconnect)))
+            }
+            v => Ok(v),
+        }
+    })
+}
+
+/// Sends a copy of the notification to all the additional listeners. Listeners
+/// which are full miss the notification and dropped listeners are removed
+fn broadcast(listeners: &Mutex<Vec<Sender<Notification>>>, notification: &Notification) {
+    if let Notification::None = notification {
+        return;
+    }
+
+    let mut listeners = match listeners.lock() {
+        Ok(listeners) => listeners,
+        Err(e) => e.into_inner(),
+    };
+
+    listeners.retain(|tx| match tx.try_send(notification.clone()) {
+        Err(TrySendError::Disconnected(_)) => false,
+        _ => true,
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_notification_and_reply(
+    notification_tx: &Sender<Notification>,
+    notification_overflow_rx: Option<&crossbeam_channel::Receiver<Notification>>,
+    notification_overflow: NotificationOverflow,
+    notification_stream_tx: Option<&mpsc::Sender<Notification>>,
+    notifications_dropped: &AtomicUsize,
+    notifications_blocked: &Arc<AtomicTask>,
+    notification: Notification,
+    reply: Request,
+) -> impl Future<Item = Request, Error = NetworkError> {
+    match (notification, notification_stream_tx) {
+        (Notification::None, _) => Either::A(future::ok(reply)),
+        // waits for space in the stream. this parks network reads while the user is slow
+        (notification, Some(tx)) => {
+            let send = tx.clone().send(notification).then(move |o| {
+                if let Err(e) = o {
+                    error!("Notification stream closed. Error = {:?}", e);
+                }
+
+                Ok(reply)
+            });
+
+            Either::B(Either::A(send))
+        }
+        (notification, None) => match notification_tx.try_send(notification) {
+            Ok(()) => {
+                Either::A(future::ok(reply))
+            }
+            // parks the task till the receiver takes a notification. this parks network
+            // reads while the user is slow
+            Err(TrySendError::Full(notification)) if notification_overflow == NotificationOverflow::Block => {
+                let notification_tx = notification_tx.clone();
+                let blocked = notifications_blocked.clone();
+                let mut notification = Some(notification);
+                let send = future::poll_fn(move || -> Poll<(), NetworkError> {
+                    // registered before the retry so that a receive in between isn't missed
+                    blocked.register();
+                    match notification_tx.try_send(notification.take().expect("Notification sent twice")) {
+                        Err(TrySendError::Full(full)) => {
+                            notification = Some(full);
+                            Ok(Async::NotReady)
+                        }
+                        _ => Ok(Async::Ready(())),
+                    }
+                });
+
+                Either::B(Either::B(send.map(move |_| reply)))
+            }
+            Err(TrySendError::Full(notification)) if notification_overflow == NotificationOverflow::DropOldest => {
+                error!("Notification channel full. Dropping the oldest notification");
+                notifications_dropped.fetch_add(1, Ordering::SeqCst);
+                if let Some(rx) = notification_overflow_rx {
+                    let _ = rx.try_recv();
+                }
+
+                let _ = notification_tx.try_send(notification);
+                Either::A(future::ok(reply))
+            }
+            Err(e) => {
                 error!("Notification send failed. Error = {:?}", e);
-                future::err(NetworkError::ReceiverCatchup)
+                if let TrySendError::Full(_) = e {
+                    notifications_dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                Either::A(future::err(NetworkError::ReceiverCatchup))
             }
         }
     }
@@ -471,18 +1447,12 @@ fn handle_notification_and_reply(notification_tx: &Sender<Notification>, notific
 
 /// Checks if a ping is necessary based on timeout error
 fn handle_incoming_stream_timeout_error(error: timeout::Error<io::Error>, mqtt_state: &mut MqttState) -> impl Future<Item = Packet, Error = NetworkError> {
-    // check if a ping to the broker is necessary
-    let out = mqtt_state.handle_outgoing_ping();
-    future::err(error).or_else(move |e| {
-        if e.is_elapsed() {
-            match out {
-                Ok(_) => future::ok(Packet::Pingreq),
-                Err(e) => future::err(e),
-            }
-        } else {
-            future::err(e.into_inner().unwrap().into())
-        }
-    })
+    // nothing received for a keep alive. ping even if the outgoing side is busy
+    if error.is_elapsed() {
+        future::result(mqtt_state.handle_incoming_idle().map(|_| Packet::Pingreq))
+    } else {
+        future::err(timeout_inner_error(error))
+    }
 }
 
 /// Checks if a ping is necessary based on timeout error
@@ -497,21 +1467,124 @@ fn handle_outgoing_stream_timeout_error(error: timeout::Error<NetworkError>, mqt
                 Err(e) => future::err(e),
             }
         } else {
-            future::err(e.into_inner().unwrap().into())
+            future::err(timeout_inner_error(e))
         }
     })
 }
 
-fn validate_userrequest(userrequest: Request, mqtt_state: &mut MqttState) -> impl Future<Item = Packet, Error = NetworkError> {
+/// Error of a stream timeout which isn't an elapsed deadline. Timer failures don't
+/// carry an error of the stream
+fn timeout_inner_error<E: Into<NetworkError>>(e: timeout::Error<E>) -> NetworkError {
+    if e.is_timer() {
+        return e.into_timer().map_or(NetworkError::Timeout, NetworkError::Timer);
+    }
+
+    e.into_inner().map_or(NetworkError::Timeout, Into::into)
+}
+
+/// Applies requests which only modify the state and returns the remaining requests
+/// which should be sent on the network
+fn validate_userrequest(userrequest: Request, mqtt_state: &mut MqttState) -> impl Future<Item = Option<Request>, Error = NetworkError> {
+    future::result(validate(userrequest, mqtt_state))
+}
+
+fn validate(userrequest: Request, mqtt_state: &mut MqttState) -> Result<Option<Request>, NetworkError> {
     match userrequest {
         Request::Reconnect(mqttoptions) => {
             mqtt_state.opts = mqttoptions;
-            future::err(NetworkError::UserReconnect)
+            Err(NetworkError::UserReconnect)
+        }
+        // publishes which waited in the request channel (or a throttle) past their ttl
+        Request::Queued(request, queued_at) if mqtt_state.is_stale(queued_at) => {
+            match *request {
+                Request::Publish(ref publish) | Request::PublishWithAck(ref publish, _) => mqtt_state.expire(publish),
+                Request::PublishBatch(ref batch) => batch.iter().for_each(|publish| mqtt_state.expire(publish)),
+                _ => (),
+            }
+
+            Ok(None)
+        }
+        // replays keep their mark till the state takes them. see `handle_userrequest`
+        Request::Replay(request) => {
+            let request = validate(*request, mqtt_state).map_err(|e| NetworkError::ReplayFailed(e.to_string()))?;
+            Ok(request.map(|request| Request::Replay(Box::new(request))))
+        }
+        // time is carried along to the state. see `user_requests`
+        Request::Queued(request, queued_at) => {
+            let request = validate(*request, mqtt_state)?;
+            Ok(request.map(|request| Request::Queued(Box::new(request), queued_at)))
+        }
+        // replays which waited in the queue past their ttl
+        Request::Publish(ref publish) if mqtt_state.is_expired(publish) => {
+            mqtt_state.expire(publish);
+            Ok(None)
+        }
+        Request::Publish(ref publish) | Request::PublishWithAck(ref publish, _) if oversized(publish, mqtt_state) => {
+            // dropping the request fails the ack waiter of this publish alone
+            error!("Dropping oversized publish. Topic = {}, Size = {}", publish.topic_name, codec::publish_len(publish));
+            Ok(None)
+        }
+        Request::PublishWithAck(publish, ack_tx) => {
+            let publish = mqtt_state.handle_outgoing_publish_with_ack(publish, ack_tx)?;
+            Ok(Some(Request::Publish(publish)))
+        }
+        // oversized publishes of a batch are dropped alone
+        Request::PublishBatch(batch) => {
+            let mut publishes = Vec::with_capacity(batch.len());
+            for publish in batch {
+                if let Some(Request::Publish(publish)) = validate(Request::Publish(publish), mqtt_state)? {
+                    publishes.push(publish);
+                }
+            }
+
+            match publishes.is_empty() {
+                true => Ok(None),
+                false => Ok(Some(Request::PublishBatch(publishes))),
+            }
+        }
+        Request::Ack(handle) => Ok(mqtt_state.handle_outgoing_ack(handle)),
+        Request::LastWill(last_will) => {
+            // used by the connect packet of the next connection
+            let opts = mqtt_state.opts.clone();
+            mqtt_state.opts = match last_will {
+                Some(last_will) => opts.set_last_will(last_will),
+                None => opts.clear_last_will(),
+            };
+            Ok(None)
         }
-        _ => future::ok(userrequest.into()),
+        _ => Ok(Some(userrequest)),
+    }
+}
+
+/// QoS0 publishes of the user (queued or not)
+fn is_qos0_publish(request: &Request) -> bool {
+    match request {
+        Request::Publish(publish) | Request::PublishWithAck(publish, _) => publish.qos == QoS::AtMostOnce,
+        Request::Queued(request, _) => is_qos0_publish(request),
+        _ => false,
     }
 }
 
+/// Fresh publishes which don't fit the maximum packet size. Already accepted (retransmitted)
+/// publishes aren't checked again
+fn oversized(publish: &Publish, mqtt_state: &MqttState) -> bool {
+    publish.pkid.is_none() && codec::publish_len(publish) > mqtt_state.opts.max_outgoing_packet_size()
+}
+
+/// Sleep before reconnection `attempt` (starting at 0). `random` (0.0 to 1.0) spreads
+/// the sleep within the jitter range
+fn backoff_delay(initial: Duration, max: Duration, multiplier: f64, jitter: f64, attempt: u32, random: f64) -> Duration {
+    let initial = initial.as_millis() as f64;
+    let max = max.as_millis() as f64;
+    let attempt = attempt.min(i32::max_value() as u32) as i32;
+
+    let delay = (initial * multiplier.max(1.0).powi(attempt)).min(max);
+    let jitter = jitter.max(0.0).min(1.0);
+    let delay = delay * (1.0 - jitter + 2.0 * jitter * random);
+
+    Duration::from_millis(delay.max(0.0).min(max) as u64)
+}
+
 /// Checks if incoming packet is mqtt connack packet. Useful after mqtt
 /// connect when we are waiting for connack but not any other packet.
 fn check_and_validate_connack(packet: Option<Packet>, framed: MqttFramed, mqtt_state: &mut MqttState) -> impl Future<Item = MqttFramed, Error = ConnectError> {
@@ -579,6 +1652,8 @@ impl From<Request> for Packet {
             Request::Disconnect => Packet::Disconnect,
             Request::Subscribe(subscribe) => Packet::Subscribe(subscribe),
             Request::Unsubscribe(unsubscribe) => Packet::Unsubscribe(unsubscribe),
+            #[cfg(feature = "danger-raw-packets")]
+            Request::Raw(packet) => packet,
             _ => unimplemented!(),
         }
     }
@@ -615,20 +1690,28 @@ mod test {
     use std::time::Duration;
     use tokio::timer::DelayQueue;
     use mqtt311::PacketIdentifier;
-    #[cfg(target_os = "linux")] use crate::client::Request;
-    use crate::client::Notification;
-    use super::{Connection, MqttOptions, MqttState, NetworkError, ConnectError, ReconnectOptions};
+    use crate::client::Request;
+    use crate::client::{ConnectionInfo, Notification};
+    use crate::client::NotificationReceiver;
+    use super::{Connection, MqttOptions, MqttState, NetworkError, ConnectError, ErrorSummary, NotificationOverflow, Reconnect, RetryPolicy};
+    use crate::mqttoptions::{Proxy, ReconnectOptions};
+    use crate::client::ConnectionStatus;
     use super::MqttFramed;
     use futures::{
-        future,
+        future::{self, Loop},
         stream::Stream,
+        task::AtomicTask,
+        Future,
     };
     use mqtt311::Packet;
     use mqtt311::Publish;
     use mqtt311::QoS;
     use std::cell::RefCell;
     use std::rc::Rc;
-    use std::sync::Arc;
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
     use std::io;
     #[cfg(target_os = "linux")] use std::time::Instant;
     use std::thread;
@@ -639,18 +1722,40 @@ mod test {
         connection_rx: crossbeam_channel::Receiver<Result<(), ConnectError>>
     }
 
+    fn connection_info(session_present: bool) -> ConnectionInfo {
+        ConnectionInfo { broker: ("localhost".to_owned(), 1883), session_present, tls: None }
+    }
+
     fn mock_mqtt_connection(mqttoptions: MqttOptions, mqtt_state: MqttState) -> (Connection, UserHandle, Runtime) {
         let (connection_tx, connection_rx) = crossbeam_channel::bounded(1);
         let (notification_tx, notification_rx) = crossbeam_channel::bounded(10);
+        let connection_status = Arc::new(Mutex::new(ConnectionStatus::Disconnected { reconnecting: true }));
 
         let mqtt_state = Rc::new(RefCell::new(mqtt_state));
         let connection = Connection {
             mqtt_state,
             notification_tx,
+            notification_overflow_rx: None,
+            notification_stream_tx: None,
+            notifications_dropped: Arc::new(AtomicUsize::new(0)),
+            notifications_blocked: Arc::new(AtomicTask::new()),
+            notification_listeners: Arc::new(Mutex::new(Vec::new())),
             connection_tx: Some(connection_tx),
-            connection_count: 0,
+            ever_connected: false,
+            reconnect_attempt: 0,
+            backoff_attempt: 0,
+            connected_at: None,
             mqttoptions,
             is_network_enabled: true,
+            connection_status,
+            status_signal: Default::default(),
+            connected_broker: Arc::new(Mutex::new(None)),
+            last_error: Arc::new(Mutex::new(None)),
+            broker_index: 0,
+            session_present: Arc::new(AtomicBool::new(false)),
+            tls_info: Arc::new(Mutex::new(None)),
+            final_session: Arc::new(Mutex::new(None)),
+            resolver: DnsResolver::new(None),
         };
 
         let userhandle = UserHandle {
@@ -662,6 +1767,31 @@ mod test {
         (connection, userhandle, runtime)
     }
 
+    /// Whether the eventloop reconnects after `next`. See `Connection::reconnect`
+    fn reconnects(connection: &mut Connection, next: Reconnect) -> bool {
+        match next {
+            Reconnect::Now => true,
+            Reconnect::Backoff => connection.next_reconnect_delay().is_some(),
+            Reconnect::Never => false,
+        }
+    }
+
+    /// Connection attempt of the eventloop. Err(true) -> Reconnect, Err(false) -> Don't reconnect
+    fn connect_or_not(connection: &mut Connection,
+                      runtime: &mut Runtime,
+                      connect: impl Future<Item = MqttFramed, Error = ConnectError>) -> Result<Option<MqttFramed>, bool> {
+        let o = runtime.block_on(connection.connect_or_not(connect));
+        connection.handle_connect_result(o).map_err(|next| reconnects(connection, next))
+    }
+
+    /// Connection of the eventloop till `mqtt_future` ends. Err(true) -> Reconnect,
+    /// Err(false) -> Don't reconnect
+    fn mqtt_io(connection: &mut Connection, mut runtime: Runtime, mqtt_future: impl Future<Item = (), Error = NetworkError>) -> Result<(), bool> {
+        let o = runtime.block_on(mqtt_future);
+        let next = connection.handle_eventloop_end(o);
+        Err(reconnects(connection, next))
+    }
+
     #[cfg(target_os = "linux")]
     fn user_requests(delay: Duration) -> impl Stream<Item = Request, Error = NetworkError> {
         let mut requests = DelayQueue::new();
@@ -722,6 +1852,47 @@ mod test {
         })
     }
 
+    #[test]
+    fn backoff_should_grow_exponentially_till_max() {
+        use super::backoff_delay;
+
+        let initial = Duration::from_secs(1);
+        let max = Duration::from_secs(30);
+        let delays: Vec<u64> = (0..7).map(|attempt| backoff_delay(initial, max, 2.0, 0.0, attempt, 0.5).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+
+        // jitter spreads the delay around the exponential value
+        assert_eq!(backoff_delay(initial, max, 2.0, 0.5, 2, 0.0), Duration::from_secs(2));
+        assert_eq!(backoff_delay(initial, max, 2.0, 0.5, 2, 1.0), Duration::from_secs(6));
+        assert_eq!(backoff_delay(initial, max, 2.0, 0.5, 10, 1.0), max);
+        assert_eq!(backoff_delay(initial, max, 2.0, 0.0, u32::max_value(), 0.0), max);
+    }
+
+    #[test]
+    fn loss_of_a_stable_connection_should_reset_the_backoff() {
+        let policy = RetryPolicy::ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883)
+            .set_reconnect_policy(policy)
+            .set_stable_connection_threshold(Duration::from_millis(100));
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, _runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        connection.ever_connected = true;
+
+        // connection which dropped right away keeps growing the backoff
+        connection.backoff_attempt = 4;
+        connection.connected_at = Some(std::time::Instant::now());
+        assert_eq!(connection.next_reconnect_delay(), Some(Duration::from_secs(16)));
+
+        connection.connected_at = Some(std::time::Instant::now() - Duration::from_millis(200));
+        assert_eq!(connection.next_reconnect_delay(), Some(Duration::from_secs(1)));
+        assert_eq!(connection.next_reconnect_delay(), Some(Duration::from_secs(2)));
+    }
+
     #[test]
     fn run_should_raise_connection_errors_based_on_reconnection_options() {
         // local broker isn't running. Should result in connection errors
@@ -748,176 +1919,1160 @@ mod test {
     }
 
     #[test]
-    fn connect_or_not_returns_correct_reconnection_behaviour_in_always_reconnect_mode() {
-        let reconnect_opt = ReconnectOptions::Always(10);
-        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
-        let mqtt_state = MqttState::new(mqttoptions.clone());
-
-        // disconnections should take user reconnection options into consideration
-        let (mut connection, userhandle, _runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
-        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
-        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+    fn certificates_should_be_loaded_for_every_connection_attempt() {
+        use crate::certs::{CertProvider, Certificates};
 
-        // results in an error but continues reconnection
-        match connection.connect_or_not(connect_future) {
-            Err(true) => (),
-            _ => panic!("Should return reconnect = true")
+        #[derive(Debug, Clone, Default)]
+        struct Provider {
+            calls: Arc<AtomicUsize>,
         }
-        assert!(userhandle.connection_rx.recv().unwrap().is_err());
-    }
 
-    #[test]
-    fn connect_or_not_returns_dontreconnect_in_afterfirstsuccess_mode_during_first_failure() {
-        // first connection
-        let reconnect_opt = ReconnectOptions::AfterFirstSuccess(5);
-        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
-        let mqtt_state = MqttState::new(mqttoptions.clone());
+        impl CertProvider for Provider {
+            fn certificates(&self) -> io::Result<Certificates> {
+                self.calls.fetch_add(1, Ordering::SeqCst);
+                Err(io::ErrorKind::NotFound.into())
+            }
+        }
 
-        // disconnections should take user reconnection options into consideration
-        let (mut connection, userhandle, _runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
-        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
-        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+        let provider = Provider::default();
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883)
+            .set_reconnect_opts(ReconnectOptions::Never)
+            .set_cert_provider(provider.clone());
 
-        // results in an error and reconnection = false during 1st reconnection
-        match connection.connect_or_not(connect_future) {
-            Err(false) => (),
-            Err(true) => panic!("Should return reconnect = false"),
-            Ok(_) => panic!("not possible")
+        match Connection::run(mqttoptions) {
+            Err(ConnectError::CertificateProvider(_)) => (),
+            o => panic!("Expecting certificate provider error. Found = {:?}", o.map(|_| ())),
         }
-        assert!(userhandle.connection_rx.recv().unwrap().is_err());
-    }
 
-    #[test]
-    fn connect_or_not_returns_reconnect_in_afterfirstsuccess_mode_during_second_failure() {
-        // first connection
-        let reconnect_opt = ReconnectOptions::AfterFirstSuccess(3);
-        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
-        let mqtt_state = MqttState::new(mqttoptions.clone());
+        assert_eq!(provider.calls.load(Ordering::SeqCst), 1);
+    }
 
-        // disconnections should take user reconnection options into consideration
-        let (mut connection, _userhandle, _runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
-        connection.connection_count = 1;
-        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
-        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+    /// Accepts the next connection of the client on a fake broker and answers the connect
+    fn accept_connection(listener: &std::net::TcpListener, session_present: bool) -> std::net::TcpStream {
+        use mqtt311::{Connack, ConnectReturnCode, MqttRead, MqttWrite};
 
-        // results in an error and reconnection = false during 1st reconnection
-        match connection.connect_or_not(connect_future) {
-            Err(true) => (),
-            Err(false) => panic!("Should return reconnect = true"),
-            Ok(_) => panic!("not possible")
+        let (mut stream, _) = listener.accept().unwrap();
+        match stream.read_packet().unwrap() {
+            Packet::Connect(_) => (),
+            packet => panic!("Expecting connect. Found = {:?}", packet),
         }
+
+        let connack = Connack { session_present, code: ConnectReturnCode::Accepted };
+        stream.write_packet(&Packet::Connack(connack)).unwrap();
+        stream
     }
 
     #[test]
-    fn mqtt_io_returns_correct_reconnection_behaviour() {
-        let reconnect_opt = ReconnectOptions::Always(10);
-        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+    fn binary_password_connections_should_limit_the_incoming_packet_size() {
+        use crate::codec::PacketTooLarge;
+        use crate::mqttoptions::SecurityOptions;
+        use mqtt311::{Connack, ConnectReturnCode, MqttWrite};
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            // mqtt311 can't read the binary password. connect is skipped unread
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0; 256];
+            let _ = stream.read(&mut connect).unwrap();
+
+            let connack = Connack { session_present: false, code: ConnectReturnCode::Accepted };
+            stream.write_packet(&Packet::Connack(connack)).unwrap();
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: false,
+                pkid: None,
+                topic_name: "hello/world".to_owned(),
+                payload: Arc::new(vec![0; 2048]),
+            };
+            stream.write_packet(&Packet::Publish(publish)).unwrap();
+            stream
+        });
+
+        let security = SecurityOptions::UsernamePasswordBytes("user".to_owned(), vec![0xff, 0x00]);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "127.0.0.1", port)
+            .set_security_opts(security)
+            .set_max_incoming_packet_size(1);
         let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
 
-        // disconnections should take user reconnection options into consideration
-        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
-        let network_future = future::err::<(), _>(NetworkError::NetworkStreamClosed);
-        let out = connection.mqtt_io(runtime, network_future);
-        assert_eq!(out, Err(true));
+        let framed = runtime.block_on(connection.mqtt_connect()).unwrap();
+        match runtime.block_on(framed.into_future()) {
+            Err((e, _)) => assert_eq!(PacketTooLarge::from_io_error(e).unwrap().size, 2048 + 16),
+            Ok((packet, _)) => panic!("Expecting oversized packet error. Found = {:?}", packet),
+        }
 
-        let mqtt_state = MqttState::new(mqttoptions.clone());
-        // user shutdown should not take reconnection options into consideration
-        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
-        connection.mqtt_state.borrow_mut().handle_outgoing_disconnect().unwrap();
-        let network_future = future::err::<(), _>(NetworkError::NetworkStreamClosed);
-        let out = connection.mqtt_io(runtime, network_future);
-        assert_eq!(out, Err(false));
+        let _stream = broker.join().unwrap();
     }
 
-    #[cfg(target_os = "linux")]
-    // incoming puback at second 1 and pingresp at periodic intervals
-    fn network_incoming_pingresps() -> impl Stream<Item = Packet, Error = io::Error> {
-        let mut acks = DelayQueue::new();
+    #[test]
+    fn eventloop_future_should_run_on_the_callers_runtime() {
+        use futures::Sink;
+        use mqtt311::{MqttRead, MqttWrite};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // broker which acks a publish and closes the connection
+        let broker = thread::spawn(move || {
+            let mut stream = accept_connection(&listener, false);
+            let publish = match stream.read_packet().unwrap() {
+                Packet::Publish(publish) => publish,
+                packet => panic!("Expecting publish. Found = {:?}", packet),
+            };
 
+            stream.write_packet(&Packet::Puback(publish.pkid.unwrap())).unwrap();
+            publish
+        });
+
+        let mqttoptions = MqttOptions::new("future-test", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+        let (eventloop, userhandle) = Connection::new(mqttoptions);
         let publish = Publish {
             dup: false,
-            qos: QoS::AtMostOnce,
+            qos: QoS::AtLeastOnce,
             retain: false,
             pkid: None,
             topic_name: "hello/world".to_owned(),
             payload: Arc::new(vec![1, 2, 3]),
         };
+        userhandle.request_tx.clone().send(Request::Publish(publish)).wait().unwrap();
 
-        acks.insert( Packet::Publish(publish), Duration::from_secs(2));
-        // out idle ping at 5000 + 500 (out ping delay wrt to keep alive)
-        acks.insert(Packet::Pingresp, Duration::from_millis(5510));
-        // in idle ping at 10520
-        acks.insert(Packet::Pingresp, Duration::from_millis(10520));
-        // in idle ping at 15530
-        acks.insert(Packet::Pingresp, Duration::from_millis(15530));
+        // eventloop ends when the broker closes the connection as reconnections are disabled
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(eventloop).unwrap();
 
-        acks.map(|v| {
-            v.into_inner()
-        }).map_err(|_e| {
-            io::Error::new(io::ErrorKind::Other, "Timer error")
-        })
+        assert_eq!(broker.join().unwrap().payload, Arc::new(vec![1, 2, 3]));
+        assert_eq!(userhandle.eventloop_done_rx.recv_timeout(Duration::from_secs(1)).unwrap(), 0);
+        match userhandle.notification_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(Notification::Connected(ConnectionInfo { session_present: false, tls: None, .. })) => (),
+            n => panic!("Expecting connected notification. Found = {:?}", n),
+        }
     }
 
-    #[cfg(target_os = "linux")]
     #[test]
-    fn reply_stream_triggers_pings_on_time() {
-        let mqttoptions = MqttOptions::default().set_keep_alive(5);
-        let mqtt_state = MqttState::new(mqttoptions.clone());
+    fn dropping_the_client_should_stop_the_eventloop_without_reconnecting() {
+        use crate::testutils::MockBroker;
 
-        let (connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
-        let network_reply_stream = network_incoming_pingresps();
-        let network_reply_stream = connection.network_reply_stream(network_reply_stream);
+        let broker = MockBroker::start().unwrap();
+        let mqttoptions = MqttOptions::new("drop-test", "127.0.0.1", broker.port()).set_reconnect_opts(ReconnectOptions::Always(1));
+        let userhandle = Connection::run(mqttoptions).unwrap();
 
-        let start = Instant::now();
+        drop(userhandle.request_tx);
+        assert_eq!(userhandle.eventloop_done_rx.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+        assert_eq!(broker.connections(), 1);
+    }
 
-        // incoming publish at second 1,
-        // so pingreq should happen at second 6, 12 and 16
-        let network_future = network_reply_stream.fold(1, |mut count, packet| {
-            let elapsed = start.elapsed().as_millis();
-            println!("Packet = {:?}, Elapsed = {:?}", packet, elapsed);
-            match packet {
-                // incoming publish at 2000. (in idle, out idle) = (7000, 5000 + 500) ---> out idle ping at 5500
-                Request::OutgoingIdlePing if count == 1 =>  assert!(elapsed > 5500 && elapsed < 5700),
-                // ping resp at 5510. (in idle, out idle) = (10510, 5500 + 5500) ---> in idle ping at 10510
-                Request::IncomingIdlePing if count == 2 =>  assert!(elapsed > 10510 && elapsed < 10700),
-                // ping resp at 10520. (in idl, out idle) = (15520, 10510 + 5500) ---> in idle ping at 15520
-                Request::IncomingIdlePing if count == 3 =>  assert!(elapsed > 15520 && elapsed < 15700),
-                _ => panic!("Expecting publish or ping")
-            }
+    #[test]
+    fn eventloop_thread_should_be_named_after_the_client() {
+        use crate::testutils::MockBroker;
 
-            count += 1;
-            future::ok::<_, NetworkError>(count)
-        });
+        let broker = MockBroker::start().unwrap();
+        let mqttoptions = MqttOptions::new("thread-test", "127.0.0.1", broker.port()).set_eventloop_stack_size(256 * 1024);
+        let userhandle = Connection::run(mqttoptions).unwrap();
 
-        match runtime.block_on(network_future) {
-            Err(NetworkError::NetworkStreamClosed) | Ok(_) => (),
-            Err(e) => panic!("Error = {:?}", e)
-        }
+        let name = userhandle.eventloop.as_ref().unwrap().thread().name().map(str::to_owned);
+        assert_eq!(name, Some("mqtt-thread-test".to_owned()));
     }
 
-    #[cfg(target_os = "linux")]
     #[test]
-    fn throttled_stream_operates_at_specified_rate() {
-        let mqttoptions = MqttOptions::default().set_throttle(5.0);
-        let mqtt_state = MqttState::new(mqttoptions.clone());
+    fn eventloop_panic_should_be_notified_and_fail_later_requests() {
+        use crate::client::MqttClient;
+        use crate::error::ClientError;
+        use mqtt311::{MqttWrite, Subscribe, SubscribeTopic};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let mut stream = accept_connection(&listener, false);
+            // clients never receive subscribes. the eventloop panics on this
+            let topics = vec![SubscribeTopic { topic_path: "hello/world".to_owned(), qos: QoS::AtMostOnce }];
+            let subscribe = Subscribe { pkid: PacketIdentifier(1), topics };
+            stream.write_packet(&Packet::Subscribe(subscribe)).unwrap();
+            stream
+        });
 
-        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let mqttoptions = MqttOptions::new("panic-test", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Never);
+        let userhandle = Connection::run(mqttoptions).unwrap();
+        let (mut client, notifications) = MqttClient::from_handle(userhandle, 10 * 1024, 10, None);
+        let _stream = broker.join().unwrap();
 
-        // note: maintain order similar to mqtt_future()
-        // generates 100 user requests
-        let user_request_stream = user_requests(Duration::from_millis(1));
-        let user_request_stream = connection.throttled_network_stream(user_request_stream);
-        let user_request_stream = connection.user_requests(user_request_stream);
-        let user_request_stream = user_request_stream.and_then(move |packet| future::ok(packet.into()));
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Connected(_)) => (),
+            n => panic!("Expecting connected notification. Found = {:?}", n),
+        }
 
-        let f = user_request_stream.fold(Instant::now(), |last, v: Packet| {
-            // println!("outgoing = {:?}", v);
-            let now = Instant::now();
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::EventloopCrashed(message)) => assert!(message.contains("Subscribe"), "{}", message),
+            n => panic!("Expecting crash notification. Found = {:?}", n),
+        }
 
-            if let Packet::Publish(Publish{pkid, ..}) = v {
-                if pkid.unwrap() > PacketIdentifier(1) {
-                    let elapsed = (now - last).as_millis();
-                    dbg!(elapsed);
+        match client.subscribe("hello/world", QoS::AtLeastOnce) {
+            Err(ClientError::EventloopTerminated) => (),
+            o => panic!("Expecting terminated eventloop. Found = {:?}", o),
+        }
+    }
+
+    fn disconnect_after_publishes(broker_port: u16, drain_timeout: Duration) -> (usize, String) {
+        let mqttoptions = MqttOptions::new("drain-test", "127.0.0.1", broker_port)
+            .set_reconnect_opts(ReconnectOptions::Always(1))
+            .set_drain_timeout(drain_timeout);
+        let userhandle = Connection::run(mqttoptions).unwrap();
+
+        let mut request_tx = userhandle.request_tx.clone();
+        for i in 0..3 {
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                pkid: None,
+                topic_name: "hello/world".to_owned(),
+                payload: Arc::new(vec![i]),
+            };
+            request_tx = request_tx.send(Request::Publish(publish)).wait().unwrap();
+        }
+        request_tx.send(Request::Disconnect).wait().unwrap();
+
+        let unacked = userhandle.eventloop_done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let reason = userhandle
+            .notification_rx
+            .iter()
+            .filter_map(|notification| match notification {
+                Notification::Disconnected(reason) => Some(reason),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+
+        (unacked, reason)
+    }
+
+    #[test]
+    fn disconnect_should_wait_for_the_acks_of_queued_publishes() {
+        use crate::testutils::MockBroker;
+
+        let broker = MockBroker::start().unwrap();
+        broker.delay_acks(Duration::from_millis(300));
+
+        let (unacked, reason) = disconnect_after_publishes(broker.port(), Duration::from_secs(5));
+        assert_eq!(unacked, 0);
+        assert_eq!(reason, "Disconnected. Unacked messages = 0");
+
+        // disconnect is sent after all the publishes are acked
+        assert!(broker.wait_until(Duration::from_secs(1), |received| received.last() == Some(&Packet::Disconnect)));
+        let received = broker.received();
+        assert_eq!(received.iter().filter(|packet| if let Packet::Publish(_) = packet { true } else { false }).count(), 3);
+        assert_eq!(broker.connections(), 1);
+    }
+
+    #[test]
+    fn disconnect_should_report_messages_unacked_after_the_drain_timeout() {
+        use crate::testutils::MockBroker;
+
+        let broker = MockBroker::start().unwrap();
+        broker.delay_acks(Duration::from_secs(10));
+
+        let (unacked, reason) = disconnect_after_publishes(broker.port(), Duration::from_millis(200));
+        assert_eq!(unacked, 3);
+        assert_eq!(reason, "Disconnected. Unacked messages = 3");
+        assert!(broker.wait_until(Duration::from_secs(1), |received| received.last() == Some(&Packet::Disconnect)));
+    }
+
+    #[test]
+    fn failed_connection_should_move_to_the_next_broker() {
+        use std::net::TcpListener;
+
+        // nothing listens on the first broker
+        let dead_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let _stream = accept_connection(&listener, false);
+            thread::sleep(Duration::from_secs(10));
+        });
+
+        let brokers = vec![("127.0.0.1".to_owned(), dead_port), ("127.0.0.1".to_owned(), port)];
+        let mqttoptions = MqttOptions::new("failover-test", "127.0.0.1", dead_port)
+            .set_brokers(brokers)
+            .set_reconnect_opts(ReconnectOptions::Always(1));
+        let userhandle = Connection::run(mqttoptions).unwrap();
+
+        match userhandle.notification_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Notification::Reconnecting { attempt: 1, error: Some(ErrorSummary::Connect(_)), .. }) => (),
+            n => panic!("Expecting reconnection after a connection error. Found = {:?}", n),
+        }
+
+        match userhandle.notification_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Notification::Connected(_)) => (),
+            n => panic!("Expecting connected notification. Found = {:?}", n),
+        }
+
+        let broker = userhandle.connected_broker.lock().unwrap().clone();
+        assert_eq!(broker, Some(("127.0.0.1".to_owned(), port)));
+    }
+
+    #[test]
+    fn unacked_publishes_should_be_replayed_in_order_with_dup_flag_after_reconnection() {
+        use futures::Sink;
+        use mqtt311::MqttRead;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (publishes_tx, publishes_rx) = crossbeam_channel::unbounded();
+
+        // broker which drops the first connection after receiving 3 publishes without acking them
+        thread::spawn(move || {
+            for (connection, count) in [3, 5].iter().enumerate() {
+                let mut stream = accept_connection(&listener, connection > 0);
+                for _ in 0..*count {
+                    match stream.read_packet().unwrap() {
+                        Packet::Publish(publish) => publishes_tx.send((connection, publish)).unwrap(),
+                        packet => panic!("Expecting publish. Found = {:?}", packet),
+                    }
+                }
+            }
+
+            thread::sleep(Duration::from_secs(10));
+        });
+
+        let mqttoptions = MqttOptions::new("replay-test", "127.0.0.1", port)
+            .set_clean_session(false)
+            .set_reconnect_opts(ReconnectOptions::Always(1));
+        let userhandle = Connection::run(mqttoptions).unwrap();
+
+        for i in 0..5 {
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                pkid: None,
+                topic_name: "hello/world".to_owned(),
+                payload: Arc::new(vec![i]),
+            };
+
+            userhandle.request_tx.clone().send(Request::Publish(publish)).wait().unwrap();
+        }
+
+        let mut publishes = Vec::new();
+        for _ in 0..8 {
+            publishes.push(publishes_rx.recv_timeout(Duration::from_secs(10)).unwrap());
+        }
+
+        let first: Vec<u8> = publishes[..3].iter().map(|(_, publish)| publish.payload[0]).collect();
+        assert_eq!(first, vec![0, 1, 2]);
+        assert!(publishes[..3].iter().all(|(connection, publish)| *connection == 0 && !publish.dup));
+
+        // replays are sent before the publishes which never went out and keep their order
+        let second: Vec<u8> = publishes[3..].iter().map(|(_, publish)| publish.payload[0]).collect();
+        assert_eq!(second, vec![0, 1, 2, 3, 4]);
+        assert!(publishes[3..].iter().all(|(connection, _)| *connection == 1));
+        assert!(publishes[3..6].iter().all(|(_, publish)| publish.dup));
+        let pkids: Vec<_> = publishes[3..6].iter().map(|(_, publish)| publish.pkid).collect();
+        let first_pkids: Vec<_> = publishes[..3].iter().map(|(_, publish)| publish.pkid).collect();
+        assert_eq!(pkids, first_pkids);
+    }
+
+    #[test]
+    fn clean_session_reconnection_should_subscribe_again_without_replaying_publishes() {
+        use futures::Sink;
+        use mqtt311::{MqttRead, MqttWrite, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (packets_tx, packets_rx) = crossbeam_channel::unbounded();
+
+        // broker which drops the first connection after a subscription and 2 unacked publishes
+        thread::spawn(move || {
+            let mut stream = accept_connection(&listener, false);
+            match stream.read_packet().unwrap() {
+                Packet::Subscribe(subscribe) => {
+                    let suback = Suback { pkid: subscribe.pkid, return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce)] };
+                    stream.write_packet(&Packet::Suback(suback)).unwrap();
+                }
+                packet => panic!("Expecting subscribe. Found = {:?}", packet),
+            }
+
+            for _ in 0..2 {
+                stream.read_packet().unwrap();
+            }
+            drop(stream);
+
+            let mut stream = accept_connection(&listener, false);
+            for _ in 0..2 {
+                packets_tx.send(stream.read_packet().unwrap()).unwrap();
+            }
+
+            thread::sleep(Duration::from_secs(10));
+        });
+
+        let mqttoptions = MqttOptions::new("clean-session-test", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::Always(1));
+        let userhandle = Connection::run(mqttoptions).unwrap();
+        let publish = |payload| Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pkid: None,
+            topic_name: "hello/world".to_owned(),
+            payload: Arc::new(vec![payload]),
+        };
+
+        let topics = vec![SubscribeTopic { topic_path: "hello/+".to_owned(), qos: QoS::AtLeastOnce }];
+        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics };
+        let mut request_tx = userhandle.request_tx.clone();
+        request_tx = request_tx.send(Request::Subscribe(subscribe)).wait().unwrap();
+        request_tx = request_tx.send(Request::Publish(publish(1))).wait().unwrap();
+        request_tx = request_tx.send(Request::Publish(publish(2))).wait().unwrap();
+
+        // publish only after the reconnection
+        let mut connections = 0;
+        while connections < 2 {
+            if let Notification::Connected(_) = userhandle.notification_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+                connections += 1;
+            }
+        }
+        request_tx.send(Request::Publish(publish(3))).wait().unwrap();
+
+        match packets_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Packet::Subscribe(subscribe) => assert_eq!(subscribe.topics[0].topic_path, "hello/+"),
+            packet => panic!("Expecting subscribe. Found = {:?}", packet),
+        }
+
+        match packets_rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            Packet::Publish(publish) => assert_eq!((publish.payload[0], publish.dup), (3, false)),
+            packet => panic!("Expecting fresh publish. Found = {:?}", packet),
+        }
+    }
+
+    #[test]
+    fn batches_should_pass_the_throttle_whole_and_take_their_pkids_together() {
+        use futures::stream;
+
+        let mqttoptions = MqttOptions::default().set_topic_throttle("slow/#", 2.0);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let publish = |topic: &str, payload| Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pkid: None,
+            topic_name: topic.to_owned(),
+            payload: Arc::new(vec![payload]),
+        };
+
+        // publish of another topic doesn't overtake the batch of the throttled topic
+        let batch = vec![publish("slow/a", 2), publish("slow/b", 3), publish("slow/c", 4)];
+        let requests = vec![Request::Publish(publish("slow/a", 1)), Request::PublishBatch(batch), Request::Publish(publish("fast", 5))];
+        let requests = connection.throttled_network_stream(stream::iter_ok(requests));
+        let requests = connection.user_requests(requests);
+
+        let (_, requests) = runtime.block_on(requests.into_future().map_err(|(e, _)| e)).unwrap();
+        let (_, requests) = runtime.block_on(requests.into_future().map_err(|(e, _)| e)).unwrap();
+        assert_eq!(connection.mqtt_state.borrow().unacked_len(), 4);
+
+        let requests = runtime.block_on(requests.collect()).unwrap();
+        let sent: Vec<(u8, u16)> = requests
+            .into_iter()
+            .map(|request| match request {
+                Request::Publish(publish) => (publish.payload[0], publish.pkid.unwrap().0),
+                request => panic!("Expecting publish. Found = {:?}", request),
+            })
+            .collect();
+        assert_eq!(sent, vec![(3, 3), (4, 4), (5, 5)]);
+    }
+
+    #[test]
+    fn connect_or_not_returns_correct_reconnection_behaviour_in_always_reconnect_mode() {
+        let reconnect_opt = ReconnectOptions::Always(10);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        // disconnections should take user reconnection options into consideration
+        let (mut connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+
+        // results in an error but continues reconnection
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(true) => (),
+            _ => panic!("Should return reconnect = true")
+        }
+        assert!(userhandle.connection_rx.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn connect_or_not_should_stop_reconnecting_after_fatal_refusals() {
+        let reconnect_opt = ReconnectOptions::Always(1);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+
+        // bad username or password
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::MqttConnectionRefused(4));
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(false) => (),
+            _ => panic!("Should return reconnect = false"),
+        }
+
+        match userhandle.notification_rx.try_recv() {
+            Ok(Notification::ConnectionRefused(4)) => (),
+            n => panic!("Expecting connection refused notification. Found = {:?}", n),
+        }
+        assert_eq!(*connection.connection_status.lock().unwrap(), ConnectionStatus::Disconnected { reconnecting: false });
+
+        // server unavailable is transient
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::MqttConnectionRefused(3));
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(true) => (),
+            _ => panic!("Should return reconnect = true"),
+        }
+
+        // users can choose to retry auth failures
+        let mqttoptions = mqttoptions.set_fatal_refusals(vec![]);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::MqttConnectionRefused(4));
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(true) => (),
+            _ => panic!("Should return reconnect = true"),
+        }
+    }
+
+    #[test]
+    fn connect_or_not_should_stop_reconnecting_after_proxy_auth_failures() {
+        let reconnect_opt = ReconnectOptions::Always(1);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+
+        let refusal = |status| ConnectError::ProxyConnect { status, reason: String::new(), authenticate: None };
+        match connect_or_not(&mut connection, &mut runtime, future::err::<MqttFramed, _>(refusal(407))) {
+            Err(false) => (),
+            _ => panic!("Should return reconnect = false"),
+        }
+
+        match userhandle.notification_rx.try_recv() {
+            Ok(Notification::ProxyRefused(407)) => (),
+            n => panic!("Expecting proxy refused notification. Found = {:?}", n),
+        }
+
+        // policy rejections of the proxy might be lifted later
+        match connect_or_not(&mut connection, &mut runtime, future::err::<MqttFramed, _>(refusal(403))) {
+            Err(true) => (),
+            _ => panic!("Should return reconnect = true"),
+        }
+
+        // credentials of the environment can be fixed before the next attempt
+        let mqttoptions = mqttoptions.set_proxy(Proxy::FromEnv);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        match connect_or_not(&mut connection, &mut runtime, future::err::<MqttFramed, _>(refusal(407))) {
+            Err(true) => (),
+            _ => panic!("Should return reconnect = true"),
+        }
+    }
+
+    #[test]
+    fn connect_or_not_returns_dontreconnect_in_afterfirstsuccess_mode_during_first_failure() {
+        // first connection
+        let reconnect_opt = ReconnectOptions::AfterFirstSuccess(5);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        // disconnections should take user reconnection options into consideration
+        let (mut connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+
+        // results in an error and reconnection = false during 1st reconnection
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(false) => (),
+            Err(true) => panic!("Should return reconnect = false"),
+            Ok(_) => panic!("not possible")
+        }
+        assert!(userhandle.connection_rx.recv().unwrap().is_err());
+    }
+
+    #[test]
+    fn connect_or_not_returns_reconnect_in_afterfirstsuccess_mode_during_second_failure() {
+        // first connection
+        let reconnect_opt = ReconnectOptions::AfterFirstSuccess(3);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        // disconnections should take user reconnection options into consideration
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+        connection.ever_connected = true;
+        let ioerror = io::Error::new(io::ErrorKind::Other, "oh no!");
+        let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(ioerror));
+
+        // results in an error and reconnection = false during 1st reconnection
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(true) => (),
+            Err(false) => panic!("Should return reconnect = true"),
+            Ok(_) => panic!("not possible")
+        }
+    }
+
+    #[test]
+    fn rejected_connack_should_not_count_as_a_successful_connection() {
+        use mqtt311::{Connack, ConnectReturnCode, MqttRead, MqttWrite};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_packet().unwrap();
+            let connack = Connack { session_present: false, code: ConnectReturnCode::ServerUnavailable };
+            stream.write_packet(&Packet::Connack(connack)).unwrap();
+            stream
+        });
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::AfterFirstSuccess(0));
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        // tcp connection succeeds but the broker refuses the mqtt connection
+        let connect_future = connection.mqtt_connect();
+        match connect_or_not(&mut connection, &mut runtime, connect_future) {
+            Err(false) => (),
+            Err(true) => panic!("Should return reconnect = false"),
+            Ok(_) => panic!("not possible"),
+        }
+
+        assert!(!connection.ever_connected);
+        let _stream = broker.join().unwrap();
+    }
+
+    #[test]
+    fn accepted_connack_should_enable_reconnections() {
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || accept_connection(&listener, false));
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "127.0.0.1", port).set_reconnect_opts(ReconnectOptions::AfterFirstSuccess(0));
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        let connect_future = connection.mqtt_connect();
+        assert!(connect_or_not(&mut connection, &mut runtime, connect_future).is_ok());
+        assert!(connection.ever_connected);
+        let _stream = broker.join().unwrap();
+
+        // failures after the first success are retried
+        for _ in 0..2 {
+            let connect_future = future::err::<MqttFramed, _>(ConnectError::Io(io::Error::new(io::ErrorKind::Other, "oh no!")));
+            match connect_or_not(&mut connection, &mut runtime, connect_future) {
+                Err(true) => (),
+                Err(false) => panic!("Should return reconnect = true"),
+                Ok(_) => panic!("not possible"),
+            }
+        }
+    }
+
+    #[test]
+    fn connect_or_not_should_follow_the_initial_policy_till_the_first_connection() {
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883)
+            .set_initial_connect_policy(RetryPolicy::Interval(Duration::from_millis(10)))
+            .set_reconnect_policy(RetryPolicy::Never);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let connect_future = || future::err::<MqttFramed, _>(ConnectError::Io(io::Error::new(io::ErrorKind::Other, "oh no!")));
+
+        // initial connection is retried any number of times
+        for _ in 0..3 {
+            match connect_or_not(&mut connection, &mut runtime, connect_future()) {
+                Err(true) => (),
+                Err(false) => panic!("Should return reconnect = true"),
+                Ok(_) => panic!("not possible"),
+            }
+        }
+
+        connection.ever_connected = true;
+        match connect_or_not(&mut connection, &mut runtime, connect_future()) {
+            Err(false) => (),
+            Err(true) => panic!("Should return reconnect = false"),
+            Ok(_) => panic!("not possible"),
+        }
+    }
+
+    #[test]
+    fn mqtt_io_returns_correct_reconnection_behaviour() {
+        let reconnect_opt = ReconnectOptions::Always(10);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        // disconnections should take user reconnection options into consideration
+        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+        let network_future = future::err::<(), _>(NetworkError::NetworkStreamClosed);
+        let out = mqtt_io(&mut connection, runtime, network_future);
+        assert_eq!(out, Err(true));
+
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        // user shutdown should not take reconnection options into consideration
+        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        connection.mqtt_state.borrow_mut().handle_outgoing_disconnect().unwrap();
+        let network_future = future::err::<(), _>(NetworkError::NetworkStreamClosed);
+        let out = mqtt_io(&mut connection, runtime, network_future);
+        assert_eq!(out, Err(false));
+    }
+
+    #[test]
+    fn connection_status_should_track_connection_success_and_network_errors() {
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        connection.handle_connection_success();
+        assert_eq!(*connection.connection_status.lock().unwrap(), ConnectionStatus::Connected);
+
+        let network_future = future::err::<(), _>(NetworkError::UserDisconnect);
+        let _ = mqtt_io(&mut connection, runtime, network_future);
+        let status = *connection.connection_status.lock().unwrap();
+        assert_eq!(status, ConnectionStatus::Disconnected { reconnecting: true });
+    }
+
+    #[test]
+    fn timer_failures_of_stream_timeouts_should_be_errors() {
+        use tokio::timer::{self, timeout};
+
+        match super::timeout_inner_error(timeout::Error::<io::Error>::timer(timer::Error::shutdown())) {
+            NetworkError::Timer(e) => assert!(e.is_shutdown()),
+            e => panic!("Expecting timer error. Found = {:?}", e),
+        }
+
+        let e = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        match super::timeout_inner_error(timeout::Error::inner(e)) {
+            NetworkError::Io(e) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            e => panic!("Expecting io error. Found = {:?}", e),
+        }
+    }
+
+    #[test]
+    fn shutdown_should_interrupt_reconnection_wait() {
+        let reconnect_opt = ReconnectOptions::Always(60);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        let (mut connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let next = connection.handle_eventloop_end(Err(NetworkError::NetworkStreamClosed));
+        assert_eq!(next, Reconnect::Backoff);
+
+        let start = std::time::Instant::now();
+        let commands = futures::stream::once::<Packet, _>(Err(NetworkError::UserShutdown));
+        match runtime.block_on(connection.reconnect(next, Rc::new(RefCell::new(commands)))) {
+            Ok(Loop::Break(_)) => (),
+            _ => panic!("Shutdown should stop the eventloop"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // lifecycle notifications
+        match userhandle.notification_rx.try_recv() {
+            Ok(Notification::Disconnected(_)) => (),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+
+        match userhandle.notification_rx.try_recv() {
+            Ok(Notification::Reconnecting { attempt: 1, delay, error }) => {
+                assert_eq!(delay, Duration::from_secs(60));
+                assert_eq!(error, Some(ErrorSummary::Network("Network stream closed".to_owned())));
+            }
+            n => panic!("Invalid notification: {:?}", n),
+        }
+    }
+
+    #[test]
+    fn user_reconnect_request_should_use_new_options_for_next_connection() {
+        use crate::mqttoptions::SecurityOptions;
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions.clone(), mqtt_state);
+
+        let security = SecurityOptions::UsernamePassword("user".to_owned(), "rotated".to_owned());
+        let new_options = mqttoptions.set_security_opts(security);
+        let network_future = {
+            let mut mqtt_state = connection.mqtt_state.borrow_mut();
+            super::validate_userrequest(Request::Reconnect(new_options), &mut mqtt_state).map(|_| ())
+        };
+
+        let out = mqtt_io(&mut connection, runtime, network_future);
+        assert_eq!(out, Err(true));
+
+        match connection.mqttoptions.security_opts() {
+            SecurityOptions::UsernamePassword(_, password) => assert_eq!(password, "rotated"),
+            o => panic!("Invalid security options {:?}", o),
+        }
+
+        let connect = connection.mqtt_state.borrow_mut().handle_outgoing_connect().unwrap();
+        assert_eq!(connect.password, Some("rotated".to_owned()));
+    }
+
+    #[test]
+    fn notifications_which_dont_fit_in_the_channel_should_be_counted() {
+        let mqttoptions = MqttOptions::default();
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, userhandle, _runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        // mock notification channel capacity is 10
+        for _ in 0..12 {
+            connection.notify(Notification::Connected(connection_info(false)));
+        }
+
+        assert_eq!(connection.notifications_dropped.load(Ordering::SeqCst), 2);
+        assert_eq!(userhandle.notification_rx.try_iter().count(), 10);
+    }
+
+    #[test]
+    fn full_notification_channel_should_follow_overflow_policy() {
+        let (notification_tx, notification_rx) = crossbeam_channel::bounded(1);
+        let dropped = AtomicUsize::new(0);
+        let blocked = Arc::new(AtomicTask::new());
+        let publish = |pkid| Notification::PubAck(PacketIdentifier(pkid));
+        let reply = || Request::PubAck(PacketIdentifier(1));
+
+        notification_tx.try_send(publish(1)).unwrap();
+
+        // oldest notification gives way to the new one
+        let o = super::handle_notification_and_reply(&notification_tx, Some(&notification_rx), NotificationOverflow::DropOldest, None, &dropped, &blocked, publish(2), reply());
+        assert!(o.wait().is_ok());
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+
+        // newest notification is dropped and the connection recreated
+        let o = super::handle_notification_and_reply(&notification_tx, None, NotificationOverflow::DropNewest, None, &dropped, &blocked, publish(3), reply());
+        match o.wait() {
+            Err(NetworkError::ReceiverCatchup) => (),
+            o => panic!("Expected receiver catchup error. Found = {:?}", o),
+        }
+        assert_eq!(dropped.load(Ordering::SeqCst), 2);
+
+        // reply waits till the receiver makes space and wakes the eventloop
+        let receiver = NotificationReceiver::new(notification_rx.clone(), Arc::new(AtomicUsize::new(0)), blocked.clone());
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            receiver.recv().unwrap()
+        });
+
+        let o = super::handle_notification_and_reply(&notification_tx, None, NotificationOverflow::Block, None, &dropped, &blocked, publish(4), reply());
+        let mut runtime = Runtime::new().unwrap();
+        assert!(runtime.block_on(o).is_ok());
+        assert_eq!(dropped.load(Ordering::SeqCst), 2);
+
+        match (consumer.join().unwrap(), notification_rx.try_recv()) {
+            (Notification::PubAck(PacketIdentifier(2)), Ok(Notification::PubAck(PacketIdentifier(4)))) => (),
+            o => panic!("Unexpected notifications = {:?}", o),
+        }
+    }
+
+    #[test]
+    fn notifications_should_be_delivered_on_the_stream_when_enabled() {
+        let (notification_tx, notification_rx) = crossbeam_channel::bounded(10);
+        let (stream_tx, stream_rx) = futures::sync::mpsc::channel(10);
+        let dropped = AtomicUsize::new(0);
+
+        let notification = Notification::Connected(connection_info(true));
+        let reply = super::handle_notification_and_reply(
+            &notification_tx,
+            None,
+            NotificationOverflow::DropNewest,
+            Some(&stream_tx),
+            &dropped,
+            &Arc::new(AtomicTask::new()),
+            notification,
+            Request::PubAck(PacketIdentifier(1)),
+        );
+        match reply.wait() {
+            Ok(Request::PubAck(PacketIdentifier(1))) => (),
+            reply => panic!("Invalid reply: {:?}", reply),
+        }
+
+        match stream_rx.into_future().wait() {
+            Ok((Some(Notification::Connected(ConnectionInfo { session_present: true, tls: None, .. })), _)) => (),
+            _ => panic!("Expected reconnection notification on the stream"),
+        }
+
+        assert!(notification_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn notifications_should_be_broadcasted_to_listeners() {
+        let (listener1_tx, listener1_rx) = crossbeam_channel::bounded(1);
+        let (listener2_tx, listener2_rx) = crossbeam_channel::bounded(1);
+        let listeners = Mutex::new(vec![listener1_tx, listener2_tx]);
+
+        super::broadcast(&listeners, &Notification::Connected(connection_info(false)));
+        super::broadcast(&listeners, &Notification::None);
+        match (listener1_rx.try_recv(), listener2_rx.try_recv()) {
+            (Ok(Notification::Connected(_)), Ok(Notification::Connected(_))) => (),
+            n => panic!("Invalid notifications: {:?}", n),
+        }
+
+        // full listeners skip the notification. dropped listeners are removed
+        drop(listener2_rx);
+        super::broadcast(&listeners, &Notification::Disconnected("Network closed".to_owned()));
+        super::broadcast(&listeners, &Notification::Connected(connection_info(false)));
+        assert_eq!(listeners.lock().unwrap().len(), 1);
+        match listener1_rx.try_recv() {
+            Ok(Notification::Disconnected(_)) => (),
+            n => panic!("Invalid notification: {:?}", n),
+        }
+    }
+
+    #[test]
+    fn last_will_request_should_update_will_of_next_connect() {
+        use mqtt311::LastWill;
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883);
+        let mut mqtt_state = MqttState::new(mqttoptions);
+        let last_will = LastWill {
+            topic: "hello/will".to_owned(),
+            message: "offline".to_owned(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        };
+
+        let request = super::validate_userrequest(Request::LastWill(Some(last_will.clone())), &mut mqtt_state).wait();
+        assert!(request.unwrap().is_none());
+        assert_eq!(mqtt_state.handle_outgoing_connect().unwrap().last_will, Some(last_will));
+
+        let _ = super::validate_userrequest(Request::LastWill(None), &mut mqtt_state).wait();
+        assert_eq!(mqtt_state.handle_outgoing_connect().unwrap().last_will, None);
+    }
+
+    #[test]
+    fn oversized_publish_request_should_fail_only_that_publish() {
+        use crate::client::AckWaiter;
+        use futures::sync::oneshot;
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_max_outgoing_packet_size(1);
+        let mut mqtt_state = MqttState::new(mqttoptions);
+        let publish = |size| Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(vec![1; size]),
+        };
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        let request = Request::PublishWithAck(publish(2048), AckWaiter::Future(ack_tx));
+        let request = super::validate_userrequest(request, &mut mqtt_state).wait();
+        assert!(request.unwrap().is_none());
+        assert!(ack_rx.wait().is_err());
+
+        let request = super::validate_userrequest(Request::Publish(publish(100)), &mut mqtt_state).wait();
+        assert!(request.unwrap().is_some());
+    }
+
+    #[test]
+    fn publishes_queued_longer_than_the_ttl_should_be_dropped_when_they_are_sent() {
+        use std::time::Instant;
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_queued_message_ttl(Duration::from_secs(1));
+        let mut mqtt_state = MqttState::new(mqttoptions);
+        let publish = |qos| Publish {
+            dup: false,
+            qos,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        // qos0 publish which waited in the request channel while offline
+        let stale = Instant::now() - Duration::from_secs(2);
+        let request = Request::Queued(Box::new(Request::Publish(publish(QoS::AtMostOnce))), stale);
+        assert!(super::validate_userrequest(request, &mut mqtt_state).wait().unwrap().is_none());
+
+        let request = Request::Queued(Box::new(Request::Publish(publish(QoS::AtLeastOnce))), Instant::now());
+        match super::validate_userrequest(request, &mut mqtt_state).wait() {
+            Ok(Some(Request::Queued(request, _))) => match *request {
+                Request::Publish(_) => (),
+                request => panic!("Expecting publish. Found = {:?}", request),
+            },
+            request => panic!("Expecting queued publish. Found = {:?}", request),
+        }
+
+        match mqtt_state.take_expired().as_slice() {
+            [Notification::Expired { topic, count: 1 }] => assert_eq!(topic, "hello/world"),
+            notifications => panic!("Expecting an expiry summary. Found = {:?}", notifications),
+        }
+    }
+
+    #[test]
+    fn replays_which_the_state_rejects_should_fail_as_replays() {
+        use mqtt311::{Subscribe, SubscribeTopic};
+
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883);
+        let mut mqtt_state = MqttState::new(mqttoptions);
+        for _ in 0..65_535 {
+            let publish = Publish {
+                dup: false,
+                qos: QoS::AtLeastOnce,
+                retain: false,
+                topic_name: "hello/world".to_owned(),
+                pkid: None,
+                payload: Arc::new(vec![1, 2, 3]),
+            };
+            mqtt_state.handle_outgoing_publish(publish).unwrap();
+        }
+
+        let topics = vec![SubscribeTopic { topic_path: "hello/world".to_owned(), qos: QoS::AtLeastOnce }];
+        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics };
+
+        // resubscribe of the previous session can't take a packet id
+        let replay = Request::Replay(Box::new(Request::Subscribe(subscribe.clone())));
+        match super::handle_userrequest(replay, &mut mqtt_state) {
+            Err(NetworkError::ReplayFailed(_)) => (),
+            o => panic!("Expecting a failed replay. Found = {:?}", o),
+        }
+
+        // same failure of a user subscribe isn't a replay
+        match super::handle_userrequest(Request::Subscribe(subscribe), &mut mqtt_state) {
+            Err(NetworkError::PacketIdsExhausted) => (),
+            o => panic!("Expecting exhausted packet ids. Found = {:?}", o),
+        }
+    }
+
+    #[test]
+    fn mqtt_io_should_not_reconnect_after_graceful_disconnect() {
+        let reconnect_opt = ReconnectOptions::Always(10);
+        let mqttoptions = MqttOptions::new("mqtt-io-test", "localhost", 1883).set_reconnect_opts(reconnect_opt);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        let (mut connection, _userhandle, runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        connection.mqtt_state.borrow_mut().handle_outgoing_disconnect().unwrap();
+        let network_future = future::ok::<(), NetworkError>(());
+        let out = mqtt_io(&mut connection, runtime, network_future);
+        assert_eq!(out, Err(false));
+    }
+
+    #[test]
+    fn redundant_pause_and_resume_commands_should_be_ignored() {
+        let commands = || futures::stream::iter_result(vec![
+            Err(NetworkError::UserReconnect),
+            Err(NetworkError::UserDisconnect),
+        ]);
+
+        // resume is ignored when the network is active
+        match super::filter_commands(commands(), true).collect().wait() {
+            Err(NetworkError::UserDisconnect) => (),
+            o => panic!("Expecting pause. Found = {:?}", o),
+        }
+
+        // pause is ignored when the network is paused
+        match super::filter_commands(commands(), false).collect().wait() {
+            Err(NetworkError::UserReconnect) => (),
+            o => panic!("Expecting resume. Found = {:?}", o),
+        }
+    }
+
+    #[test]
+    fn outgoing_stream_should_end_after_disconnect_packet() {
+        let packets = vec![Ok(Packet::Pingreq), Ok(Packet::Disconnect), Ok(Packet::Pingreq)];
+        let stream = futures::stream::iter_result(packets);
+        let packets = super::until_disconnect(stream).collect().wait().unwrap();
+        assert_eq!(packets, vec![Packet::Pingreq, Packet::Disconnect]);
+    }
+
+    #[cfg(target_os = "linux")]
+    // incoming puback at second 1 and pingresp at periodic intervals
+    fn network_incoming_pingresps() -> impl Stream<Item = Packet, Error = io::Error> {
+        let mut acks = DelayQueue::new();
+
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pkid: None,
+            topic_name: "hello/world".to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        acks.insert( Packet::Publish(publish), Duration::from_secs(2));
+        // out idle ping at 5000 + 500 (out ping delay wrt to keep alive)
+        acks.insert(Packet::Pingresp, Duration::from_millis(5510));
+        // in idle ping at 10520
+        acks.insert(Packet::Pingresp, Duration::from_millis(10520));
+        // in idle ping at 15530
+        acks.insert(Packet::Pingresp, Duration::from_millis(15530));
+
+        acks.map(|v| {
+            v.into_inner()
+        }).map_err(|_e| {
+            io::Error::new(io::ErrorKind::Other, "Timer error")
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reply_stream_triggers_pings_on_time() {
+        let mqttoptions = MqttOptions::default().set_keep_alive(5);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        let (connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let network_reply_stream = network_incoming_pingresps();
+        let network_reply_stream = connection.network_reply_stream(network_reply_stream);
+
+        let start = Instant::now();
+
+        // incoming publish at second 1,
+        // so pingreq should happen at second 6, 12 and 16
+        let network_future = network_reply_stream.fold(1, |mut count, packet| {
+            let elapsed = start.elapsed().as_millis();
+            println!("Packet = {:?}, Elapsed = {:?}", packet, elapsed);
+            match packet {
+                // incoming publish at 2000. (in idle, out idle) = (7000, 5000 + 500) ---> out idle ping at 5500
+                Request::OutgoingIdlePing if count == 1 =>  assert!(elapsed > 5500 && elapsed < 5700),
+                // ping resp at 5510. (in idle, out idle) = (10510, 5500 + 5500) ---> in idle ping at 10510
+                Request::IncomingIdlePing if count == 2 =>  assert!(elapsed > 10510 && elapsed < 10700),
+                // ping resp at 10520. (in idl, out idle) = (15520, 10510 + 5500) ---> in idle ping at 15520
+                Request::IncomingIdlePing if count == 3 =>  assert!(elapsed > 15520 && elapsed < 15700),
+                _ => panic!("Expecting publish or ping")
+            }
+
+            count += 1;
+            future::ok::<_, NetworkError>(count)
+        });
+
+        match runtime.block_on(network_future) {
+            Err(NetworkError::NetworkStreamClosed) | Ok(_) => (),
+            Err(e) => panic!("Error = {:?}", e)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn throttled_stream_operates_at_specified_rate() {
+        let mqttoptions = MqttOptions::default().set_throttle(5.0);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        // note: maintain order similar to mqtt_future()
+        // generates 100 user requests
+        let user_request_stream = user_requests(Duration::from_millis(1));
+        let user_request_stream = connection.throttled_network_stream(user_request_stream);
+        let user_request_stream = connection.user_requests(user_request_stream);
+        let user_request_stream = user_request_stream.and_then(move |packet| future::ok(packet.into()));
+
+        let f = user_request_stream.fold(Instant::now(), |last, v: Packet| {
+            // println!("outgoing = {:?}", v);
+            let now = Instant::now();
+
+            if let Packet::Publish(Publish{pkid, ..}) = v {
+                if pkid.unwrap() > PacketIdentifier(1) {
+                    let elapsed = (now - last).as_millis();
+                    dbg!(elapsed);
                     assert!(elapsed > 190 && elapsed < 220)
                 }
             }  
@@ -965,6 +3120,73 @@ mod test {
         let _ = runtime.block_on(network_stream);
     }
 
+    #[test]
+    fn pending_qos0_publishes_should_be_dropped_at_the_inflight_limit() {
+        use crate::mqttoptions::InflightOverflow;
+        use futures::stream;
+        use tokio::timer::Timeout;
+
+        let mqttoptions = MqttOptions::default().set_inflight(1).set_inflight_overflow(InflightOverflow::DropQos0);
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (mut connection, _userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+        let publish = |qos| Request::Publish(Publish {
+            dup: false,
+            qos,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(vec![1, 2, 3]),
+        });
+
+        let requests = vec![publish(QoS::AtLeastOnce), publish(QoS::AtMostOnce), publish(QoS::AtMostOnce), publish(QoS::AtLeastOnce)];
+        let requests = connection.inflight_limited_request_stream(stream::iter_ok(requests));
+        let requests = connection.user_requests(requests);
+
+        // first publish fills the in flight queue
+        let (request, requests) = runtime.block_on(requests.into_future().map_err(|(e, _)| e)).unwrap();
+        match request {
+            Some(Request::Publish(publish)) => assert_eq!(publish.qos, QoS::AtLeastOnce),
+            request => panic!("Expecting qos1 publish. Found = {:?}", request),
+        }
+
+        // qos0 publishes behind it are dropped and the next qos1 publish waits for an ack
+        let next = Timeout::new(requests.into_future().map_err(|(e, _)| e), Duration::from_millis(200));
+        match runtime.block_on(next) {
+            Err(ref e) if e.is_elapsed() => (),
+            _ => panic!("Expecting the qos1 publish to wait for an ack"),
+        }
+
+        assert_eq!(connection.mqtt_state.borrow().stats().snapshot().publishes_dropped, 2);
+    }
+
+    #[test]
+    fn oversized_incoming_packets_should_be_notified_without_disconnecting() {
+        use crate::codec::PacketTooLarge;
+
+        let mqttoptions = MqttOptions::default();
+        let mqtt_state = MqttState::new(mqttoptions.clone());
+        let (connection, userhandle, mut runtime) = mock_mqtt_connection(mqttoptions, mqtt_state);
+
+        let too_large = PacketTooLarge { topic: Some("hello/world".to_owned()), size: 10 * 1024 * 1024 };
+        let packets = vec![Err(io::Error::new(io::ErrorKind::InvalidData, too_large)), Ok(Packet::Puback(PacketIdentifier(1)))];
+        let network_stream = futures::stream::iter_result(packets);
+        let network_reply_stream = connection.network_reply_stream(network_stream);
+
+        // unsolicited puback errors after the oversized packet
+        match runtime.block_on(network_reply_stream.collect()) {
+            Err(NetworkError::Unsolicited) => (),
+            o => panic!("Expecting unsolicited puback error. Found = {:?}", o),
+        }
+
+        match userhandle.notification_rx.try_recv() {
+            Ok(Notification::PayloadTooLarge { topic: Some(topic), size }) => {
+                assert_eq!(topic, "hello/world");
+                assert_eq!(size, 10 * 1024 * 1024);
+            }
+            n => panic!("Expecting payload too large notification. Found = {:?}", n),
+        }
+    }
+
     #[test]
     fn reply_stream_results_in_an_error_when_notification_receiver_doesnt_catchup() {
         let mqttoptions = MqttOptions::default().set_inflight(50);
@@ -996,8 +3218,8 @@ mod test {
         thread::spawn(move || {
             for (count, notification) in userhandle.notification_rx.iter().enumerate() {
                 match notification {
-                    Notification::Reconnection if count == 0 => (),
-                    Notification::Disconnection if count == 21 => (),
+                    Notification::Connected(_) if count <= 1 => (),
+                    Notification::Disconnected(_) if count == 22 => (),
                     Notification::Publish(_) if count != 0 || count != 21 => (),
                     n => panic!("Not expected notification {:?}", n)
                 }
@@ -1013,7 +3235,7 @@ mod test {
             future::ok(())
         });
 
-        let _ = connection.mqtt_io(runtime, network_future);
+        let _ = mqtt_io(&mut connection, runtime, network_future);
     }
 }
 
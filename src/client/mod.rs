@@ -1,11 +1,33 @@
 //! Structs to interact with mqtt eventloop
-use crate::error::{ClientError, ConnectError};
+use crate::codec;
+use crate::error::{ClientError, ConnectError, ErrorSummary, PublishError, WaitError};
+use crate::session::SessionState;
+use crate::stats::{Stats, StatsSnapshot};
+use crate::topic;
+#[cfg(feature = "mqtt5")]
+use crate::v5;
 use crate::MqttOptions;
-use crossbeam_channel;
-use futures::{sync::mpsc, Future, Sink};
-use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, Unsubscribe, SubscribeTopic};
-use std::sync::Arc;
+use crossbeam_channel::{self, RecvError, RecvTimeoutError, TryRecvError};
+use futures::{
+    sync::{mpsc, oneshot},
+    task::AtomicTask,
+    Future, Poll, Sink, Stream,
+};
+#[cfg(feature = "danger-raw-packets")]
+use mqtt311::Packet;
+use mqtt311::{LastWill, PacketIdentifier, Publish, QoS, Subscribe, SubscribeReturnCodes, Unsubscribe, SubscribeTopic};
+#[cfg(any(feature = "json", feature = "cbor"))]
+use serde::Serialize;
+use std::fmt;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 
+#[doc(hidden)]
+pub mod buffer;
 #[doc(hidden)]
 pub mod connection;
 #[doc(hidden)]
@@ -14,21 +36,244 @@ pub mod mqttstate;
 pub mod network;
 #[doc(hidden)]
 pub mod prepend;
+#[doc(hidden)]
+pub mod priority;
+#[doc(hidden)]
+pub mod throttle;
 
 /// Incoming notifications from the broker
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Notification {
-    Reconnection,
-    Disconnection,
+    /// Successful connection (or reconnection) to the broker. `session_present` of
+    /// the info tells if the broker resumed the previous session
+    Connected(ConnectionInfo),
+    /// First connection attempt of a client which didn't wait for it (`start_async`
+    /// or `new`) failed. Later attempts are notified as `Reconnecting`
+    ConnectFailed(ErrorSummary),
+    /// Broker answered the last ping after `rtt`. Only with
+    /// `MqttOptions::set_ping_notifications`
+    PingResponse { rtt: Duration },
+    /// Connection to the broker is lost. Contains the reason
+    Disconnected(String),
+    /// Eventloop waits for `delay` before the reconnection `attempt` (starts from 1
+    /// after every disconnection or connection failure). `error` caused the reconnection
+    Reconnecting { attempt: u32, delay: Duration, error: Option<ErrorSummary> },
+    /// Broker refused the connection with a return code which is configured as fatal
+    /// (see `MqttOptions::set_fatal_refusals`). Eventloop stops after this
+    ConnectionRefused(u8),
+    /// Http proxy refused the tunnel with a status which is configured as fatal (see
+    /// `MqttOptions::set_fatal_proxy_statuses`). Eventloop stops after this
+    ProxyRefused(u16),
+    /// Incoming packet bigger than the maximum packet size is dropped. `topic` is known
+    /// when it arrived along with the packet's fixed header
+    PayloadTooLarge { topic: Option<String>, size: usize },
+    /// `count` publishes to `topic` were older than the queued message ttl (see
+    /// `MqttOptions::set_queued_message_ttl`) and are dropped instead of being sent
+    Expired { topic: String, count: usize },
+    /// Eventloop panicked with this message. This is the last notification and
+    /// requests fail with `ClientError::EventloopTerminated` afterwards
+    EventloopCrashed(String),
     Publish(Publish),
+    /// Qos1 publish in manual ack mode (see `MqttOptions::set_manual_acks`). Broker
+    /// redelivers it after a reconnection till the handle is passed to `MqttClient::ack`
+    ManualAckPublish(Publish, AckHandle),
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
-    SubAck(PacketIdentifier),
+    /// Subscription acknowledgement with the granted qos (or failure) of every
+    /// requested filter in order and the topic filters rejected by the broker
+    SubAck {
+        pkid: PacketIdentifier,
+        return_codes: Vec<SubscribeReturnCodes>,
+        rejected: Vec<String>,
+    },
+    UnsubAck(PacketIdentifier),
+    /// Mqtt 5 broker failed the publish `pkid` with the reason code of its puback,
+    /// pubrec or pubcomp. Publisher waiting for the ack sees `PublishError::Dropped`
+    #[cfg(feature = "mqtt5")]
+    Rejected { pkid: PacketIdentifier, reason_code: u8, reason_string: Option<String> },
     None,
 }
 
+/// Receiver of the notifications sent by the eventloop
+pub struct NotificationReceiver {
+    rx: crossbeam_channel::Receiver<Notification>,
+    dropped: Arc<AtomicUsize>,
+    // eventloop waiting for space in the channel. see `NotificationOverflow::Block`
+    blocked: Arc<AtomicTask>,
+}
+
+impl NotificationReceiver {
+    pub(crate) fn new(rx: crossbeam_channel::Receiver<Notification>, dropped: Arc<AtomicUsize>, blocked: Arc<AtomicTask>) -> NotificationReceiver {
+        NotificationReceiver { rx, dropped, blocked }
+    }
+
+    /// Blocks until the next notification. Errors when the eventloop is done
+    pub fn recv(&self) -> Result<Notification, RecvError> {
+        let notification = self.rx.recv();
+        self.notify_received();
+        notification
+    }
+
+    /// Blocks until the next notification or until the timeout elapses
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Notification, RecvTimeoutError> {
+        let notification = self.rx.recv_timeout(timeout);
+        self.notify_received();
+        notification
+    }
+
+    /// Returns the next notification if one is available without blocking
+    pub fn try_recv(&self) -> Result<Notification, TryRecvError> {
+        let notification = self.rx.try_recv();
+        self.notify_received();
+        notification
+    }
+
+    /// Underlying crossbeam receiver. Useful with crossbeam's `select!`. Call
+    /// `notify_received` after taking notifications from it
+    pub fn receiver(&self) -> &crossbeam_channel::Receiver<Notification> {
+        &self.rx
+    }
+
+    /// Wakes the eventloop if it waits for space in the channel (`NotificationOverflow::Block`).
+    /// Only needed for notifications taken straight from `receiver`. The other methods
+    /// of this receiver do it on their own
+    pub fn notify_received(&self) {
+        self.blocked.notify();
+    }
+
+    /// Number of notifications the eventloop couldn't deliver because this
+    /// channel was full
+    pub fn dropped_count(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl IntoIterator for NotificationReceiver {
+    type Item = Notification;
+    type IntoIter = NotificationIntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        NotificationIntoIter { receiver: self }
+    }
+}
+
+impl<'a> IntoIterator for &'a NotificationReceiver {
+    type Item = Notification;
+    type IntoIter = NotificationIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        NotificationIter { receiver: self }
+    }
+}
+
+/// Blocking iterator over the notifications. Ends when the eventloop is done
+pub struct NotificationIter<'a> {
+    receiver: &'a NotificationReceiver,
+}
+
+impl<'a> Iterator for NotificationIter<'a> {
+    type Item = Notification;
+
+    fn next(&mut self) -> Option<Notification> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Owning blocking iterator over the notifications. Ends when the eventloop is done
+pub struct NotificationIntoIter {
+    receiver: NotificationReceiver,
+}
+
+impl Iterator for NotificationIntoIter {
+    type Item = Notification;
+
+    fn next(&mut self) -> Option<Notification> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Current state of the connection to the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    /// `reconnecting` is false when the eventloop is paused or stopped
+    Disconnected { reconnecting: bool },
+}
+
+/// Details of an established connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub broker: (String, u16),
+    pub session_present: bool,
+    /// `None` for connections without tls
+    pub tls: Option<TlsInfo>,
+}
+
+/// Parameters negotiated by the tls handshake of a connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// Protocol version (e.g `TLSv1_2`)
+    pub version: String,
+    /// Cipher suite (e.g `TLS13_AES_256_GCM_SHA384`)
+    pub cipher_suite: String,
+    /// Sha256 fingerprint (hex) of the broker's certificate
+    pub peer_certificate_sha256: Option<String>,
+    /// Expiry (`notAfter`) of the broker's certificate
+    pub peer_certificate_expiry: Option<SystemTime>,
+}
+
+/// Wakes the clients waiting for a change of the connection status. Used with the
+/// connection status mutex
+#[derive(Debug, Default)]
+pub(crate) struct StatusSignal {
+    changed: Condvar,
+    stopped: AtomicBool,
+}
+
+impl StatusSignal {
+    pub(crate) fn notify(&self) {
+        self.changed.notify_all();
+    }
+
+    /// Marks the eventloop as terminated. Call before the last status update
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Puback of an incoming qos1 publish which the user sends after handling the publish
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckHandle {
+    pkid: PacketIdentifier,
+    topic: String,
+    // connection which received the publish
+    connection: u64,
+}
+
+impl AckHandle {
+    pub(crate) fn new(pkid: PacketIdentifier, topic: String, connection: u64) -> AckHandle {
+        AckHandle { pkid, topic, connection }
+    }
+
+    pub fn pkid(&self) -> PacketIdentifier {
+        self.pkid
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub(crate) fn connection(&self) -> u64 {
+        self.connection
+    }
+}
+
 #[doc(hidden)]
 /// Requests by the client to mqtt event loop. Request are
 /// handle one by one#[derive(Debug)]
@@ -36,19 +281,81 @@ pub enum Notification {
 #[derive(Debug)]
 pub enum Request {
     Publish(Publish),
+    // expanded into publishes in order once it is past the throttles
+    PublishBatch(Vec<Publish>),
+    PublishWithAck(Publish, AckWaiter),
+    // publish (or batch) and the time it was written to the request channel. age of
+    // the publish for the queued message ttl
+    Queued(Box<Request>, Instant),
+    // packet of the previous session replayed after a reconnection. failures of the
+    // state are raised as `NetworkError::ReplayFailed`
+    Replay(Box<Request>),
     Subscribe(Subscribe),
+    SubscribeWithChannel(Subscribe, crossbeam_channel::Sender<Publish>),
     Unsubscribe(Unsubscribe),
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
+    Ack(AckHandle),
     IncomingIdlePing,
     OutgoingIdlePing,
     Reconnect(MqttOptions),
+    LastWill(Option<LastWill>),
+    #[cfg(feature = "danger-raw-packets")]
+    Raw(Packet),
     Disconnect,
     None,
 }
 
+#[doc(hidden)]
+/// Publisher waiting for the broker to acknowledge its publish
+pub enum AckWaiter {
+    Blocking(crossbeam_channel::Sender<()>),
+    Future(oneshot::Sender<()>),
+}
+
+impl AckWaiter {
+    pub(crate) fn notify(self) {
+        match self {
+            AckWaiter::Blocking(tx) => {
+                let _ = tx.try_send(());
+            }
+            AckWaiter::Future(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AckWaiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AckWaiter::Blocking(_) => write!(f, "Blocking"),
+            AckWaiter::Future(_) => write!(f, "Future"),
+        }
+    }
+}
+
+/// Future returned by [publish_async] which resolves when the broker
+/// acknowledges the publish. Resolves with `PublishError::Dropped` when
+/// the publish is dropped before an acknowledgement
+///
+/// [publish_async]: struct.MqttClient.html#method.publish_async
+#[must_use = "futures do nothing unless polled"]
+pub struct PublishHandle {
+    ack_rx: oneshot::Receiver<()>,
+}
+
+impl Future for PublishHandle {
+    type Item = ();
+    type Error = PublishError;
+
+    fn poll(&mut self) -> Poll<(), PublishError> {
+        self.ack_rx.poll().map_err(|_canceled| PublishError::Dropped)
+    }
+}
+
 #[doc(hidden)]
 /// Commands sent by the client to mqtt event loop. Commands
 /// are of higher priority and will be `select`ed along with
@@ -59,6 +366,7 @@ pub enum Request {
 pub enum Command {
     Pause,
     Resume,
+    Shutdown,
 }
 
 #[doc(hidden)]
@@ -66,64 +374,360 @@ pub enum Command {
 pub struct UserHandle {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
-    notification_rx: crossbeam_channel::Receiver<Notification>,
+    notification_rx: NotificationReceiver,
+    notification_stream_rx: Option<mpsc::Receiver<Notification>>,
+    notification_listeners: Arc<Mutex<Vec<crossbeam_channel::Sender<Notification>>>>,
+    eventloop_done_rx: crossbeam_channel::Receiver<usize>,
+    eventloop: Option<JoinHandle<()>>,
+    connection_status: Arc<Mutex<ConnectionStatus>>,
+    status_signal: Arc<StatusSignal>,
+    connected_broker: Arc<Mutex<Option<(String, u16)>>>,
+    last_error: Arc<Mutex<Option<ErrorSummary>>>,
+    session_present: Arc<AtomicBool>,
+    tls_info: Arc<Mutex<Option<TlsInfo>>>,
+    final_session: Arc<Mutex<Option<SessionState>>>,
+    stats: Arc<Stats>,
+    #[cfg(feature = "mqtt5")]
+    v5: Arc<v5::Context>,
 }
 
-/// Handle to send requests and commands to the network eventloop
+/// Handle to send requests and commands to the network eventloop. Clones share the
+/// eventloop and can be moved to other threads.
+///
+/// Requests (publish, subscribe ..) of all the clones go through one bounded channel.
+/// Every clone has a slot of its own in it, so a request only waits when the channel is
+/// full (see `MqttOptions::set_request_channel_capacity`). `stats` and `session_present`
+/// are atomic reads. Connection status, errors and listeners are behind mutexes which
+/// are held only to copy the value
 #[derive(Clone)]
 pub struct MqttClient {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
-    max_packet_size: usize,
+    eventloop_done_rx: crossbeam_channel::Receiver<usize>,
+    eventloop: Arc<Mutex<Option<JoinHandle<()>>>>,
+    connection_status: Arc<Mutex<ConnectionStatus>>,
+    status_signal: Arc<StatusSignal>,
+    connected_broker: Arc<Mutex<Option<(String, u16)>>>,
+    last_error: Arc<Mutex<Option<ErrorSummary>>>,
+    session_present: Arc<AtomicBool>,
+    tls_info: Arc<Mutex<Option<TlsInfo>>>,
+    final_session: Arc<Mutex<Option<SessionState>>>,
+    stats: Arc<Stats>,
+    #[cfg(feature = "mqtt5")]
+    v5: Arc<v5::Context>,
+    notification_stream_rx: Arc<Mutex<Option<mpsc::Receiver<Notification>>>>,
+    notification_listeners: Arc<Mutex<Vec<crossbeam_channel::Sender<Notification>>>>,
+    max_outgoing_packet_size: usize,
+    notification_channel_capacity: usize,
+    // publishes are stamped with the time they are queued when there is a ttl
+    queued_message_ttl: Option<Duration>,
 }
 
 impl MqttClient {
     /// Starts a new mqtt connection in a thread and returns [mqttclient]
-    /// instance to send requests/commands to the event loop and a crossbeam
-    /// channel receiver to receive notifications sent by the event loop.
+    /// instance to send requests/commands to the event loop and a
+    /// `NotificationReceiver` to receive notifications sent by the event loop.
     ///
     /// See `select.rs` example
     /// [mqttclient]: struct.MqttClient.html
-    pub fn start(opts: MqttOptions) -> Result<(Self, crossbeam_channel::Receiver<Notification>), ConnectError> {
-        let max_packet_size = opts.max_packet_size();
+    pub fn start(opts: MqttOptions) -> Result<(Self, NotificationReceiver), ConnectError> {
+        let max_outgoing_packet_size = opts.max_outgoing_packet_size();
+        let notification_channel_capacity = opts.notification_channel_capacity();
+        let queued_message_ttl = opts.queued_message_ttl();
+        let user_handle = connection::Connection::run(opts)?;
+        Ok(MqttClient::from_handle(user_handle, max_outgoing_packet_size, notification_channel_capacity, queued_message_ttl))
+    }
+
+    /// Same as `start` but doesn't wait for the initial connection. Requests are queued
+    /// till the eventloop connects and the result of the first attempt is notified as
+    /// `Notification::Connected` or `Notification::ConnectFailed`. When the eventloop
+    /// stops after a failure (e.g `ReconnectOptions::Never`), requests fail with
+    /// `ClientError::EventloopTerminated`. Errors only when the thread can't be spawned
+    pub fn start_async(opts: MqttOptions) -> Result<(Self, NotificationReceiver), ConnectError> {
+        let max_outgoing_packet_size = opts.max_outgoing_packet_size();
+        let notification_channel_capacity = opts.notification_channel_capacity();
+        let queued_message_ttl = opts.queued_message_ttl();
+        let user_handle = connection::Connection::run_async(opts)?;
+        Ok(MqttClient::from_handle(user_handle, max_outgoing_packet_size, notification_channel_capacity, queued_message_ttl))
+    }
+
+    /// Creates the client without spawning a thread. The returned future is the eventloop
+    /// which should be spawned on a current thread executor by the caller (it isn't `Send`).
+    /// Unlike `start`, this doesn't wait for the initial connection and the errors of the
+    /// initial connection are handled as per the reconnection options.
+    /// `stop` waits for the eventloop future and can't be called from the executor thread
+    pub fn new(opts: MqttOptions) -> (Self, NotificationReceiver, impl Future<Item = (), Error = ()>) {
+        let max_outgoing_packet_size = opts.max_outgoing_packet_size();
+        let notification_channel_capacity = opts.notification_channel_capacity();
+        let queued_message_ttl = opts.queued_message_ttl();
+        let (eventloop, user_handle) = connection::Connection::new(opts);
+        let (client, notification_rx) = MqttClient::from_handle(user_handle, max_outgoing_packet_size, notification_channel_capacity, queued_message_ttl);
+        (client, notification_rx, eventloop)
+    }
+
+    fn from_handle(
+        user_handle: UserHandle,
+        max_outgoing_packet_size: usize,
+        notification_channel_capacity: usize,
+        queued_message_ttl: Option<Duration>,
+    ) -> (Self, NotificationReceiver) {
         let UserHandle {
             request_tx,
             command_tx,
             notification_rx,
-        } = connection::Connection::run(opts)?;
+            notification_stream_rx,
+            notification_listeners,
+            eventloop_done_rx,
+            eventloop,
+            connection_status,
+            status_signal,
+            connected_broker,
+            last_error,
+            session_present,
+            tls_info,
+            final_session,
+            stats,
+            #[cfg(feature = "mqtt5")]
+            v5,
+        } = user_handle;
 
         let client = MqttClient {
             request_tx,
             command_tx,
-            max_packet_size,
+            eventloop_done_rx,
+            eventloop: Arc::new(Mutex::new(eventloop)),
+            connection_status,
+            status_signal,
+            connected_broker,
+            last_error,
+            session_present,
+            tls_info,
+            final_session,
+            stats,
+            #[cfg(feature = "mqtt5")]
+            v5,
+            notification_stream_rx: Arc::new(Mutex::new(notification_stream_rx)),
+            notification_listeners,
+            max_outgoing_packet_size,
+            notification_channel_capacity,
+            queued_message_ttl,
         };
 
-        Ok((client, notification_rx))
+        (client, notification_rx)
     }
 
-    /// Requests the eventloop for mqtt publish
+    /// Requests the eventloop for mqtt publish. `retained` sets the mqtt retain
+    /// bit which is preserved when the publish is retransmitted after a reconnection.
+    /// Payloads are copied into the network write buffer when they are encoded.
+    /// Retransmissions share the payload of the first attempt
+    /// Retain bit of incoming publishes is available on `Notification::Publish`
     pub fn publish<S, V, B>(&mut self, topic: S, qos: QoS, retained: B, payload: V) -> Result<(), ClientError>
     where
         S: Into<String>,
         V: Into<Vec<u8>>,
         B: Into<bool>,
     {
-        let payload = payload.into();
-        if payload.len() > self.max_packet_size {
-            return Err(ClientError::PacketSizeLimitExceeded);
+        let publish = self.build_publish(topic, qos, retained.into(), payload)?;
+
+        let request = self.queued(Request::Publish(publish));
+        let tx = &mut self.request_tx;
+        tx.send(request).wait()?;
+        Ok(())
+    }
+
+    /// Same as `publish` with mqtt 5 properties (message expiry, content type, response
+    /// topic etc). Retransmissions carry the same properties. Properties are dropped
+    /// by connections of other protocol versions
+    #[cfg(feature = "mqtt5")]
+    pub fn publish_with_properties<S, V, B>(&mut self, topic: S, qos: QoS, retained: B, payload: V, properties: v5::Properties) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+        B: Into<bool>,
+    {
+        let publish = self.build_publish(topic, qos, retained.into(), payload)?;
+        let properties_len = properties.encoded_len().ok_or(ClientError::PropertyTooLong)?;
+        let actual = codec::publish_len(&publish) + properties_len;
+        if actual > self.max_outgoing_packet_size {
+            return Err(ClientError::PayloadTooLarge { limit: self.max_outgoing_packet_size, actual });
+        }
+
+        self.v5.set_publish_properties(&publish.payload, properties);
+        let request = self.queued(Request::Publish(publish));
+        let tx = &mut self.request_tx;
+        tx.send(request).wait()?;
+        Ok(())
+    }
+
+    /// Mqtt 5 properties of an incoming publish. `None` when the broker didn't send any
+    #[cfg(feature = "mqtt5")]
+    pub fn publish_properties(&self, publish: &Publish) -> Option<v5::Properties> {
+        self.v5.publish_properties(publish)
+    }
+
+    /// Reason code and properties (assigned client id, server keep alive, receive maximum
+    /// etc) of the last mqtt 5 connack
+    #[cfg(feature = "mqtt5")]
+    pub fn connack_properties(&self) -> Option<(u8, v5::Properties)> {
+        self.v5.connack()
+    }
+
+    /// Requests the eventloop for mqtt publish without blocking. Fails with
+    /// `ClientError::RequestChannelFull` which gives back the publish when the
+    /// request channel is full
+    pub fn try_publish<S, V, B>(&mut self, topic: S, qos: QoS, retained: B, payload: V) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+        B: Into<bool>,
+    {
+        let publish = self.build_publish(topic, qos, retained.into(), payload)?;
+
+        let request = self.queued(Request::Publish(publish));
+        match self.request_tx.try_send(request) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.is_disconnected() => Err(ClientError::EventloopTerminated),
+            Err(e) => match e.into_inner() {
+                Request::Publish(publish) => Err(ClientError::RequestChannelFull(publish)),
+                Request::Queued(request, _) => match *request {
+                    Request::Publish(publish) => Err(ClientError::RequestChannelFull(publish)),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            },
+        }
+    }
+
+    /// Requests the eventloop for mqtt publish of all the messages with a single request.
+    /// Every message is validated before anything is sent. The eventloop publishes the
+    /// batch in order without requests of other clones in between. QoS1 and QoS2 messages
+    /// of the batch take their packet ids together (the next free ones, in order). A batch
+    /// counts as one request for the in flight limit and the throttles
+    pub fn publish_batch<S, V>(&mut self, messages: Vec<(S, QoS, V)>) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let mut batch = Vec::with_capacity(messages.len());
+        for (topic, qos, payload) in messages {
+            batch.push(self.build_publish(topic, qos, false, payload)?);
+        }
+
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let request = self.queued(Request::PublishBatch(batch));
+        let tx = &mut self.request_tx;
+        tx.send(request).wait()?;
+        Ok(())
+    }
+
+    /// Requests the eventloop for mqtt publish of `payload` serialized as json. Serialization
+    /// errors are returned here and never reach the eventloop
+    #[cfg(feature = "json")]
+    pub fn publish_json<S, T>(&mut self, topic: S, qos: QoS, payload: &T) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_json::to_vec(payload)?;
+        self.publish(topic, qos, false, payload)
+    }
+
+    /// Requests the eventloop for mqtt publish of `payload` serialized as cbor
+    #[cfg(feature = "cbor")]
+    pub fn publish_cbor<S, T>(&mut self, topic: S, qos: QoS, payload: &T) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        T: Serialize + ?Sized,
+    {
+        let payload = serde_cbor::to_vec(payload)?;
+        self.publish(topic, qos, false, payload)
+    }
+
+    /// Requests the eventloop for mqtt publish and blocks until the broker acknowledges
+    /// it (puback for QoS1 and pubcomp for QoS2) or the timeout elapses. QoS0 publishes
+    /// return once the eventloop takes them. Waiting continues across reconnections
+    /// which replay the publish
+    pub fn publish_and_wait_ack<S, V>(&mut self, topic: S, qos: QoS, payload: V, timeout: Duration) -> Result<(), PublishError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let publish = self.build_publish(topic, qos, false, payload)?;
+        let (ack_tx, ack_rx) = crossbeam_channel::bounded(1);
+
+        let request = self.queued(Request::PublishWithAck(publish, AckWaiter::Blocking(ack_tx)));
+        let tx = &mut self.request_tx;
+        tx.send(request).wait().map_err(ClientError::from)?;
+
+        match ack_rx.recv_timeout(timeout) {
+            Ok(()) => Ok(()),
+            Err(RecvTimeoutError::Timeout) => Err(PublishError::Timeout),
+            Err(RecvTimeoutError::Disconnected) => Err(PublishError::Dropped),
+        }
+    }
+
+    /// Requests the eventloop for mqtt publish and returns a future which resolves when
+    /// the broker acknowledges the publish (puback for QoS1 and pubcomp for QoS2). QoS0
+    /// handles resolve once the eventloop takes the publish
+    pub fn publish_async<S, V, B>(&mut self, topic: S, qos: QoS, retained: B, payload: V) -> Result<PublishHandle, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+        B: Into<bool>,
+    {
+        let publish = self.build_publish(topic, qos, retained.into(), payload)?;
+        let (ack_tx, ack_rx) = oneshot::channel();
+
+        let request = self.queued(Request::PublishWithAck(publish, AckWaiter::Future(ack_tx)));
+        let tx = &mut self.request_tx;
+        tx.send(request).wait()?;
+        Ok(PublishHandle { ack_rx })
+    }
+
+    /// Stamps the publish request with the current time when queued messages expire.
+    /// Expiry is checked by the eventloop when the publish is about to be sent
+    fn queued(&self, request: Request) -> Request {
+        match self.queued_message_ttl {
+            Some(_) => Request::Queued(Box::new(request), Instant::now()),
+            None => request,
+        }
+    }
+
+    fn build_publish<S, V>(&self, topic: S, qos: QoS, retain: bool, payload: V) -> Result<Publish, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        if !topic::valid_topic(&topic) {
+            return Err(ClientError::InvalidTopic(topic));
         }
 
         let publish = Publish {
             dup: false,
             qos,
-            retain: retained.into(),
-            topic_name: topic.into(),
+            retain,
+            topic_name: topic,
             pkid: None,
-            payload: Arc::new(payload),
+            payload: Arc::new(payload.into()),
         };
 
+        let actual = codec::publish_len(&publish);
+        if actual > self.max_outgoing_packet_size {
+            return Err(ClientError::PayloadTooLarge { limit: self.max_outgoing_packet_size, actual });
+        }
+
+        Ok(publish)
+    }
+
+    /// Sends the puback of a publish received in manual ack mode. Handles of publishes
+    /// received before a reconnection are ignored as the broker redelivers those publishes
+    pub fn ack(&mut self, handle: AckHandle) -> Result<(), ClientError> {
         let tx = &mut self.request_tx;
-        tx.send(Request::Publish(publish)).wait()?;
+        tx.send(Request::Ack(handle)).wait()?;
         Ok(())
     }
 
@@ -133,7 +737,7 @@ impl MqttClient {
         S: Into<String>,
     {
         let topic = SubscribeTopic {
-            topic_path: topic.into(),
+            topic_path: validate_filter(topic.into())?,
             qos,
         };
         let subscribe = Subscribe {
@@ -146,14 +750,66 @@ impl MqttClient {
         Ok(())
     }
 
-    /// Requests the eventloop for mqtt unsubscribe
+    /// Requests the eventloop for mqtt subscribe and returns a channel which receives
+    /// all the incoming publishes matching this filter. These publishes aren't
+    /// delivered on the notification channel. Unsubscribing from the filter (or
+    /// dropping the receiver) removes the channel
+    pub fn subscribe_with_channel<S>(&mut self, topic: S, qos: QoS) -> Result<crossbeam_channel::Receiver<Publish>, ClientError>
+    where
+        S: Into<String>,
+    {
+        let topic = SubscribeTopic {
+            topic_path: validate_filter(topic.into())?,
+            qos,
+        };
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![topic],
+        };
+
+        let (publish_tx, publish_rx) = crossbeam_channel::bounded(self.notification_channel_capacity);
+        let tx = &mut self.request_tx;
+        tx.send(Request::SubscribeWithChannel(subscribe, publish_tx)).wait()?;
+        Ok(publish_rx)
+    }
+
+    /// Requests the eventloop to subscribe to multiple topics with a single mqtt
+    /// subscribe packet. Filters rejected by the broker are reported in `Notification::SubAck`
+    pub fn subscribe_many<S>(&mut self, topics: Vec<(S, QoS)>) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+    {
+        if topics.is_empty() {
+            return Err(ClientError::ZeroSubscriptions);
+        }
+
+        let topics = topics
+            .into_iter()
+            .map(|(topic, qos)| -> Result<SubscribeTopic, ClientError> {
+                let topic_path = validate_filter(topic.into())?;
+                Ok(SubscribeTopic { topic_path, qos })
+            })
+            .collect::<Result<Vec<SubscribeTopic>, ClientError>>()?;
+
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics,
+        };
+
+        let tx = &mut self.request_tx;
+        tx.send(Request::Subscribe(subscribe)).wait()?;
+        Ok(())
+    }
+
+    /// Requests the eventloop for mqtt unsubscribe. Broker's acknowledgement
+    /// is delivered as `Notification::UnsubAck`
     pub fn unsubscribe<S>(&mut self, topic: S) -> Result<(), ClientError>
-        where
-            S: Into<String>,
+    where
+        S: Into<String>,
     {
         let unsubscribe = Unsubscribe {
             pkid: PacketIdentifier::zero(),
-            topics: vec![topic.into()],
+            topics: vec![validate_filter(topic.into())?],
         };
 
         let tx = &mut self.request_tx;
@@ -161,9 +817,47 @@ impl MqttClient {
         Ok(())
     }
 
+    /// Sends a hand crafted packet directly to the network bypassing all the
+    /// session bookkeeping (packet ids, retransmissions, acks, inflight limits).
+    ///
+    /// Meant for protocol testing. Maintaining a valid session with raw packets is
+    /// the caller's responsibility. E.g acks to a raw publish are unsolicited for
+    /// the eventloop and will disconnect the client
+    #[cfg(feature = "danger-raw-packets")]
+    pub fn send_packet(&mut self, packet: Packet) -> Result<(), ClientError> {
+        let tx = &mut self.request_tx;
+        tx.send(Request::Raw(packet)).wait()?;
+        Ok(())
+    }
+
+    /// Requests the eventloop to reconnect to the broker with new options. Useful to
+    /// rotate credentials and certificates without restarting the client
+    pub fn reconnect_with(&mut self, options: MqttOptions) -> Result<(), ClientError> {
+        let tx = &mut self.request_tx;
+        tx.send(Request::Reconnect(options)).wait()?;
+        Ok(())
+    }
+
+    /// Updates the last will and testament. New will is sent in the connect
+    /// packet of the next (re)connection
+    pub fn set_last_will(&mut self, last_will: LastWill) -> Result<(), ClientError> {
+        let tx = &mut self.request_tx;
+        tx.send(Request::LastWill(Some(last_will))).wait()?;
+        Ok(())
+    }
+
+    /// Clears the last will and testament from the next (re)connection
+    pub fn clear_last_will(&mut self) -> Result<(), ClientError> {
+        let tx = &mut self.request_tx;
+        tx.send(Request::LastWill(None)).wait()?;
+        Ok(())
+    }
+
     /// Commands the network eventloop to disconnect from the broker.
     /// ReconnectOptions are not in affect here. [Resume] the
-    /// network for reconnection
+    /// network for reconnection. Unacked messages of the paused session are
+    /// replayed after resume like a normal reconnection. Pausing an already
+    /// paused network is a no-op
     ///
     /// [Resume]: struct.MqttClient.html#method.resume
     pub fn pause(&mut self) -> Result<(), ClientError> {
@@ -173,20 +867,186 @@ impl MqttClient {
     }
 
     /// Commands the network eventloop to reconnect to the broker and
-    /// resume network io
+    /// resume network io. Resuming an active network is a no-op
     pub fn resume(&mut self) -> Result<(), ClientError> {
         let tx = &mut self.command_tx;
         tx.send(Command::Resume).wait()?;
         Ok(())
     }
 
-    /// Commands the network eventloop to gracefully shutdown
-    /// the connection to the broker.
-    pub fn shutdown(&mut self) -> Result<(), ClientError> {
+    /// Requests the network eventloop to send mqtt disconnect, close the connection
+    /// to the broker and stop. Reconnection options are not considered here.
+    /// Requests queued before the disconnect are sent and their acks are awaited
+    /// for `MqttOptions::drain_timeout`. Messages still unacked after that are
+    /// reported in `Notification::Disconnected`
+    pub fn disconnect(&mut self) -> Result<(), ClientError> {
         let tx = &mut self.request_tx;
         tx.send(Request::Disconnect).wait()?;
         Ok(())
     }
+
+    /// Current status of the connection to the broker
+    pub fn connection_status(&self) -> ConnectionStatus {
+        match self.connection_status.lock() {
+            Ok(status) => *status,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    /// Blocks till the eventloop is connected to the broker (returns right away when
+    /// it already is). Fails with `WaitError::Timeout` when `timeout` elapses first and
+    /// with `WaitError::EventloopTerminated` when the eventloop stops
+    pub fn wait_for_connected(&self, timeout: Duration) -> Result<ConnectionInfo, WaitError> {
+        let deadline = Instant::now() + timeout;
+        let mut status = self.connection_status.lock().unwrap_or_else(|e| e.into_inner());
+
+        let broker = loop {
+            // broker is cleared just before the status of a broken connection
+            if *status == ConnectionStatus::Connected {
+                if let Some(broker) = self.connected_broker() {
+                    break broker;
+                }
+            }
+
+            if self.status_signal.stopped() {
+                return Err(WaitError::EventloopTerminated);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(WaitError::Timeout);
+            }
+
+            status = match self.status_signal.changed.wait_timeout(status, deadline - now) {
+                Ok((status, _)) => status,
+                Err(e) => e.into_inner().0,
+            };
+        };
+
+        drop(status);
+        Ok(ConnectionInfo { broker, session_present: self.session_present(), tls: self.tls_info() })
+    }
+
+    /// Tls parameters of the current connection. `None` while disconnected and for
+    /// connections without tls
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match self.tls_info.lock() {
+            Ok(tls_info) => tls_info.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Address of the broker of the current connection. `None` while disconnected
+    pub fn connected_broker(&self) -> Option<(String, u16)> {
+        match self.connected_broker.lock() {
+            Ok(broker) => broker.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Error which failed (or broke) the last connection to the broker. Kept after
+    /// a successful reconnection
+    pub fn last_error(&self) -> Option<ErrorSummary> {
+        match self.last_error.lock() {
+            Ok(error) => error.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Connection and traffic counters of the eventloop since the start (or the last
+    /// `reset_stats`)
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Zeroes the counters of `stats`
+    pub fn reset_stats(&self) {
+        self.stats.reset()
+    }
+
+    /// Tells if the broker resumed the previous session in the last connection. When
+    /// `false`, subscriptions should be made again and publishes which weren't acked
+    /// in the previous session are discarded
+    pub fn session_present(&self) -> bool {
+        self.session_present.load(Ordering::SeqCst)
+    }
+
+    /// Snapshot of the session once the eventloop stopped (e.g after `disconnect` or
+    /// `stop` of a clone of this client). Pass it to `MqttOptions::set_initial_session`
+    /// of the next process to resume the session. `None` while the eventloop runs
+    pub fn export_session(&self) -> Option<SessionState> {
+        match self.final_session.lock() {
+            Ok(session) => session.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    /// Registers an additional receiver of all the notifications. Notifications are
+    /// skipped for listeners whose channel is full (other listeners and the main
+    /// notification channel aren't affected). Dropping the receiver unregisters it
+    pub fn add_notification_listener(&self) -> crossbeam_channel::Receiver<Notification> {
+        let (tx, rx) = crossbeam_channel::bounded(self.notification_channel_capacity);
+        match self.notification_listeners.lock() {
+            Ok(mut listeners) => listeners.push(tx),
+            Err(e) => e.into_inner().push(tx),
+        }
+
+        rx
+    }
+
+    /// Futures stream of notifications for tokio applications. Needs
+    /// `MqttOptions::set_notification_stream`. A slow consumer of the stream makes the
+    /// eventloop stop reading the network instead of dropping notifications. The
+    /// stream can be taken only once across all the clones of the client. Returns
+    /// `None` when the stream is disabled or already taken
+    pub fn notifications_stream(&self) -> Option<impl Stream<Item = Notification, Error = ()>> {
+        match self.notification_stream_rx.lock() {
+            Ok(mut rx) => rx.take(),
+            Err(e) => e.into_inner().take(),
+        }
+    }
+
+    /// Commands the network eventloop to gracefully shutdown
+    /// the connection to the broker.
+    #[deprecated(note = "Use `disconnect`. `stop` stops the eventloop and waits for it")]
+    pub fn shutdown(&mut self) -> Result<(), ClientError> {
+        self.disconnect()
+    }
+
+    /// Commands the network eventloop to stop (interrupting any wait between
+    /// reconnections) and waits up to `timeout` for the eventloop thread to exit.
+    /// Returns the number of QoS1 & 2 messages which were still unacknowledged.
+    /// Use [disconnect] before this to close the mqtt connection gracefully
+    ///
+    /// [disconnect]: struct.MqttClient.html#method.disconnect
+    pub fn stop(self, timeout: Duration) -> Result<usize, ClientError> {
+        // never blocks. every sender has a guaranteed slot in the command channel, so
+        // a fresh sender gets the shutdown through even when the channel is full.
+        // eventloop might already be dead. result is collected below
+        let _ = self.command_tx.clone().try_send(Command::Shutdown);
+
+        let unacked = match self.eventloop_done_rx.recv_timeout(timeout) {
+            Ok(unacked) => unacked,
+            Err(RecvTimeoutError::Timeout) => return Err(ClientError::ShutdownTimeout),
+            Err(RecvTimeoutError::Disconnected) => return Err(ClientError::EventloopTerminated),
+        };
+
+        if let Ok(mut eventloop) = self.eventloop.lock() {
+            if let Some(eventloop) = eventloop.take() {
+                let _ = eventloop.join();
+            }
+        }
+
+        Ok(unacked)
+    }
+}
+
+fn validate_filter(filter: String) -> Result<String, ClientError> {
+    if topic::valid_filter(&filter) {
+        Ok(filter)
+    } else {
+        Err(ClientError::InvalidTopicFilter(filter))
+    }
 }
 
 // use std::fmt;
@@ -1,13 +1,23 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
     result::Result,
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::client::{Notification, Request};
+use crate::client::{AckHandle, AckWaiter, Notification, Request, TlsInfo};
+use crate::codec;
+use crate::hooks;
 use crate::error::{ConnectError, NetworkError};
-use crate::mqttoptions::{MqttOptions, SecurityOptions};
-use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Subscribe, Protocol};
+use crate::mqttoptions::{MqttOptions, Protocol as MqttProtocol, SecurityOptions};
+use crate::persistence::Persisted;
+use crate::session::SessionState;
+use crate::stats::Stats;
+use crate::topic;
+#[cfg(feature = "mqtt5")]
+use crate::v5;
+use crossbeam_channel::{Sender, TrySendError};
+use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Suback, Subscribe, SubscribeReturnCodes, SubscribeTopic, Unsubscribe, Protocol};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MqttConnectionStatus {
@@ -24,16 +34,73 @@ pub(crate) struct MqttState {
     // --------  State  ----------
     connection_status: MqttConnectionStatus,
     await_pingresp: bool,
+    // Time of the last pingreq. Round trip time is measured with its pingresp
+    ping_sent_at: Option<Instant>,
+    // Network isn't read while a notification waits for space. Pingresps can't arrive
+    inbound_paused: bool,
+    // Time the network reads resumed after the last pause
+    inbound_resumed_at: Option<Instant>,
     last_incoming: Instant,
     last_outgoing: Instant,
     last_pkid: PacketIdentifier,
+    // Packet ids of outgoing packets which aren't completely acknowledged yet
+    pkids_in_use: HashSet<u16>,
+    session_present: bool,
+    // Tls parameters of the current connection
+    tls_info: Option<TlsInfo>,
+    // Successful connections. Acks of manual ack mode are valid only in their connection
+    connections: u64,
 
     // Stores outgoing data to handle quality of service
     outgoing_pub: VecDeque<Publish>, // QoS1 & 2 publishes
+    // encoded size of `outgoing_pub`. kept along with the queue for the in flight limit
+    outgoing_pub_bytes: usize,
     outgoing_rel: VecDeque<PacketIdentifier>,
-
-    // Store incoming data to handle quality of service
-    incoming_pub: VecDeque<PacketIdentifier>, // QoS2 publishes
+    // Subscriptions waiting for suback
+    outgoing_sub: VecDeque<Subscribe>,
+    // Unsubscribes waiting for unsuback
+    outgoing_unsub: VecDeque<PacketIdentifier>,
+    // Topics subscribed during the lifetime of the client. Subscribed again when the
+    // broker starts a fresh session
+    subscriptions: Vec<SubscribeTopic>,
+
+    // Publishes and releases persisted by the previous run of the client. Sent after the
+    // first connack
+    restored: VecDeque<Persisted>,
+
+    // Store incoming data to handle quality of service. QoS2 publishes which are
+    // delivered but not released yet. Resends of these aren't delivered again
+    incoming_rec: HashSet<u16>,
+
+    // Publishers waiting for acknowledgement (puback/pubcomp) by pkid
+    ack_waiters: HashMap<u16, AckWaiter>,
+
+    // Time of the last (re)transmission of outgoing publishes by pkid
+    publish_sent_at: HashMap<u16, Instant>,
+    // Retransmitted publishes. Broker might ack both the copies
+    retransmitted: HashSet<u16>,
+    // Ids of acked retransmissions (by the time of the first ack). Reserved till the
+    // duplicate ack arrives or the ack timeout passes so that it doesn't ack a new publish
+    duplicate_acks: HashMap<u16, Instant>,
+    // Time at which outgoing publishes got their pkid. Age of the publish for the
+    // queued message ttl
+    publish_queued_at: HashMap<u16, Instant>,
+    // Publishes dropped by the ttl by topic which aren't notified yet
+    expired: Vec<(String, usize)>,
+
+    // Subscription filters whose publishes are delivered on a dedicated channel
+    routes: Vec<(String, Sender<Publish>)>,
+    // Channels of subscriptions (by pkid) waiting for the suback
+    pending_routes: HashMap<u16, Sender<Publish>>,
+
+    // Reconnect with a fresh jwt at this instant (gcloud iot)
+    jwt_refresh: Option<Instant>,
+
+    // Counters shared with the client
+    stats: Arc<Stats>,
+    // Properties and reason codes of the mqtt 5 packets. Shared with the codec and the client
+    #[cfg(feature = "mqtt5")]
+    v5: Arc<v5::Context>,
 }
 
 /// Design: `MqttState` methods will just modify the state of the object
@@ -45,17 +112,112 @@ pub(crate) struct MqttState {
 
 impl MqttState {
     pub fn new(opts: MqttOptions) -> Self {
-        MqttState {
+        MqttState::with_stats(opts, Arc::new(Stats::default()))
+    }
+
+    /// State which updates `stats`
+    pub fn with_stats(opts: MqttOptions, stats: Arc<Stats>) -> Self {
+        let restored = load_persisted(&opts);
+        let pkids_in_use = restored.iter().map(|persisted| persisted_pkid(persisted).0).collect();
+        let session = opts.initial_session().cloned();
+
+        let mut state = MqttState {
             opts,
             connection_status: MqttConnectionStatus::Disconnected,
             await_pingresp: false,
+            ping_sent_at: None,
+            inbound_paused: false,
+            inbound_resumed_at: None,
             last_incoming: Instant::now(),
             last_outgoing: Instant::now(),
             last_pkid: PacketIdentifier(0),
+            pkids_in_use,
+            session_present: false,
+            tls_info: None,
+            connections: 0,
             outgoing_pub: VecDeque::new(),
+            outgoing_pub_bytes: 0,
             outgoing_rel: VecDeque::new(),
-            incoming_pub: VecDeque::new(),
+            outgoing_sub: VecDeque::new(),
+            outgoing_unsub: VecDeque::new(),
+            subscriptions: Vec::new(),
+            restored,
+            incoming_rec: HashSet::new(),
+            ack_waiters: HashMap::new(),
+            publish_sent_at: HashMap::new(),
+            retransmitted: HashSet::new(),
+            duplicate_acks: HashMap::new(),
+            publish_queued_at: HashMap::new(),
+            expired: Vec::new(),
+            routes: Vec::new(),
+            pending_routes: HashMap::new(),
+            jwt_refresh: None,
+            stats,
+            #[cfg(feature = "mqtt5")]
+            v5: Arc::new(v5::Context::default()),
+        };
+
+        if let Some(session) = session {
+            state.import_session(&session);
+        }
+
+        state
+    }
+
+    /// Snapshot of the outgoing queues, the unreleased incoming qos2 publishes and the
+    /// last pkid. Persisted publishes which aren't sent yet are part of the queue
+    pub fn export_session(&self) -> SessionState {
+        let mut publishes = Vec::new();
+        let mut releases = Vec::new();
+        for persisted in self.restored.iter().cloned() {
+            match persisted {
+                Persisted::Publish(publish) => publishes.push(publish),
+                Persisted::Release(pkid) => releases.push(pkid),
+            }
+        }
+
+        publishes.extend(self.outgoing_pub.iter().cloned());
+        releases.extend(self.outgoing_rel.iter().cloned());
+        let mut incoming: Vec<u16> = self.incoming_rec.iter().cloned().collect();
+        incoming.sort();
+
+        SessionState::new(self.last_pkid, &publishes, &releases, &incoming)
+    }
+
+    /// Resumes the session of a previous run. Queues are replayed after a connack which
+    /// resumes the session (and dropped otherwise) like the ones of a reconnection
+    fn import_session(&mut self, session: &SessionState) {
+        self.last_pkid = session.last_pkid();
+        for publish in session.publishes() {
+            if let Some(pkid) = publish.pkid {
+                self.pkids_in_use.insert(pkid.0);
+            }
+
+            self.push_outgoing_pub(publish);
+        }
+
+        for pkid in session.releases() {
+            self.pkids_in_use.insert(pkid.0);
+            self.outgoing_rel.push_back(pkid);
         }
+
+        self.incoming_rec.extend(session.incoming());
+        self.update_outstanding_stats();
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        self.stats.clone()
+    }
+
+    /// Shares the mqtt 5 properties and reason codes with the client
+    #[cfg(feature = "mqtt5")]
+    pub fn set_v5_context(&mut self, context: Arc<v5::Context>) {
+        self.v5 = context;
+    }
+
+    #[cfg(feature = "mqtt5")]
+    pub fn v5_context(&self) -> Arc<v5::Context> {
+        self.v5.clone()
     }
 
     pub fn handle_outgoing_mqtt_packet(&mut self, packet: Packet) -> Result<Request, NetworkError> {
@@ -68,11 +230,18 @@ impl MqttState {
                 let subscription = self.handle_outgoing_subscribe(subs)?;
                 Request::Subscribe(subscription)
             }
+            Packet::Unsubscribe(unsub) => {
+                let unsubscription = self.handle_outgoing_unsubscribe(unsub)?;
+                Request::Unsubscribe(unsubscription)
+            }
+            Packet::Pubrel(pkid) => self.handle_outgoing_pubrel(pkid)?,
+            Packet::Puback(pkid) => Request::PubAck(pkid),
             Packet::Disconnect => self.handle_outgoing_disconnect()?,
             _ => unimplemented!(),
         };
 
         self.last_outgoing = Instant::now();
+        self.update_outstanding_stats();
         Ok(out)
     }
 
@@ -84,14 +253,15 @@ impl MqttState {
     // E.g For incoming QoS1 publish packet, this method returns (Publish, Puback). Publish packet will
     // be forwarded to user and Pubck packet will be written to network
     pub fn handle_incoming_mqtt_packet(&mut self, packet: Packet) -> Result<(Notification, Request), NetworkError> {
+        let is_pingreq = packet == Packet::Pingreq;
 
         let out = match packet {
             Packet::Pingresp => self.handle_incoming_pingresp(),
             // TODO: Remove this with async await. This is just to satisfy combinator rules during timeout
             Packet::Pingreq => self.handle_incoming_pingreq(),
             Packet::Publish(publish) => self.handle_incoming_publish(publish.clone()),
-            Packet::Suback(_pkid) => Ok((Notification::None, Request::None)),
-            Packet::Unsuback(_pkid) => Ok((Notification::None, Request::None)),
+            Packet::Suback(suback) => self.handle_incoming_suback(suback),
+            Packet::Unsuback(pkid) => self.handle_incoming_unsuback(pkid),
             Packet::Puback(pkid) => self.handle_incoming_puback(pkid),
             Packet::Pubrec(pkid) => self.handle_incoming_pubrec(pkid),
             Packet::Pubrel(pkid) => self.handle_incoming_pubrel(pkid),
@@ -99,53 +269,186 @@ impl MqttState {
             _ => panic!("{:?}", packet),
         };
 
-        self.last_incoming = Instant::now();
+        // pingreq is injected by the eventloop on incoming timeouts. brokers never send it
+        if !is_pingreq {
+            self.last_incoming = Instant::now();
+        }
+
+        self.update_outstanding_stats();
         out
     }
 
+    /// Creates a connect packet. Jwt (if any) is signed afresh for every connection
     pub fn handle_outgoing_connect(&mut self) -> Result<Connect, ConnectError> {
         self.connection_status = MqttConnectionStatus::Handshake;
-        connect_packet(&self.opts)
+        let connect = connect_packet(&self.opts)?;
+        self.jwt_refresh = jwt_refresh_deadline(&self.opts);
+        #[cfg(feature = "mqtt5")]
+        self.v5.reset(self.opts.connect_properties().clone());
+        Ok(connect)
     }
 
     pub fn handle_incoming_connack(&mut self, connack: Connack) -> Result<(), ConnectError> {
         let response = connack.code;
-        if response != ConnectReturnCode::Accepted {
+        if response == ConnectReturnCode::RefusedProtocolVersion {
             self.connection_status = MqttConnectionStatus::Disconnected;
-            Err(ConnectError::MqttConnectionRefused(response.to_u8()))
+            Err(ConnectError::UnsupportedProtocolVersion)
+        } else if response != ConnectReturnCode::Accepted {
+            self.connection_status = MqttConnectionStatus::Disconnected;
+            Err(ConnectError::MqttConnectionRefused(self.refused_code(response)))
         } else {
             self.connection_status = MqttConnectionStatus::Connected;
+            self.session_present = connack.session_present;
+            self.connections += 1;
+            self.stats.connack_received();
             self.handle_previous_session();
 
+            // don't resend publishes and releases into a session which the broker forgot
+            if !self.session_present {
+                self.clear_session();
+            }
+
             Ok(())
         }
     }
 
+    /// Return code of a refused connection. Reason code of the connack with mqtt 5
+    fn refused_code(&self, code: ConnectReturnCode) -> u8 {
+        #[cfg(feature = "mqtt5")]
+        {
+            if let Some((reason_code, _)) = self.v5.connack() {
+                return reason_code;
+            }
+        }
+
+        code.to_u8()
+    }
+
+    /// Time at which the connection should be recreated before the jwt expires
+    pub fn jwt_refresh_at(&self) -> Option<Instant> {
+        self.jwt_refresh
+    }
+
+    /// Tells if the broker resumed the previous session in the last connack
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    pub(crate) fn set_tls_info(&mut self, tls_info: Option<TlsInfo>) {
+        self.tls_info = tls_info;
+    }
+
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        self.tls_info.clone()
+    }
+
     pub fn handle_outgoing_disconnect(&mut self) -> Result<Request, NetworkError> {
         self.connection_status = MqttConnectionStatus::Disconnecting;
         Ok(Request::Disconnect)
     }
 
+    /// Returns requests of the last session which should be retransmitted. Releases of
+    /// QoS2 publishes which already received a pubrec are replayed before the remaining
+    /// publishes so that the broker can complete the exchange without a duplicate delivery.
+    /// Nothing is replayed in a clean session but all the topics are subscribed again
+    /// (unless auto resubscribe is disabled). Publishes older than the queued message
+    /// ttl are dropped instead of being replayed (see `take_expired`). Entries persisted
+    /// by the previous run of the client are replayed (without the dup flag) even in a
+    /// clean session
     pub fn handle_reconnection(&mut self) -> VecDeque<Request> {
-        if self.opts.clean_session() {
-            VecDeque::new()
-        } else {
-            //TODO: Write unittest for checking state during reconnection
-            self.outgoing_pub.split_off(0).into_iter().map(Request::Publish).collect()
+        // persisted entries are sent even in a clean session. broker never completed them
+        let mut publishes = Vec::new();
+        let mut requests = VecDeque::new();
+        for persisted in self.restored.split_off(0) {
+            match persisted {
+                Persisted::Publish(publish) => publishes.push(publish),
+                Persisted::Release(pkid) => requests.push_back(Request::PubRel(pkid)),
+            }
+        }
+
+        // publishes which might have reached the broker are marked as duplicates
+        if !self.opts.clean_session() {
+            requests.extend(self.outgoing_rel.split_off(0).into_iter().map(Request::PubRel));
+            self.outgoing_pub_bytes = 0;
+            publishes.extend(self.outgoing_pub.split_off(0).into_iter().map(|mut publish| {
+                publish.dup = true;
+                publish
+            }));
+        }
+
+        let (expired, publishes): (Vec<Publish>, Vec<Publish>) = publishes.into_iter().partition(|publish| self.is_expired(publish));
+        for publish in expired {
+            self.expire(&publish);
         }
+
+        // fresh session on the broker doesn't have the subscriptions
+        let fresh_session = self.opts.clean_session() || !self.session_present;
+        if self.opts.auto_resubscribe() && fresh_session && !self.subscriptions.is_empty() {
+            let topics = self.subscriptions.clone();
+            requests.push_front(Request::Subscribe(Subscribe { pkid: PacketIdentifier::zero(), topics }));
+        }
+
+        requests.extend(publishes.into_iter().map(Request::Publish));
+        requests
     }
 
-    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Publish {
+    fn add_packet_id_and_save(&mut self, mut publish: Publish) -> Result<Publish, NetworkError> {
         let publish = if publish.pkid == None {
-            let pkid = self.next_pkid();
+            let pkid = self.next_pkid()?;
             publish.pkid = Some(pkid);
             publish
         } else {
             publish
         };
 
-        self.outgoing_pub.push_back(publish.clone());
-        publish
+        if let Some(pkid) = publish.pkid {
+            // replayed publishes of a persistent session keep their ids
+            self.pkids_in_use.insert(pkid.0);
+            self.publish_sent_at.insert(pkid.0, Instant::now());
+            self.publish_queued_at.entry(pkid.0).or_insert_with(Instant::now);
+            self.retransmitted.remove(&pkid.0);
+            self.persist(pkid, &publish);
+        }
+
+        self.push_outgoing_pub(publish.clone());
+        Ok(publish)
+    }
+
+    /// Marks qos1 publishes which aren't acked within `timeout` of their last transmission
+    /// as duplicates and returns them (in queue order) for retransmission. Also releases the
+    /// ids of acked retransmissions whose duplicate ack didn't arrive within `timeout`
+    pub fn handle_ack_timeout(&mut self, timeout: Duration) -> Vec<Publish> {
+        let now = Instant::now();
+        let mut retransmits = Vec::new();
+
+        let pkids_in_use = &mut self.pkids_in_use;
+        self.duplicate_acks.retain(|pkid, acked_at| {
+            let waiting = now.duration_since(*acked_at) < timeout;
+            if !waiting {
+                pkids_in_use.remove(pkid);
+            }
+
+            waiting
+        });
+
+        for publish in self.outgoing_pub.iter_mut().filter(|publish| publish.qos == QoS::AtLeastOnce) {
+            let pkid = match publish.pkid {
+                Some(pkid) => pkid.0,
+                None => continue,
+            };
+
+            match self.publish_sent_at.get_mut(&pkid) {
+                Some(sent_at) if now.duration_since(*sent_at) >= timeout => {
+                    *sent_at = now;
+                    publish.dup = true;
+                    self.retransmitted.insert(pkid);
+                    retransmits.push(publish.clone());
+                }
+                _ => (),
+            }
+        }
+
+        retransmits
     }
 
     /// Sets next packet id if pkid is None (fresh publish) and adds it to the
@@ -154,17 +457,175 @@ impl MqttState {
         
         let publish = match publish.qos {
             QoS::AtMostOnce => publish,
-            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish),
+            QoS::AtLeastOnce | QoS::ExactlyOnce => self.add_packet_id_and_save(publish)?,
         };
 
         debug!("Publish. Topic = {:?}, Pkid = {:?}, Payload Size = {:?}", publish.topic_name, publish.pkid, publish.payload.len());
+        self.stats.publish_sent(publish.qos);
+        hooks::dispatch(&self.opts, |handler| handler.on_publish_sent(&publish));
+        Ok(publish)
+    }
+
+    /// Sets packet id of the publish early and saves the publisher to be notified
+    /// when the publish is acknowledged. QoS0 publishers are notified immediately
+    pub fn handle_outgoing_publish_with_ack(&mut self, mut publish: Publish, waiter: AckWaiter) -> Result<Publish, NetworkError> {
+        match publish.qos {
+            QoS::AtMostOnce => waiter.notify(),
+            QoS::AtLeastOnce | QoS::ExactlyOnce => {
+                let pkid = self.next_pkid()?;
+                publish.pkid = Some(pkid);
+                self.ack_waiters.insert(pkid.0, waiter);
+            }
+        }
+
         Ok(publish)
     }
 
+    fn persist(&self, pkid: PacketIdentifier, publish: &Publish) {
+        if let Some(persistence) = self.opts.persistence() {
+            if let Err(e) = persistence.store(pkid, publish) {
+                error!("Failed to persist publish. Pkid = {:?}, Error = {:?}", pkid, e);
+            }
+        }
+    }
+
+    fn persist_release(&self, pkid: PacketIdentifier) {
+        if let Some(persistence) = self.opts.persistence() {
+            if let Err(e) = persistence.store_release(pkid) {
+                error!("Failed to persist release. Pkid = {:?}, Error = {:?}", pkid, e);
+            }
+        }
+    }
+
+    fn unpersist(&self, pkid: PacketIdentifier) {
+        if let Some(persistence) = self.opts.persistence() {
+            if let Err(e) = persistence.remove(pkid) {
+                error!("Failed to remove persisted publish. Pkid = {:?}, Error = {:?}", pkid, e);
+            }
+        }
+    }
+
+    fn notify_ack_waiter(&mut self, pkid: PacketIdentifier) {
+        if let Some(waiter) = self.ack_waiters.remove(&pkid.0) {
+            waiter.notify();
+        }
+    }
+
+    /// Failure reason code of an mqtt 5 ack as a notification. Waiter of the publish is
+    /// dropped (the publisher sees `PublishError::Dropped`)
+    #[cfg(feature = "mqtt5")]
+    fn rejection(&mut self, ack: &Packet, pkid: PacketIdentifier) -> Option<Notification> {
+        let (reason_code, reason_string) = self.v5.take_ack(ack)?;
+        if !v5::is_failure(reason_code) {
+            return None;
+        }
+
+        error!("Publish rejected. Pkid = {:?}, Reason code = {:#04x}, Reason = {:?}", pkid, reason_code, reason_string);
+        self.ack_waiters.remove(&pkid.0);
+        Some(Notification::Rejected { pkid, reason_code, reason_string })
+    }
+
+    #[cfg(not(feature = "mqtt5"))]
+    fn rejection(&mut self, _ack: &Packet, _pkid: PacketIdentifier) -> Option<Notification> {
+        None
+    }
+
     pub fn publish_queue_len(&self) -> usize {
         self.outgoing_pub.len()
     }
 
+    /// Encoded size of the unacknowledged outgoing publishes
+    pub fn publish_queue_bytes(&self) -> usize {
+        self.outgoing_pub_bytes
+    }
+
+    fn push_outgoing_pub(&mut self, publish: Publish) {
+        self.outgoing_pub_bytes += codec::publish_len(&publish);
+        self.outgoing_pub.push_back(publish);
+    }
+
+    fn remove_outgoing_pub(&mut self, index: usize) -> Option<Publish> {
+        let publish = self.outgoing_pub.remove(index)?;
+        self.outgoing_pub_bytes -= codec::publish_len(&publish);
+        Some(publish)
+    }
+
+    /// True when the unacked publishes reach the in flight limits (count or bytes)
+    pub fn publish_queue_full(&self) -> bool {
+        if self.outgoing_pub.len() >= self.opts.inflight() {
+            return true;
+        }
+
+        match self.opts.inflight_bytes() {
+            Some(limit) => !self.outgoing_pub.is_empty() && self.publish_queue_bytes() >= limit,
+            None => false,
+        }
+    }
+
+    fn update_outstanding_stats(&self) {
+        self.stats.set_acks_outstanding(self.unacked_len());
+        self.stats.set_queue(self.outgoing_pub.len(), self.publish_queue_bytes());
+    }
+
+    /// True when every packet id is held by an unacknowledged packet
+    pub fn pkids_exhausted(&self) -> bool {
+        self.pkids_in_use.len() >= 65_535
+    }
+
+    /// Number of QoS1 & 2 messages which are not completely acknowledged yet
+    pub fn unacked_len(&self) -> usize {
+        self.outgoing_pub.len() + self.outgoing_rel.len()
+    }
+
+    /// True when the publish is older than the queued message ttl. Age is counted from
+    /// the time the client queued the publish (see `set_queued_at`)
+    pub fn is_expired(&self, publish: &Publish) -> bool {
+        let queued_at = publish.pkid.and_then(|pkid| self.publish_queued_at.get(&pkid.0));
+        queued_at.map_or(false, |queued_at| self.is_stale(*queued_at))
+    }
+
+    /// True when a request queued at `queued_at` is older than the queued message ttl
+    pub fn is_stale(&self, queued_at: Instant) -> bool {
+        self.opts.queued_message_ttl().map_or(false, |ttl| queued_at.elapsed() >= ttl)
+    }
+
+    /// Counts the age of a sent publish from the time the client queued it instead of
+    /// the time it got its pkid
+    pub fn set_queued_at(&mut self, publish: &Publish, queued_at: Instant) {
+        if let Some(pkid) = publish.pkid {
+            self.publish_queued_at.insert(pkid.0, queued_at);
+        }
+    }
+
+    /// Forgets an expired publish. Its publisher (if waiting for the ack) gets an error
+    pub fn expire(&mut self, publish: &Publish) {
+        warn!("Dropping expired publish. Topic = {}, Pkid = {:?}", publish.topic_name, publish.pkid);
+        if let Some(pkid) = publish.pkid {
+            if let Some(index) = self.outgoing_pub.iter().position(|p| p.pkid == Some(pkid)) {
+                self.remove_outgoing_pub(index);
+            }
+
+            self.pkids_in_use.remove(&pkid.0);
+            self.publish_sent_at.remove(&pkid.0);
+            self.publish_queued_at.remove(&pkid.0);
+            self.retransmitted.remove(&pkid.0);
+            self.ack_waiters.remove(&pkid.0);
+            self.unpersist(pkid);
+        }
+
+        match self.expired.iter_mut().find(|(topic, _)| *topic == publish.topic_name) {
+            Some((_, count)) => *count += 1,
+            None => self.expired.push((publish.topic_name.clone(), 1)),
+        }
+
+        self.update_outstanding_stats();
+    }
+
+    /// Summary (by topic) of the publishes dropped by the ttl since the last call
+    pub fn take_expired(&mut self) -> Vec<Notification> {
+        self.expired.drain(..).map(|(topic, count)| Notification::Expired { topic, count }).collect()
+    }
+
     pub fn is_disconnecting(&self) -> bool {
         match self.connection_status {
             MqttConnectionStatus::Disconnecting => true,
@@ -176,7 +637,20 @@ impl MqttState {
     pub fn handle_incoming_puback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
         match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
             Some(index) => {
-                let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
+                let _publish = self.remove_outgoing_pub(index).expect("Wrong index");
+                self.publish_sent_at.remove(&pkid.0);
+                self.publish_queued_at.remove(&pkid.0);
+                if self.retransmitted.remove(&pkid.0) {
+                    self.duplicate_acks.insert(pkid.0, Instant::now());
+                } else {
+                    self.pkids_in_use.remove(&pkid.0);
+                }
+                self.unpersist(pkid);
+                if let Some(rejection) = self.rejection(&Packet::Puback(pkid), pkid) {
+                    return Ok((rejection, Request::None));
+                }
+
+                self.notify_ack_waiter(pkid);
 
                 let request = Request::None;
                 let notification = if cfg!(feature = "acknotify") {
@@ -187,6 +661,11 @@ impl MqttState {
 
                 Ok((notification, request))
             }
+            None if self.duplicate_acks.remove(&pkid.0).is_some() => {
+                debug!("Duplicate puback of a retransmitted publish: {:?}", pkid);
+                self.pkids_in_use.remove(&pkid.0);
+                Ok((Notification::None, Request::None))
+            }
             None => {
                 error!("Unsolicited puback packet: {:?}", pkid);
                 // let queue: VecDeque<Option<PacketIdentifier>> = self.outgoing_pub.iter().map(|p| p.pkid).collect();
@@ -196,10 +675,27 @@ impl MqttState {
     }
 
     pub fn handle_incoming_pubrec(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        // broker might resend pubrec for a release which is already in flight (e.g after
+        // a reconnection). Answer with the same release again
+        if self.outgoing_rel.contains(&pkid) {
+            return Ok((Notification::None, Request::PubRel(pkid)));
+        }
+
         match self.outgoing_pub.iter().position(|x| x.pkid == Some(pkid)) {
             Some(index) => {
-                let _publish = self.outgoing_pub.remove(index).expect("Wrong index");
+                let _publish = self.remove_outgoing_pub(index).expect("Wrong index");
+                self.publish_sent_at.remove(&pkid.0);
+                // broker has the publish. release isn't subject to the ttl
+                self.publish_queued_at.remove(&pkid.0);
+                // broker won't deliver a rejected publish. there's nothing to release
+                if let Some(rejection) = self.rejection(&Packet::Pubrec(pkid), pkid) {
+                    self.pkids_in_use.remove(&pkid.0);
+                    self.unpersist(pkid);
+                    return Ok((rejection, Request::None));
+                }
+
                 self.outgoing_rel.push_back(pkid);
+                self.persist_release(pkid);
 
                 let reply = Request::PubRel(pkid);
                 let notification = if cfg!(feature = "acknotify") {
@@ -221,38 +717,109 @@ impl MqttState {
     // should be sent back on network as ack
     pub fn handle_incoming_publish(&mut self, publish: Publish) -> Result<(Notification, Request), NetworkError> {
         let qos = publish.qos;
+        let pkid = publish.pkid;
+        self.stats.publish_received(qos);
+
+        // broker resends the publish till it receives pubrec. answer again without delivering
+        if let (QoS::ExactlyOnce, Some(pkid)) = (qos, pkid) {
+            if self.incoming_rec.contains(&pkid.0) {
+                debug!("Duplicate qos2 publish: {:?}", pkid);
+                return Ok((Notification::None, Request::PubRec(pkid)));
+            }
+        }
+
+        hooks::dispatch(&self.opts, |handler| handler.on_message(&publish));
+
+        let notification = self.route_incoming_publish(publish)?;
 
         match qos {
-            QoS::AtMostOnce => {
-                let notification = Notification::Publish(publish);
-                Ok((notification, Request::None))
-            }
+            QoS::AtMostOnce => Ok((notification, Request::None)),
             QoS::AtLeastOnce => {
-                let pkid = publish.pkid.unwrap();
-                let request = Request::PubAck(pkid);
-                let notification = Notification::Publish(publish);
-                Ok((notification, request))
+                let pkid = pkid.unwrap();
+                match notification {
+                    // user acks the publish once it's handled
+                    Notification::Publish(publish) if self.opts.manual_acks() => {
+                        let handle = AckHandle::new(pkid, publish.topic_name.clone(), self.connections);
+                        Ok((Notification::ManualAckPublish(publish, handle), Request::None))
+                    }
+                    notification => Ok((notification, Request::PubAck(pkid))),
+                }
             }
             QoS::ExactlyOnce => {
-                let pkid = publish.pkid.unwrap();
+                let pkid = pkid.unwrap();
                 let request = Request::PubRec(pkid);
-                let notification = Notification::Publish(publish);
 
-                self.incoming_pub.push_back(pkid);
+                self.incoming_rec.insert(pkid.0);
                 Ok((notification, request))
             }
         }
     }
 
+    /// Puback of a publish acknowledged by the user in manual ack mode. Acks of publishes
+    /// received by an earlier connection are dropped. Broker redelivers those publishes
+    /// and their pkids might be in use by different publishes now
+    pub fn handle_outgoing_ack(&self, handle: AckHandle) -> Option<Request> {
+        if handle.connection() != self.connections {
+            debug!("Dropping ack of an earlier connection. Pkid = {:?}, Topic = {}", handle.pkid(), handle.topic());
+            return None;
+        }
+
+        Some(Request::PubAck(handle.pkid()))
+    }
+
+    /// Forgets a qos2 publish whose notification couldn't be delivered so that the
+    /// broker's resend is delivered
+    pub fn forget_incoming_publish(&mut self, pkid: PacketIdentifier) {
+        self.incoming_rec.remove(&pkid.0);
+    }
+
+    /// Adds a subscription filter whose matching publishes should be delivered on `tx`
+    /// instead of the notification channel
+    pub fn add_route(&mut self, filter: String, tx: Sender<Publish>) {
+        self.routes.push((filter, tx));
+    }
+
+    // Sends the publish to channels of all the matching subscription filters. Publishes
+    // without a matching route are returned as a notification for the global channel.
+    // Nothing is sent when a channel is full so that the redelivery isn't a duplicate
+    fn route_incoming_publish(&mut self, publish: Publish) -> Result<Notification, NetworkError> {
+        // only the eventloop sends on the routes. free space can't be taken in between
+        let full = self.routes.iter().any(|(filter, tx)| topic::matches(filter, &publish.topic_name) && tx.is_full());
+        if full {
+            error!("Subscription channel full. Topic = {}", publish.topic_name);
+            return Err(NetworkError::ReceiverCatchup);
+        }
+
+        let mut routed = false;
+        self.routes.retain(|(filter, tx)| {
+            if !topic::matches(filter, &publish.topic_name) {
+                return true;
+            }
+
+            match tx.try_send(publish.clone()) {
+                Ok(()) => routed = true,
+                Err(TrySendError::Full(_)) => error!("Subscription channel full. Topic = {}", publish.topic_name),
+                // receiver is dropped by the user. remove the route
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+
+            true
+        });
+
+        match routed {
+            true => Ok(Notification::None),
+            false => Ok(Notification::Publish(publish)),
+        }
+    }
+
     pub fn handle_incoming_pubrel(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
-        match self.incoming_pub.iter().position(|x| *x == pkid) {
-            Some(index) => {
-                let _pkid = self.incoming_pub.remove(index);
+        match self.incoming_rec.remove(&pkid.0) {
+            true => {
                 let notification = Notification::None;
                 let reply = Request::PubComp(pkid);
                 Ok((notification, reply))
             }
-            None => {
+            false => {
                 error!("Unsolicited pubrel packet: {:?}", pkid);
                 Err(NetworkError::Unsolicited)
             }
@@ -263,6 +830,13 @@ impl MqttState {
         match self.outgoing_rel.iter().position(|x| *x == pkid) {
             Some(index) => {
                 self.outgoing_rel.remove(index).expect("Wrong index");
+                self.pkids_in_use.remove(&pkid.0);
+                self.unpersist(pkid);
+                if let Some(rejection) = self.rejection(&Packet::Pubcomp(pkid), pkid) {
+                    return Ok((rejection, Request::None));
+                }
+
+                self.notify_ack_waiter(pkid);
                 let request = Request::None;
                 let notification = if cfg!(feature = "acknotify") {
                     Notification::PubComp(pkid)
@@ -279,6 +853,18 @@ impl MqttState {
         }
     }
 
+    /// Saves the release (retransmitted from last session) again so that the incoming
+    /// pubcomp can be matched
+    pub fn handle_outgoing_pubrel(&mut self, pkid: PacketIdentifier) -> Result<Request, NetworkError> {
+        if !self.outgoing_rel.contains(&pkid) {
+            self.outgoing_rel.push_back(pkid);
+        }
+
+        self.pkids_in_use.insert(pkid.0);
+
+        Ok(Request::PubRel(pkid))
+    }
+
     // check when the last control packet/pingreq packet
     // is received and return the status which tells if
     // keep alive time has exceeded
@@ -288,15 +874,20 @@ impl MqttState {
         let elapsed_in = self.last_incoming.elapsed();
         let elapsed_out = self.last_outgoing.elapsed();
 
-        // raise error if last ping didn't receive ack
-        if self.await_pingresp {
+        // zero keep alive disables pings
+        if keep_alive == Duration::from_secs(0) {
+            return Ok(false);
+        }
+
+        // raise error if last ping didn't receive ack. pingresp of a paused network
+        // isn't read yet. keep pinging to keep the connection alive
+        if self.pingresp_overdue() {
             error!("Error awaiting for last ping response");
             return Err(NetworkError::AwaitPingResp);
         }
 
-
         let ping = if elapsed_in > keep_alive || elapsed_out > keep_alive {
-            self.await_pingresp = true;
+            self.ping();
             true
         } else {
             false
@@ -311,23 +902,162 @@ impl MqttState {
         Ok(ping)
     }
 
+    /// Marks the network reads as paused by a slow notification consumer
+    pub fn set_inbound_paused(&mut self, paused: bool) {
+        if self.inbound_paused && !paused {
+            self.inbound_resumed_at = Some(Instant::now());
+        }
+
+        self.inbound_paused = paused;
+    }
+
+    /// True when the pingresp of the last ping is missing for longer than a keep alive.
+    /// Pingresp of a ping sent before (or during) a pause can be behind the packets which
+    /// paused the reads. It is due a keep alive after the reads resume
+    fn pingresp_overdue(&self) -> bool {
+        if !self.await_pingresp || self.inbound_paused {
+            return false;
+        }
+
+        let keep_alive = self.opts.keep_alive();
+        self.inbound_resumed_at.map_or(true, |resumed_at| resumed_at.elapsed() >= keep_alive)
+    }
+
+    /// Nothing was received for a keep alive. Pings (with a pingresp deadline of another
+    /// keep alive) even when outgoing packets keep the connection busy. Writes into a dead
+    /// connection keep succeeding for a long time. Only the missing pingresp reveals it
+    pub fn handle_incoming_idle(&mut self) -> Result<(), NetworkError> {
+        if self.pingresp_overdue() {
+            error!("Error awaiting for last ping response");
+            return Err(NetworkError::AwaitPingResp);
+        }
+
+        debug!("Ping. Nothing received for {} millisecs", self.last_incoming.elapsed().as_millis());
+        self.ping();
+        Ok(())
+    }
+
+    fn ping(&mut self) {
+        self.await_pingresp = true;
+        self.ping_sent_at = Some(Instant::now());
+        self.stats.ping_sent();
+    }
+
     pub fn handle_incoming_pingreq(&mut self) -> Result<(Notification, Request), NetworkError> {
         Ok((Notification::None, Request::IncomingIdlePing))
     }
 
     pub fn handle_incoming_pingresp(&mut self) -> Result<(Notification, Request), NetworkError> {
         self.await_pingresp = false;
+        let rtt = match self.ping_sent_at.take() {
+            Some(sent_at) => sent_at.elapsed(),
+            None => return Ok((Notification::None, Request::None)),
+        };
+
+        self.stats.pingresp_received(rtt);
+        if self.opts.ping_notifications() {
+            return Ok((Notification::PingResponse { rtt }, Request::None));
+        }
+
         Ok((Notification::None, Request::None))
     }
 
     pub fn handle_outgoing_subscribe(&mut self, mut subscription: Subscribe) -> Result<Subscribe, NetworkError> {        
-        let pkid = self.next_pkid();
+        let pkid = self.next_pkid()?;
         subscription.pkid = pkid;
+        self.outgoing_sub.push_back(subscription.clone());
+
+        for topic in subscription.topics.iter() {
+            self.subscriptions.retain(|subscribed| subscribed.topic_path != topic.topic_path);
+            self.subscriptions.push(topic.clone());
+        }
 
         debug!("Subscribe. Topics = {:?}, Pkid = {:?}", subscription.topics, subscription.pkid);
         Ok(subscription)
     }
 
+    /// Subscribe whose publishes are delivered on `tx`. Routes of the filters are added
+    /// once the broker accepts them
+    pub fn handle_outgoing_subscribe_with_channel(&mut self, subscription: Subscribe, tx: Sender<Publish>) -> Result<Request, NetworkError> {
+        let request = self.handle_outgoing_mqtt_packet(Packet::Subscribe(subscription))?;
+        if let Request::Subscribe(ref subscription) = request {
+            self.pending_routes.insert(subscription.pkid.0, tx);
+        }
+
+        Ok(request)
+    }
+
+    /// Matches the suback with the pending subscription and reports the granted qos
+    /// of every filter along with the filters which are rejected by the broker
+    pub fn handle_incoming_suback(&mut self, suback: Suback) -> Result<(Notification, Request), NetworkError> {
+        match self.outgoing_sub.iter().position(|x| x.pkid == suback.pkid) {
+            Some(index) => {
+                let subscription = self.outgoing_sub.remove(index).expect("Wrong index");
+                self.pkids_in_use.remove(&suback.pkid.0);
+                let route = self.pending_routes.remove(&suback.pkid.0);
+                let mut rejected = Vec::new();
+
+                for (topic, code) in subscription.topics.into_iter().zip(suback.return_codes.iter()) {
+                    match *code {
+                        SubscribeReturnCodes::Success(qos) if qos != topic.qos => {
+                            warn!("Subscription downgraded. Topic = {}, Requested = {:?}, Granted = {:?}", topic.topic_path, topic.qos, qos);
+                        }
+                        SubscribeReturnCodes::Success(_) => (),
+                        SubscribeReturnCodes::Failure => {
+                            rejected.push(topic.topic_path);
+                            continue;
+                        }
+                    }
+
+                    if let Some(tx) = route.as_ref() {
+                        self.add_route(topic.topic_path, tx.clone());
+                    }
+                }
+
+                if !rejected.is_empty() {
+                    error!("Subscriptions rejected. Pkid = {:?}, Topics = {:?}", suback.pkid, rejected);
+                    self.subscriptions.retain(|subscribed| !rejected.contains(&subscribed.topic_path));
+                }
+
+                let notification = Notification::SubAck {
+                    pkid: suback.pkid,
+                    return_codes: suback.return_codes,
+                    rejected,
+                };
+                Ok((notification, Request::None))
+            }
+            None => {
+                error!("Unsolicited suback packet: {:?}", suback.pkid);
+                Err(NetworkError::Unsolicited)
+            }
+        }
+    }
+
+    pub fn handle_outgoing_unsubscribe(&mut self, mut unsubscription: Unsubscribe) -> Result<Unsubscribe, NetworkError> {
+        let pkid = self.next_pkid()?;
+        unsubscription.pkid = pkid;
+        self.outgoing_unsub.push_back(pkid);
+        self.routes.retain(|(filter, _)| !unsubscription.topics.contains(filter));
+        self.subscriptions.retain(|subscribed| !unsubscription.topics.contains(&subscribed.topic_path));
+
+        debug!("Unsubscribe. Topics = {:?}, Pkid = {:?}", unsubscription.topics, unsubscription.pkid);
+        Ok(unsubscription)
+    }
+
+    pub fn handle_incoming_unsuback(&mut self, pkid: PacketIdentifier) -> Result<(Notification, Request), NetworkError> {
+        match self.outgoing_unsub.iter().position(|x| *x == pkid) {
+            Some(index) => {
+                self.outgoing_unsub.remove(index).expect("Wrong index");
+                self.pkids_in_use.remove(&pkid.0);
+                Ok((Notification::UnsubAck(pkid), Request::None))
+            }
+            None => {
+                error!("Unsolicited unsuback packet: {:?}", pkid);
+                Err(NetworkError::Unsolicited)
+            }
+        }
+    }
+
     // pub fn handle_incoming_suback(&mut self, ack: Suback) -> Result<(), SubackError> {
     //     if ack.return_codes.iter().any(|v| *v == SubscribeReturnCodes::Failure) {
     //         Err(SubackError::Rejected)
@@ -338,39 +1068,126 @@ impl MqttState {
 
     fn handle_previous_session(&mut self) {
         self.await_pingresp = false;
+        self.ping_sent_at = None;
+        self.inbound_paused = false;
+        self.inbound_resumed_at = None;
 
         if self.opts.clean_session() {
-            self.outgoing_pub.clear();
+            self.clear_session();
         }
 
         self.last_incoming = Instant::now();
         self.last_outgoing = Instant::now();
     }
 
+    fn clear_session(&mut self) {
+        for pkid in self.outgoing_pub.iter().filter_map(|publish| publish.pkid).chain(self.outgoing_rel.iter().cloned()) {
+            self.pkids_in_use.remove(&pkid.0);
+            self.unpersist(pkid);
+        }
+
+        for pkid in self.ack_waiters.keys().chain(self.duplicate_acks.keys()) {
+            self.pkids_in_use.remove(pkid);
+        }
+
+        // acks of these never arrive in the new session
+        for pkid in self.outgoing_sub.iter().map(|subscription| subscription.pkid).chain(self.outgoing_unsub.iter().cloned()) {
+            self.pkids_in_use.remove(&pkid.0);
+        }
+
+        self.outgoing_pub.clear();
+        self.outgoing_pub_bytes = 0;
+        self.outgoing_rel.clear();
+        self.outgoing_sub.clear();
+        self.pending_routes.clear();
+        self.outgoing_unsub.clear();
+        self.publish_sent_at.clear();
+        self.publish_queued_at.clear();
+        self.retransmitted.clear();
+        self.duplicate_acks.clear();
+        self.incoming_rec.clear();
+        // dropping the waiters tells the publishers that these are never acked
+        self.ack_waiters.clear();
+        self.update_outstanding_stats();
+    }
+
     // http://stackoverflow.com/questions/11115364/mqtt-messageid-practical-implementation
-    fn next_pkid(&mut self) -> PacketIdentifier {
+    // Ids which are still held by unacked packets are skipped after a rollover so that
+    // an ack is never matched against the wrong packet
+    fn next_pkid(&mut self) -> Result<PacketIdentifier, NetworkError> {
         let PacketIdentifier(mut pkid) = self.last_pkid;
-        if pkid == 65_535 {
-            pkid = 0;
+        for _ in 0..65_535 {
+            pkid = if pkid == 65_535 { 1 } else { pkid + 1 };
+            if self.pkids_in_use.insert(pkid) {
+                self.last_pkid = PacketIdentifier(pkid);
+                return Ok(self.last_pkid);
+            }
+        }
+
+        error!("All packet ids are in use");
+        Err(NetworkError::PacketIdsExhausted)
+    }
+}
+
+/// Publishes and releases saved by the previous run of the client
+fn load_persisted(mqttoptions: &MqttOptions) -> VecDeque<Persisted> {
+    let persistence = match mqttoptions.persistence() {
+        Some(persistence) => persistence,
+        None => return VecDeque::new(),
+    };
+
+    let has_pkid = |persisted: &Persisted| match persisted {
+        Persisted::Publish(publish) => publish.pkid.is_some(),
+        Persisted::Release(_) => true,
+    };
+
+    match persistence.load_all() {
+        Ok(entries) => entries.into_iter().filter(has_pkid).collect(),
+        Err(e) => {
+            error!("Failed to load persisted publishes. Error = {:?}", e);
+            VecDeque::new()
         }
-        self.last_pkid = PacketIdentifier(pkid + 1);
-        self.last_pkid
+    }
+}
+
+fn persisted_pkid(persisted: &Persisted) -> PacketIdentifier {
+    match persisted {
+        Persisted::Publish(publish) => publish.pkid.expect("Persisted publish without pkid"),
+        Persisted::Release(pkid) => *pkid,
     }
 }
 
 fn connect_packet(mqttoptions: &MqttOptions) -> Result<Connect, ConnectError> {
     let (username, password) = match mqttoptions.security_opts() {
         SecurityOptions::UsernamePassword(username, password) => (Some(username), Some(password)),
+        // `Connect` can't hold a binary password. it's added while encoding. see `codec::encode_connect`
+        SecurityOptions::UsernamePasswordBytes(username, _) => (Some(username), None),
+        SecurityOptions::UsernameOnly(username) => (Some(username), None),
         #[cfg(feature = "jwt")]
         SecurityOptions::GcloudIot(projectname, key, expiry) => {
             let username = Some("unused".to_owned());
             let password = Some(gen_iotcore_password(projectname, &key, expiry)?);
             (username, password)
         }
+        #[cfg(feature = "azure")]
+        SecurityOptions::AzureIotHub { hub_name, device_id, shared_access_key, token_ttl } => {
+            let host = format!("{}.azure-devices.net", hub_name);
+            let username = Some(format!("{}/{}/?api-version=2018-06-30", host, device_id));
+            let resource = format!("{}/devices/{}", host, device_id);
+            let password = Some(gen_azure_sas_token(&resource, &shared_access_key, token_ttl)?);
+            (username, password)
+        }
         SecurityOptions::None => (None, None),
     };
+    let protocol = match mqttoptions.protocol() {
+        MqttProtocol::MQTT31 => Protocol::MQIsdp(3),
+        MqttProtocol::MQTT311 => Protocol::MQTT(4),
+        #[cfg(feature = "mqtt5")]
+        MqttProtocol::MQTT5 => Protocol::MQTT(5),
+    };
+
     let connect = Connect {
-        protocol: Protocol::MQTT(4),
+        protocol,
         keep_alive: mqttoptions.keep_alive().as_secs() as u16,
         client_id: mqttoptions.client_id(),
         clean_session: mqttoptions.clean_session(),
@@ -381,6 +1198,18 @@ fn connect_packet(mqttoptions: &MqttOptions) -> Result<Connect, ConnectError> {
     Ok(connect)
 }
 
+/// Jwts are refreshed after 90% of their validity (expiry is in minutes)
+fn jwt_refresh_deadline(mqttoptions: &MqttOptions) -> Option<Instant> {
+    match mqttoptions.security_opts() {
+        #[cfg(feature = "jwt")]
+        SecurityOptions::GcloudIot(_, _, expiry) => {
+            let validity = Duration::from_secs(expiry.max(0) as u64 * 60);
+            Some(Instant::now() + validity * 9 / 10)
+        }
+        _ => None,
+    }
+}
+
 #[cfg(feature = "jwt")]
 // Generates a new password for mqtt client authentication
 fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<String, ConnectError> {
@@ -409,14 +1238,40 @@ fn gen_iotcore_password(project: String, key: &[u8], expiry: i64) -> Result<Stri
     Ok(encode(&jwt_header, &claims, &key)?)
 }
 
-#[cfg(test)]
-mod test {
-    use std::{sync::Arc, thread, time::Duration};
+#[cfg(feature = "azure")]
+// Generates a sas token which expires after `ttl` for azure iot hub authentication
+fn gen_azure_sas_token(resource: &str, key: &str, ttl: Duration) -> Result<String, ConnectError> {
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-    use super::{MqttConnectionStatus, MqttState};
-    use crate::client::{Notification, Request};
-    use crate::error::NetworkError;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_else(|_| Duration::from_secs(0));
+    azure_sas_token(resource, key, (now + ttl).as_secs())
+}
+
+#[cfg(feature = "azure")]
+fn azure_sas_token(resource: &str, key: &str, expiry: u64) -> Result<String, ConnectError> {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use url::form_urlencoded::byte_serialize;
+
+    let resource: String = byte_serialize(resource.as_bytes()).collect();
+    let key = base64::decode(key).map_err(|_| ConnectError::InvalidSharedAccessKey)?;
+    let mut mac = Hmac::<Sha256>::new_varkey(&key).map_err(|_| ConnectError::InvalidSharedAccessKey)?;
+    mac.input(format!("{}\n{}", resource, expiry).as_bytes());
+
+    let signature = base64::encode(&mac.result().code());
+    let signature: String = byte_serialize(signature.as_bytes()).collect();
+    Ok(format!("SharedAccessSignature sr={}&sig={}&se={}", resource, signature, expiry))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, sync::Arc, thread, time::Duration};
+
+    use super::{MqttConnectionStatus, MqttState};
+    use crate::client::{AckWaiter, Notification, Request};
+    use crate::error::NetworkError;
     use crate::mqttoptions::MqttOptions;
+    use crate::persistence::FilePersistence;
     use mqtt311::*;
 
     fn build_outgoing_publish(qos: QoS) -> Publish {
@@ -446,17 +1301,101 @@ mod test {
         MqttState::new(opts)
     }
 
+    #[test]
+    fn exported_session_should_resume_in_a_new_state() {
+        let mut mqtt = build_mqttstate();
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        let released = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_pubrec(released.pkid.unwrap()).unwrap();
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 5)).unwrap();
+        let session = mqtt.export_session();
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_clean_session(false).set_initial_session(session);
+        let mut mqtt = MqttState::new(opts);
+        assert_eq!(mqtt.unacked_len(), 2);
+
+        // resent qos2 publish of the previous process isn't delivered again
+        match mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 5)).unwrap() {
+            (Notification::None, Request::PubRec(PacketIdentifier(5))) => (),
+            o => panic!("Expecting pubrec without notification. Found = {:?}", o),
+        }
+
+        mqtt.handle_incoming_connack(Connack { session_present: true, code: ConnectReturnCode::Accepted }).unwrap();
+        let replay: Vec<Request> = mqtt.handle_reconnection().into_iter().collect();
+        match replay.as_slice() {
+            [Request::PubRel(pkid), Request::Publish(replayed)] => {
+                assert_eq!(*pkid, released.pkid.unwrap());
+                assert_eq!(replayed, &Publish { dup: true, ..publish });
+            }
+            o => panic!("Expecting release and publish replays. Found = {:?}", o),
+        }
+
+        // packet ids continue from the previous process
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(3)));
+    }
+
     #[test]
     fn next_pkid_roll() {
         let mut mqtt = build_mqttstate();
         let mut pkt_id = PacketIdentifier(0);
 
         for _ in 0..65536 {
-            pkt_id = mqtt.next_pkid();
+            pkt_id = mqtt.next_pkid().unwrap();
+            mqtt.pkids_in_use.remove(&pkt_id.0);
         }
         assert_eq!(PacketIdentifier(1), pkt_id);
     }
 
+    #[test]
+    fn pkids_of_unacked_publishes_should_be_skipped_after_rollover() {
+        let mut mqtt = build_mqttstate();
+
+        // broker which never acks the first publish and acks the rest 100 publishes late
+        let mut stuck = build_outgoing_publish(QoS::AtLeastOnce);
+        stuck.payload = Arc::new(vec![0xFF]);
+        let stuck = mqtt.handle_outgoing_publish(stuck).unwrap();
+        assert_eq!(stuck.pkid, Some(PacketIdentifier(1)));
+
+        let mut unacked = VecDeque::new();
+        for i in 0..70_000u32 {
+            let mut publish = build_outgoing_publish(QoS::AtLeastOnce);
+            publish.payload = Arc::new(i.to_be_bytes().to_vec());
+            let publish = mqtt.handle_outgoing_publish(publish).unwrap();
+            assert_ne!(publish.pkid, stuck.pkid);
+            unacked.push_back(publish);
+
+            if unacked.len() > 100 {
+                let acked = unacked.pop_front().unwrap();
+                mqtt.handle_incoming_puback(acked.pkid.unwrap()).unwrap();
+                assert!(mqtt.outgoing_pub.iter().all(|publish| publish.payload != acked.payload));
+            }
+        }
+
+        assert_eq!(mqtt.publish_queue_len(), 101);
+        assert_eq!(mqtt.outgoing_pub.front().unwrap().payload, stuck.payload);
+    }
+
+    #[test]
+    fn exhausted_pkids_should_error_till_an_ack_frees_one() {
+        let mut mqtt = build_mqttstate();
+
+        for _ in 0..65_535 {
+            mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        }
+
+        assert!(mqtt.pkids_exhausted());
+        match mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)) {
+            Err(NetworkError::PacketIdsExhausted) => (),
+            o => panic!("Expecting exhausted packet ids. Found = {:?}", o),
+        }
+
+        mqtt.handle_incoming_puback(PacketIdentifier(500)).unwrap();
+        assert!(!mqtt.pkids_exhausted());
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(500)));
+    }
+
     #[test]
     fn outgoing_publish_handle_should_set_pkid_correctly_and_add_publish_to_queue_correctly() {
         let mut mqtt = build_mqttstate();
@@ -509,11 +1448,67 @@ mod test {
         mqtt.handle_incoming_publish(publish2).unwrap();
         mqtt.handle_incoming_publish(publish3).unwrap();
 
-        let pkid = *mqtt.incoming_pub.get(0).unwrap();
-
         // only qos2 publish should be add to queue
-        assert_eq!(mqtt.incoming_pub.len(), 1);
-        assert_eq!(pkid, PacketIdentifier(3));
+        assert_eq!(mqtt.incoming_rec.len(), 1);
+        assert!(mqtt.incoming_rec.contains(&3));
+    }
+
+    #[test]
+    fn manual_acks_should_be_sent_only_by_the_user_in_the_same_connection() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = mqtt.opts.clone().set_manual_acks(true);
+        let connack = Connack { session_present: false, code: ConnectReturnCode::Accepted };
+        mqtt.handle_incoming_connack(connack.clone()).unwrap();
+
+        let handle = match mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 2)).unwrap() {
+            (Notification::ManualAckPublish(_, handle), Request::None) => handle,
+            o => panic!("Expecting manual ack publish without reply. Found = {:?}", o),
+        };
+
+        assert_eq!(handle.pkid(), PacketIdentifier(2));
+        match mqtt.handle_outgoing_ack(handle.clone()) {
+            Some(Request::PubAck(PacketIdentifier(2))) => (),
+            o => panic!("Expecting puback. Found = {:?}", o),
+        }
+
+        // broker redelivers the publishes of the last connection
+        mqtt.handle_incoming_connack(connack).unwrap();
+        assert!(mqtt.handle_outgoing_ack(handle).is_none());
+    }
+
+    #[test]
+    fn resent_qos2_publish_should_be_delivered_only_once() {
+        let opts = MqttOptions::default().set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+
+        match mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 7)).unwrap() {
+            (Notification::Publish(_), Request::PubRec(PacketIdentifier(7))) => (),
+            o => panic!("Expecting publish and pubrec. Found = {:?}", o),
+        }
+
+        // broker didn't receive the pubrec before the reconnection and resends the publish
+        let connack = Connack {
+            session_present: true,
+            code: ConnectReturnCode::Accepted,
+        };
+        mqtt.handle_incoming_connack(connack).unwrap();
+        let mut publish = build_incoming_publish(QoS::ExactlyOnce, 7);
+        publish.dup = true;
+        match mqtt.handle_incoming_publish(publish).unwrap() {
+            (Notification::None, Request::PubRec(PacketIdentifier(7))) => (),
+            o => panic!("Expecting only pubrec. Found = {:?}", o),
+        }
+
+        match mqtt.handle_incoming_pubrel(PacketIdentifier(7)).unwrap() {
+            (Notification::None, Request::PubComp(PacketIdentifier(7))) => (),
+            o => panic!("Expecting pubcomp. Found = {:?}", o),
+        }
+
+        // pkid is free to be used by the next publish
+        match mqtt.handle_incoming_publish(build_incoming_publish(QoS::ExactlyOnce, 7)).unwrap() {
+            (Notification::Publish(_), Request::PubRec(PacketIdentifier(7))) => (),
+            o => panic!("Expecting publish and pubrec. Found = {:?}", o),
+        }
     }
 
     #[test]
@@ -554,6 +1549,78 @@ mod test {
         assert_eq!(mqtt.outgoing_pub.len(), 0);
     }
 
+    #[test]
+    fn ack_waiters_should_be_notified_on_puback_and_pubcomp() {
+        let mut mqtt = build_mqttstate();
+        let (qos1_tx, qos1_rx) = crossbeam_channel::bounded(1);
+        let (qos2_tx, mut qos2_rx) = futures::sync::oneshot::channel();
+
+        let publish = mqtt.handle_outgoing_publish_with_ack(build_outgoing_publish(QoS::AtLeastOnce), AckWaiter::Blocking(qos1_tx)).unwrap();
+        mqtt.handle_outgoing_publish(publish).unwrap();
+        let publish = mqtt.handle_outgoing_publish_with_ack(build_outgoing_publish(QoS::ExactlyOnce), AckWaiter::Future(qos2_tx)).unwrap();
+        mqtt.handle_outgoing_publish(publish).unwrap();
+
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        assert!(qos1_rx.try_recv().is_ok());
+
+        mqtt.handle_incoming_pubrec(PacketIdentifier(2)).unwrap();
+        assert_eq!(qos2_rx.try_recv().unwrap(), None);
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(2)).unwrap();
+        assert_eq!(qos2_rx.try_recv().unwrap(), Some(()));
+    }
+
+    #[cfg(feature = "mqtt5")]
+    fn decode_v5(mqtt: &MqttState, bytes: Vec<u8>) -> Packet {
+        use crate::codec::MqttCodec;
+        use tokio::codec::Decoder;
+
+        let mut codec = MqttCodec::new();
+        codec.set_v5(mqtt.v5_context());
+        codec.decode(&mut bytes::BytesMut::from(bytes)).unwrap().unwrap()
+    }
+
+    #[cfg(feature = "mqtt5")]
+    #[test]
+    fn rejected_puback_should_drop_the_ack_waiter_and_notify_the_reason() {
+        let mut mqtt = build_mqttstate();
+        let (ack_tx, ack_rx) = crossbeam_channel::bounded(1);
+        let publish = mqtt.handle_outgoing_publish_with_ack(build_outgoing_publish(QoS::AtLeastOnce), AckWaiter::Blocking(ack_tx)).unwrap();
+        mqtt.handle_outgoing_publish(publish).unwrap();
+
+        // puback 1 with 'quota exceeded'
+        let puback = decode_v5(&mqtt, vec![0x40, 0x03, 0x00, 0x01, 0x97]);
+        assert_eq!(puback, Packet::Puback(PacketIdentifier(1)));
+        match mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap() {
+            (Notification::Rejected { pkid, reason_code, reason_string }, Request::None) => {
+                assert_eq!((pkid, reason_code, reason_string), (PacketIdentifier(1), 0x97, None));
+            }
+            result => panic!("Expecting a rejection. Found = {:?}", result),
+        }
+
+        assert!(ack_rx.try_recv().is_err());
+        assert_eq!(mqtt.outgoing_pub.len(), 0);
+        assert!(!mqtt.pkids_in_use.contains(&1));
+    }
+
+    #[cfg(feature = "mqtt5")]
+    #[test]
+    fn refused_mqtt5_connection_should_carry_the_reason_code() {
+        let mut mqtt = MqttState::new(MqttOptions::new("test-id", "127.0.0.1", 1883).set_protocol(crate::mqttoptions::Protocol::MQTT5));
+        let connect = mqtt.handle_outgoing_connect().unwrap();
+        assert_eq!(connect.protocol, Protocol::MQTT(5));
+
+        // not authorized
+        let connack = match decode_v5(&mqtt, vec![0x20, 0x03, 0x00, 0x87, 0x00]) {
+            Packet::Connack(connack) => connack,
+            packet => panic!("Expecting a connack. Found = {:?}", packet),
+        };
+
+        match mqtt.handle_incoming_connack(connack) {
+            Err(crate::error::ConnectError::MqttConnectionRefused(code)) => assert_eq!(code, 0x87),
+            result => panic!("Expecting a refusal. Found = {:?}", result),
+        }
+    }
+
     #[test]
     fn incoming_pubrec_should_release_correct_publish_from_queue_and_add_releaseid_to_rel_queue() {
         let mut mqtt = build_mqttstate();
@@ -631,6 +1698,156 @@ mod test {
         assert_eq!(mqtt.outgoing_pub.len(), 0);
     }
 
+    #[test]
+    fn incoming_suback_should_report_rejected_topics_of_a_batch_subscription() {
+        let mut mqtt = build_mqttstate();
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec![
+                SubscribeTopic { topic_path: "hello/world".to_owned(), qos: QoS::AtLeastOnce },
+                SubscribeTopic { topic_path: "hello/denied".to_owned(), qos: QoS::AtLeastOnce },
+            ],
+        };
+
+        let subscribe = mqtt.handle_outgoing_subscribe(subscribe).unwrap();
+        let suback = Suback {
+            pkid: subscribe.pkid,
+            return_codes: vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure],
+        };
+
+        let (notification, _) = mqtt.handle_incoming_suback(suback.clone()).unwrap();
+        match notification {
+            Notification::SubAck { pkid, return_codes, rejected } => {
+                assert_eq!(pkid, PacketIdentifier(1));
+                assert_eq!(return_codes[0], SubscribeReturnCodes::Success(QoS::AtLeastOnce));
+                assert_eq!(return_codes[1], SubscribeReturnCodes::Failure);
+                assert_eq!(rejected, vec!["hello/denied".to_owned()]);
+            }
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        assert_eq!(mqtt.outgoing_sub.len(), 0);
+        assert!(mqtt.handle_incoming_suback(suback).is_err());
+    }
+
+    #[test]
+    fn incoming_publish_should_be_routed_to_matching_subscription_channel() {
+        let mut mqtt = build_mqttstate();
+        let (tx, rx) = crossbeam_channel::bounded(10);
+        mqtt.add_route("hello/+".to_owned(), tx);
+
+        let (notification, _) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 1)).unwrap();
+        match notification {
+            Notification::None => (),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+        assert_eq!(rx.try_recv().unwrap().pkid, Some(PacketIdentifier(1)));
+
+        // unmatched publishes fall back to the notification channel
+        let mut publish = build_incoming_publish(QoS::AtLeastOnce, 2);
+        publish.topic_name = "world/hello".to_owned();
+        let (notification, _) = mqtt.handle_incoming_publish(publish).unwrap();
+        match notification {
+            Notification::Publish(publish) => assert_eq!(publish.pkid, Some(PacketIdentifier(2))),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        // unsubscribe removes the route
+        let unsubscribe = Unsubscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec!["hello/+".to_owned()],
+        };
+        mqtt.handle_outgoing_unsubscribe(unsubscribe).unwrap();
+        let (notification, _) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 3)).unwrap();
+        match notification {
+            Notification::Publish(publish) => assert_eq!(publish.pkid, Some(PacketIdentifier(3))),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn routes_should_be_added_for_accepted_filters_only() {
+        let mut mqtt = build_mqttstate();
+        let (tx, rx) = crossbeam_channel::bounded(10);
+        let topics = vec![
+            SubscribeTopic { topic_path: "hello/+".to_owned(), qos: QoS::AtLeastOnce },
+            SubscribeTopic { topic_path: "#".to_owned(), qos: QoS::AtLeastOnce },
+        ];
+        let subscribe = Subscribe { pkid: PacketIdentifier::zero(), topics };
+        mqtt.handle_outgoing_subscribe_with_channel(subscribe, tx).unwrap();
+
+        // no routes till the suback
+        let (notification, _) = mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 1)).unwrap();
+        match notification {
+            Notification::Publish(_) => (),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        let return_codes = vec![SubscribeReturnCodes::Success(QoS::AtLeastOnce), SubscribeReturnCodes::Failure];
+        mqtt.handle_incoming_suback(Suback { pkid: PacketIdentifier(1), return_codes }).unwrap();
+        assert_eq!(mqtt.routes.len(), 1);
+        assert_eq!(mqtt.routes[0].0, "hello/+");
+
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 2)).unwrap();
+        assert_eq!(rx.try_recv().unwrap().pkid, Some(PacketIdentifier(2)));
+    }
+
+    #[test]
+    fn publish_should_reach_no_route_when_a_matching_channel_is_full() {
+        let mut mqtt = build_mqttstate();
+        let (tx1, rx1) = crossbeam_channel::bounded(10);
+        let (tx2, rx2) = crossbeam_channel::bounded(1);
+        mqtt.add_route("hello/+".to_owned(), tx1);
+        mqtt.add_route("hello/world".to_owned(), tx2);
+
+        mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 1)).unwrap();
+        assert!(mqtt.handle_incoming_publish(build_incoming_publish(QoS::AtLeastOnce, 2)).is_err());
+
+        // publish 2 is delivered by the redelivery alone
+        assert_eq!(rx1.try_iter().count(), 1);
+        assert_eq!(rx2.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn incoming_unsuback_should_match_pending_unsubscribe_and_notify_user() {
+        let mut mqtt = build_mqttstate();
+        let unsubscribe = Unsubscribe {
+            pkid: PacketIdentifier::zero(),
+            topics: vec!["hello/world".to_owned()],
+        };
+
+        let unsubscribe = mqtt.handle_outgoing_unsubscribe(unsubscribe).unwrap();
+        assert_eq!(unsubscribe.pkid, PacketIdentifier(1));
+        assert_eq!(mqtt.outgoing_unsub.len(), 1);
+
+        let (notification, request) = mqtt.handle_incoming_unsuback(PacketIdentifier(1)).unwrap();
+        match notification {
+            Notification::UnsubAck(PacketIdentifier(pkid)) => assert_eq!(pkid, 1),
+            _ => panic!("Invalid notification: {:?}", notification),
+        }
+
+        match request {
+            Request::None => (),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+
+        assert_eq!(mqtt.outgoing_unsub.len(), 0);
+        assert!(mqtt.handle_incoming_unsuback(PacketIdentifier(1)).is_err());
+    }
+
+    #[test]
+    fn zero_keep_alive_should_disable_pings() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_keep_alive(0);
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+        thread::sleep(Duration::from_millis(10));
+
+        assert!(!mqtt.handle_outgoing_ping().unwrap());
+        assert!(!mqtt.handle_outgoing_ping().unwrap());
+        assert_eq!(mqtt.handle_outgoing_connect().unwrap().keep_alive, 0);
+    }
+
     #[test]
     fn outgoing_ping_handle_should_throw_errors_for_no_pingresp() {
         let mut mqtt = build_mqttstate();
@@ -659,6 +1876,56 @@ mod test {
         }
     }
 
+    #[test]
+    fn silent_broker_should_be_pinged_even_while_publishing() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_keep_alive(10);
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+
+        // continuous qos0 publishes keep the outgoing side busy
+        let publish = build_outgoing_publish(QoS::AtMostOnce);
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish)).unwrap();
+        assert!(!mqtt.handle_outgoing_ping().unwrap());
+
+        mqtt.handle_incoming_idle().unwrap();
+        let (_, request) = mqtt.handle_incoming_mqtt_packet(Packet::Pingreq).unwrap();
+        match request {
+            Request::IncomingIdlePing => (),
+            request => panic!("Expecting ping. Found = {:?}", request),
+        }
+
+        // broker is gone when the ping isn't answered till the next incoming timeout
+        match mqtt.handle_incoming_idle() {
+            Err(NetworkError::AwaitPingResp) => (),
+            o => panic!("Should throw pingresp await error. Found = {:?}", o),
+        }
+
+        mqtt.handle_incoming_mqtt_packet(Packet::Pingresp).unwrap();
+        mqtt.handle_incoming_idle().unwrap();
+    }
+
+    #[test]
+    fn pingresp_should_be_due_a_keep_alive_after_the_reads_resume() {
+        let mut mqtt = build_mqttstate();
+        mqtt.opts = MqttOptions::default().set_keep_alive(1);
+        mqtt.connection_status = MqttConnectionStatus::Connected;
+
+        mqtt.handle_incoming_idle().unwrap();
+        mqtt.set_inbound_paused(true);
+        thread::sleep(Duration::from_millis(1100));
+
+        // pingresp can't be read while paused. pings keep the connection alive
+        assert_eq!(mqtt.handle_outgoing_ping().unwrap(), true);
+        mqtt.set_inbound_paused(false);
+        assert!(mqtt.handle_incoming_idle().is_ok());
+
+        thread::sleep(Duration::from_millis(1100));
+        match mqtt.handle_outgoing_ping() {
+            Err(NetworkError::AwaitPingResp) => (),
+            o => panic!("Expecting pingresp await error. Found = {:?}", o),
+        }
+    }
+
     #[test]
     fn outgoing_ping_handle_should_succeed_if_pingresp_is_received() {
         let mut mqtt = build_mqttstate();
@@ -684,6 +1951,28 @@ mod test {
         }
     }
 
+    #[test]
+    fn pingresp_should_record_the_round_trip_time() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_ping_notifications(true);
+        let mut mqtt = MqttState::new(opts);
+
+        // unsolicited pingresp has nothing to measure
+        match mqtt.handle_incoming_pingresp().unwrap() {
+            (Notification::None, Request::None) => (),
+            o => panic!("Expecting no notification. Found = {:?}", o),
+        }
+
+        mqtt.handle_incoming_idle().unwrap();
+        thread::sleep(Duration::from_millis(10));
+        let rtt = match mqtt.handle_incoming_pingresp().unwrap() {
+            (Notification::PingResponse { rtt }, Request::None) => rtt,
+            o => panic!("Expecting ping response. Found = {:?}", o),
+        };
+
+        assert!(rtt >= Duration::from_millis(10));
+        assert_eq!(mqtt.stats().snapshot().ping_rtt.map(|ping_rtt| ping_rtt.last), Some(rtt));
+    }
+
     #[test]
     fn previous_session_handle_should_reset_everything_in_clean_session() {
         let mut mqtt = build_mqttstate();
@@ -805,6 +2094,194 @@ mod test {
         assert_eq!(3, pubs.len());
     }
 
+    #[test]
+    fn unacked_messages_should_be_discarded_when_broker_forgets_the_session() {
+        let opts = MqttOptions::default().set_clean_session(false);
+        let mut mqtt = MqttState::new(opts);
+        let _ = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce));
+        let _ = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce));
+
+        let connack = Connack {
+            session_present: true,
+            code: ConnectReturnCode::Accepted,
+        };
+        mqtt.handle_incoming_connack(connack).unwrap();
+        assert!(mqtt.session_present());
+        assert_eq!(mqtt.outgoing_pub.len(), 2);
+
+        let connack = Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+        };
+        mqtt.handle_incoming_connack(connack).unwrap();
+        assert!(!mqtt.session_present());
+        assert_eq!(mqtt.handle_reconnection().len(), 0);
+    }
+
+    #[test]
+    fn stats_should_count_publishes_and_outstanding_acks() {
+        let mut mqtt = build_mqttstate();
+        let stats = mqtt.stats();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::AtLeastOnce))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(build_outgoing_publish(QoS::ExactlyOnce))).unwrap();
+        assert_eq!(stats.snapshot().acks_outstanding, 2);
+
+        mqtt.handle_incoming_mqtt_packet(Packet::Puback(PacketIdentifier(1))).unwrap();
+        mqtt.handle_incoming_mqtt_packet(Packet::Publish(build_incoming_publish(QoS::AtMostOnce, 0))).unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.publishes_sent, [0, 1, 1]);
+        assert_eq!(snapshot.publishes_received, [1, 0, 0]);
+        assert_eq!(snapshot.acks_outstanding, 1);
+    }
+
+    #[test]
+    fn clean_session_reconnection_should_only_subscribe_again() {
+        let mut mqtt = build_mqttstate();
+        let topics = vec![
+            SubscribeTopic { topic_path: "a/+".to_owned(), qos: QoS::AtLeastOnce },
+            SubscribeTopic { topic_path: "b/#".to_owned(), qos: QoS::AtMostOnce },
+            SubscribeTopic { topic_path: "c".to_owned(), qos: QoS::AtMostOnce },
+        ];
+        let subscription = mqtt.handle_outgoing_subscribe(Subscribe { pkid: PacketIdentifier::zero(), topics }).unwrap();
+        let suback = Suback {
+            pkid: subscription.pkid,
+            return_codes: vec![
+                SubscribeReturnCodes::Success(QoS::AtLeastOnce),
+                SubscribeReturnCodes::Success(QoS::AtMostOnce),
+                SubscribeReturnCodes::Failure,
+            ],
+        };
+        mqtt.handle_incoming_suback(suback).unwrap();
+        let unsubscription = Unsubscribe { pkid: PacketIdentifier::zero(), topics: vec!["b/#".to_owned()] };
+        mqtt.handle_outgoing_unsubscribe(unsubscription).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+
+        let connack = Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+        };
+        mqtt.handle_incoming_connack(connack).unwrap();
+        let requests = mqtt.handle_reconnection();
+        assert_eq!(requests.len(), 1);
+        match requests.front() {
+            Some(Request::Subscribe(subscription)) => {
+                let topics: Vec<&str> = subscription.topics.iter().map(|topic| topic.topic_path.as_str()).collect();
+                assert_eq!(topics, vec!["a/+"]);
+            }
+            request => panic!("Expecting subscribe. Found = {:?}", request),
+        }
+
+        // pending unsubscribe of the last session doesn't hold its pkid
+        assert!(mqtt.pkids_in_use.is_empty());
+
+        // subscriptions managed by the user
+        mqtt.opts = mqtt.opts.clone().set_auto_resubscribe(false);
+        mqtt.handle_incoming_connack(Connack { session_present: false, code: ConnectReturnCode::Accepted }).unwrap();
+        assert!(mqtt.handle_reconnection().is_empty());
+    }
+
+    #[test]
+    fn unacked_publishes_should_be_restored_from_persistence_after_restart() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-{}", uuid::Uuid::new_v4()));
+        let opts = MqttOptions::default().set_persistence(FilePersistence::new(&dir).unwrap());
+
+        let mut mqtt = MqttState::new(opts.clone());
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_pubrec(PacketIdentifier(3)).unwrap();
+        drop(mqtt);
+
+        // restart. clean session connack doesn't discard the restored publishes
+        let mut mqtt = MqttState::new(opts);
+        let connack = Connack {
+            session_present: false,
+            code: ConnectReturnCode::Accepted,
+        };
+        mqtt.handle_incoming_connack(connack).unwrap();
+        let requests = mqtt.handle_reconnection();
+        assert_eq!(requests.len(), 2);
+
+        // broker already has the publish which got a pubrec. it's only released
+        match requests.get(0) {
+            Some(Request::PubRel(pkid)) => assert_eq!(*pkid, PacketIdentifier(3)),
+            request => panic!("Expecting restored release. Found = {:?}", request),
+        }
+
+        match requests.get(1) {
+            Some(Request::Publish(publish)) => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(2)));
+                assert!(!publish.dup);
+            }
+            request => panic!("Expecting restored publish. Found = {:?}", request),
+        }
+
+        // restored pkids aren't reused
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(1)));
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(4)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reconnection_should_preserve_retain_flag_of_unacked_publishes() {
+        let mut mqtt = build_mqttstate();
+
+        let opts = MqttOptions::default().set_clean_session(false);
+        mqtt.opts = opts;
+
+        let mut publish = build_outgoing_publish(QoS::AtLeastOnce);
+        publish.retain = true;
+        let _ = mqtt.handle_outgoing_publish(publish);
+
+        let requests = mqtt.handle_reconnection();
+        match requests.get(0) {
+            Some(Request::Publish(publish)) => assert!(publish.retain),
+            r => panic!("Invalid replay request: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn reconnection_should_replay_pending_releases_before_publishes_in_persistent_session() {
+        let mut mqtt = build_mqttstate();
+
+        let opts = MqttOptions::default().set_clean_session(false);
+        mqtt.opts = opts;
+
+        let publish = build_outgoing_publish(QoS::ExactlyOnce);
+        let _ = mqtt.handle_outgoing_publish(publish.clone());
+        let _ = mqtt.handle_outgoing_publish(publish);
+
+        // disconnection after pubrec of first publish
+        mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        let requests = mqtt.handle_reconnection();
+        assert_eq!(mqtt.outgoing_rel.len(), 0);
+
+        match (requests.get(0), requests.get(1)) {
+            (Some(Request::PubRel(PacketIdentifier(1))), Some(Request::Publish(publish))) => {
+                assert_eq!(publish.pkid, Some(PacketIdentifier(2)))
+            }
+            r => panic!("Invalid replay requests: {:?}", r),
+        }
+
+        // replayed release should be tracked again and completed by pubcomp
+        mqtt.handle_outgoing_mqtt_packet(Packet::Pubrel(PacketIdentifier(1))).unwrap();
+        assert_eq!(mqtt.outgoing_rel.len(), 1);
+
+        // duplicate pubrec should result in the same release
+        let (_, request) = mqtt.handle_incoming_pubrec(PacketIdentifier(1)).unwrap();
+        match request {
+            Request::PubRel(PacketIdentifier(1)) => (),
+            _ => panic!("Invalid network request: {:?}", request),
+        }
+
+        mqtt.handle_incoming_pubcomp(PacketIdentifier(1)).unwrap();
+        assert_eq!(mqtt.outgoing_rel.len(), 0);
+    }
+
     #[test]
     fn connect_should_respect_options() {
         use crate::mqttoptions::SecurityOptions::UsernamePassword;
@@ -838,4 +2315,195 @@ mod test {
             }
         );
     }
+
+    #[test]
+    fn connect_should_use_mqtt31_protocol_when_configured() {
+        use crate::error::ConnectError;
+        use crate::mqttoptions::Protocol as MqttProtocol;
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_protocol(MqttProtocol::MQTT31);
+        let mut mqtt = MqttState::new(opts);
+
+        let connect = mqtt.handle_outgoing_connect().unwrap();
+        assert_eq!(connect.protocol, Protocol::MQIsdp(3));
+
+        let connack = Connack {
+            session_present: false,
+            code: ConnectReturnCode::RefusedProtocolVersion,
+        };
+
+        match mqtt.handle_incoming_connack(connack) {
+            Err(ConnectError::UnsupportedProtocolVersion) => (),
+            o => panic!("Expecting unsupported protocol version error. Got = {:?}", o),
+        }
+    }
+
+    #[test]
+    fn unacked_qos1_publishes_should_be_retransmitted_after_ack_timeout() {
+        let mut mqtt = build_mqttstate();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+
+        // nothing is due yet
+        assert!(mqtt.handle_ack_timeout(Duration::from_secs(10)).is_empty());
+
+        let retransmits = mqtt.handle_ack_timeout(Duration::from_secs(0));
+        let pkids: Vec<Option<PacketIdentifier>> = retransmits.iter().map(|publish| publish.pkid).collect();
+        assert_eq!(pkids, vec![Some(PacketIdentifier(1)), Some(PacketIdentifier(3))]);
+        assert!(retransmits.iter().all(|publish| publish.dup));
+
+        // broker might ack both the copies
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        assert!(mqtt.handle_incoming_puback(PacketIdentifier(1)).is_err());
+        assert_eq!(mqtt.outgoing_pub.len(), 2);
+    }
+
+    #[test]
+    fn pkid_of_a_retransmitted_publish_should_be_reserved_till_the_duplicate_ack() {
+        let mut mqtt = build_mqttstate();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        mqtt.handle_ack_timeout(Duration::from_secs(0));
+
+        // first ack of the retransmitted publishes. ids aren't reused yet
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.handle_incoming_puback(PacketIdentifier(2)).unwrap();
+        mqtt.last_pkid = PacketIdentifier(65_535);
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(3)));
+
+        // duplicate ack releases the id
+        mqtt.handle_incoming_puback(PacketIdentifier(1)).unwrap();
+        mqtt.last_pkid = PacketIdentifier(65_535);
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(1)));
+
+        // and so does the ack timeout when the duplicate ack never arrives
+        mqtt.handle_ack_timeout(Duration::from_secs(0));
+        mqtt.last_pkid = PacketIdentifier(1);
+        let publish = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        assert_eq!(publish.pkid, Some(PacketIdentifier(2)));
+    }
+
+    #[test]
+    fn connect_should_allow_username_without_password() {
+        use crate::mqttoptions::SecurityOptions;
+
+        let security = SecurityOptions::UsernameOnly("user".to_owned());
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        let connect = MqttState::new(opts).handle_outgoing_connect().unwrap();
+        assert_eq!(connect.username, Some("user".to_owned()));
+        assert_eq!(connect.password, None);
+
+        // binary password is added by the codec
+        let security = SecurityOptions::UsernamePasswordBytes("user".to_owned(), vec![0xde, 0xad]);
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        let connect = MqttState::new(opts).handle_outgoing_connect().unwrap();
+        assert_eq!(connect.username, Some("user".to_owned()));
+        assert_eq!(connect.password, None);
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn connect_should_sign_a_fresh_jwt_and_schedule_its_refresh() {
+        use crate::mqttoptions::SecurityOptions;
+        use std::time::Instant;
+
+        let key = include_bytes!("../../examples/tlsfiles/rsa_private.der").to_vec();
+        let security = SecurityOptions::GcloudIot("test-project".to_owned(), key, 60);
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_security_opts(security);
+        let mut mqtt = MqttState::new(opts);
+
+        let connect = mqtt.handle_outgoing_connect().unwrap();
+        assert!(connect.password.is_some());
+
+        // 90% of an hour
+        let refresh = mqtt.jwt_refresh_at().unwrap();
+        assert!(refresh > Instant::now() + Duration::from_secs(53 * 60));
+        assert!(refresh <= Instant::now() + Duration::from_secs(54 * 60));
+
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883);
+        let mut mqtt = MqttState::new(opts);
+        mqtt.handle_outgoing_connect().unwrap();
+        assert!(mqtt.jwt_refresh_at().is_none());
+    }
+
+    #[cfg(feature = "azure")]
+    #[test]
+    fn azure_sas_token_should_be_signed_with_shared_access_key() {
+        use super::azure_sas_token;
+        use crate::mqttoptions::SecurityOptions;
+
+        let key = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+        let token = azure_sas_token("myhub.azure-devices.net/devices/bike1", key, 1_600_000_000).unwrap();
+        let expected = "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fbike1\
+                        &sig=Ztznidxnf8MzHTe5hXqIdW%2BLer3WsHxHXI6fN2uYO0k%3D&se=1600000000";
+        assert_eq!(token, expected);
+        assert!(azure_sas_token("myhub.azure-devices.net/devices/bike1", "not base64!", 0).is_err());
+
+        let security = SecurityOptions::AzureIotHub {
+            hub_name: "myhub".to_owned(),
+            device_id: "bike1".to_owned(),
+            shared_access_key: key.to_owned(),
+            token_ttl: Duration::from_secs(3600),
+        };
+        let opts = MqttOptions::new("bike1", "myhub.azure-devices.net", 8883).set_security_opts(security);
+        let mut mqtt = MqttState::new(opts);
+
+        let connect = mqtt.handle_outgoing_connect().unwrap();
+        assert_eq!(connect.username.unwrap(), "myhub.azure-devices.net/bike1/?api-version=2018-06-30");
+        assert!(connect.password.unwrap().starts_with("SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fbike1&sig="));
+    }
+
+    #[test]
+    fn publish_queue_should_be_full_at_either_inflight_limit() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883).set_inflight(3).set_inflight_bytes(2000);
+        let mut mqtt = MqttState::new(opts);
+        let publish = |size| Publish { payload: Arc::new(vec![0; size]), ..build_outgoing_publish(QoS::AtLeastOnce) };
+
+        // a publish bigger than the budget goes out when nothing is in flight
+        let big = mqtt.handle_outgoing_publish(publish(4000)).unwrap();
+        assert!(mqtt.publish_queue_full());
+        mqtt.handle_incoming_puback(big.pkid.unwrap()).unwrap();
+        assert!(!mqtt.publish_queue_full());
+
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish(10))).unwrap();
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish(10))).unwrap();
+        assert!(!mqtt.publish_queue_full());
+        mqtt.handle_outgoing_mqtt_packet(Packet::Publish(publish(10))).unwrap();
+        assert!(mqtt.publish_queue_full());
+
+        let snapshot = mqtt.stats().snapshot();
+        assert_eq!(snapshot.queued_publishes, 3);
+        let bytes: usize = mqtt.outgoing_pub.iter().map(crate::codec::publish_len).sum();
+        assert_eq!(snapshot.queued_bytes, bytes as u64);
+    }
+
+    #[test]
+    fn publishes_older_than_the_ttl_should_not_be_replayed() {
+        let opts = MqttOptions::new("test-id", "127.0.0.1", 1883)
+            .set_clean_session(false)
+            .set_queued_message_ttl(Duration::from_millis(100));
+        let mut mqtt = MqttState::new(opts);
+
+        let stale = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::AtLeastOnce)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let fresh = mqtt.handle_outgoing_publish(build_outgoing_publish(QoS::ExactlyOnce)).unwrap();
+
+        let requests = mqtt.handle_reconnection();
+        assert_eq!(requests.len(), 1);
+        match requests.front() {
+            Some(Request::Publish(publish)) => assert_eq!(publish.pkid, fresh.pkid),
+            request => panic!("Expecting fresh publish. Found = {:?}", request),
+        }
+
+        assert!(!mqtt.pkids_in_use.contains(&stale.pkid.unwrap().0));
+        match mqtt.take_expired().as_slice() {
+            [Notification::Expired { topic, count: 1 }] => assert_eq!(topic, "hello/world"),
+            notifications => panic!("Expecting an expiry summary. Found = {:?}", notifications),
+        }
+        assert!(mqtt.take_expired().is_empty());
+    }
 }
@@ -40,6 +40,13 @@ where
     pub fn insert(&mut self, items: impl IntoIterator<Item = <S as Stream>::Item>) {
         self.items.extend(items)
     }
+
+    /// Insert items before present items
+    pub fn prepend(&mut self, items: impl IntoIterator<Item = <S as Stream>::Item>) {
+        let mut items: VecDeque<_> = items.into_iter().collect();
+        items.append(&mut self.items);
+        self.items = items;
+    }
 }
 
 impl<S> Stream for Prependable<S>
@@ -0,0 +1,98 @@
+use futures::{Async, Poll, Stream};
+
+pub trait Prioritize: Stream {
+    /// Merges `low` into this stream. Items of this stream which are ready are always
+    /// yielded before the items of `low`. Ends when both the streams end
+    fn prioritized<L>(self, low: L) -> Prioritized<Self, L>
+    where
+        Self: Sized,
+        L: Stream<Item = Self::Item, Error = Self::Error>,
+    {
+        new(self, low)
+    }
+}
+
+impl<T: ?Sized> Prioritize for T where T: Stream {}
+
+#[must_use = "streams do nothing unless polled"]
+pub struct Prioritized<H, L> {
+    high: H,
+    low: L,
+    high_done: bool,
+    low_done: bool,
+}
+
+pub fn new<H, L>(high: H, low: L) -> Prioritized<H, L>
+where
+    H: Stream,
+    L: Stream<Item = H::Item, Error = H::Error>,
+{
+    Prioritized {
+        high,
+        low,
+        high_done: false,
+        low_done: false,
+    }
+}
+
+impl<H, L> Stream for Prioritized<H, L>
+where
+    H: Stream,
+    L: Stream<Item = H::Item, Error = H::Error>,
+{
+    type Item = H::Item;
+    type Error = H::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.high_done {
+            match self.high.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.high_done = true,
+                Async::NotReady => (),
+            }
+        }
+
+        if !self.low_done {
+            match self.low.poll()? {
+                Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+                Async::Ready(None) => self.low_done = true,
+                Async::NotReady => (),
+            }
+        }
+
+        if self.high_done && self.low_done {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Prioritize;
+    use futures::{stream, Future, Stream};
+
+    #[test]
+    fn ready_items_of_the_high_priority_stream_should_be_yielded_first() {
+        let high = stream::iter_ok::<_, ()>(vec!["pingreq", "puback"]);
+        let low = stream::iter_ok(vec!["publish 1", "publish 2", "publish 3"]);
+
+        let items = high.prioritized(low).collect().wait().unwrap();
+        assert_eq!(items, vec!["pingreq", "puback", "publish 1", "publish 2", "publish 3"]);
+    }
+
+    #[test]
+    fn low_priority_stream_should_progress_while_the_high_priority_stream_is_idle() {
+        let (tx, rx) = futures::sync::mpsc::unbounded::<&str>();
+        let low = stream::iter_ok(vec!["publish 1", "publish 2"]);
+        let mut stream = rx.prioritized(low).wait();
+
+        assert_eq!(stream.next(), Some(Ok("publish 1")));
+        tx.unbounded_send("pingreq").unwrap();
+        assert_eq!(stream.next(), Some(Ok("pingreq")));
+        assert_eq!(stream.next(), Some(Ok("publish 2")));
+        drop(tx);
+        assert_eq!(stream.next(), None);
+    }
+}
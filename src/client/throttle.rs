@@ -0,0 +1,222 @@
+use crate::client::Request;
+use crate::error::NetworkError;
+use crate::topic;
+use futures::{Async, Future, Poll, Stream};
+use std::{
+    cmp,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+/// Requests buffered across the buckets before the source isn't read anymore
+const MAX_BUFFERED: usize = 100;
+
+/// Publishes of topics which match the filter (all the other requests when `filter` is
+/// `None`) in the order they arrived
+struct Bucket {
+    filter: Option<String>,
+    // unthrottled when `None`
+    interval: Option<Duration>,
+    next: Instant,
+    requests: VecDeque<Request>,
+}
+
+impl Bucket {
+    fn new(filter: Option<String>, rate: Option<f32>) -> Bucket {
+        let interval = rate.map(|rate| Duration::from_nanos((1_000_000_000.0 / rate) as u64));
+        Bucket { filter, interval, next: Instant::now(), requests: VecDeque::new() }
+    }
+
+    fn accepts(&self, request: &Request) -> bool {
+        match (&self.filter, request) {
+            (Some(_), Request::Queued(request, _)) | (Some(_), Request::Replay(request)) => self.accepts(request),
+            (None, _) => true,
+            (Some(filter), Request::Publish(publish)) | (Some(filter), Request::PublishWithAck(publish, _)) => {
+                topic::matches(filter, &publish.topic_name)
+            }
+            (Some(_), _) => false,
+        }
+    }
+}
+
+/// Publishes (queued, replayed or not) are the requests which are bucketed by topic
+fn is_publish(request: &Request) -> bool {
+    match request {
+        Request::Publish(_) | Request::PublishWithAck(..) => true,
+        Request::Queued(request, _) | Request::Replay(request) => is_publish(request),
+        _ => false,
+    }
+}
+
+/// Paces publishes with a rate per topic filter. Every filter has a bucket of its own
+/// so that a slow bucket doesn't hold the publishes of the others. Requests which match
+/// no filter use the `fallback` rate (unthrottled when `None`). Order is kept within a bucket.
+/// Other requests (batches, subscribes, acks, disconnect ..) wait till the publishes read before
+/// them are out so that e.g a disconnect doesn't overtake the buffered publishes
+#[must_use = "streams do nothing unless polled"]
+pub struct TopicThrottle<S> {
+    requests: S,
+    // first matching bucket takes the request. fallback bucket is the last
+    buckets: Vec<Bucket>,
+    // request which isn't a publish waiting for the buffered publishes
+    held: Option<Request>,
+    delay: Option<Delay>,
+    done: bool,
+    // error of the source. returned once the buffered requests are out
+    error: Option<NetworkError>,
+}
+
+impl<S> TopicThrottle<S>
+where
+    S: Stream<Item = Request, Error = NetworkError>,
+{
+    pub fn new(requests: S, rules: &[(String, f32)], fallback: Option<f32>) -> TopicThrottle<S> {
+        let mut buckets: Vec<Bucket> = rules.iter().map(|(filter, rate)| Bucket::new(Some(filter.clone()), Some(*rate))).collect();
+        buckets.push(Bucket::new(None, fallback));
+
+        TopicThrottle { requests, buckets, held: None, delay: None, done: false, error: None }
+    }
+
+    fn buffered(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.requests.len()).sum()
+    }
+
+    fn read_source(&mut self) {
+        loop {
+            if let Some(request) = self.held.take() {
+                if self.buffered() > 0 {
+                    self.held = Some(request);
+                    return;
+                }
+
+                if let Some(fallback) = self.buckets.last_mut() {
+                    fallback.requests.push_back(request);
+                }
+            }
+
+            if self.done || self.buffered() >= MAX_BUFFERED {
+                return;
+            }
+
+            match self.requests.poll() {
+                Ok(Async::Ready(Some(request))) => match is_publish(&request) {
+                    true => {
+                        if let Some(bucket) = self.buckets.iter_mut().find(|bucket| bucket.accepts(&request)) {
+                            bucket.requests.push_back(request);
+                        }
+                    }
+                    false => self.held = Some(request),
+                },
+                Ok(Async::Ready(None)) => self.done = true,
+                Ok(Async::NotReady) => return,
+                Err(e) => {
+                    self.error = Some(e);
+                    self.done = true;
+                }
+            }
+        }
+    }
+}
+
+impl<S> Stream for TopicThrottle<S>
+where
+    S: Stream<Item = Request, Error = NetworkError>,
+{
+    type Item = Request;
+    type Error = NetworkError;
+
+    fn poll(&mut self) -> Poll<Option<Request>, NetworkError> {
+        self.read_source();
+
+        loop {
+            let now = Instant::now();
+            let mut wake_at: Option<Instant> = None;
+            for bucket in self.buckets.iter_mut().filter(|bucket| !bucket.requests.is_empty()) {
+                if bucket.next <= now {
+                    if let Some(interval) = bucket.interval {
+                        bucket.next = now + interval;
+                    }
+
+                    return Ok(Async::Ready(bucket.requests.pop_front()));
+                }
+
+                wake_at = Some(wake_at.map_or(bucket.next, |wake_at| cmp::min(wake_at, bucket.next)));
+            }
+
+            let wake_at = match wake_at {
+                Some(wake_at) => wake_at,
+                None if self.done => match self.error.take() {
+                    Some(e) => return Err(e),
+                    None => return Ok(Async::Ready(None)),
+                },
+                None => return Ok(Async::NotReady),
+            };
+
+            let delay = self.delay.get_or_insert_with(|| Delay::new(wake_at));
+            delay.reset(wake_at);
+            if delay.poll().map_err(NetworkError::Timer)?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TopicThrottle;
+    use crate::client::Request;
+    use crate::error::NetworkError;
+    use futures::{stream, Stream};
+    use mqtt311::{Publish, QoS};
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::runtime::current_thread::Runtime;
+
+    fn publish(topic: &str) -> Request {
+        Request::Publish(Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            pkid: None,
+            topic_name: topic.to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        })
+    }
+
+    #[test]
+    fn slow_topics_should_not_hold_the_other_publishes() {
+        let requests = vec![publish("logs/a"), publish("logs/b"), publish("logs/c"), publish("alarm/1"), publish("alarm/2")];
+        let requests = stream::iter_ok::<_, NetworkError>(requests);
+        let rules = vec![("logs/#".to_owned(), 4.0)];
+
+        let start = Instant::now();
+        let throttled = TopicThrottle::new(requests, &rules, None).map(|request| match request {
+            Request::Publish(publish) => (publish.topic_name, start.elapsed()),
+            request => panic!("Expecting publish. Found = {:?}", request),
+        });
+        let published = Runtime::new().unwrap().block_on(throttled.collect()).unwrap();
+
+        let topics: Vec<&str> = published.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(topics, vec!["logs/a", "alarm/1", "alarm/2", "logs/b", "logs/c"]);
+        assert!(published[2].1 < Duration::from_millis(100));
+        assert!(published[3].1 >= Duration::from_millis(250));
+        assert!(published[4].1 >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn other_requests_should_wait_for_the_publishes_before_them() {
+        let requests = vec![publish("logs/a"), publish("logs/b"), Request::Disconnect, publish("alarm/1")];
+        let requests = stream::iter_ok::<_, NetworkError>(requests);
+        let rules = vec![("logs/#".to_owned(), 10.0)];
+
+        let throttled = TopicThrottle::new(requests, &rules, None).map(|request| match request {
+            Request::Publish(publish) => publish.topic_name,
+            request => format!("{:?}", request),
+        });
+        let sent = Runtime::new().unwrap().block_on(throttled.collect()).unwrap();
+        assert_eq!(sent, vec!["logs/a", "logs/b", "Disconnect", "alarm/1"]);
+    }
+}
@@ -1,13 +1,192 @@
 //! Codec to convert incoming bytes of a tcp stream into mqtt packets
 //! and outgoing mqtt packets to raw bytes
-use bytes::BytesMut;
-use mqtt311::{self, MqttRead, MqttWrite, Packet};
-use std::io::{self, Cursor, ErrorKind};
+use crate::stats::Stats;
+#[cfg(feature = "mqtt5")]
+use crate::v5;
+use bytes::{BufMut, BytesMut};
+use failure::Fail;
+use mqtt311::{self, Connect, MqttRead, MqttWrite, Packet, Publish, QoS};
+use std::io::{self, Cursor, ErrorKind, Write};
+use std::sync::Arc;
+use std::{cmp, error, fmt};
 use tokio::codec::{Decoder, Encoder};
 
 /// Mqtt codec
-#[derive(Debug)]
-pub struct MqttCodec;
+#[derive(Debug, Default)]
+pub struct MqttCodec {
+    stats: Option<Arc<Stats>>,
+    max_packet_size: Option<usize>,
+    // bytes of an oversized packet which are yet to be skipped
+    discard: usize,
+    #[cfg(feature = "mqtt5")]
+    v5: Option<Arc<v5::Context>>,
+}
+
+/// Incoming packet which is bigger than the maximum packet size. Decoder skips the
+/// bytes of the packet and returns this as the error (`io::ErrorKind::InvalidData`).
+/// Decoding continues with the next packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketTooLarge {
+    /// Topic of a publish when it arrived along with the fixed header
+    pub topic: Option<String>,
+    /// Size of the packet
+    pub size: usize,
+}
+
+impl fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Packet too large. Size = {}, Topic = {:?}", self.size, self.topic)
+    }
+}
+
+impl error::Error for PacketTooLarge {}
+
+impl PacketTooLarge {
+    /// Gets the oversized packet out of a decode error. Gives back other errors
+    pub fn from_io_error(e: io::Error) -> Result<PacketTooLarge, io::Error> {
+        let too_large = e.get_ref().and_then(|e| e.downcast_ref::<PacketTooLarge>()).cloned();
+        match too_large {
+            Some(too_large) => Ok(too_large),
+            None => Err(e),
+        }
+    }
+}
+
+impl MqttCodec {
+    pub fn new() -> MqttCodec {
+        MqttCodec::default()
+    }
+
+    /// Counts the bytes of the encoded and decoded packets in `stats`
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = Some(stats);
+    }
+
+    /// Skips incoming packets bigger than `size` bytes without buffering them
+    pub fn set_max_packet_size(&mut self, size: usize) {
+        self.max_packet_size = Some(size);
+    }
+
+    /// Frames packets as per mqtt 5. Properties and reason codes go through `context`
+    #[cfg(feature = "mqtt5")]
+    pub fn set_v5(&mut self, context: Arc<v5::Context>) {
+        self.v5 = Some(context);
+    }
+
+    /// Encodes a connect with a binary password. See `encode_connect`
+    pub fn encode_connect(&self, connect: &Connect, password: &[u8]) -> io::Result<Vec<u8>> {
+        #[cfg(feature = "mqtt5")]
+        {
+            if let Some(context) = self.v5.as_ref() {
+                return v5::encode_connect(connect, password, context);
+            }
+        }
+
+        encode_connect(connect, password)
+    }
+
+    /// Decodes an mqtt 5 packet once all of its bytes arrive
+    #[cfg(feature = "mqtt5")]
+    fn decode_v5(&mut self, buf: &mut BytesMut, context: &v5::Context) -> io::Result<Option<Packet>> {
+        let (header_len, remaining_len) = match fixed_header(buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let len = header_len + remaining_len;
+        if buf.len() < len {
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(len);
+        if let Some(stats) = self.stats.as_ref() {
+            stats.add_bytes_received(len);
+        }
+
+        v5::read_packet(frame[0], &frame[header_len..], context).map(Some)
+    }
+
+    fn write_packet(&self, packet: &Packet, buf: &mut BytesMut) -> io::Result<()> {
+        #[cfg(feature = "mqtt5")]
+        {
+            if let Some(context) = self.v5.as_ref() {
+                return v5::write_packet(packet, context, buf);
+            }
+        }
+
+        // NOTE: `BytesMut` writer doesn't grow the buffer. Reserve enough space to write
+        // the packet directly into the buffer without an intermediate copy of the payload
+        buf.reserve(max_encoded_len(packet));
+        let mut writer = PacketWriter((&mut *buf).writer());
+        writer.write_packet(packet).map_err(|e| {
+            error!("Encode error. Error = {:?}", e);
+            encode_error(e)
+        })
+    }
+
+    /// Drops the buffered bytes of an oversized packet. True when the packet is
+    /// completely skipped
+    fn skip(&mut self, buf: &mut BytesMut) -> bool {
+        let len = cmp::min(self.discard, buf.len());
+        buf.split_to(len);
+        self.discard -= len;
+        if let Some(stats) = self.stats.as_ref() {
+            stats.add_bytes_received(len);
+        }
+
+        self.discard == 0
+    }
+
+    /// Checks the size in the fixed header against the maximum packet size as soon as
+    /// the header arrives
+    fn check_size(&mut self, buf: &mut BytesMut) -> io::Result<()> {
+        let max_packet_size = match self.max_packet_size {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+
+        let (header_len, remaining_len) = match fixed_header(buf)? {
+            Some(header) => header,
+            None => return Ok(()),
+        };
+
+        let size = header_len + remaining_len;
+        if size <= max_packet_size {
+            return Ok(());
+        }
+
+        // publish = fixed header, topic length (2 bytes), topic
+        let topic = if buf[0] >> 4 == 3 && buf.len() >= header_len + 2 {
+            let topic_len = (usize::from(buf[header_len]) << 8) | usize::from(buf[header_len + 1]);
+            buf.get(header_len + 2..header_len + 2 + topic_len).map(|topic| String::from_utf8_lossy(topic).into_owned())
+        } else {
+            None
+        };
+
+        error!("Skipping incoming packet bigger than the limit. Size = {}, Topic = {:?}", size, topic);
+        self.discard = size;
+        self.skip(buf);
+        Err(io::Error::new(ErrorKind::InvalidData, PacketTooLarge { topic, size }))
+    }
+}
+
+/// Length of the fixed header and the remaining length of the packet in `buf`. `None`
+/// till the whole fixed header arrives
+fn fixed_header(buf: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut remaining_len = 0;
+    for (i, byte) in buf.iter().skip(1).take(4).enumerate() {
+        remaining_len |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((i + 2, remaining_len)));
+        }
+    }
+
+    if buf.len() >= 5 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "Malformed remaining length"));
+    }
+
+    Ok(None)
+}
 
 impl Decoder for MqttCodec {
     type Item = Packet;
@@ -20,10 +199,23 @@ impl Decoder for MqttCodec {
         // Ok(0) => translated to UnexpectedEOF by `byteorder` crate.
         // `read` call Ok(0) happens when buffer specified was 0 bytes in len
         // https://doc.rust-lang.org/std/io/trait.Read.html#tymethod.read
+        if self.discard > 0 && !self.skip(buf) {
+            return Ok(None);
+        }
+
         if buf.len() < 2 {
             return Ok(None);
         }
 
+        self.check_size(buf)?;
+
+        #[cfg(feature = "mqtt5")]
+        {
+            if let Some(context) = self.v5.clone() {
+                return self.decode_v5(buf, &context);
+            }
+        }
+
         let (packet, len) = {
             let mut buf_ref = buf.as_ref();
             match buf_ref.read_packet_with_len() {
@@ -38,7 +230,7 @@ impl Decoder for MqttCodec {
                         }
                     } else {
                         error!("mqtt3 read error = {:?}", e);
-                        return Err(io::Error::new(ErrorKind::Other, "Mqtt Error"));
+                        return Err(io::Error::new(ErrorKind::InvalidData, e.compat()));
                     }
                 }
                 Ok(v) => v,
@@ -57,6 +249,9 @@ impl Decoder for MqttCodec {
         // println!("{:?}, {:?}, {:?}", len, packet, buf.len());
 
         buf.split_to(len);
+        if let Some(stats) = self.stats.as_ref() {
+            stats.add_bytes_received(len);
+        }
 
         Ok(Some(packet))
     }
@@ -67,16 +262,252 @@ impl Encoder for MqttCodec {
     type Error = io::Error;
 
     fn encode(&mut self, msg: Packet, buf: &mut BytesMut) -> io::Result<()> {
-        let mut stream = Cursor::new(Vec::new());
+        let len = buf.len();
+        self.write_packet(&msg, buf)?;
 
-        // TODO: Implement `write_packet` for `&mut BytesMut`
-        if let Err(e) = stream.write_packet(&msg) {
-            error!("Encode error. Error = {:?}", e);
-            return Err(io::Error::new(io::ErrorKind::Other, "Unable to encode!"));
+        if let Some(stats) = self.stats.as_ref() {
+            stats.add_bytes_sent(buf.len() - len);
         }
 
-        buf.extend(stream.get_ref());
-
         Ok(())
     }
 }
+
+/// Any writer as an mqtt writer. mqtt311 implements `MqttWrite` only for a few
+/// std writers
+struct PacketWriter<W>(W);
+
+impl<W: Write> Write for PacketWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> MqttWrite for PacketWriter<W> {}
+
+/// Encodes a connect packet with a binary password. mqtt311's `Connect` can only
+/// carry utf8 passwords. Password of `connect` should be `None`
+pub fn encode_connect(connect: &Connect, password: &[u8]) -> io::Result<Vec<u8>> {
+    if password.len() > 65535 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Password too long"));
+    }
+
+    let mut packet = Cursor::new(Vec::new());
+    if let Err(e) = packet.write_packet(&Packet::Connect(connect.clone())) {
+        error!("Encode error. Error = {:?}", e);
+        return Err(encode_error(e));
+    }
+
+    let mut packet = packet.into_inner();
+
+    // skip the fixed header. remaining length takes 1 to 4 bytes
+    let mut header_len = 2;
+    while packet[header_len - 1] & 0x80 != 0 {
+        header_len += 1;
+    }
+
+    // connect flags follow the protocol name and level
+    let mut body = packet.split_off(header_len);
+    let name_len = (usize::from(body[0]) << 8) | usize::from(body[1]);
+    body[2 + name_len + 1] |= 0x40;
+    body.extend_from_slice(&(password.len() as u16).to_be_bytes());
+    body.extend_from_slice(password);
+
+    let mut out = vec![packet[0]];
+    let mut remaining_len = body.len();
+    loop {
+        let mut byte = (remaining_len % 128) as u8;
+        remaining_len /= 128;
+        if remaining_len > 0 {
+            byte |= 0x80;
+        }
+
+        out.push(byte);
+        if remaining_len == 0 {
+            break;
+        }
+    }
+
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// Io error which keeps the mqtt error as its cause
+fn encode_error(e: mqtt311::Error) -> io::Error {
+    match e {
+        mqtt311::Error::Io(e) => e,
+        e => io::Error::new(ErrorKind::InvalidInput, e.compat()),
+    }
+}
+
+/// Encoded size of the publish packet (fixed header included)
+pub fn publish_len(publish: &Publish) -> usize {
+    let pkid = if publish.qos == QoS::AtMostOnce { 0 } else { 2 };
+    let remaining_len = 2 + publish.topic_name.len() + pkid + publish.payload.len();
+    let remaining_len_bytes = match remaining_len {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    };
+
+    1 + remaining_len_bytes + remaining_len
+}
+
+/// Upper bound of the encoded size of a packet
+pub(crate) fn max_encoded_len(packet: &Packet) -> usize {
+    // fixed header (max 5 bytes) + packet identifier + connect protocol name, level, flags & keep alive
+    let overhead = 5 + 2 + 16;
+    let variable = match packet {
+        Packet::Publish(publish) => 2 + publish.topic_name.len() + publish.payload.len(),
+        Packet::Connect(connect) => {
+            let will = connect.last_will.as_ref().map_or(0, |will| 4 + will.topic.len() + will.message.len());
+            let username = connect.username.as_ref().map_or(0, |username| 2 + username.len());
+            let password = connect.password.as_ref().map_or(0, |password| 2 + password.len());
+            2 + connect.client_id.len() + will + username + password
+        }
+        Packet::Subscribe(subscribe) => subscribe.topics.iter().map(|topic| 3 + topic.topic_path.len()).sum(),
+        Packet::Unsubscribe(unsubscribe) => unsubscribe.topics.iter().map(|topic| 2 + topic.len()).sum(),
+        Packet::Suback(suback) => suback.return_codes.len(),
+        _ => 0,
+    };
+
+    overhead + variable
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_connect, MqttCodec, PacketTooLarge};
+    use crate::stats::Stats;
+    use bytes::BytesMut;
+    use mqtt311::{Connect, MqttWrite, Packet, PacketIdentifier, Protocol, Publish, QoS};
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use tokio::codec::{Decoder, Encoder};
+
+    #[test]
+    fn large_publish_is_encoded_in_place_and_decoded_back() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(10)),
+            payload: Arc::new(vec![7; 1024 * 1024]),
+        };
+
+        let mut buf = BytesMut::new();
+        MqttCodec::new().encode(Packet::Publish(publish.clone()), &mut buf).unwrap();
+        MqttCodec::new().encode(Packet::Pingreq, &mut buf).unwrap();
+
+        assert_eq!(MqttCodec::new().decode(&mut buf).unwrap(), Some(Packet::Publish(publish)));
+        assert_eq!(MqttCodec::new().decode(&mut buf).unwrap(), Some(Packet::Pingreq));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn publish_len_should_match_the_encoded_size() {
+        for &(qos, size) in &[(QoS::AtMostOnce, 0), (QoS::AtLeastOnce, 100), (QoS::ExactlyOnce, 20_000), (QoS::AtLeastOnce, 3_000_000)] {
+            let publish = Publish {
+                dup: false,
+                qos,
+                retain: false,
+                topic_name: "hello/world".to_owned(),
+                pkid: if qos == QoS::AtMostOnce { None } else { Some(PacketIdentifier(1)) },
+                payload: Arc::new(vec![1; size]),
+            };
+
+            let mut buf = BytesMut::new();
+            let expected = super::publish_len(&publish);
+            MqttCodec::new().encode(Packet::Publish(publish), &mut buf).unwrap();
+            assert_eq!(buf.len(), expected);
+        }
+    }
+
+    #[test]
+    fn oversized_publish_should_be_skipped_without_buffering() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(10)),
+            payload: Arc::new(vec![7; 10 * 1024 * 1024]),
+        };
+
+        let mut packets = BytesMut::new();
+        MqttCodec::new().encode(Packet::Publish(publish), &mut packets).unwrap();
+        MqttCodec::new().encode(Packet::Pingresp, &mut packets).unwrap();
+        let size = packets.len() - 2;
+
+        let mut codec = MqttCodec::new();
+        codec.set_max_packet_size(256 * 1024);
+
+        // network delivers the bytes in chunks
+        let mut buf = BytesMut::new();
+        let mut errors = Vec::new();
+        let mut decoded = Vec::new();
+        for chunk in packets.chunks(64 * 1024) {
+            buf.extend_from_slice(chunk);
+            loop {
+                match codec.decode(&mut buf) {
+                    Ok(Some(packet)) => decoded.push(packet),
+                    Ok(None) => break,
+                    Err(e) => errors.push(PacketTooLarge::from_io_error(e).unwrap()),
+                }
+            }
+
+            assert!(buf.len() <= 64 * 1024);
+        }
+
+        let too_large = PacketTooLarge { topic: Some("hello/world".to_owned()), size };
+        assert_eq!(errors, vec![too_large]);
+        assert_eq!(decoded, vec![Packet::Pingresp]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encoded_and_decoded_bytes_should_be_counted() {
+        let stats = Arc::new(Stats::default());
+        let mut codec = MqttCodec::new();
+        codec.set_stats(stats.clone());
+
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Pingreq, &mut buf).unwrap();
+        codec.encode(Packet::Puback(PacketIdentifier(1)), &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_sent, 6);
+        assert_eq!(snapshot.bytes_received, 2);
+    }
+
+    #[test]
+    fn binary_password_should_be_appended_to_connect() {
+        let connect = Connect {
+            protocol: Protocol::MQTT(4),
+            keep_alive: 10,
+            client_id: "test-id".to_owned(),
+            clean_session: true,
+            last_will: None,
+            username: Some("user".to_owned()),
+            password: None,
+        };
+
+        // same bytes as a utf8 password
+        let mut expected = Cursor::new(Vec::new());
+        let packet = Connect { password: Some("pass".to_owned()), ..connect.clone() };
+        expected.write_packet(&Packet::Connect(packet)).unwrap();
+        assert_eq!(encode_connect(&connect, b"pass").unwrap(), expected.into_inner());
+
+        // remaining length grows to 2 bytes
+        let password = vec![0xff; 200];
+        let encoded = encode_connect(&connect, &password).unwrap();
+        assert_eq!(&encoded[1..3], &[((encoded.len() - 3) % 128) as u8 | 0x80, 1]);
+        assert!(encoded.ends_with(&password));
+    }
+}
@@ -1,91 +1,367 @@
 //! All errors
 use crate::client::{Command, Request};
 use crossbeam_channel::RecvError;
-use derive_more::From;
-use failure::Fail;
+use derive_more::{Display, From};
 use futures::sync::mpsc::SendError;
 #[cfg(feature = "jwt")]
 use jsonwebtoken;
-use mqtt311::Packet;
+use mqtt311::{Packet, Publish};
+use std::error::Error;
+use std::fmt;
 use std::io::Error as IoError;
 use tokio::timer::{self, timeout};
 
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, Fail, From)]
+#[derive(Debug, Display, From)]
 pub enum ClientError {
-    #[fail(display = "No subscriptions")]
+    #[display(fmt = "Subscribe needs at least one topic")]
     ZeroSubscriptions,
-    #[fail(display = "Packet size limit has crossed maximum")]
-    PacketSizeLimitExceeded,
-    #[fail(display = "Client id should not be empty")]
+    #[display(fmt = "Publish of {} bytes exceeds the maximum packet size of {} bytes", actual, limit)]
+    PayloadTooLarge { limit: usize, actual: usize },
+    #[display(fmt = "Client id should not be empty")]
     EmptyClientId,
-    #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
-    MpscRequestSend(SendError<Request>),
-    #[fail(display = "Failed sending request to connection thread. Error = {}", _0)]
-    MpscCommandSend(SendError<Command>),
+    #[display(fmt = "Eventloop didn't stop in time")]
+    ShutdownTimeout,
+    #[display(fmt = "Eventloop is not running")]
+    EventloopTerminated,
+    #[display(fmt = "Request channel is full")]
+    RequestChannelFull(Publish),
+    #[display(fmt = "Invalid topic = {:?}", _0)]
+    InvalidTopic(String),
+    #[display(fmt = "Invalid topic filter = {:?}", _0)]
+    InvalidTopicFilter(String),
+    #[cfg(feature = "mqtt5")]
+    #[display(fmt = "Mqtt 5 property longer than 65535 bytes")]
+    PropertyTooLong,
+    #[cfg(feature = "json")]
+    #[display(fmt = "Json serialization failed. Error = {}", _0)]
+    Json(serde_json::Error),
+    #[cfg(feature = "cbor")]
+    #[display(fmt = "Cbor serialization failed. Error = {}", _0)]
+    Cbor(serde_cbor::error::Error),
 }
 
-#[derive(Debug, Fail, From)]
-pub enum MqttError {
-    #[fail(display = "Connection failed")]
-    ConnectError,
-    #[fail(display = "Network call failed")]
-    NetworkError,
+/// Failure to deserialize the payload of an incoming publish
+#[cfg(any(feature = "json", feature = "cbor"))]
+#[derive(Debug, Display, From)]
+pub enum DecodeError {
+    #[cfg(feature = "json")]
+    #[display(fmt = "Invalid json payload. Error = {}", _0)]
+    Json(serde_json::Error),
+    #[cfg(feature = "cbor")]
+    #[display(fmt = "Invalid cbor payload. Error = {}", _0)]
+    Cbor(serde_cbor::error::Error),
+}
+
+#[derive(Debug, Display, From)]
+pub enum PublishError {
+    #[display(fmt = "Client error = {}", _0)]
+    Client(ClientError),
+    #[display(fmt = "Acknowledgement not received in time")]
+    Timeout,
+    #[display(fmt = "Publish dropped by the eventloop before acknowledgement")]
+    Dropped,
+}
+
+#[derive(Debug, Display, PartialEq)]
+pub enum TopicError {
+    #[display(fmt = "Topic is empty")]
+    Empty,
+    #[display(fmt = "Topic is longer than 65535 bytes. Length = {}", _0)]
+    TooLong(usize),
+    #[display(fmt = "Topic contains a null character. Topic = {:?}", _0)]
+    NullCharacter(String),
+    #[display(fmt = "Topic name contains a wildcard. Topic = {:?}", _0)]
+    Wildcard(String),
+    #[display(fmt = "Wildcard doesn't occupy an entire level (or # isn't the last level). Filter = {:?}", _0)]
+    MisplacedWildcard(String),
+}
+
+#[derive(Debug, Display, PartialEq)]
+pub enum WaitError {
+    #[display(fmt = "Connection not established in time")]
+    Timeout,
+    #[display(fmt = "Eventloop terminated")]
+    EventloopTerminated,
+}
+
+#[derive(Debug, Display, PartialEq)]
+pub enum OptionsError {
+    #[display(fmt = "Client id should not be empty or start with a space. Id = {:?}", _0)]
+    InvalidClientId(String),
+    #[display(fmt = "Throttle rate should be a positive number. Rate = {}", _0)]
+    InvalidThrottle(f32),
+    #[display(fmt = "Zero in flight is not allowed")]
+    ZeroInflight,
+    #[display(fmt = "Connection timeout should be non zero")]
+    ZeroConnectionTimeout,
+    #[display(fmt = "At least one broker is necessary")]
+    NoBrokers,
+    #[display(fmt = "Invalid topic filter = {:?}", _0)]
+    InvalidTopicFilter(String),
+    #[display(fmt = "Unsupported session state version = {}", _0)]
+    UnsupportedSessionVersion(u32),
+}
+
+#[derive(Debug, Display, From, PartialEq)]
+pub enum UrlError {
+    #[display(fmt = "Url should be of the form scheme://host[:port][?query]. Url = {:?}", _0)]
+    Malformed(String),
+    #[display(fmt = "Unsupported scheme = {:?}. Expecting tcp, mqtt, ssl, mqtts, ws or wss", _0)]
+    UnsupportedScheme(String),
+    #[display(fmt = "Invalid host = {:?}", _0)]
+    InvalidHost(String),
+    #[display(fmt = "Invalid port = {:?}", _0)]
+    InvalidPort(String),
+    #[display(fmt = "Port is necessary for scheme = {:?}", _0)]
+    MissingPort(String),
+    #[display(fmt = "Unknown query parameter = {:?}", _0)]
+    UnknownParameter(String),
+    #[display(fmt = "Invalid value of query parameter {} = {:?}", _0, _1)]
+    InvalidParameter(String, String),
+    #[display(fmt = "Websocket urls need the websocket feature")]
+    WebsocketDisabled,
+    #[display(fmt = "Invalid options. Error = {}", _0)]
+    Options(OptionsError),
 }
 
 // TODO: Modify mqtt311 to return enums for mqtt connect error
-#[derive(Debug, Fail, From)]
+#[derive(Debug, Display, From)]
 pub enum ConnectError {
-    #[fail(display = "Mqtt connection failed. Error = {}", _0)]
+    #[display(fmt = "Broker refused the connection. Return code = {}", _0)]
     MqttConnectionRefused(u8),
+    #[display(fmt = "Broker doesn't support the protocol version")]
+    UnsupportedProtocolVersion,
     #[cfg(feature = "jwt")]
-    #[fail(display = "Mqtt connection failed. Error = {}", _0)]
+    #[display(fmt = "Jwt creation failed. Error = {}", _0)]
     Jwt(jsonwebtoken::errors::Error),
-    #[fail(display = "Io failed. Error = {}", _0)]
+    #[display(fmt = "Io failed. Error = {}", _0)]
     Io(IoError),
-    #[fail(display = "Receiving connection status failed. Error = {}", _0)]
+    #[display(fmt = "Binding to local address failed. Error = {}", _0)]
+    Bind(IoError),
+    #[display(fmt = "Receiving connection status failed. Error = {}", _0)]
     Recv(RecvError),
-    #[fail(display = "Empty dns list")]
+    #[display(fmt = "Couldn't spawn the eventloop thread. Error = {}", _0)]
+    Spawn(IoError),
+    #[display(fmt = "Couldn't create the eventloop runtime. Error = {}", _0)]
+    Runtime(IoError),
+    #[display(fmt = "Empty dns list")]
     DnsListEmpty,
-    #[fail(display = "Couldn't create mqtt connection in time")]
+    #[display(fmt = "Couldn't resolve host = {}", _0)]
+    DnsResolve(String),
+    #[display(fmt = "Couldn't create mqtt connection in time")]
     Timeout,
-    #[fail(
-        display = "Unsolicited packet received while waiting for connack. Recived packet = {:?}",
-        _0
-    )]
+    #[display(fmt = "Unsolicited packet received while waiting for connack. Received packet = {:?}", _0)]
     NotConnackPacket(Packet),
-    #[fail(display = "Empty response")]
+    #[display(fmt = "Empty response")]
     NoResponse,
-    #[fail(display = "Builder doesn't contain certificate authority")]
+    #[display(fmt = "Http proxy refused the tunnel. Status = {} {}, Proxy-Authenticate = {:?}", status, reason, authenticate)]
+    ProxyConnect { status: u16, reason: String, authenticate: Option<String> },
+    #[display(fmt = "Tls needs a certificate authority (or webpki roots) to verify the broker")]
     NoCertificateAuthority,
+    #[display(fmt = "Invalid certificate")]
+    InvalidCertificate,
+    #[display(fmt = "Invalid private key")]
+    InvalidPrivateKey,
+    #[display(fmt = "Couldn't decrypt the client key. Wrong passphrase?")]
+    InvalidClientKey,
+    #[display(fmt = "Couldn't load certificates. Error = {}", _0)]
+    CertificateProvider(IoError),
+    #[display(fmt = "Tls hostname is not a valid dns name")]
+    InvalidTlsHostname,
+    #[display(fmt = "Invalid websocket url")]
+    InvalidWebsocketUrl,
+    #[display(fmt = "Shared access key should be valid base64")]
+    InvalidSharedAccessKey,
 }
 
-#[derive(Debug, Fail, From)]
+#[derive(Debug, Display, From)]
 pub enum NetworkError {
-    #[fail(display = "Io failed. Error = {}", _0)]
+    #[display(fmt = "Io failed. Error = {}", _0)]
     Io(IoError),
-    #[fail(display = "Last ping response not received")]
+    #[display(fmt = "Last ping response not received")]
     AwaitPingResp,
-    #[fail(display = "Client not in connected state")]
+    #[display(fmt = "Client not in connected state")]
     InvalidState,
-    #[fail(display = "Couldn't ping in time")]
+    #[display(fmt = "Couldn't ping in time")]
     Timeout,
-    #[fail(display = "Received unsolicited acknowledgment")]
+    #[display(fmt = "Received unsolicited acknowledgment")]
     Unsolicited,
-    #[fail(display = "Tokio timer error = {}", _0)]
+    #[display(fmt = "All packet ids are in use by unacknowledged packets")]
+    PacketIdsExhausted,
+    #[display(fmt = "Tokio timer error = {}", _0)]
     Timer(timer::Error),
-    #[fail(display = "Tokio timer error = {}", _0)]
+    #[display(fmt = "Network io timed out. Error = {}", _0)]
     TimeOut(timeout::Error<IoError>),
-    #[fail(display = "User requested for reconnect")]
+    #[display(fmt = "User requested for reconnect")]
     UserReconnect,
-    #[fail(display = "User requested for disconnect")]
+    #[display(fmt = "User requested for disconnect")]
     UserDisconnect,
-    #[fail(display = "Network stream closed")]
+    #[display(fmt = "User requested for shutdown")]
+    UserShutdown,
+    #[display(fmt = "Jwt is about to expire")]
+    JwtExpiring,
+    #[display(fmt = "Network stream closed")]
     NetworkStreamClosed,
-    #[fail(display = "Throttle error while rate limiting")]
+    #[display(fmt = "Throttling of requests failed")]
     Throttle,
-    #[fail(display = "Notification receiver is slower than incoming packets")]
+    #[display(fmt = "Notification receiver is slower than incoming packets")]
     ReceiverCatchup,
-    #[fail(display = "Dummy error for converting () to network error")]
-    Blah,
+    #[display(fmt = "Client is dropped. User request channel closed")]
+    UserRequestChannelClosed,
+    #[display(fmt = "User command channel failed")]
+    UserCommandChannelClosed,
+    #[display(fmt = "Replay of the previous session failed. Error = {}", _0)]
+    ReplayFailed(String),
+}
+
+// requests can only fail to reach the eventloop when it is gone
+impl From<SendError<Request>> for ClientError {
+    fn from(_: SendError<Request>) -> ClientError {
+        ClientError::EventloopTerminated
+    }
+}
+
+impl From<SendError<Command>> for ClientError {
+    fn from(_: SendError<Command>) -> ClientError {
+        ClientError::EventloopTerminated
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "json")]
+            ClientError::Json(e) => Some(e),
+            #[cfg(feature = "cbor")]
+            ClientError::Cbor(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(any(feature = "json", feature = "cbor"))]
+impl Error for DecodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "json")]
+            DecodeError::Json(e) => Some(e),
+            #[cfg(feature = "cbor")]
+            DecodeError::Cbor(e) => Some(e),
+        }
+    }
+}
+
+impl Error for PublishError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PublishError::Client(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for TopicError {}
+
+impl Error for WaitError {}
+
+impl Error for OptionsError {}
+
+impl Error for UrlError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UrlError::Options(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "jwt")]
+            ConnectError::Jwt(e) => Some(e),
+            ConnectError::Io(e) | ConnectError::Bind(e) | ConnectError::Spawn(e) | ConnectError::Runtime(e) => Some(e),
+            ConnectError::CertificateProvider(e) => Some(e),
+            ConnectError::Recv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Error for NetworkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NetworkError::Io(e) => Some(e),
+            NetworkError::Timer(e) => Some(e),
+            NetworkError::TimeOut(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Cloneable description of the error which broke (or failed) the last connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorSummary {
+    /// Broker's address couldn't be resolved
+    Dns(String),
+    /// Broker refused the connection with this connack return code
+    Refused(u8),
+    /// Tcp, tls, proxy or mqtt handshake failure while connecting
+    Connect(String),
+    /// Failure of an established connection
+    Network(String),
+}
+
+impl fmt::Display for ErrorSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorSummary::Dns(e) => write!(f, "Dns error. {}", e),
+            ErrorSummary::Refused(code) => write!(f, "Connection refused. Code = {}", code),
+            ErrorSummary::Connect(e) => write!(f, "Connection error. {}", e),
+            ErrorSummary::Network(e) => write!(f, "Network error. {}", e),
+        }
+    }
+}
+
+impl Error for ErrorSummary {}
+
+impl<'a> From<&'a ConnectError> for ErrorSummary {
+    fn from(e: &ConnectError) -> ErrorSummary {
+        match e {
+            ConnectError::DnsListEmpty | ConnectError::DnsResolve(_) => ErrorSummary::Dns(e.to_string()),
+            ConnectError::MqttConnectionRefused(code) => ErrorSummary::Refused(*code),
+            e => ErrorSummary::Connect(e.to_string()),
+        }
+    }
+}
+
+impl<'a> From<&'a NetworkError> for ErrorSummary {
+    fn from(e: &NetworkError) -> ErrorSummary {
+        ErrorSummary::Network(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientError, ConnectError, NetworkError, PublishError};
+    use std::{error::Error, io};
+
+    fn boxed<E: Error + Send + Sync + 'static>(e: E) -> Box<dyn Error + Send + Sync> {
+        Box::new(e)
+    }
+
+    #[test]
+    fn errors_should_keep_their_causes() {
+        let e = ConnectError::Io(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"));
+        assert_eq!(e.to_string(), "Io failed. Error = refused");
+        assert_eq!(e.source().unwrap().to_string(), "refused");
+
+        let e = boxed(NetworkError::from(io::Error::new(io::ErrorKind::BrokenPipe, "broken")));
+        assert_eq!(e.source().unwrap().to_string(), "broken");
+
+        let e = PublishError::Client(ClientError::EventloopTerminated);
+        assert_eq!(e.source().unwrap().to_string(), "Eventloop is not running");
+        assert!(boxed(NetworkError::UserRequestChannelClosed).source().is_none());
+    }
 }
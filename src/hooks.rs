@@ -0,0 +1,78 @@
+//! Synchronous callbacks of the eventloop for connection and message events
+use crate::client::ConnectionInfo;
+use crate::mqttoptions::MqttOptions;
+use mqtt311::Publish;
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    time::Duration,
+};
+
+/// Handler of eventloop events. All the methods do nothing by default.
+///
+/// Handlers are called on the eventloop and hold up the network till they return.
+/// Keep them fast (e.g update counters) and hand the rest over to other threads.
+/// Panics of a handler are logged and don't affect the connection
+pub trait MqttEventHandler: Send + Sync + fmt::Debug {
+    /// Broker accepted the connection
+    fn on_connect(&self, _info: &ConnectionInfo) {}
+    /// Connection to the broker is lost (or closed by the user) for `reason`
+    fn on_disconnect(&self, _reason: &str) {}
+    /// Publish is handed to the network (retransmissions included)
+    fn on_publish_sent(&self, _publish: &Publish) {}
+    /// Publish from the broker. Redeliveries of qos2 publishes aren't reported again
+    fn on_message(&self, _publish: &Publish) {}
+    /// Eventloop waits for `delay` before the connection `attempt`
+    fn on_reconnect_attempt(&self, _attempt: u32, _delay: Duration) {}
+}
+
+/// Calls the event handler of the options (if any). Panics of the handler are swallowed
+pub(crate) fn dispatch<F: FnOnce(&dyn MqttEventHandler)>(mqttoptions: &MqttOptions, f: F) {
+    let handler = match mqttoptions.event_handler() {
+        Some(handler) => handler,
+        None => return,
+    };
+
+    if panic::catch_unwind(AssertUnwindSafe(|| f(&*handler))).is_err() {
+        error!("Event handler panicked");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dispatch, MqttEventHandler};
+    use crate::mqttoptions::MqttOptions;
+    use std::{
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[derive(Debug, Default)]
+    struct Handler {
+        attempts: AtomicU32,
+    }
+
+    impl MqttEventHandler for Handler {
+        fn on_reconnect_attempt(&self, attempt: u32, _delay: Duration) {
+            self.attempts.store(attempt, Ordering::SeqCst);
+            if attempt > 1 {
+                panic!("buggy handler");
+            }
+        }
+    }
+
+    #[test]
+    fn panicking_handlers_should_not_unwind_into_the_eventloop() {
+        let handler = Arc::new(Handler::default());
+        let options = MqttOptions::new("id", "127.0.0.1", 1883).set_event_handler(handler.clone());
+        dispatch(&options, |handler| handler.on_reconnect_attempt(1, Duration::from_secs(1)));
+        dispatch(&options, |handler| handler.on_reconnect_attempt(2, Duration::from_secs(1)));
+        assert_eq!(handler.attempts.load(Ordering::SeqCst), 2);
+
+        // handlers without the callback do nothing
+        dispatch(&options, |handler| handler.on_disconnect("Eventloop stopped"));
+    }
+}
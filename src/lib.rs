@@ -65,7 +65,8 @@
 //!     // select between mqtt notifications and other channel rx
 //!     loop {
 //!         select! {
-//!             recv(notifications) -> notification => {
+//!             recv(notifications.receiver()) -> notification => {
+//!                 notifications.notify_received();
 //!                 println!("{:?}", notification)
 //!             }
 //!             recv(done_rx) -> _done => break
@@ -114,14 +115,40 @@
 #[macro_use]
 extern crate log;
 
+pub mod certs;
 pub mod client;
 pub mod codec;
 pub mod error;
+pub mod hooks;
 pub mod mqttoptions;
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub mod payload;
+pub mod persistence;
+pub mod session;
+pub mod stats;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod testutils;
+pub mod topic;
+#[cfg(feature = "mqtt5")]
+pub mod v5;
 
-pub use crate::client::{MqttClient, Notification};
-pub use crate::mqttoptions::{MqttOptions, Proxy, ReconnectOptions, SecurityOptions};
-pub use crate::error::{ConnectError, ClientError};
+pub use crate::client::{
+    AckHandle, ConnectionInfo, ConnectionStatus, MqttClient, Notification, NotificationIntoIter, NotificationIter, NotificationReceiver, PublishHandle, TlsInfo,
+};
+pub use crate::mqttoptions::{
+    AddressPreference, AsyncStream, ConnectFuture, Connector, InflightOverflow, MqttOptions, NotificationOverflow, Protocol, Proxy,
+    ProxyTlsConfig, ReconnectOptions, RetryPolicy, SecurityOptions, TcpOptions,
+};
+pub use crate::certs::{CertProvider, Certificates, FileCertProvider};
+pub use crate::hooks::MqttEventHandler;
+pub use crate::session::SessionState;
+pub use crate::error::{ConnectError, ClientError, ErrorSummary, OptionsError, PublishError, TopicError, UrlError, WaitError};
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub use crate::error::DecodeError;
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub use crate::payload::DecodePayload;
+pub use crate::persistence::{FilePersistence, Persisted, Persistence};
+pub use crate::stats::{PingRtt, StatsSnapshot};
 pub use crossbeam_channel::Receiver;
 #[doc(hidden)]
 pub use mqtt311::*;
@@ -1,9 +1,26 @@
 //! Options to set mqtt client behaviour
+use crate::certs::CertProvider;
+use crate::error::{ConnectError, OptionsError, UrlError};
+use crate::hooks::MqttEventHandler;
+use crate::persistence::Persistence;
+use crate::session::{SessionState, SESSION_VERSION};
+use crate::topic;
+#[cfg(feature = "mqtt5")]
+use crate::v5;
+use futures::Future;
 use mqtt311::LastWill;
-use std::time::Duration;
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use uuid::Uuid;
 
 /// Control how the connection is re-established if it is lost.
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug)]
 pub enum ReconnectOptions {
     /// Don't automatically reconnect
     Never,
@@ -15,6 +32,85 @@ pub enum ReconnectOptions {
     ///
     /// Before a reconnection attempt, sleep for the specified amount of time (in seconds).
     Always(u64),
+    /// Always reconnect automatically with exponentially increasing sleeps.
+    ///
+    /// Sleep starts at `initial` and is multiplied by `multiplier` after every failed
+    /// attempt till `max`. Each sleep is randomized by +/- `jitter` fraction (0.0 to 1.0)
+    /// so that a fleet of clients doesn't reconnect in lockstep. Backoff is reset once
+    /// a connection stays up for `MqttOptions::stable_connection_threshold`
+    ExponentialBackoff {
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+        jitter: f64,
+    },
+}
+
+// Backoff factors are compared bitwise so that equality stays reflexive (`Eq`)
+impl PartialEq for ReconnectOptions {
+    fn eq(&self, other: &ReconnectOptions) -> bool {
+        use ReconnectOptions::*;
+
+        match (self, other) {
+            (Never, Never) => true,
+            (AfterFirstSuccess(a), AfterFirstSuccess(b)) | (Always(a), Always(b)) => a == b,
+            (
+                ExponentialBackoff { initial, max, multiplier, jitter },
+                ExponentialBackoff { initial: other_initial, max: other_max, multiplier: other_multiplier, jitter: other_jitter },
+            ) => initial == other_initial && max == other_max && multiplier.to_bits() == other_multiplier.to_bits() && jitter.to_bits() == other_jitter.to_bits(),
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ReconnectOptions {}
+
+impl ReconnectOptions {
+    /// Retry policies of the initial connection and of the reconnections
+    fn policies(self) -> (RetryPolicy, RetryPolicy) {
+        match self {
+            ReconnectOptions::Never => (RetryPolicy::Never, RetryPolicy::Never),
+            ReconnectOptions::AfterFirstSuccess(time) => (RetryPolicy::Never, RetryPolicy::Interval(Duration::from_secs(time))),
+            ReconnectOptions::Always(time) => {
+                let policy = RetryPolicy::Interval(Duration::from_secs(time));
+                (policy, policy)
+            }
+            ReconnectOptions::ExponentialBackoff { initial, max, multiplier, jitter } => {
+                let policy = RetryPolicy::ExponentialBackoff { initial, max, multiplier, jitter };
+                (policy, policy)
+            }
+        }
+    }
+
+    /// Closest options of the retry policies. Follows the reconnection policy when the
+    /// policies can't be expressed together. Intervals are rounded down to seconds
+    fn from_policies(initial_connect: RetryPolicy, reconnect: RetryPolicy) -> ReconnectOptions {
+        match (initial_connect, reconnect) {
+            (_, RetryPolicy::Never) => ReconnectOptions::Never,
+            (RetryPolicy::Never, RetryPolicy::Interval(time)) => ReconnectOptions::AfterFirstSuccess(time.as_secs()),
+            (_, RetryPolicy::Interval(time)) => ReconnectOptions::Always(time.as_secs()),
+            (_, RetryPolicy::ExponentialBackoff { initial, max, multiplier, jitter }) => {
+                ReconnectOptions::ExponentialBackoff { initial, max, multiplier, jitter }
+            }
+        }
+    }
+}
+
+/// Retries of the connection attempts. See `MqttOptions::set_initial_connect_policy`
+/// and `MqttOptions::set_reconnect_policy`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RetryPolicy {
+    /// Don't retry
+    Never,
+    /// Sleep for this long before every attempt
+    Interval(Duration),
+    /// Exponentially increasing sleeps. Same as `ReconnectOptions::ExponentialBackoff`
+    ExponentialBackoff {
+        initial: Duration,
+        max: Duration,
+        multiplier: f64,
+        jitter: f64,
+    },
 }
 
 /// Client authentication option for mqtt connect packet
@@ -24,10 +120,113 @@ pub enum SecurityOptions {
     None,
     /// Use the specified `(username, password)` tuple to authenticate.
     UsernamePassword(String, String),
+    /// Use the specified `(username, password)` tuple with a binary (non utf8) password.
+    UsernamePasswordBytes(String, Vec<u8>),
+    /// Authenticate with just a username
+    UsernameOnly(String),
     #[cfg(feature = "jwt")]
     /// Authenticate against a Google Cloud IoT Core project with the triple
     /// `(project name, private_key.der to sign jwt, expiry in seconds)`.
     GcloudIot(String, Vec<u8>, i64),
+    #[cfg(feature = "azure")]
+    /// Authenticate a device against an Azure IoT Hub (`{hub_name}.azure-devices.net`)
+    /// with a sas token signed by the device's base64 `shared_access_key`. A fresh token,
+    /// valid for `token_ttl`, is generated for every connection. Client id should be the
+    /// device id
+    AzureIotHub {
+        hub_name: String,
+        device_id: String,
+        shared_access_key: String,
+        token_ttl: Duration,
+    },
+}
+
+/// Socket options applied to the tcp stream after connect
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TcpOptions {
+    /// Disables nagle's algorithm so that small packets are sent immediately
+    pub nodelay: bool,
+    /// Idle time before tcp keepalive probes. Detects dead connections (e.g
+    /// expired nat mappings) independent of mqtt pings
+    pub keepalive: Option<Duration>,
+    /// Size of the socket's send buffer in bytes
+    pub send_buffer: Option<usize>,
+    /// Size of the socket's receive buffer in bytes
+    pub recv_buffer: Option<usize>,
+}
+
+/// Stream of a custom transport (e.g a serial link or a quic stream)
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> AsyncStream for T {}
+
+/// Future of a stream of a custom transport
+pub type ConnectFuture = Box<dyn Future<Item = Box<dyn AsyncStream>, Error = ConnectError> + Send>;
+
+/// Opens streams of a custom transport. See `MqttOptions::set_custom_transport`
+#[derive(Clone)]
+pub struct Connector(Arc<dyn Fn() -> ConnectFuture + Send + Sync>);
+
+impl Connector {
+    /// Opens a new stream
+    pub fn connect(&self) -> ConnectFuture {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connector")
+    }
+}
+
+/// Order in which resolved broker addresses are tried
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AddressPreference {
+    /// Order returned by the resolver
+    AsResolved,
+    /// Ipv4 addresses before ipv6 addresses
+    PreferV4,
+    /// Ipv6 addresses before ipv4 addresses
+    PreferV6,
+}
+
+/// What the eventloop does with a notification when the notification channel is full
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NotificationOverflow {
+    /// Wait for space in the channel. Network isn't read till then (backpressure).
+    /// Eventloop is woken by the `NotificationReceiver` when it takes a notification
+    Block,
+    /// Drop the oldest notification in the channel to make space for the new one
+    DropOldest,
+    /// Drop the new notification without acknowledging it and reconnect. Broker
+    /// redelivers the unacked publish in a persistent session
+    DropNewest,
+}
+
+/// What the eventloop does with the pending requests while the in flight limits (count
+/// or bytes) are reached
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InflightOverflow {
+    /// Stop reading requests till an ack frees space. Requests wait in the request
+    /// channel and a full channel blocks the publishers (backpressure)
+    Block,
+    /// Drop the qos0 publishes at the head of the pending requests (the oldest ones)
+    /// to make space for the newer requests. Other requests wait like with `Block`
+    DropQos0,
+}
+
+/// Mqtt protocol version of the connection
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Protocol {
+    /// Mqtt 3.1 (protocol name `MQIsdp`, level 3) for legacy brokers. Client ids
+    /// are limited to 23 characters
+    MQTT31,
+    /// Mqtt 3.1.1 (protocol name `MQTT`, level 4)
+    MQTT311,
+    /// Mqtt 5 (protocol name `MQTT`, level 5). See the `v5` module for what's supported
+    #[cfg(feature = "mqtt5")]
+    MQTT5,
 }
 
 /// Mqtt through http proxy
@@ -38,107 +237,439 @@ pub enum Proxy {
     /// Tunnel through a proxy using http connect.
     /// (Proxy name, Port, priave_key.der to sign jwt, Expiry in seconds)
     HttpConnect(String, u16, Vec<u8>, i64),
+    /// Tunnel through a proxy using http connect with optional basic
+    /// authentication. Credentials are (username, password)
+    HttpConnectBasic {
+        host: String,
+        port: u16,
+        credentials: Option<(String, String)>,
+    },
+    /// Proxy of the `http_proxy`, `https_proxy` (for tls) and `all_proxy` environment
+    /// variables with credentials of the url. Connects directly when none is set or the
+    /// broker matches `no_proxy`. `https://` proxies are connected to over tls
+    FromEnv,
+}
+
+/// Tls with the http proxy itself (https proxy). Independent of the broker's tls.
+/// Without a certificate authority the webpki roots are used (`webpki-roots` feature)
+#[derive(Clone, Debug, Default)]
+pub struct ProxyTlsConfig {
+    /// Pem or der encoded certificate authority of the proxy
+    pub ca: Option<Vec<u8>>,
+    /// (certificate, private key) when the proxy requires client authentication
+    pub client_auth: Option<(Vec<u8>, Vec<u8>)>,
 }
 
 /// Mqtt options
 #[derive(Clone, Debug)]
 pub struct MqttOptions {
-    /// broker address that you want to connect to
-    broker_addr: String,
-    port: u16,
+    /// broker addresses that you want to connect to. tried in order on failures
+    brokers: Vec<(String, u16)>,
+    /// reconnect to the last successful broker instead of the next one
+    sticky_broker: bool,
     /// keep alive time to send pingreq to broker when the connection is idle
     keep_alive: Duration,
     /// clean (or) persistent session
     clean_session: bool,
+    /// mqtt protocol version
+    protocol: Protocol,
+    /// properties of the mqtt 5 connect
+    #[cfg(feature = "mqtt5")]
+    connect_properties: v5::Properties,
     /// client identifier
     client_id: String,
     /// tcp connection timeout
     connection_timeout: Duration,
+    /// connect with tls even without a certificate authority
+    tls: bool,
     /// connection method
     ca: Option<Vec<u8>>,
+    /// skip verification of broker's certificate
+    accept_invalid_certs: bool,
+    /// trust bundled webpki root certificates
+    webpki_roots: bool,
+    /// hostname for sni and certificate verification
+    tls_hostname: Option<String>,
+    /// websocket path to carry mqtt over websockets
+    websocket: Option<String>,
+    /// unix domain socket of a local broker
+    unix_socket: Option<PathBuf>,
+    /// user provided transport
+    custom_transport: Option<Connector>,
+    /// local address to bind outgoing connections to
+    bind_address: Option<IpAddr>,
+    /// tcp socket options
+    tcp_options: TcpOptions,
+    /// order of resolved addresses to connect to
+    address_preference: AddressPreference,
+    /// time to connect to a single resolved address
+    address_timeout: Duration,
+    /// time to resolve broker's address
+    dns_timeout: Duration,
+    /// time for which resolved addresses are reused
+    dns_cache_ttl: Option<Duration>,
     client_auth: Option<(Vec<u8>, Vec<u8>)>,
+    /// passphrase of an encrypted client key
+    client_key_passphrase: Option<String>,
+    /// pkcs12 bundle of client certificate chain and key with its password
+    client_identity: Option<(Vec<u8>, String)>,
+    /// source of ca and client certificates loaded for every connection
+    cert_provider: Option<Arc<dyn CertProvider>>,
     alpn: Option<Vec<Vec<u8>>>,
     /// proxy
     proxy: Proxy,
-    /// reconnection options
-    reconnect: ReconnectOptions,
+    /// tls with the proxy
+    proxy_tls: Option<ProxyTlsConfig>,
+    /// retries till the first successful connection
+    initial_connect: RetryPolicy,
+    /// retries after a connection is lost
+    reconnect: RetryPolicy,
     /// security options
     security: SecurityOptions,
-    /// maximum packet size
-    max_packet_size: usize,
+    /// maximum size of the packets from the broker
+    max_incoming_packet_size: usize,
+    /// maximum size of the packets to the broker
+    max_outgoing_packet_size: usize,
     /// last will and testament
     last_will: Option<LastWill>,
     /// request (publish, subscribe) channel capacity
     request_channel_capacity: usize,
     /// notification channel capacity
     notification_channel_capacity: usize,
+    /// deliver notifications on a futures stream instead of crossbeam channel
+    notification_stream: bool,
+    /// policy when the notification channel is full
+    notification_overflow: NotificationOverflow,
     /// maximum number of outgoing messages per second
     throttle: Option<f32>,
+    /// maximum number of outgoing publishes per second by topic filter
+    topic_throttles: Vec<(String, f32)>,
+    /// bytes of outgoing packets coalesced before a write. 0 disables the buffering
+    write_buffer_size: usize,
     /// maximum number of outgoing inflight messages
     inflight: usize,
+    /// maximum size (in bytes) of the outgoing inflight messages
+    inflight_bytes: Option<usize>,
+    /// policy of the pending requests when the in flight limits are reached
+    inflight_overflow: InflightOverflow,
+    /// time after which unacked qos1 publishes are retransmitted
+    ack_timeout: Option<Duration>,
+    /// store of unacked outgoing publishes which survives restarts
+    persistence: Option<Arc<dyn Persistence>>,
+    /// callbacks of eventloop events
+    event_handler: Option<Arc<dyn MqttEventHandler>>,
+    /// connack return codes which stop the reconnections
+    fatal_refusals: Vec<u8>,
+    /// proxy connect statuses which stop the reconnections
+    fatal_proxy_statuses: Vec<u16>,
+    /// subscribe again to the topics of the last connection in a fresh session
+    auto_resubscribe: bool,
+    /// user acknowledges incoming qos1 publishes
+    manual_acks: bool,
+    /// time to wait for the acks of inflight messages before disconnecting
+    drain_timeout: Duration,
+    /// age after which queued publishes are dropped instead of being sent
+    queued_message_ttl: Option<Duration>,
+    /// stack size of the eventloop thread
+    eventloop_stack_size: Option<usize>,
+    /// notify the round trip time of every ping
+    ping_notifications: bool,
+    /// uptime after which a connection resets the reconnection backoff
+    stable_connection_threshold: Duration,
+    /// session exported by a previous run of the client
+    initial_session: Option<SessionState>,
 }
 
 impl Default for MqttOptions {
     fn default() -> Self {
         MqttOptions {
-            broker_addr: "127.0.0.1".into(),
-            port: 1883,
+            brokers: vec![("127.0.0.1".into(), 1883)],
+            sticky_broker: true,
             keep_alive: Duration::from_secs(30),
             clean_session: true,
+            protocol: Protocol::MQTT311,
+            #[cfg(feature = "mqtt5")]
+            connect_properties: v5::Properties::default(),
             client_id: "test-client".into(),
             connection_timeout: Duration::from_secs(10),
+            tls: false,
             ca: None,
+            accept_invalid_certs: false,
+            webpki_roots: false,
+            tls_hostname: None,
+            websocket: None,
+            unix_socket: None,
+            custom_transport: None,
+            bind_address: None,
+            tcp_options: TcpOptions::default(),
+            address_preference: AddressPreference::AsResolved,
+            address_timeout: Duration::from_secs(5),
+            dns_timeout: Duration::from_secs(5),
+            dns_cache_ttl: None,
             client_auth: None,
+            client_key_passphrase: None,
+            client_identity: None,
+            cert_provider: None,
             alpn: None,
             proxy: Proxy::None,
-            reconnect: ReconnectOptions::AfterFirstSuccess(10),
+            proxy_tls: None,
+            initial_connect: RetryPolicy::Never,
+            reconnect: RetryPolicy::Interval(Duration::from_secs(10)),
             security: SecurityOptions::None,
-            max_packet_size: 256 * 1024,
+            max_incoming_packet_size: 256 * 1024,
+            max_outgoing_packet_size: 256 * 1024,
             last_will: None,
             request_channel_capacity: 10,
             notification_channel_capacity: 10,
+            notification_stream: false,
+            notification_overflow: NotificationOverflow::DropNewest,
             throttle: None,
+            topic_throttles: Vec::new(),
+            write_buffer_size: 0,
             inflight: 100,
+            inflight_bytes: None,
+            inflight_overflow: InflightOverflow::Block,
+            ack_timeout: None,
+            persistence: None,
+            event_handler: None,
+            fatal_refusals: vec![2, 4, 5],
+            fatal_proxy_statuses: vec![407],
+            auto_resubscribe: true,
+            manual_acks: false,
+            drain_timeout: Duration::from_secs(5),
+            queued_message_ttl: None,
+            eventloop_stack_size: None,
+            ping_notifications: false,
+            stable_connection_threshold: Duration::from_secs(60),
+            initial_session: None,
         }
     }
 }
 
 impl MqttOptions {
-    /// New mqtt options
+    /// New mqtt options. Panics for an invalid client id. See `try_new`
     pub fn new<S: Into<String>, T: Into<String>>(id: S, host: T, port: u16) -> MqttOptions {
+        match MqttOptions::try_new(id, host, port) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// New mqtt options. Errors when the client id is empty or starts with a space
+    pub fn try_new<S: Into<String>, T: Into<String>>(id: S, host: T, port: u16) -> Result<MqttOptions, OptionsError> {
         // TODO: Validate if addr is proper address type
         let id = id.into();
         if id.starts_with(' ') || id.is_empty() {
-            panic!("Invalid client id")
+            return Err(OptionsError::InvalidClientId(id));
         }
 
-        MqttOptions {
-            broker_addr: host.into(),
-            port,
+        let options = MqttOptions {
+            brokers: vec![(host.into(), port)],
+            sticky_broker: true,
             keep_alive: Duration::from_secs(60),
             clean_session: true,
+            protocol: Protocol::MQTT311,
+            #[cfg(feature = "mqtt5")]
+            connect_properties: v5::Properties::default(),
             connection_timeout: Duration::from_secs(10),
             client_id: id,
+            tls: false,
             ca: None,
+            accept_invalid_certs: false,
+            webpki_roots: false,
+            tls_hostname: None,
+            websocket: None,
+            unix_socket: None,
+            custom_transport: None,
+            bind_address: None,
+            tcp_options: TcpOptions::default(),
+            address_preference: AddressPreference::AsResolved,
+            address_timeout: Duration::from_secs(5),
+            dns_timeout: Duration::from_secs(5),
+            dns_cache_ttl: None,
             client_auth: None,
+            client_key_passphrase: None,
+            client_identity: None,
+            cert_provider: None,
             alpn: None,
             proxy: Proxy::None,
-            reconnect: ReconnectOptions::AfterFirstSuccess(10),
+            proxy_tls: None,
+            initial_connect: RetryPolicy::Never,
+            reconnect: RetryPolicy::Interval(Duration::from_secs(10)),
             security: SecurityOptions::None,
-            max_packet_size: 256 * 1024,
+            max_incoming_packet_size: 256 * 1024,
+            max_outgoing_packet_size: 256 * 1024,
             last_will: None,
             request_channel_capacity: 10,
             notification_channel_capacity: 10,
+            notification_stream: false,
+            notification_overflow: NotificationOverflow::DropNewest,
             throttle: None,
+            topic_throttles: Vec::new(),
+            write_buffer_size: 0,
             inflight: 100,
+            inflight_bytes: None,
+            inflight_overflow: InflightOverflow::Block,
+            ack_timeout: None,
+            persistence: None,
+            event_handler: None,
+            fatal_refusals: vec![2, 4, 5],
+            fatal_proxy_statuses: vec![407],
+            auto_resubscribe: true,
+            manual_acks: false,
+            drain_timeout: Duration::from_secs(5),
+            queued_message_ttl: None,
+            eventloop_stack_size: None,
+            ping_notifications: false,
+            stable_connection_threshold: Duration::from_secs(60),
+            initial_session: None,
+        };
+
+        Ok(options)
+    }
+
+    /// Options from a broker url like `ssl://broker.example.com:8883?client_id=dev42`.
+    /// Schemes `tcp`/`mqtt` (default port 1883), `ssl`/`mqtts` (tls, default port 8883)
+    /// and `ws`/`wss` (websocket feature, port and optional path) are supported. Query
+    /// parameters `client_id`, `keep_alive` (seconds) and `clean_session` are applied.
+    /// A random client id is used when the url doesn't have one
+    pub fn from_url(url: &str) -> Result<MqttOptions, UrlError> {
+        let (scheme, rest) = match url.find("://") {
+            Some(index) => (&url[..index], &url[index + 3..]),
+            None => return Err(UrlError::Malformed(url.to_owned())),
+        };
+
+        let (rest, query) = match rest.find('?') {
+            Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+            None => (rest, None),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, ""),
+        };
+
+        let (tls, default_port, websocket) = match scheme {
+            "tcp" | "mqtt" => (false, Some(1883), false),
+            "ssl" | "mqtts" => (true, Some(8883), false),
+            "ws" => (false, None, true),
+            "wss" => (true, None, true),
+            _ => return Err(UrlError::UnsupportedScheme(scheme.to_owned())),
+        };
+
+        if !websocket && path != "" && path != "/" {
+            return Err(UrlError::Malformed(url.to_owned()));
+        }
+
+        let (host, port) = split_host_port(authority)?;
+        let port = match port.or(default_port) {
+            Some(port) => port,
+            None => return Err(UrlError::MissingPort(scheme.to_owned())),
+        };
+
+        let mut client_id = format!("rumqtt-{}", Uuid::new_v4().to_simple());
+        let mut keep_alive = None;
+        let mut clean_session = None;
+        for parameter in query.into_iter().flat_map(|query| query.split('&')).filter(|parameter| !parameter.is_empty()) {
+            let (key, value) = match parameter.find('=') {
+                Some(index) => (&parameter[..index], &parameter[index + 1..]),
+                None => (parameter, ""),
+            };
+
+            let invalid = || UrlError::InvalidParameter(key.to_owned(), value.to_owned());
+            match key {
+                "client_id" => client_id = value.to_owned(),
+                "keep_alive" => keep_alive = Some(value.parse::<u16>().map_err(|_| invalid())?),
+                "clean_session" => clean_session = Some(value.parse::<bool>().map_err(|_| invalid())?),
+                _ => return Err(UrlError::UnknownParameter(key.to_owned())),
+            }
+        }
+
+        let mut options = MqttOptions::try_new(client_id, host, port)?.set_tls(tls);
+        if let Some(keep_alive) = keep_alive {
+            options = options.set_keep_alive(keep_alive);
         }
+
+        if let Some(clean_session) = clean_session {
+            options = options.set_clean_session(clean_session);
+        }
+
+        if websocket {
+            options = options.url_websocket(if path == "" { "/mqtt" } else { path })?;
+        }
+
+        Ok(options)
+    }
+
+    #[cfg(feature = "websocket")]
+    fn url_websocket(self, path: &str) -> Result<Self, UrlError> {
+        Ok(self.set_websocket(path))
+    }
+
+    #[cfg(not(feature = "websocket"))]
+    fn url_websocket(self, _path: &str) -> Result<Self, UrlError> {
+        Err(UrlError::WebsocketDisabled)
     }
 
-    /// Broker address
+    /// Broker address. First of the brokers when there are many
     pub fn broker_address(&self) -> (String, u16) {
-        (self.broker_addr.clone(), self.port)
+        self.brokers[0].clone()
+    }
+
+    /// Connects to a single broker. Replaces the brokers set by `set_brokers`
+    pub fn set_broker_address<S: Into<String>>(mut self, host: S, port: u16) -> Self {
+        self.brokers = vec![(host.into(), port)];
+        self
     }
 
+    /// Set the brokers of a cluster. A failed connection attempt moves to the next
+    /// broker in the list. Panics for an empty list. See `try_set_brokers`
+    pub fn set_brokers(self, brokers: Vec<(String, u16)>) -> Self {
+        match self.try_set_brokers(brokers) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Set the brokers of a cluster. Errors for an empty list
+    pub fn try_set_brokers(mut self, brokers: Vec<(String, u16)>) -> Result<Self, OptionsError> {
+        if brokers.is_empty() {
+            return Err(OptionsError::NoBrokers);
+        }
+
+        self.brokers = brokers;
+        Ok(self)
+    }
+
+    pub fn brokers(&self) -> Vec<(String, u16)> {
+        self.brokers.clone()
+    }
+
+    /// Reconnections after a lost connection start from the broker of the lost connection
+    /// when set (default) and from the next broker otherwise
+    pub fn set_sticky_broker(mut self, sticky: bool) -> Self {
+        self.sticky_broker = sticky;
+        self
+    }
+
+    pub fn sticky_broker(&self) -> bool {
+        self.sticky_broker
+    }
+
+    /// Connects with tls. Unlike the other tls options, this fails the connection (instead
+    /// of connecting without tls) when there isn't a certificate authority, webpki roots
+    /// or `danger_accept_invalid_certs` to verify the broker
+    pub fn set_tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    /// Set the certificate authority (pem or der encoded) to verify the broker. All
+    /// the certificates of a pem bundle are trusted
     pub fn set_ca(mut self, ca: Vec<u8>) -> Self {
         self.ca = Some(ca);
         self
@@ -148,20 +679,268 @@ impl MqttOptions {
         self.ca.clone()
     }
 
+    /// Connects with tls without verifying the broker's certificate chain and
+    /// hostname. A certificate authority isn't necessary. Dangerous. Only for
+    /// testing against brokers with self signed certificates
+    #[cfg(feature = "danger-insecure-tls")]
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Broker's certificate isn't verified
+    pub fn accept_invalid_certs(&self) -> bool {
+        self.accept_invalid_certs
+    }
+
+    /// Verifies the broker with the bundled webpki (mozilla) root certificates. Useful
+    /// for brokers with certificates from public authorities (e.g let's encrypt).
+    /// Certificate authority set with `set_ca` is trusted as well
+    #[cfg(feature = "webpki-roots")]
+    pub fn set_webpki_roots(mut self, enable: bool) -> Self {
+        self.webpki_roots = enable;
+        self
+    }
+
+    /// Bundled webpki root certificates are trusted
+    pub fn webpki_roots(&self) -> bool {
+        self.webpki_roots
+    }
+
+    /// Hostname used for sni and verification of the broker's certificate. Useful when
+    /// the broker address is an ip. Broker address is used when not set
+    pub fn set_tls_hostname<S: Into<String>>(mut self, hostname: S) -> Self {
+        self.tls_hostname = Some(hostname.into());
+        self
+    }
+
+    /// Hostname for tls verification
+    pub fn tls_hostname(&self) -> Option<String> {
+        self.tls_hostname.clone()
+    }
+
+    /// Connects to the broker with mqtt over websocket at `path` (e.g `/mqtt`).
+    /// Websocket runs over tls (`wss`) when tls is configured and through the
+    /// http proxy tunnel when a proxy is configured
+    #[cfg(feature = "websocket")]
+    pub fn set_websocket<S: Into<String>>(mut self, path: S) -> Self {
+        self.websocket = Some(path.into());
+        self
+    }
+
+    /// Websocket path
+    pub fn websocket(&self) -> Option<String> {
+        self.websocket.clone()
+    }
+
+    /// Connects to a broker on the same host over the unix domain socket at
+    /// `path` instead of tcp. Broker address, tls and proxy options are ignored
+    #[cfg(unix)]
+    pub fn set_unix_socket<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.unix_socket = Some(path.into());
+        self
+    }
+
+    /// Unix domain socket path
+    pub fn unix_socket(&self) -> Option<PathBuf> {
+        self.unix_socket.clone()
+    }
+
+    /// Runs mqtt over the streams opened by `connector` instead of tcp. `connector` is
+    /// called for every connection attempt (reconnections included). Broker address, tls,
+    /// proxy and socket options are ignored
+    pub fn set_custom_transport<F>(mut self, connector: F) -> Self
+    where
+        F: Fn() -> ConnectFuture + Send + Sync + 'static,
+    {
+        self.custom_transport = Some(Connector(Arc::new(connector)));
+        self
+    }
+
+    /// Connector of the custom transport
+    pub fn custom_transport(&self) -> Option<Connector> {
+        self.custom_transport.clone()
+    }
+
+    /// Binds outgoing tcp connections (and reconnections) to a local address. Useful
+    /// to pin the traffic to a network interface on multi homed hosts
+    pub fn set_bind_address(mut self, address: IpAddr) -> Self {
+        self.bind_address = Some(address);
+        self
+    }
+
+    /// Local address of outgoing connections
+    pub fn bind_address(&self) -> Option<IpAddr> {
+        self.bind_address
+    }
+
+    /// Set tcp socket options like nodelay and keepalive
+    pub fn set_tcp_options(mut self, tcp_options: TcpOptions) -> Self {
+        self.tcp_options = tcp_options;
+        self
+    }
+
+    /// Tcp socket options
+    pub fn tcp_options(&self) -> TcpOptions {
+        self.tcp_options
+    }
+
+    /// Set the order in which resolved broker addresses are tried
+    pub fn set_address_preference(mut self, preference: AddressPreference) -> Self {
+        self.address_preference = preference;
+        self
+    }
+
+    /// Order of resolved broker addresses
+    pub fn address_preference(&self) -> AddressPreference {
+        self.address_preference
+    }
+
+    /// Set the time to connect to each of the resolved broker addresses before
+    /// moving to the next one. Connection timeout still bounds all the attempts
+    pub fn set_address_timeout(mut self, timeout: Duration) -> Self {
+        self.address_timeout = timeout;
+        self
+    }
+
+    /// Time to connect to a single resolved address
+    pub fn address_timeout(&self) -> Duration {
+        self.address_timeout
+    }
+
+    /// Set the time to resolve broker's address. Resolution failures (and
+    /// timeouts) result in `ConnectError::DnsResolve`
+    pub fn set_dns_timeout(mut self, timeout: Duration) -> Self {
+        self.dns_timeout = timeout;
+        self
+    }
+
+    /// Time to resolve broker's address
+    pub fn dns_timeout(&self) -> Duration {
+        self.dns_timeout
+    }
+
+    /// Set the time for which the resolved addresses of a broker are reused across
+    /// reconnections. `None` (default) resolves afresh for every connection attempt,
+    /// which follows brokers whose addresses change (e.g during a failover)
+    pub fn set_dns_cache_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.dns_cache_ttl = ttl;
+        self
+    }
+
+    /// Time for which resolved addresses are reused
+    pub fn dns_cache_ttl(&self) -> Option<Duration> {
+        self.dns_cache_ttl
+    }
+
+    /// Reads the certificate authority (pem or der encoded) from a file
+    pub fn set_ca_file<P: AsRef<Path>>(self, path: P) -> Result<Self, io::Error> {
+        let ca = fs::read(path)?;
+        Ok(self.set_ca(ca))
+    }
+
+    /// Set the client certificate and private key (pem or der encoded) for tls
+    /// client authentication
     pub fn set_client_auth(mut self, cert: Vec<u8>, key: Vec<u8>) -> Self {
         self.client_auth = Some((cert, key));
+        self.client_key_passphrase = None;
+        self
+    }
+
+    /// Set the client certificate and an encrypted pkcs8 private key (`BEGIN ENCRYPTED
+    /// PRIVATE KEY` pem or der) for tls client authentication. The key is decrypted in
+    /// memory for every connection. A wrong `passphrase` fails the connection with
+    /// `ConnectError::InvalidClientKey`
+    #[cfg(feature = "encrypted-keys")]
+    pub fn set_client_auth_encrypted(mut self, cert: Vec<u8>, key: Vec<u8>, passphrase: &str) -> Self {
+        self.client_auth = Some((cert, key));
+        self.client_key_passphrase = Some(passphrase.to_owned());
         self
     }
 
+    /// Reads the client certificate and private key (pem or der encoded) from files
+    pub fn set_client_auth_files<P: AsRef<Path>, Q: AsRef<Path>>(self, cert_path: P, key_path: Q) -> Result<Self, io::Error> {
+        let cert = fs::read(cert_path)?;
+        let key = fs::read(key_path)?;
+        Ok(self.set_client_auth(cert, key))
+    }
+
     pub fn client_auth(&self) -> Option<(Vec<u8>, Vec<u8>)> {
         self.client_auth.clone()
     }
 
+    /// Passphrase of the encrypted client key
+    pub fn client_key_passphrase(&self) -> Option<String> {
+        self.client_key_passphrase.clone()
+    }
+
+    /// Set a pkcs12 (`.p12`) bundle of the client certificate, its intermediate certificates
+    /// and the private key for tls client authentication. Takes precedence over `set_client_auth`.
+    /// Certificate chain is expected with the client certificate first (as openssl exports it)
+    #[cfg(feature = "pkcs12")]
+    pub fn set_client_identity_pkcs12(mut self, identity: Vec<u8>, password: &str) -> Self {
+        self.client_identity = Some((identity, password.to_owned()));
+        self
+    }
+
+    /// Reads the pkcs12 bundle for client authentication from a file
+    #[cfg(feature = "pkcs12")]
+    pub fn set_client_identity_pkcs12_file<P: AsRef<Path>>(self, path: P, password: &str) -> Result<Self, io::Error> {
+        let identity = fs::read(path)?;
+        Ok(self.set_client_identity_pkcs12(identity, password))
+    }
+
+    /// Pkcs12 bundle for client authentication and its password
+    pub fn client_identity_pkcs12(&self) -> Option<(Vec<u8>, String)> {
+        self.client_identity.clone()
+    }
+
+    /// Loads the certificate authority and client certificate & key from `provider` before
+    /// every connection attempt instead of using the ones set with `set_ca`/`set_client_auth`.
+    /// Rotated certificates are used from the next reconnection
+    pub fn set_cert_provider<P: CertProvider + 'static>(mut self, provider: P) -> Self {
+        self.cert_provider = Some(Arc::new(provider));
+        self
+    }
+
+    /// Source of the tls certificates
+    pub fn cert_provider(&self) -> Option<Arc<dyn CertProvider>> {
+        self.cert_provider.clone()
+    }
+
+    /// Set the time to establish a connection. This covers tcp connect, tls handshake
+    /// and the wait for connack. Failing to connect in time results in
+    /// `ConnectError::Timeout`. Panics for zero. See `try_set_connect_timeout`
+    pub fn set_connect_timeout(self, timeout: Duration) -> Self {
+        match self.try_set_connect_timeout(timeout) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Set the time to establish a connection. Errors for zero
+    pub fn try_set_connect_timeout(mut self, timeout: Duration) -> Result<Self, OptionsError> {
+        if timeout == Duration::from_secs(0) {
+            return Err(OptionsError::ZeroConnectionTimeout);
+        }
+
+        self.connection_timeout = timeout;
+        Ok(self)
+    }
+
+    /// Time to establish a connection
+    pub fn connect_timeout(&self) -> Duration {
+        self.connection_timeout
+    }
+
+    /// Set the time (in seconds) to establish a connection
+    #[deprecated(note = "Use `set_connect_timeout`")]
     pub fn set_connection_timeout(mut self, secs: u16) -> Self {
-        self.connection_timeout =  Duration::from_secs(u64::from(secs));
+        self.connection_timeout = Duration::from_secs(u64::from(secs));
         self
     }
 
+    #[deprecated(note = "Use `connect_timeout`")]
     pub fn connection_timeout(&self) -> Duration {
         self.connection_timeout
     }
@@ -176,12 +955,8 @@ impl MqttOptions {
     }
 
     /// Set number of seconds after which client should ping the broker
-    /// if there is no other data exchange
+    /// if there is no other data exchange. Zero disables keep alive pings
     pub fn set_keep_alive(mut self, secs: u16) -> Self {
-        if secs < 5 {
-            panic!("Keep alives should be >= 5  secs");
-        }
-
         self.keep_alive = Duration::from_secs(u64::from(secs));
         self
     }
@@ -196,15 +971,39 @@ impl MqttOptions {
         self.client_id.clone()
     }
 
-    /// Set packet size limit (in Kilo Bytes)
-    pub fn set_max_packet_size(mut self, sz: usize) -> Self {
-        self.max_packet_size = sz * 1024;
+    /// Set packet size limit (in Kilo Bytes) of both the incoming and outgoing packets
+    pub fn set_max_packet_size(self, sz: usize) -> Self {
+        self.set_max_incoming_packet_size(sz).set_max_outgoing_packet_size(sz)
+    }
+
+    /// Set size limit (in Kilo Bytes) of the packets from the broker. Bigger packets
+    /// are skipped
+    pub fn set_max_incoming_packet_size(mut self, sz: usize) -> Self {
+        self.max_incoming_packet_size = sz * 1024;
         self
     }
 
-    /// Maximum packet size
+    /// Set size limit (in Kilo Bytes) of the packets to the broker. Bigger publishes
+    /// are rejected with `ClientError::PayloadTooLarge`
+    pub fn set_max_outgoing_packet_size(mut self, sz: usize) -> Self {
+        self.max_outgoing_packet_size = sz * 1024;
+        self
+    }
+
+    /// Maximum size (in bytes) of an incoming packet
+    pub fn max_incoming_packet_size(&self) -> usize {
+        self.max_incoming_packet_size
+    }
+
+    /// Maximum size (in bytes) of an incoming packet
+    #[deprecated(note = "Use `max_incoming_packet_size` (or `max_outgoing_packet_size`)")]
     pub fn max_packet_size(&self) -> usize {
-        self.max_packet_size
+        self.max_incoming_packet_size()
+    }
+
+    /// Maximum size (in bytes) of an outgoing packet
+    pub fn max_outgoing_packet_size(&self) -> usize {
+        self.max_outgoing_packet_size
     }
 
     /// `clean_session = true` removes all the state from queues & instructs the broker
@@ -223,6 +1022,32 @@ impl MqttOptions {
         self.clean_session
     }
 
+    /// Set the mqtt protocol version. Brokers which don't support the version
+    /// refuse the connection with `ConnectError::UnsupportedProtocolVersion`
+    pub fn set_protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Mqtt protocol version
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Set the properties of the connect of `Protocol::MQTT5` (session expiry, receive
+    /// maximum, user properties etc)
+    #[cfg(feature = "mqtt5")]
+    pub fn set_connect_properties(mut self, properties: v5::Properties) -> Self {
+        self.connect_properties = properties;
+        self
+    }
+
+    /// Properties of the mqtt 5 connect
+    #[cfg(feature = "mqtt5")]
+    pub fn connect_properties(&self) -> &v5::Properties {
+        &self.connect_properties
+    }
+
     pub fn set_proxy(mut self, proxy: Proxy) -> Self {
         self.proxy = proxy;
         self
@@ -232,15 +1057,56 @@ impl MqttOptions {
         self.proxy.clone()
     }
 
+    /// Connects to the http proxy over tls. The `CONNECT` tunnel (and the broker's
+    /// tls, if any) runs inside this session
+    pub fn set_proxy_tls(mut self, config: ProxyTlsConfig) -> Self {
+        self.proxy_tls = Some(config);
+        self
+    }
+
+    /// Tls settings of the connection to the http proxy
+    pub fn proxy_tls(&self) -> Option<ProxyTlsConfig> {
+        self.proxy_tls.clone()
+    }
+
     /// Time interval after which client should retry for new
-    /// connection if there are any disconnections. By default, no retry will happen
+    /// connection if there are any disconnections. Sets both the initial connection
+    /// and the reconnection policies. By default, only reconnections are retried
     pub fn set_reconnect_opts(mut self, opts: ReconnectOptions) -> Self {
-        self.reconnect = opts;
+        let (initial_connect, reconnect) = opts.policies();
+        self.initial_connect = initial_connect;
+        self.reconnect = reconnect;
         self
     }
 
-    /// Reconnection options
+    /// Reconnection options of the retry policies. Policies which `ReconnectOptions`
+    /// can't express are approximated by the reconnection policy
+    #[deprecated(note = "Use `initial_connect_policy` and `reconnect_policy`")]
     pub fn reconnect_opts(&self) -> ReconnectOptions {
+        ReconnectOptions::from_policies(self.initial_connect, self.reconnect)
+    }
+
+    /// Set the retries of the connection attempts till the client connects for the
+    /// first time. `MqttClient::start` returns the error of the first attempt when
+    /// this is `RetryPolicy::Never` and doesn't wait for the connection otherwise
+    pub fn set_initial_connect_policy(mut self, policy: RetryPolicy) -> Self {
+        self.initial_connect = policy;
+        self
+    }
+
+    /// Retries till the first connection
+    pub fn initial_connect_policy(&self) -> RetryPolicy {
+        self.initial_connect
+    }
+
+    /// Set the retries after the client was connected at least once
+    pub fn set_reconnect_policy(mut self, policy: RetryPolicy) -> Self {
+        self.reconnect = policy;
+        self
+    }
+
+    /// Retries after the first connection
+    pub fn reconnect_policy(&self) -> RetryPolicy {
         self.reconnect
     }
 
@@ -262,12 +1128,19 @@ impl MqttOptions {
         self
     }
 
+    /// Clear last will and testament
+    pub fn clear_last_will(mut self) -> Self {
+        self.last_will = None;
+        self
+    }
+
     /// Last will and testament
     pub fn last_will(&self) -> Option<mqtt311::LastWill> {
         self.last_will.clone()
     }
 
-    /// Set notification channel capacity
+    /// Set notification channel capacity. Notifications which don't fit are handled as
+    /// per `set_notification_overflow`
     pub fn set_notification_channel_capacity(mut self, capacity: usize) -> Self {
         self.notification_channel_capacity = capacity;
         self
@@ -278,7 +1151,64 @@ impl MqttOptions {
         self.notification_channel_capacity
     }
 
-    /// Set request channel capacity
+    /// Delivers notifications on a futures stream (see `MqttClient::notifications_stream`)
+    /// instead of the crossbeam channel. Unlike the crossbeam channel, a slow stream
+    /// consumer doesn't drop notifications. Eventloop stops reading the network until
+    /// there is space in the stream
+    pub fn set_notification_stream(mut self, enable: bool) -> Self {
+        self.notification_stream = enable;
+        self
+    }
+
+    /// Notifications are delivered on a futures stream
+    pub fn notification_stream(&self) -> bool {
+        self.notification_stream
+    }
+
+    /// Set what happens to notifications when the notification channel is full. Dropped
+    /// notifications are counted in `NotificationReceiver::dropped_count`. Connection
+    /// state notifications (which are sent outside the network) are never blocked on
+    pub fn set_notification_overflow(mut self, overflow: NotificationOverflow) -> Self {
+        self.notification_overflow = overflow;
+        self
+    }
+
+    /// Policy when the notification channel is full
+    pub fn notification_overflow(&self) -> NotificationOverflow {
+        self.notification_overflow
+    }
+
+    /// Stops reading the network (and acking incoming publishes) while the notification
+    /// channel is full instead of dropping notifications. Same as
+    /// `NotificationOverflow::Block`. Pings are still sent to the broker meanwhile.
+    /// Disabling switches `Block` back to the default policy. Other policies are kept
+    pub fn set_inbound_backpressure(self, enable: bool) -> Self {
+        match (enable, self.notification_overflow) {
+            (true, _) => self.set_notification_overflow(NotificationOverflow::Block),
+            (false, NotificationOverflow::Block) => self.set_notification_overflow(NotificationOverflow::DropNewest),
+            (false, _) => self,
+        }
+    }
+
+    /// Network reads wait for space in the notification channel
+    pub fn inbound_backpressure(&self) -> bool {
+        self.notification_overflow == NotificationOverflow::Block
+    }
+
+    /// Notifies the round trip time of every ping (`Notification::PingResponse`). Off by
+    /// default. Round trip times are part of the stats either way
+    pub fn set_ping_notifications(mut self, enable: bool) -> Self {
+        self.ping_notifications = enable;
+        self
+    }
+
+    /// Round trip times of pings are notified
+    pub fn ping_notifications(&self) -> bool {
+        self.ping_notifications
+    }
+
+    /// Set request channel capacity. Every clone of the client can queue one request
+    /// more than the capacity before the requests block (or `try_publish` fails)
     pub fn set_request_channel_capacity(mut self, capacity: usize) -> Self {
         self.request_channel_capacity = capacity;
         self
@@ -289,14 +1219,25 @@ impl MqttOptions {
         self.request_channel_capacity
     }
 
-    /// Enables throttling and sets outoing message rate to the specified 'rate'
-    pub fn set_throttle(mut self, rate: f32) -> Self {
-        if rate <= 0.0 {
-            panic!("throttle rate should be a positive number.");
+    /// Enables throttling and sets outoing message rate to the specified 'rate'.
+    /// User requests are paced with timers. Acks and pings aren't throttled.
+    /// Panics for invalid rates. See `try_set_throttle`
+    pub fn set_throttle(self, rate: f32) -> Self {
+        match self.try_set_throttle(rate) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Enables throttling and sets outoing message rate to the specified 'rate'.
+    /// Errors when the rate isn't a positive number
+    pub fn try_set_throttle(mut self, rate: f32) -> Result<Self, OptionsError> {
+        if rate <= 0.0 || rate.is_nan() {
+            return Err(OptionsError::InvalidThrottle(rate));
         }
 
         self.throttle = Some(rate);
-        self
+        Ok(self)
     }
 
     /// Outgoing message rate
@@ -304,25 +1245,298 @@ impl MqttOptions {
         self.throttle
     }
 
-    /// Set number of concurrent in flight messages
-    pub fn set_inflight(mut self, inflight: usize) -> Self {
+    /// Coalesces outgoing packets into writes of up to `size` bytes. Packets are written
+    /// when the buffer fills or when there are no more requests. Publishes then wait up
+    /// to a millisecond for more to batch with while acks and pings are written right
+    /// away. Disabled (0) by default, which writes the packets as soon as they are ready
+    pub fn set_write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = size;
+        self
+    }
+
+    pub fn write_buffer_size(&self) -> usize {
+        self.write_buffer_size
+    }
+
+    /// Sets the outgoing rate of the publishes whose topics match `filter`. Publishes of
+    /// a filter don't wait for the ones of the other filters. The first matching filter
+    /// (in the order they are set) applies. Publishes which match no filter use the
+    /// global throttle (and are unthrottled without it).
+    /// Panics for invalid filters or rates. See `try_set_topic_throttle`
+    pub fn set_topic_throttle<S: Into<String>>(self, filter: S, rate: f32) -> Self {
+        match self.try_set_topic_throttle(filter, rate) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Sets the outgoing rate of the publishes whose topics match `filter`. Errors for
+    /// invalid filters and when the rate isn't a positive number
+    pub fn try_set_topic_throttle<S: Into<String>>(mut self, filter: S, rate: f32) -> Result<Self, OptionsError> {
+        let filter = filter.into();
+        if !topic::valid_filter(&filter) {
+            return Err(OptionsError::InvalidTopicFilter(filter));
+        }
+
+        if rate <= 0.0 || rate.is_nan() {
+            return Err(OptionsError::InvalidThrottle(rate));
+        }
+
+        self.topic_throttles.push((filter, rate));
+        Ok(self)
+    }
+
+    /// Outgoing publish rates by topic filter
+    pub fn topic_throttles(&self) -> &[(String, f32)] {
+        &self.topic_throttles
+    }
+
+    /// Set number of concurrent in flight messages. Panics for zero. See `try_set_inflight`
+    pub fn set_inflight(self, inflight: usize) -> Self {
+        match self.try_set_inflight(inflight) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Set number of concurrent in flight messages. Errors for zero
+    pub fn try_set_inflight(mut self, inflight: usize) -> Result<Self, OptionsError> {
         if inflight == 0 {
-            panic!("zero in flight is not allowed")
+            return Err(OptionsError::ZeroInflight);
         }
 
         self.inflight = inflight;
-        self
+        Ok(self)
     }
 
     /// Number of concurrent in flight messages
     pub fn inflight(&self) -> usize {
         self.inflight
     }
+
+    /// Set the total size (in bytes) of the in flight messages. Like the count limit, user
+    /// requests wait once the unacked publishes reach this size. A publish is always sent
+    /// when nothing is in flight, even if it is bigger than the limit. Requests are read
+    /// again as soon as an ack frees space (there is no retry delay)
+    pub fn set_inflight_bytes(mut self, bytes: usize) -> Self {
+        self.inflight_bytes = Some(bytes);
+        self
+    }
+
+    /// Total size of the in flight messages
+    pub fn inflight_bytes(&self) -> Option<usize> {
+        self.inflight_bytes
+    }
+
+    /// Set what happens to the pending requests while the in flight limits are reached.
+    /// Dropped publishes are counted in `StatsSnapshot::publishes_dropped`
+    pub fn set_inflight_overflow(mut self, overflow: InflightOverflow) -> Self {
+        self.inflight_overflow = overflow;
+        self
+    }
+
+    /// Policy when the in flight limits are reached
+    pub fn inflight_overflow(&self) -> InflightOverflow {
+        self.inflight_overflow
+    }
+
+    /// Retransmits (with dup flag) qos1 publishes which aren't acked in this time without
+    /// waiting for a reconnection. Publishes are checked once every `timeout`. Disabled by default
+    pub fn set_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Time after which unacked qos1 publishes are retransmitted
+    pub fn ack_timeout(&self) -> Option<Duration> {
+        self.ack_timeout
+    }
+
+    /// Saves outgoing qos1 & 2 publishes till they are acknowledged. Publishes saved
+    /// by a previous run of the client are sent after the first connection
+    pub fn set_persistence<P: Persistence + 'static>(mut self, persistence: P) -> Self {
+        self.persistence = Some(Arc::new(persistence));
+        self
+    }
+
+    /// Store of unacked outgoing publishes
+    pub fn persistence(&self) -> Option<Arc<dyn Persistence>> {
+        self.persistence.clone()
+    }
+
+    /// Calls `handler` on connections, disconnections, reconnection attempts and sent
+    /// and received publishes. Handlers run on the eventloop and should be fast
+    pub fn set_event_handler(mut self, handler: Arc<dyn MqttEventHandler>) -> Self {
+        self.event_handler = Some(handler);
+        self
+    }
+
+    /// Handler of eventloop events
+    pub fn event_handler(&self) -> Option<Arc<dyn MqttEventHandler>> {
+        self.event_handler.clone()
+    }
+
+    /// Connack return codes after which the eventloop stops instead of reconnecting.
+    /// Defaults to identifier rejected (2), bad username or password (4) and not
+    /// authorized (5). An empty list retries all the refusals as per reconnect options
+    pub fn set_fatal_refusals(mut self, codes: Vec<u8>) -> Self {
+        self.fatal_refusals = codes;
+        self
+    }
+
+    /// Connack return codes which stop the reconnections
+    pub fn fatal_refusals(&self) -> Vec<u8> {
+        self.fatal_refusals.clone()
+    }
+
+    /// Http proxy `CONNECT` statuses after which the eventloop stops instead of reconnecting.
+    /// Defaults to proxy authentication required (407) as the credentials of the options
+    /// don't change. 407 isn't fatal with `Proxy::FromEnv` whose credentials are read for
+    /// every attempt. An empty list retries all the refusals as per reconnect options
+    pub fn set_fatal_proxy_statuses(mut self, statuses: Vec<u16>) -> Self {
+        self.fatal_proxy_statuses = statuses;
+        self
+    }
+
+    /// Proxy connect statuses which stop the reconnections
+    pub fn fatal_proxy_statuses(&self) -> Vec<u16> {
+        self.fatal_proxy_statuses.clone()
+    }
+
+    /// Subscribes again (before replaying the unacked publishes) to all the topics of
+    /// the last connection when the broker starts a fresh session. Enabled by default.
+    /// Disable to manage the subscriptions after a `Notification::Connected` yourself
+    pub fn set_auto_resubscribe(mut self, enable: bool) -> Self {
+        self.auto_resubscribe = enable;
+        self
+    }
+
+    /// Subscriptions are restored after reconnections
+    pub fn auto_resubscribe(&self) -> bool {
+        self.auto_resubscribe
+    }
+
+    /// Incoming qos1 publishes are delivered as `Notification::ManualAckPublish` and
+    /// acknowledged only when the user calls `MqttClient::ack`. Publishes delivered on
+    /// the channels of `subscribe_with_channel` are still acknowledged automatically
+    pub fn set_manual_acks(mut self, enable: bool) -> Self {
+        self.manual_acks = enable;
+        self
+    }
+
+    /// User acknowledges incoming qos1 publishes
+    pub fn manual_acks(&self) -> bool {
+        self.manual_acks
+    }
+
+    /// Set the time for which a disconnect (or a dropped client) waits for the acks of
+    /// the queued and inflight messages before sending mqtt disconnect. Zero disconnects
+    /// right after the queued messages are written
+    pub fn set_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Time to wait for the outstanding acks before disconnecting
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Set the uptime after which a connection is considered stable. Reconnections
+    /// after the loss of a stable connection start again from the initial backoff
+    pub fn set_stable_connection_threshold(mut self, threshold: Duration) -> Self {
+        self.stable_connection_threshold = threshold;
+        self
+    }
+
+    /// Uptime after which the reconnection backoff is reset
+    pub fn stable_connection_threshold(&self) -> Duration {
+        self.stable_connection_threshold
+    }
+
+    /// Set the age after which publishes which are waiting to be (re)sent are dropped.
+    /// Age is counted from the time the client queues the publish and is checked when
+    /// it's about to be sent. This covers publishes waiting in the request channel while
+    /// offline, throttled publishes and replays. Drops are summarized in `Notification::Expired`
+    pub fn set_queued_message_ttl(mut self, ttl: Duration) -> Self {
+        self.queued_message_ttl = Some(ttl);
+        self
+    }
+
+    /// Age after which queued publishes are dropped
+    pub fn queued_message_ttl(&self) -> Option<Duration> {
+        self.queued_message_ttl
+    }
+
+    /// Set the stack size (in bytes) of the thread which `MqttClient::start` spawns for
+    /// the eventloop. Defaults to the rust default (2 MB unless `RUST_MIN_STACK` is set)
+    pub fn set_eventloop_stack_size(mut self, size: usize) -> Self {
+        self.eventloop_stack_size = Some(size);
+        self
+    }
+
+    /// Stack size of the eventloop thread
+    pub fn eventloop_stack_size(&self) -> Option<usize> {
+        self.eventloop_stack_size
+    }
+
+    /// Resumes the session exported (`MqttClient::export_session`) by a previous run of
+    /// the client. Needs a persistent session (`set_clean_session(false)`) to be of use.
+    /// Panics for snapshots of other versions. See `try_set_initial_session`
+    pub fn set_initial_session(self, session: SessionState) -> Self {
+        match self.try_set_initial_session(session) {
+            Ok(options) => options,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Resumes the session exported by a previous run of the client. Errors for
+    /// snapshots of other versions
+    pub fn try_set_initial_session(mut self, session: SessionState) -> Result<Self, OptionsError> {
+        if session.version() != SESSION_VERSION {
+            return Err(OptionsError::UnsupportedSessionVersion(session.version()));
+        }
+
+        self.initial_session = Some(session);
+        Ok(self)
+    }
+
+    /// Session resumed at the start
+    pub fn initial_session(&self) -> Option<&SessionState> {
+        self.initial_session.as_ref()
+    }
+}
+
+/// Host and the optional port of a url authority. Ipv6 hosts are in brackets
+pub(crate) fn split_host_port(authority: &str) -> Result<(&str, Option<u16>), UrlError> {
+    let (host, port) = if authority.starts_with('[') {
+        match authority.find(']') {
+            Some(index) if index + 1 == authority.len() => (&authority[1..index], ""),
+            Some(index) if authority[index + 1..].starts_with(':') => (&authority[1..index], &authority[index + 2..]),
+            _ => return Err(UrlError::InvalidHost(authority.to_owned())),
+        }
+    } else {
+        match authority.rfind(':') {
+            Some(index) => (&authority[..index], &authority[index + 1..]),
+            None => (authority, ""),
+        }
+    };
+
+    if host.is_empty() || host.contains('@') {
+        return Err(UrlError::InvalidHost(authority.to_owned()));
+    }
+
+    match port {
+        "" => Ok((host, None)),
+        port => port.parse().map(|port| (host, Some(port))).map_err(|_| UrlError::InvalidPort(port.to_owned())),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::mqttoptions::{MqttOptions, ReconnectOptions};
+    use crate::error::{OptionsError, UrlError};
+    use crate::mqttoptions::{MqttOptions, NotificationOverflow, ReconnectOptions, RetryPolicy};
+    use std::time::Duration;
 
     #[test]
     #[should_panic]
@@ -339,4 +1553,129 @@ mod test {
             .set_reconnect_opts(ReconnectOptions::Always(10))
             .set_clean_session(true);
     }
+
+    #[test]
+    fn invalid_options_should_return_errors() {
+        let id = MqttOptions::try_new(" client_a", "127.0.0.1", 1883).unwrap_err();
+        assert_eq!(id, OptionsError::InvalidClientId(" client_a".to_owned()));
+
+        let options = MqttOptions::try_new("client_a", "127.0.0.1", 1883).unwrap();
+        assert_eq!(options.clone().try_set_throttle(0.0).unwrap_err(), OptionsError::InvalidThrottle(0.0));
+        assert_eq!(options.clone().try_set_inflight(0).unwrap_err(), OptionsError::ZeroInflight);
+        assert_eq!(options.clone().try_set_connect_timeout(Duration::from_secs(0)).unwrap_err(), OptionsError::ZeroConnectionTimeout);
+        assert_eq!(options.clone().try_set_brokers(Vec::new()).unwrap_err(), OptionsError::NoBrokers);
+        let filter = OptionsError::InvalidTopicFilter("logs/#/a".to_owned());
+        assert_eq!(options.clone().try_set_topic_throttle("logs/#/a", 5.0).unwrap_err(), filter);
+        assert_eq!(options.clone().try_set_topic_throttle("logs/#", -1.0).unwrap_err(), OptionsError::InvalidThrottle(-1.0));
+
+        let options = options.try_set_throttle(10.0).and_then(|o| o.try_set_inflight(10)).unwrap();
+        assert_eq!(options.throttle(), Some(10.0));
+        assert_eq!(options.inflight(), 10);
+    }
+
+    #[test]
+    fn packet_size_limits_should_be_set_together_or_per_direction() {
+        let options = MqttOptions::new("client_a", "127.0.0.1", 1883);
+        assert_eq!(options.max_incoming_packet_size(), 256 * 1024);
+        assert_eq!(options.max_outgoing_packet_size(), 256 * 1024);
+
+        let options = options.set_max_packet_size(10);
+        assert_eq!(options.max_incoming_packet_size(), 10 * 1024);
+        assert_eq!(options.max_outgoing_packet_size(), 10 * 1024);
+
+        let options = options.set_max_incoming_packet_size(2048).set_max_outgoing_packet_size(4);
+        assert_eq!(options.max_incoming_packet_size(), 2048 * 1024);
+        assert_eq!(options.max_outgoing_packet_size(), 4 * 1024);
+    }
+
+    #[test]
+    fn disabling_inbound_backpressure_should_keep_drop_policies() {
+        let options = MqttOptions::new("client_a", "127.0.0.1", 1883).set_inbound_backpressure(true);
+        assert_eq!(options.notification_overflow(), NotificationOverflow::Block);
+
+        let options = options.set_inbound_backpressure(false);
+        assert_eq!(options.notification_overflow(), NotificationOverflow::DropNewest);
+
+        let options = options.set_notification_overflow(NotificationOverflow::DropOldest).set_inbound_backpressure(false);
+        assert_eq!(options.notification_overflow(), NotificationOverflow::DropOldest);
+    }
+
+    #[test]
+    fn broker_urls_should_set_address_tls_and_query_parameters() {
+        let options = MqttOptions::from_url("ssl://broker.example.com?client_id=dev42&keep_alive=5&clean_session=false").unwrap();
+        assert_eq!(options.broker_address(), ("broker.example.com".to_owned(), 8883));
+        assert_eq!(options.client_id(), "dev42");
+        assert_eq!(options.keep_alive(), Duration::from_secs(5));
+        assert!(options.tls());
+        assert!(!options.clean_session());
+
+        let options = MqttOptions::from_url("mqtt://[::1]:1884/").unwrap();
+        assert_eq!(options.broker_address(), ("::1".to_owned(), 1884));
+        assert!(!options.tls());
+        assert!(options.clean_session());
+    }
+
+    #[test]
+    fn invalid_broker_urls_should_return_errors() {
+        let error = |url| MqttOptions::from_url(url).unwrap_err();
+
+        assert_eq!(error("broker.example.com:1883"), UrlError::Malformed("broker.example.com:1883".to_owned()));
+        assert_eq!(error("http://broker.example.com"), UrlError::UnsupportedScheme("http".to_owned()));
+        assert_eq!(error("tcp://:1883"), UrlError::InvalidHost(":1883".to_owned()));
+        assert_eq!(error("tcp://broker.example.com:mqtt"), UrlError::InvalidPort("mqtt".to_owned()));
+        assert_eq!(error("tcp://broker.example.com?qos=1"), UrlError::UnknownParameter("qos".to_owned()));
+        let keep_alive = UrlError::InvalidParameter("keep_alive".to_owned(), "-1".to_owned());
+        assert_eq!(error("tcp://broker.example.com?keep_alive=-1"), keep_alive);
+        assert_eq!(error("tcp://broker.example.com?client_id="), UrlError::Options(OptionsError::InvalidClientId(String::new())));
+        assert_eq!(error("ws://broker.example.com/mqtt"), UrlError::MissingPort("ws".to_owned()));
+    }
+
+    #[test]
+    fn reconnect_options_should_compare_backoff_factors_bitwise() {
+        let backoff = |multiplier| ReconnectOptions::ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier,
+            jitter: 0.1,
+        };
+
+        assert_eq!(backoff(2.0), backoff(2.0));
+        assert_eq!(backoff(std::f64::NAN), backoff(std::f64::NAN));
+        assert_ne!(backoff(2.0), backoff(3.0));
+        assert_ne!(ReconnectOptions::Always(3), ReconnectOptions::AfterFirstSuccess(3));
+    }
+
+    #[test]
+    fn reconnect_options_should_set_both_retry_policies() {
+        let options = MqttOptions::new("id", "127.0.0.1", 1883);
+        assert_eq!(options.initial_connect_policy(), RetryPolicy::Never);
+        assert_eq!(options.reconnect_policy(), RetryPolicy::Interval(Duration::from_secs(10)));
+
+        let options = options.set_reconnect_opts(ReconnectOptions::Always(3));
+        assert_eq!(options.initial_connect_policy(), RetryPolicy::Interval(Duration::from_secs(3)));
+        assert_eq!(options.reconnect_policy(), RetryPolicy::Interval(Duration::from_secs(3)));
+
+        let options = options.set_reconnect_opts(ReconnectOptions::Never).set_initial_connect_policy(RetryPolicy::Interval(Duration::from_secs(2)));
+        assert_eq!(options.initial_connect_policy(), RetryPolicy::Interval(Duration::from_secs(2)));
+        assert_eq!(options.reconnect_policy(), RetryPolicy::Never);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn reconnect_options_should_be_read_back_from_the_retry_policies() {
+        let backoff = ReconnectOptions::ExponentialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.1,
+        };
+
+        for opts in vec![ReconnectOptions::Never, ReconnectOptions::AfterFirstSuccess(5), ReconnectOptions::Always(3), backoff] {
+            let options = MqttOptions::new("id", "127.0.0.1", 1883).set_reconnect_opts(opts);
+            assert_eq!(options.reconnect_opts(), opts);
+        }
+
+        let options = MqttOptions::new("id", "127.0.0.1", 1883);
+        assert_eq!(options.reconnect_opts(), ReconnectOptions::AfterFirstSuccess(10));
+    }
 }
@@ -0,0 +1,69 @@
+//! Typed payloads of incoming publishes (`json` and `cbor` features)
+use crate::error::DecodeError;
+use mqtt311::Publish;
+use serde::de::DeserializeOwned;
+
+/// Deserializes the payload of a publish. Implemented for the publish of `Notification::Publish`
+pub trait DecodePayload {
+    #[cfg(feature = "json")]
+    fn decode_json<T: DeserializeOwned>(&self) -> Result<T, DecodeError>;
+    #[cfg(feature = "cbor")]
+    fn decode_cbor<T: DeserializeOwned>(&self) -> Result<T, DecodeError>;
+}
+
+impl DecodePayload for Publish {
+    #[cfg(feature = "json")]
+    fn decode_json<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
+        Ok(serde_json::from_slice(&self.payload)?)
+    }
+
+    #[cfg(feature = "cbor")]
+    fn decode_cbor<T: DeserializeOwned>(&self) -> Result<T, DecodeError> {
+        Ok(serde_cbor::from_slice(&self.payload)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DecodePayload;
+    use mqtt311::{Publish, QoS};
+    use serde_derive::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        id: u32,
+        value: f32,
+    }
+
+    fn publish(payload: Vec<u8>) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic_name: "hello/world".to_owned(),
+            pkid: None,
+            payload: Arc::new(payload),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_payloads_should_be_decoded_and_garbage_should_be_an_error() {
+        let reading = Reading { id: 1, value: 2.5 };
+        let payload = serde_json::to_vec(&reading).unwrap();
+
+        assert_eq!(publish(payload).decode_json::<Reading>().unwrap(), reading);
+        assert!(publish(b"garbage".to_vec()).decode_json::<Reading>().is_err());
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_payloads_should_be_decoded_and_garbage_should_be_an_error() {
+        let reading = Reading { id: 1, value: 2.5 };
+        let payload = serde_cbor::to_vec(&reading).unwrap();
+
+        assert_eq!(publish(payload).decode_cbor::<Reading>().unwrap(), reading);
+        assert!(publish(b"garbage".to_vec()).decode_cbor::<Reading>().is_err());
+    }
+}
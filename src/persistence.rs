@@ -0,0 +1,266 @@
+//! Storage of outgoing qos1 & 2 publishes which should survive restarts
+use failure::Fail;
+use mqtt311::{MqttRead, MqttWrite, Packet, PacketIdentifier, Publish};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+};
+
+/// Saved state of an outgoing publish
+#[derive(Debug, Clone, PartialEq)]
+pub enum Persisted {
+    /// Publish which isn't acknowledged yet. Published again after a restart
+    Publish(Publish),
+    /// QoS2 publish which the broker received (pubrec) but which isn't released yet.
+    /// Only the release is sent after a restart
+    Release(PacketIdentifier),
+}
+
+/// Store of unacknowledged outgoing publishes. Publishes are stored before they are
+/// written to the network, removed when they are acknowledged and loaded (and
+/// retransmitted) when the client starts
+pub trait Persistence: Send + Sync + fmt::Debug {
+    /// Saves the publish. Overwrites the entry previously saved with the same pkid
+    fn store(&self, pkid: PacketIdentifier, publish: &Publish) -> io::Result<()>;
+    /// Marks the saved qos2 publish as received by the broker. It keeps its position
+    /// in the order of the saved entries
+    fn store_release(&self, pkid: PacketIdentifier) -> io::Result<()>;
+    /// Removes the entry once the broker acknowledged (puback/pubcomp) it
+    fn remove(&self, pkid: PacketIdentifier) -> io::Result<()>;
+    /// All the saved entries in the order they were saved. Entries which can't be read
+    /// are skipped
+    fn load_all(&self) -> io::Result<Vec<Persisted>>;
+}
+
+/// Saves every entry as a file (named after its pkid) in a directory. Files start with
+/// a sequence number which orders the entries
+#[derive(Debug, Clone)]
+pub struct FilePersistence {
+    dir: PathBuf,
+    sequence: Arc<AtomicU64>,
+}
+
+impl FilePersistence {
+    /// Uses `dir` (created if necessary) to save the publishes
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FilePersistence> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        // new entries are ordered after the ones of the previous runs
+        let last = entries(&dir)?.into_iter().map(|(sequence, _)| sequence).max().unwrap_or(0);
+        let sequence = Arc::new(AtomicU64::new(last + 1));
+        Ok(FilePersistence { dir, sequence })
+    }
+
+    fn path(&self, pkid: PacketIdentifier, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", pkid.0, extension))
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.sequence.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Writes `sequence` followed by `data`. A partially written file is never mistaken
+    /// for an entry
+    fn write(&self, pkid: PacketIdentifier, extension: &str, sequence: u64, data: &[u8]) -> io::Result<()> {
+        let tmp = self.path(pkid, "tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&sequence.to_be_bytes())?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.path(pkid, extension))
+    }
+}
+
+impl Persistence for FilePersistence {
+    fn store(&self, pkid: PacketIdentifier, publish: &Publish) -> io::Result<()> {
+        let mut buf = Cursor::new(Vec::new());
+        buf.write_packet(&Packet::Publish(publish.clone()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.compat()))?;
+
+        self.write(pkid, "pub", self.next_sequence(), buf.get_ref())?;
+        remove_file(&self.path(pkid, "rel"))
+    }
+
+    fn store_release(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        let sequence = match read_entry(&self.path(pkid, "pub")) {
+            Ok((sequence, _)) => sequence,
+            Err(_) => self.next_sequence(),
+        };
+
+        // release is preferred when the publish isn't removed yet
+        self.write(pkid, "rel", sequence, &[])?;
+        remove_file(&self.path(pkid, "pub"))
+    }
+
+    fn remove(&self, pkid: PacketIdentifier) -> io::Result<()> {
+        remove_file(&self.path(pkid, "pub"))?;
+        remove_file(&self.path(pkid, "rel"))
+    }
+
+    /// Entries in the order of their sequence numbers
+    fn load_all(&self) -> io::Result<Vec<Persisted>> {
+        let mut entries = entries(&self.dir)?;
+        entries.sort_by_key(|(sequence, _)| *sequence);
+        Ok(entries.into_iter().map(|(_, persisted)| persisted).collect())
+    }
+}
+
+/// Readable entries in `dir` with their sequence numbers
+fn entries(dir: &Path) -> io::Result<Vec<(u64, Persisted)>> {
+    let mut entries: HashMap<u16, (u64, Persisted)> = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let (sequence, persisted) = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("pub") | Some("rel") => match read_entry(&path) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Skipping corrupt persisted publish. Path = {:?}, Error = {:?}", path, e);
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+
+        let pkid = match persisted {
+            Persisted::Publish(ref publish) => publish.pkid.expect("Persisted publish without pkid").0,
+            Persisted::Release(pkid) => pkid.0,
+        };
+
+        // release wins over the publish of an interrupted `store_release`
+        let released = match entries.get(&pkid) {
+            Some((_, Persisted::Release(_))) => true,
+            _ => false,
+        };
+
+        if !released {
+            entries.insert(pkid, (sequence, persisted));
+        }
+    }
+
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        o => o,
+    }
+}
+
+fn read_entry(path: &Path) -> io::Result<(u64, Persisted)> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing sequence number"));
+    }
+
+    let mut sequence = [0; 8];
+    sequence.copy_from_slice(&buf[..8]);
+    let sequence = u64::from_be_bytes(sequence);
+
+    let pkid = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u16>().ok());
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("rel") => match pkid {
+            Some(pkid) if buf.len() == 8 => Ok((sequence, Persisted::Release(PacketIdentifier(pkid)))),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid release")),
+        },
+        _ => read_publish(&buf[8..], pkid).map(|publish| (sequence, Persisted::Publish(publish))),
+    }
+}
+
+fn read_publish(mut packet: &[u8], pkid: Option<u16>) -> io::Result<Publish> {
+    match packet.read_packet() {
+        Ok(Packet::Publish(ref publish)) if publish.pkid.is_some() && publish.pkid.map(|pkid| pkid.0) == pkid => {
+            Ok(publish.clone())
+        }
+        Ok(packet) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected packet = {:?}", packet))),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.compat())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FilePersistence, Persisted, Persistence};
+    use mqtt311::{PacketIdentifier, Publish, QoS};
+    use std::{fs, sync::Arc};
+    use uuid::Uuid;
+
+    fn publish(pkid: u16) -> Publish {
+        Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            pkid: Some(PacketIdentifier(pkid)),
+            topic_name: "hello/world".to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        }
+    }
+
+    #[test]
+    fn stored_publishes_should_be_loaded_till_they_are_removed() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-{}", Uuid::new_v4()));
+        let persistence = FilePersistence::new(&dir).unwrap();
+
+        persistence.store(PacketIdentifier(1), &publish(1)).unwrap();
+        persistence.store(PacketIdentifier(2), &publish(2)).unwrap();
+        persistence.remove(PacketIdentifier(1)).unwrap();
+        persistence.remove(PacketIdentifier(3)).unwrap();
+
+        assert_eq!(persistence.load_all().unwrap(), vec![Persisted::Publish(publish(2))]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn entries_should_be_loaded_in_the_order_they_were_saved() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-{}", Uuid::new_v4()));
+        let persistence = FilePersistence::new(&dir).unwrap();
+
+        persistence.store(PacketIdentifier(3), &publish(3)).unwrap();
+        persistence.store(PacketIdentifier(1), &publish(1)).unwrap();
+        persistence.store(PacketIdentifier(2), &publish(2)).unwrap();
+
+        // release keeps the position of its publish
+        persistence.store_release(PacketIdentifier(1)).unwrap();
+
+        // restart continues the sequence
+        let persistence = FilePersistence::new(&dir).unwrap();
+        persistence.store(PacketIdentifier(4), &publish(4)).unwrap();
+
+        let expected = vec![
+            Persisted::Publish(publish(3)),
+            Persisted::Release(PacketIdentifier(1)),
+            Persisted::Publish(publish(2)),
+            Persisted::Publish(publish(4)),
+        ];
+        assert_eq!(persistence.load_all().unwrap(), expected);
+
+        persistence.remove(PacketIdentifier(1)).unwrap();
+        assert_eq!(persistence.load_all().unwrap().len(), 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_entries_should_be_skipped() {
+        let dir = std::env::temp_dir().join(format!("rumqtt-{}", Uuid::new_v4()));
+        let persistence = FilePersistence::new(&dir).unwrap();
+
+        persistence.store(PacketIdentifier(1), &publish(1)).unwrap();
+        persistence.store(PacketIdentifier(2), &publish(2)).unwrap();
+
+        // truncated, garbage, mismatched pkid and an interrupted write
+        let stored = fs::read(dir.join("2.pub")).unwrap();
+        fs::write(dir.join("2.pub"), &stored[..stored.len() - 2]).unwrap();
+        fs::write(dir.join("3.pub"), b"garbage").unwrap();
+        fs::copy(dir.join("1.pub"), dir.join("4.pub")).unwrap();
+        fs::write(dir.join("5.tmp"), &stored).unwrap();
+
+        assert_eq!(persistence.load_all().unwrap(), vec![Persisted::Publish(publish(1))]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Snapshot of the client side of a persistent session to resume it in another process.
+//! Serializable with serde when the `session` feature is enabled
+use mqtt311::{PacketIdentifier, Publish, QoS};
+#[cfg(feature = "session")]
+use serde_derive::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Format of the snapshots of this version of the client. Snapshots of other versions
+/// are refused by `MqttOptions::try_set_initial_session`
+pub const SESSION_VERSION: u32 = 1;
+
+/// Outgoing qos1 & 2 queues, incoming qos2 publishes which aren't released yet and the
+/// last packet id of a session. See `MqttClient::export_session`
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "session", derive(Serialize, Deserialize))]
+pub struct SessionState {
+    version: u32,
+    last_pkid: u16,
+    publishes: Vec<SessionPublish>,
+    releases: Vec<u16>,
+    incoming: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "session", derive(Serialize, Deserialize))]
+struct SessionPublish {
+    pkid: u16,
+    qos: u8,
+    retain: bool,
+    topic: String,
+    payload: Vec<u8>,
+}
+
+impl SessionState {
+    pub(crate) fn new(last_pkid: PacketIdentifier, publishes: &[Publish], releases: &[PacketIdentifier], incoming: &[u16]) -> SessionState {
+        let publishes = publishes
+            .iter()
+            .filter_map(|publish| {
+                let pkid = publish.pkid?;
+                Some(SessionPublish {
+                    pkid: pkid.0,
+                    qos: publish.qos.to_u8(),
+                    retain: publish.retain,
+                    topic: publish.topic_name.clone(),
+                    payload: publish.payload.to_vec(),
+                })
+            })
+            .collect();
+
+        SessionState {
+            version: SESSION_VERSION,
+            last_pkid: last_pkid.0,
+            publishes,
+            releases: releases.iter().map(|pkid| pkid.0).collect(),
+            incoming: incoming.to_vec(),
+        }
+    }
+
+    /// Format version of the snapshot
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// True when nothing of the session is outstanding
+    pub fn is_empty(&self) -> bool {
+        self.publishes.is_empty() && self.releases.is_empty() && self.incoming.is_empty()
+    }
+
+    pub(crate) fn last_pkid(&self) -> PacketIdentifier {
+        PacketIdentifier(self.last_pkid)
+    }
+
+    /// Unacked publishes. These are replayed (as duplicates) after the connack
+    pub(crate) fn publishes(&self) -> Vec<Publish> {
+        self.publishes
+            .iter()
+            .filter_map(|publish| {
+                let qos = QoS::from_u8(publish.qos).ok()?;
+                Some(Publish {
+                    dup: true,
+                    qos,
+                    retain: publish.retain,
+                    pkid: Some(PacketIdentifier(publish.pkid)),
+                    topic_name: publish.topic.clone(),
+                    payload: Arc::new(publish.payload.clone()),
+                })
+            })
+            .collect()
+    }
+
+    /// Pubrels which aren't completed by a pubcomp
+    pub(crate) fn releases(&self) -> Vec<PacketIdentifier> {
+        self.releases.iter().cloned().map(PacketIdentifier).collect()
+    }
+
+    /// Incoming qos2 publishes which are delivered but not released
+    pub(crate) fn incoming(&self) -> &[u16] {
+        &self.incoming
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SessionState, SESSION_VERSION};
+    use crate::error::OptionsError;
+    use crate::mqttoptions::MqttOptions;
+    use mqtt311::{PacketIdentifier, Publish, QoS};
+    use std::sync::Arc;
+
+    #[test]
+    fn publishes_should_survive_the_snapshot() {
+        let publish = Publish {
+            dup: false,
+            qos: QoS::ExactlyOnce,
+            retain: true,
+            pkid: Some(PacketIdentifier(7)),
+            topic_name: "hello/world".to_owned(),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        let session = SessionState::new(PacketIdentifier(9), &[publish.clone()], &[PacketIdentifier(8)], &[3]);
+        assert_eq!(session.version(), SESSION_VERSION);
+        assert_eq!(session.last_pkid(), PacketIdentifier(9));
+        assert_eq!(session.publishes(), vec![Publish { dup: true, ..publish }]);
+        assert_eq!(session.releases(), vec![PacketIdentifier(8)]);
+        assert_eq!(session.incoming(), &[3]);
+    }
+
+    #[test]
+    fn snapshots_of_other_versions_should_be_refused() {
+        let session = SessionState::new(PacketIdentifier(1), &[], &[], &[]);
+        let old = SessionState { version: SESSION_VERSION + 1, ..session.clone() };
+        let options = MqttOptions::new("id", "127.0.0.1", 1883);
+
+        assert_eq!(options.clone().try_set_initial_session(old).unwrap_err(), OptionsError::UnsupportedSessionVersion(SESSION_VERSION + 1));
+        assert_eq!(options.set_initial_session(session.clone()).initial_session(), Some(&session));
+    }
+}
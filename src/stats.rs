@@ -0,0 +1,280 @@
+//! Connection and traffic counters of the eventloop. With the `metrics` feature, updates
+//! are also recorded with the `metrics` facade and labelled with the client id
+use mqtt311::QoS;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Counters updated by the eventloop. Cumulative over reconnections
+#[derive(Debug, Default)]
+pub struct Stats {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    publishes_sent: [AtomicU64; 3],
+    publishes_received: [AtomicU64; 3],
+    acks_outstanding: AtomicU64,
+    queued_publishes: AtomicU64,
+    queued_bytes: AtomicU64,
+    publishes_dropped: AtomicU64,
+    pings_sent: AtomicU64,
+    reconnects: AtomicU64,
+    last_connack: Mutex<Option<SystemTime>>,
+    ping_rtt: Mutex<Option<RttCounter>>,
+    #[cfg(feature = "metrics")]
+    client_id: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RttCounter {
+    last: Duration,
+    min: Duration,
+    max: Duration,
+    total: Duration,
+    samples: u32,
+}
+
+/// Round trip times of the pings answered by the broker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingRtt {
+    pub last: Duration,
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+    /// Pingresps received
+    pub samples: u32,
+}
+
+/// Copy of the counters at an instant. Per qos counters are indexed by qos
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub publishes_sent: [u64; 3],
+    pub publishes_received: [u64; 3],
+    /// QoS1 & 2 messages which aren't completely acknowledged yet
+    pub acks_outstanding: u64,
+    /// Outgoing qos1 & 2 publishes waiting for their ack and their size in bytes
+    pub queued_publishes: u64,
+    pub queued_bytes: u64,
+    /// QoS0 publishes dropped as per `InflightOverflow::DropQos0`
+    pub publishes_dropped: u64,
+    pub pings_sent: u64,
+    /// Successful connections after the first one
+    pub reconnects: u64,
+    /// Time of the last successful connack
+    pub last_connack: Option<SystemTime>,
+    /// `None` till the first pingresp
+    pub ping_rtt: Option<PingRtt>,
+}
+
+impl Stats {
+    pub(crate) fn new(_client_id: &str) -> Stats {
+        Stats {
+            #[cfg(feature = "metrics")]
+            client_id: _client_id.to_owned(),
+            ..Stats::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        StatsSnapshot {
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+            publishes_sent: [load(&self.publishes_sent[0]), load(&self.publishes_sent[1]), load(&self.publishes_sent[2])],
+            publishes_received: [
+                load(&self.publishes_received[0]),
+                load(&self.publishes_received[1]),
+                load(&self.publishes_received[2]),
+            ],
+            acks_outstanding: load(&self.acks_outstanding),
+            queued_publishes: load(&self.queued_publishes),
+            queued_bytes: load(&self.queued_bytes),
+            publishes_dropped: load(&self.publishes_dropped),
+            pings_sent: load(&self.pings_sent),
+            reconnects: load(&self.reconnects),
+            last_connack: *self.last_connack.lock().unwrap_or_else(|e| e.into_inner()),
+            ping_rtt: self.ping_rtt.lock().unwrap_or_else(|e| e.into_inner()).map(|rtt| PingRtt {
+                last: rtt.last,
+                min: rtt.min,
+                max: rtt.max,
+                avg: rtt.total / rtt.samples,
+                samples: rtt.samples,
+            }),
+        }
+    }
+
+    /// Zeroes all the counters
+    pub fn reset(&self) {
+        let counters = self.publishes_sent.iter().chain(self.publishes_received.iter());
+        let counters = counters.chain(vec![
+            &self.bytes_sent,
+            &self.bytes_received,
+            &self.acks_outstanding,
+            &self.queued_publishes,
+            &self.queued_bytes,
+            &self.publishes_dropped,
+            &self.pings_sent,
+            &self.reconnects,
+        ]);
+        for counter in counters {
+            counter.store(0, Ordering::Relaxed);
+        }
+
+        *self.last_connack.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        *self.ping_rtt.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    pub(crate) fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_bytes_sent_total", bytes as u64, "client_id" => self.client_id.clone());
+    }
+
+    pub(crate) fn add_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_bytes_received_total", bytes as u64, "client_id" => self.client_id.clone());
+    }
+
+    pub(crate) fn publish_sent(&self, qos: QoS) {
+        self.publishes_sent[qos.to_u8() as usize].fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.record_publish("outgoing", qos);
+    }
+
+    pub(crate) fn publish_received(&self, qos: QoS) {
+        self.publishes_received[qos.to_u8() as usize].fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.record_publish("incoming", qos);
+    }
+
+    pub(crate) fn set_acks_outstanding(&self, count: usize) {
+        self.acks_outstanding.store(count as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("mqtt_acks_outstanding", count as f64, "client_id" => self.client_id.clone());
+    }
+
+    pub(crate) fn set_queue(&self, publishes: usize, bytes: usize) {
+        self.queued_publishes.store(publishes as u64, Ordering::Relaxed);
+        self.queued_bytes.store(bytes as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        {
+            metrics::gauge!("mqtt_queued_publishes", publishes as f64, "client_id" => self.client_id.clone());
+            metrics::gauge!("mqtt_queued_bytes", bytes as f64, "client_id" => self.client_id.clone());
+        }
+    }
+
+    pub(crate) fn publish_dropped(&self) {
+        self.publishes_dropped.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_publishes_dropped_total", 1, "client_id" => self.client_id.clone());
+    }
+
+    pub(crate) fn ping_sent(&self) {
+        self.pings_sent.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_pings_sent_total", 1, "client_id" => self.client_id.clone());
+    }
+
+    pub(crate) fn pingresp_received(&self, rtt: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::gauge!("mqtt_ping_rtt_seconds", rtt.as_secs_f64(), "client_id" => self.client_id.clone());
+
+        let mut counter = self.ping_rtt.lock().unwrap_or_else(|e| e.into_inner());
+        let counter = counter.get_or_insert(RttCounter { last: rtt, min: rtt, max: rtt, total: Duration::from_secs(0), samples: 0 });
+
+        // average restarts instead of overflowing
+        if counter.samples == u32::max_value() {
+            counter.total = Duration::from_secs(0);
+            counter.samples = 0;
+        }
+
+        counter.last = rtt;
+        counter.min = counter.min.min(rtt);
+        counter.max = counter.max.max(rtt);
+        counter.total += rtt;
+        counter.samples += 1;
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_reconnects_total", 1, "client_id" => self.client_id.clone());
+    }
+
+    /// Notifications which didn't fit in the channel of the user
+    pub(crate) fn notification_dropped(&self) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_notifications_dropped_total", 1, "client_id" => self.client_id.clone());
+    }
+
+    /// Connection attempts after a failure or a disconnection
+    pub(crate) fn reconnect_attempt(&self) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("mqtt_reconnect_attempts_total", 1, "client_id" => self.client_id.clone());
+    }
+
+    #[cfg(feature = "metrics")]
+    fn record_publish(&self, direction: &'static str, qos: QoS) {
+        let qos = match qos {
+            QoS::AtMostOnce => "0",
+            QoS::AtLeastOnce => "1",
+            QoS::ExactlyOnce => "2",
+        };
+
+        metrics::counter!("mqtt_publishes_total", 1, "client_id" => self.client_id.clone(), "direction" => direction, "qos" => qos);
+    }
+
+    pub(crate) fn connack_received(&self) {
+        *self.last_connack.lock().unwrap_or_else(|e| e.into_inner()) = Some(SystemTime::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PingRtt, Stats};
+    use mqtt311::QoS;
+    use std::time::Duration;
+
+    #[test]
+    fn reset_should_zero_the_counters() {
+        let stats = Stats::default();
+        stats.add_bytes_sent(10);
+        stats.publish_sent(QoS::ExactlyOnce);
+        stats.publish_received(QoS::AtMostOnce);
+        stats.connack_received();
+        stats.set_queue(2, 100);
+        stats.publish_dropped();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.bytes_sent, 10);
+        assert_eq!(snapshot.publishes_sent, [0, 0, 1]);
+        assert_eq!(snapshot.publishes_received, [1, 0, 0]);
+        assert!(snapshot.last_connack.is_some());
+        assert_eq!((snapshot.queued_publishes, snapshot.queued_bytes), (2, 100));
+        assert_eq!(snapshot.publishes_dropped, 1);
+
+        stats.reset();
+        assert_eq!(stats.snapshot(), Default::default());
+    }
+
+    #[test]
+    fn ping_round_trips_should_be_summarized() {
+        let stats = Stats::default();
+        assert_eq!(stats.snapshot().ping_rtt, None);
+
+        for millis in [30, 10, 20].iter() {
+            stats.pingresp_received(Duration::from_millis(*millis));
+        }
+
+        let millis = Duration::from_millis;
+        let rtt = PingRtt { last: millis(20), min: millis(10), max: millis(30), avg: millis(20), samples: 3 };
+        assert_eq!(stats.snapshot().ping_rtt, Some(rtt));
+    }
+}
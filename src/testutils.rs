@@ -0,0 +1,495 @@
+//! Scriptable broker on a local port and in-memory transport to test the eventloop
+//! without a real broker (`test-helpers` feature)
+use crate::codec::MqttCodec;
+use crate::error::ConnectError;
+use crate::mqttoptions::{AsyncStream, MqttOptions};
+use crate::topic;
+use bytes::BytesMut;
+use crossbeam_channel::{self, Receiver};
+use futures::{future, task::{self, Task}, Async, Poll};
+use mqtt311::{
+    self, Connack, ConnectReturnCode, MqttRead, MqttWrite, Packet, Publish, QoS, Suback, SubscribeReturnCodes,
+};
+use std::{
+    cmp,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::codec::{Decoder, Encoder};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Behaviour of the broker for the next connections and packets
+#[derive(Debug, Clone, Default)]
+struct Script {
+    refuse: Option<u8>,
+    drop_after: Option<usize>,
+    ack_delay: Duration,
+    session_present: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    script: Script,
+    received: Vec<Packet>,
+    connections: usize,
+}
+
+/// Mqtt 3.1.1 broker which answers connect, subscribe, unsubscribe, publish (all qos)
+/// and ping. Publishes are sent back (as qos0) to a client subscribed to their topic.
+/// Every accepted connection is served by a thread of its own
+#[derive(Debug, Clone)]
+pub struct MockBroker {
+    port: u16,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockBroker {
+    /// Starts the broker on a random local port
+    pub fn start() -> io::Result<MockBroker> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let broker_state = state.clone();
+        thread::Builder::new().name("rumqtt-mock-broker".to_owned()).spawn(move || {
+            for stream in listener.incoming().filter_map(Result::ok) {
+                let state = broker_state.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, state) {
+                        debug!("Mock broker connection closed. Error = {:?}", e);
+                    }
+                });
+            }
+        })?;
+
+        Ok(MockBroker { port, state })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Refuses the next connects with the connack return `code`
+    pub fn refuse_connect(&self, code: u8) {
+        self.state().script.refuse = Some(code);
+    }
+
+    /// Accepts the next connects again
+    pub fn accept_connect(&self) {
+        self.state().script.refuse = None;
+    }
+
+    /// Closes the next connection once it received `packets` packets (connect
+    /// included). The last packet isn't answered
+    pub fn drop_connection_after(&self, packets: usize) {
+        self.state().script.drop_after = Some(packets);
+    }
+
+    /// Delays acks (connack and pingresp excluded) by `delay`
+    pub fn delay_acks(&self, delay: Duration) {
+        self.state().script.ack_delay = delay;
+    }
+
+    /// Session present flag of the next connacks
+    pub fn set_session_present(&self, session_present: bool) {
+        self.state().script.session_present = session_present;
+    }
+
+    /// All the packets received across connections, in order
+    pub fn received(&self) -> Vec<Packet> {
+        self.state().received.clone()
+    }
+
+    /// Number of connects received
+    pub fn connections(&self) -> usize {
+        self.state().connections
+    }
+
+    /// Waits till the received packets satisfy `condition`. False when `timeout` elapses first
+    pub fn wait_until<F: Fn(&[Packet]) -> bool>(&self, timeout: Duration, condition: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition(&self.state().received) {
+            if Instant::now() > deadline {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        true
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn serve(mut stream: TcpStream, state: Arc<Mutex<State>>) -> mqtt311::Result<()> {
+    let mut subscriptions: Vec<String> = Vec::new();
+    let mut drop_after = None;
+    let mut count = 0;
+
+    loop {
+        let packet = stream.read_packet()?;
+        count += 1;
+
+        let script = {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            state.received.push(packet.clone());
+            if let Packet::Connect(_) = packet {
+                state.connections += 1;
+                drop_after = state.script.drop_after.take();
+            }
+
+            state.script.clone()
+        };
+
+        if drop_after == Some(count) {
+            stream.shutdown(Shutdown::Both)?;
+            return Ok(());
+        }
+
+        let ack = match packet {
+            Packet::Connect(_) => {
+                let (session_present, code) = match script.refuse {
+                    Some(code) => (false, ConnectReturnCode::from_u8(code)?),
+                    None => (script.session_present, ConnectReturnCode::Accepted),
+                };
+
+                stream.write_packet(&Packet::Connack(Connack { session_present, code }))?;
+                if code != ConnectReturnCode::Accepted {
+                    return Ok(());
+                }
+
+                continue;
+            }
+            Packet::Pingreq => {
+                stream.write_packet(&Packet::Pingresp)?;
+                continue;
+            }
+            Packet::Disconnect => return Ok(()),
+            Packet::Subscribe(subscribe) => {
+                let return_codes = subscribe.topics.iter().map(|topic| SubscribeReturnCodes::Success(topic.qos)).collect();
+                subscriptions.extend(subscribe.topics.into_iter().map(|topic| topic.topic_path));
+                Packet::Suback(Suback { pkid: subscribe.pkid, return_codes })
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                subscriptions.retain(|filter| !unsubscribe.topics.contains(filter));
+                Packet::Unsuback(unsubscribe.pkid)
+            }
+            Packet::Publish(publish) => {
+                if subscriptions.iter().any(|filter| topic::matches(filter, &publish.topic_name)) {
+                    let echo = Publish { dup: false, qos: QoS::AtMostOnce, retain: false, pkid: None, ..publish.clone() };
+                    stream.write_packet(&Packet::Publish(echo))?;
+                }
+
+                match (publish.qos, publish.pkid) {
+                    (QoS::AtLeastOnce, Some(pkid)) => Packet::Puback(pkid),
+                    (QoS::ExactlyOnce, Some(pkid)) => Packet::Pubrec(pkid),
+                    _ => continue,
+                }
+            }
+            Packet::Pubrel(pkid) => Packet::Pubcomp(pkid),
+            _ => continue,
+        };
+
+        if script.ack_delay == Duration::from_secs(0) {
+            stream.write_packet(&ack)?;
+            continue;
+        }
+
+        let mut writer = stream.try_clone()?;
+        thread::spawn(move || {
+            thread::sleep(script.ack_delay);
+            let _ = writer.write_packet(&ack);
+        });
+    }
+}
+
+/// Bytes in one direction of an in-memory pipe
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    // bytes the pipe holds before writes block. unbounded when `None`
+    capacity: Option<usize>,
+    // eventloop task waiting for bytes
+    reader: Option<Task>,
+    // eventloop task waiting for room
+    writer: Option<Task>,
+}
+
+impl Pipe {
+    fn read(&mut self, to: &mut [u8]) -> usize {
+        let len = cmp::min(self.buf.len(), to.len());
+        for (to, from) in to.iter_mut().zip(self.buf.drain(..len)) {
+            *to = from;
+        }
+
+        if let Some(task) = self.writer.take() {
+            task.notify();
+        }
+
+        len
+    }
+}
+
+#[derive(Debug, Default)]
+struct Channel {
+    pipe: Mutex<Pipe>,
+    readable: Condvar,
+}
+
+impl Channel {
+    fn bounded(capacity: usize) -> Channel {
+        let pipe = Pipe { capacity: Some(capacity), ..Pipe::default() };
+        Channel { pipe: Mutex::new(pipe), readable: Condvar::new() }
+    }
+
+    fn pipe(&self) -> MutexGuard<'_, Pipe> {
+        self.pipe.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes what fits in the pipe. Only the eventloop task writes to a bounded
+    /// pipe. Task is notified when there is room again
+    fn write(&self, data: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.pipe();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let len = match pipe.capacity {
+            Some(capacity) => cmp::min(data.len(), capacity.saturating_sub(pipe.buf.len())),
+            None => data.len(),
+        };
+
+        if len == 0 && !data.is_empty() {
+            pipe.writer = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        pipe.buf.extend(&data[..len]);
+        self.wake(&mut pipe);
+        Ok(len)
+    }
+
+    fn close(&self) {
+        let mut pipe = self.pipe();
+        pipe.closed = true;
+        self.wake(&mut pipe);
+    }
+
+    fn wake(&self, pipe: &mut Pipe) {
+        if let Some(task) = pipe.reader.take() {
+            task.notify();
+        }
+
+        self.readable.notify_all();
+    }
+
+    /// Read of a futures task. Task is notified when there are bytes to read
+    fn poll_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pipe = self.pipe();
+        if pipe.buf.is_empty() && !pipe.closed {
+            pipe.reader = Some(task::current());
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+
+        Ok(pipe.read(buf))
+    }
+
+    /// Blocking read of a test thread. Zero at the end of the stream
+    fn read_timeout(&self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let mut pipe = self.pipe();
+        while pipe.buf.is_empty() && !pipe.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+
+            pipe = match self.readable.wait_timeout(pipe, deadline - now) {
+                Ok((pipe, _)) => pipe,
+                Err(e) => e.into_inner().0,
+            };
+        }
+
+        Ok(pipe.read(buf))
+    }
+}
+
+/// Client's end of an in-memory pipe. Closes both directions when dropped
+#[derive(Debug)]
+pub struct DuplexStream {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+}
+
+impl Read for DuplexStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.incoming.poll_read(buf)
+    }
+}
+
+impl Write for DuplexStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for DuplexStream {}
+impl AsyncWrite for DuplexStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.outgoing.close();
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+}
+
+/// Test's end of an in-memory pipe. Reads and writes exact bytes (or packets through
+/// `MqttCodec`) with blocking calls. Closes both directions when dropped
+#[derive(Debug)]
+pub struct DuplexEnd {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+    codec: MqttCodec,
+    // bytes read from the pipe but not returned yet
+    buf: BytesMut,
+}
+
+impl DuplexEnd {
+    /// Writes `bytes` to the client as they are. Write partial packets to test framing
+    pub fn write_all(&self, bytes: &[u8]) -> io::Result<()> {
+        self.outgoing.write(bytes).map(|_| ())
+    }
+
+    /// Encodes `packet` and writes it to the client
+    pub fn send(&mut self, packet: Packet) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        self.codec.encode(packet, &mut buf)?;
+        self.write_all(&buf)
+    }
+
+    /// Reads exactly `len` bytes written by the client
+    pub fn read_exact(&mut self, len: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        while self.buf.len() < len {
+            if !self.fill(deadline)? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(self.buf.split_to(len).to_vec())
+    }
+
+    /// Next packet written by the client. `None` once the client closed the connection
+    pub fn next_packet(&mut self, timeout: Duration) -> io::Result<Option<Packet>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(packet) = self.codec.decode(&mut self.buf)? {
+                return Ok(Some(packet));
+            }
+
+            if !self.fill(deadline)? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Closes the connection like a broker (or network) would
+    pub fn close(&self) {
+        self.incoming.close();
+        self.outgoing.close();
+    }
+
+    /// Reads available bytes into the buffer. False at the end of the stream
+    fn fill(&mut self, deadline: Instant) -> io::Result<bool> {
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        let mut buf = [0; 1024];
+        let len = self.incoming.read_timeout(&mut buf, timeout)?;
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len > 0)
+    }
+}
+
+impl Drop for DuplexEnd {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// In-memory pipe. Bytes written to one end are read from the other
+pub fn duplex() -> (DuplexStream, DuplexEnd) {
+    pipes(Channel::default())
+}
+
+/// In-memory pipe which holds at most `capacity` bytes of the client. Writes of the
+/// client block till the test reads. Emulates a slow broker (or network)
+pub fn bounded_duplex(capacity: usize) -> (DuplexStream, DuplexEnd) {
+    pipes(Channel::bounded(capacity))
+}
+
+fn pipes(outgoing: Channel) -> (DuplexStream, DuplexEnd) {
+    let (incoming, outgoing) = (Arc::new(Channel::default()), Arc::new(outgoing));
+    let stream = DuplexStream { incoming: incoming.clone(), outgoing: outgoing.clone() };
+    let end = DuplexEnd { incoming: outgoing, outgoing: incoming, codec: MqttCodec::new(), buf: BytesMut::new() };
+    (stream, end)
+}
+
+/// Test's side of the connections of a client with an in-memory transport
+#[derive(Debug)]
+pub struct DuplexHandle {
+    connections: Receiver<DuplexEnd>,
+}
+
+impl DuplexHandle {
+    /// Next connection of the client. `None` when none is made within `timeout`
+    pub fn accept(&self, timeout: Duration) -> Option<DuplexEnd> {
+        self.connections.recv_timeout(timeout).ok()
+    }
+}
+
+/// Connects the client of `mqttoptions` over a new in-memory pipe for every connection
+/// attempt. The test ends of the pipes are handed over by the returned handle
+pub fn in_memory_transport(mqttoptions: MqttOptions) -> (MqttOptions, DuplexHandle) {
+    transport(mqttoptions, duplex)
+}
+
+/// `in_memory_transport` over pipes which hold at most `capacity` bytes of the client.
+/// See `bounded_duplex`
+pub fn bounded_in_memory_transport(mqttoptions: MqttOptions, capacity: usize) -> (MqttOptions, DuplexHandle) {
+    transport(mqttoptions, move || bounded_duplex(capacity))
+}
+
+fn transport<F>(mqttoptions: MqttOptions, duplex: F) -> (MqttOptions, DuplexHandle)
+where
+    F: Fn() -> (DuplexStream, DuplexEnd) + Send + Sync + 'static,
+{
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let mqttoptions = mqttoptions.set_custom_transport(move || {
+        let (stream, end) = duplex();
+        let stream = match tx.send(end) {
+            Ok(_) => Ok(Box::new(stream) as Box<dyn AsyncStream>),
+            Err(_) => Err(ConnectError::Io(io::ErrorKind::ConnectionRefused.into())),
+        };
+
+        Box::new(future::result(stream))
+    });
+
+    (mqttoptions, DuplexHandle { connections: rx })
+}
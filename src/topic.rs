@@ -0,0 +1,170 @@
+//! Validation and matching of mqtt topic names and topic filters as per mqtt 3.1.1
+//! (section 4.7). Levels are separated by `/` and can be empty
+
+use crate::error::TopicError;
+
+/// Maximum length of a topic (or filter) in bytes
+const MAX_TOPIC_LEN: usize = 65535;
+
+/// Checks if a topic name is valid to publish to. Topic names should be non
+/// empty and shouldn't contain wildcards or null characters
+pub fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    validate_common(topic)?;
+    if topic.contains(|c: char| c == '+' || c == '#') {
+        return Err(TopicError::Wildcard(topic.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Checks if a topic filter is valid to subscribe to. `+` should occupy an
+/// entire level and `#` should occupy the entire last level of the filter
+pub fn validate_filter(filter: &str) -> Result<(), TopicError> {
+    validate_common(filter)?;
+
+    let levels: Vec<&str> = filter.split('/').collect();
+    let last = levels.len() - 1;
+    for (index, level) in levels.iter().enumerate() {
+        match *level {
+            "#" if index != last => return Err(TopicError::MisplacedWildcard(filter.to_owned())),
+            "#" | "+" => (),
+            level if level.contains(|c: char| c == '+' || c == '#') => return Err(TopicError::MisplacedWildcard(filter.to_owned())),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_common(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    if topic.len() > MAX_TOPIC_LEN {
+        return Err(TopicError::TooLong(topic.len()));
+    }
+
+    if topic.contains('\0') {
+        return Err(TopicError::NullCharacter(topic.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Same as `validate_topic` without the reason
+pub fn valid_topic(topic: &str) -> bool {
+    validate_topic(topic).is_ok()
+}
+
+/// Same as `validate_filter` without the reason
+pub fn valid_filter(filter: &str) -> bool {
+    validate_filter(filter).is_ok()
+}
+
+/// Checks if a topic name matches a (valid) topic filter. `+` matches exactly one
+/// level and `#` matches the parent level and any number of child levels. Topics
+/// starting with `$` aren't matched by filters starting with a wildcard
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => (),
+            (Some(f), Some(t)) if f == t => (),
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, validate_filter, validate_topic};
+    use crate::error::TopicError;
+
+    #[test]
+    fn topics_should_be_validated_as_per_spec() {
+        let long = "a".repeat(65536);
+        let cases = vec![
+            ("sensors/room1/temp", Ok(())),
+            ("/", Ok(())),
+            ("sensors//temp", Ok(())),
+            ("$SYS/uptime", Ok(())),
+            ("sensors temp", Ok(())),
+            ("", Err(TopicError::Empty)),
+            ("sensors/+/temp", Err(TopicError::Wildcard("sensors/+/temp".to_owned()))),
+            ("sensors/#", Err(TopicError::Wildcard("sensors/#".to_owned()))),
+            ("sensors\0/temp", Err(TopicError::NullCharacter("sensors\0/temp".to_owned()))),
+            (long.as_str(), Err(TopicError::TooLong(65536))),
+        ];
+
+        for (topic, expected) in cases {
+            assert_eq!(validate_topic(topic), expected, "topic = {:?}", topic);
+        }
+    }
+
+    #[test]
+    fn filters_should_be_validated_as_per_spec() {
+        let misplaced = |filter: &str| Err(TopicError::MisplacedWildcard(filter.to_owned()));
+        let cases = vec![
+            ("sensors/+/temp", Ok(())),
+            ("sensors/#", Ok(())),
+            ("#", Ok(())),
+            ("+", Ok(())),
+            ("+/+", Ok(())),
+            ("/+", Ok(())),
+            ("sensors//+", Ok(())),
+            ("$SYS/#", Ok(())),
+            ("", Err(TopicError::Empty)),
+            ("sensors/#/temp", misplaced("sensors/#/temp")),
+            ("sensors/room+/temp", misplaced("sensors/room+/temp")),
+            ("sensors#", misplaced("sensors#")),
+            ("#/", misplaced("#/")),
+            ("++", misplaced("++")),
+            ("a\0/#", Err(TopicError::NullCharacter("a\0/#".to_owned()))),
+        ];
+
+        for (filter, expected) in cases {
+            assert_eq!(validate_filter(filter), expected, "filter = {:?}", filter);
+        }
+    }
+
+    #[test]
+    fn filters_should_match_topics_as_per_spec() {
+        let cases = vec![
+            ("sport/tennis/player1", "sport/tennis/player1", true),
+            ("sport/tennis/+", "sport/tennis/player1", true),
+            ("sport/+/player1", "sport/tennis/player1", true),
+            ("sport/#", "sport", true),
+            ("sport/#", "sport/tennis/player1", true),
+            ("sport/tennis/#", "sport/tennis", true),
+            ("#", "sport/tennis", true),
+            ("#", "/", true),
+            ("sport/+", "sport/", true),
+            ("+/+", "/finance", true),
+            ("/+", "/finance", true),
+            ("sport/+/player1", "sport//player1", true),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("sport/+", "sport", false),
+            ("sport/+", "sport/tennis/player1", false),
+            ("sport/tennis", "sport/tennis/player1", false),
+            ("sport/tennis/player1", "sport/tennis", false),
+            ("sport/tennis", "Sport/Tennis", false),
+            ("+", "/finance", false),
+            ("#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+        ];
+
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(filter, topic), expected, "filter = {:?}, topic = {:?}", filter, topic);
+        }
+    }
+}
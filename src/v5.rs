@@ -0,0 +1,743 @@
+//! Mqtt 5 properties and reason codes (`mqtt5` feature). Connections of `Protocol::MQTT5`
+//! still hand mqtt311 packets to the eventloop. Parts of the packets which those can't
+//! carry (properties, reason codes) go through a `Context` shared by the codec, the state
+//! and the client. Topic aliases, subscription options, enhanced authentication and the
+//! properties of subscribes, unsubscribes and last wills aren't supported
+use bytes::{BufMut, BytesMut};
+use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Publish, QoS, Suback, SubscribeReturnCodes};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// Maximum remaining length of a packet
+const MAX_REMAINING_LEN: usize = 268_435_455;
+
+/// Properties of connect, connack and publish packets. `None` properties aren't sent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Properties {
+    /// Payload is utf8 (1) or unspecified bytes (0). Publish
+    pub payload_format_indicator: Option<u8>,
+    /// Seconds after which the broker drops the message if it isn't delivered. Publish
+    pub message_expiry_interval: Option<u32>,
+    /// Mime type of the payload. Publish
+    pub content_type: Option<String>,
+    /// Topic for the response of a request. Publish
+    pub response_topic: Option<String>,
+    /// Matches a response to its request. Publish
+    pub correlation_data: Option<Vec<u8>>,
+    /// Seconds the broker keeps the session after the connection closes. Connect and connack
+    pub session_expiry_interval: Option<u32>,
+    /// Client id picked by the broker for an empty client id. Connack
+    pub assigned_client_identifier: Option<String>,
+    /// Keep alive which the broker wants instead of the one of the connect. Connack
+    pub server_keep_alive: Option<u16>,
+    /// Human readable reason of a reason code. Connack and acks
+    pub reason_string: Option<String>,
+    /// Limit of unacknowledged qos1 and qos2 publishes. Connect and connack
+    pub receive_maximum: Option<u16>,
+    /// Connack. The client doesn't use topic aliases (don't set it in the connect)
+    pub topic_alias_maximum: Option<u16>,
+    /// Highest qos the broker supports. Connack
+    pub maximum_qos: Option<u8>,
+    /// Broker supports retained messages (1) or not (0). Connack
+    pub retain_available: Option<u8>,
+    /// Largest packet the sender accepts. Connect and connack
+    pub maximum_packet_size: Option<u32>,
+    /// Name value pairs of the application
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Properties {
+    fn is_empty(&self) -> bool {
+        *self == Properties::default()
+    }
+
+    /// Encoded size of the properties (length included). `None` when a property is
+    /// longer than 65535 bytes
+    pub(crate) fn encoded_len(&self) -> Option<usize> {
+        let mut buf = Vec::new();
+        self.write(&mut buf).ok()?;
+        Some(buf.len())
+    }
+
+    /// Writes the length of the properties followed by the properties
+    fn write(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut properties = Vec::new();
+        if let Some(format) = self.payload_format_indicator {
+            properties.push(0x01);
+            properties.push(format);
+        }
+        if let Some(expiry) = self.message_expiry_interval {
+            properties.push(0x02);
+            properties.extend_from_slice(&expiry.to_be_bytes());
+        }
+        if let Some(content_type) = self.content_type.as_ref() {
+            properties.push(0x03);
+            write_string(&mut properties, content_type)?;
+        }
+        if let Some(topic) = self.response_topic.as_ref() {
+            properties.push(0x08);
+            write_string(&mut properties, topic)?;
+        }
+        if let Some(data) = self.correlation_data.as_ref() {
+            properties.push(0x09);
+            write_binary(&mut properties, data)?;
+        }
+        if let Some(expiry) = self.session_expiry_interval {
+            properties.push(0x11);
+            properties.extend_from_slice(&expiry.to_be_bytes());
+        }
+        if let Some(client_id) = self.assigned_client_identifier.as_ref() {
+            properties.push(0x12);
+            write_string(&mut properties, client_id)?;
+        }
+        if let Some(keep_alive) = self.server_keep_alive {
+            properties.push(0x13);
+            properties.extend_from_slice(&keep_alive.to_be_bytes());
+        }
+        if let Some(reason) = self.reason_string.as_ref() {
+            properties.push(0x1F);
+            write_string(&mut properties, reason)?;
+        }
+        if let Some(maximum) = self.receive_maximum {
+            properties.push(0x21);
+            properties.extend_from_slice(&maximum.to_be_bytes());
+        }
+        if let Some(maximum) = self.topic_alias_maximum {
+            properties.push(0x22);
+            properties.extend_from_slice(&maximum.to_be_bytes());
+        }
+        if let Some(qos) = self.maximum_qos {
+            properties.push(0x24);
+            properties.push(qos);
+        }
+        if let Some(available) = self.retain_available {
+            properties.push(0x25);
+            properties.push(available);
+        }
+        for (key, value) in self.user_properties.iter() {
+            properties.push(0x26);
+            write_string(&mut properties, key)?;
+            write_string(&mut properties, value)?;
+        }
+        if let Some(size) = self.maximum_packet_size {
+            properties.push(0x27);
+            properties.extend_from_slice(&size.to_be_bytes());
+        }
+
+        write_varint(out, properties.len())?;
+        out.extend_from_slice(&properties);
+        Ok(())
+    }
+
+    /// Reads the length of the properties and the properties. Properties which aren't
+    /// supported are skipped
+    fn read(buf: &mut &[u8]) -> io::Result<Properties> {
+        let len = read_varint(buf)?;
+        let mut buf = take(buf, len)?;
+        let buf = &mut buf;
+
+        let mut properties = Properties::default();
+        while !buf.is_empty() {
+            match read_varint(buf)? {
+                0x01 => properties.payload_format_indicator = Some(read_u8(buf)?),
+                0x02 => properties.message_expiry_interval = Some(read_u32(buf)?),
+                0x03 => properties.content_type = Some(read_string(buf)?),
+                0x08 => properties.response_topic = Some(read_string(buf)?),
+                0x09 => properties.correlation_data = Some(read_binary(buf)?.to_vec()),
+                0x11 => properties.session_expiry_interval = Some(read_u32(buf)?),
+                0x12 => properties.assigned_client_identifier = Some(read_string(buf)?),
+                0x13 => properties.server_keep_alive = Some(read_u16(buf)?),
+                0x1F => properties.reason_string = Some(read_string(buf)?),
+                0x21 => properties.receive_maximum = Some(read_u16(buf)?),
+                0x22 => properties.topic_alias_maximum = Some(read_u16(buf)?),
+                0x24 => properties.maximum_qos = Some(read_u8(buf)?),
+                0x25 => properties.retain_available = Some(read_u8(buf)?),
+                0x26 => {
+                    let key = read_string(buf)?;
+                    let value = read_string(buf)?;
+                    properties.user_properties.push((key, value));
+                }
+                0x27 => properties.maximum_packet_size = Some(read_u32(buf)?),
+                // subscription identifier
+                0x0B => {
+                    read_varint(buf)?;
+                }
+                // topic alias
+                0x23 => {
+                    read_u16(buf)?;
+                }
+                // will delay interval
+                0x18 => {
+                    read_u32(buf)?;
+                }
+                // authentication method, response information and server reference
+                0x15 | 0x1A | 0x1C => {
+                    read_string(buf)?;
+                }
+                // authentication data
+                0x16 => {
+                    read_binary(buf)?;
+                }
+                // requests and the availability of wildcard, shared and identified subscriptions
+                0x17 | 0x19 | 0x28 | 0x29 | 0x2A => {
+                    read_u8(buf)?;
+                }
+                id => {
+                    error!("Unknown property. Id = {:#04x}", id);
+                    return Err(malformed("Unknown property"));
+                }
+            }
+        }
+
+        Ok(properties)
+    }
+}
+
+/// Reason codes of 0x80 and above tell that the request failed
+pub fn is_failure(reason_code: u8) -> bool {
+    reason_code >= 0x80
+}
+
+/// Parts of the mqtt 5 packets of a client which mqtt311's packets can't carry. The codec
+/// fills it while decoding and reads it while encoding
+#[derive(Debug, Default)]
+pub struct Context {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // properties of the next connect
+    connect: Properties,
+    // reason code and properties of the last connack
+    connack: Option<(u8, Properties)>,
+    // reason codes and reason strings of the incoming acks which aren't plain successes.
+    // by packet type and packet id
+    acks: HashMap<(u8, u16), (u8, Option<String>)>,
+    // properties of incoming and outgoing publishes by the address of their payload
+    publishes: HashMap<usize, (Weak<Vec<u8>>, Properties)>,
+}
+
+impl Context {
+    fn inner(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts a connection which sends the `connect` properties. Forgets the connack and
+    /// the acks of the previous connection
+    pub(crate) fn reset(&self, connect: Properties) {
+        let mut inner = self.inner();
+        inner.connect = connect;
+        inner.connack = None;
+        inner.acks.clear();
+    }
+
+    fn connect_properties(&self) -> Properties {
+        self.inner().connect.clone()
+    }
+
+    /// Reason code and properties of the last connack
+    pub fn connack(&self) -> Option<(u8, Properties)> {
+        self.inner().connack.clone()
+    }
+
+    fn set_connack(&self, reason_code: u8, properties: Properties) {
+        self.inner().connack = Some((reason_code, properties));
+    }
+
+    /// Properties of an incoming publish or of a publish sent with properties. Found as
+    /// long as the payload of the publish is alive
+    pub fn publish_properties(&self, publish: &Publish) -> Option<Properties> {
+        let inner = self.inner();
+        let (payload, properties) = inner.publishes.get(&address(&publish.payload))?;
+        match payload.upgrade() {
+            Some(payload) if Arc::ptr_eq(&payload, &publish.payload) => Some(properties.clone()),
+            _ => None,
+        }
+    }
+
+    /// Sends `properties` with the publishes (and retransmissions) of `payload`
+    pub(crate) fn set_publish_properties(&self, payload: &Arc<Vec<u8>>, properties: Properties) {
+        let mut inner = self.inner();
+        // payloads which are dropped can't be looked up again
+        inner.publishes.retain(|_, (payload, _)| payload.upgrade().is_some());
+        inner.publishes.insert(address(payload), (Arc::downgrade(payload), properties));
+    }
+
+    fn set_ack(&self, ack: &Packet, reason_code: u8, reason_string: Option<String>) {
+        if let Some(key) = ack_key(ack) {
+            self.inner().acks.insert(key, (reason_code, reason_string));
+        }
+    }
+
+    /// Reason code and reason string of an incoming puback, pubrec or pubcomp. `None`
+    /// for successes without a reason string
+    pub(crate) fn take_ack(&self, ack: &Packet) -> Option<(u8, Option<String>)> {
+        let key = ack_key(ack)?;
+        self.inner().acks.remove(&key)
+    }
+}
+
+fn address(payload: &Arc<Vec<u8>>) -> usize {
+    &**payload as *const Vec<u8> as usize
+}
+
+fn ack_key(ack: &Packet) -> Option<(u8, u16)> {
+    match ack {
+        Packet::Puback(pkid) => Some((4, pkid.0)),
+        Packet::Pubrec(pkid) => Some((5, pkid.0)),
+        Packet::Pubcomp(pkid) => Some((7, pkid.0)),
+        _ => None,
+    }
+}
+
+/// Packet of the first byte of the fixed header and the rest of the packet after the
+/// fixed header. Properties and reason codes are saved in the context. A disconnect by
+/// the broker is an `io::ErrorKind::ConnectionAborted` error
+pub(crate) fn read_packet(header: u8, body: &[u8], context: &Context) -> io::Result<Packet> {
+    let mut body = body;
+    let buf = &mut body;
+
+    let packet = match header >> 4 {
+        2 => {
+            let flags = read_u8(buf)?;
+            let reason_code = read_u8(buf)?;
+            // brokers without mqtt 5 refuse the connection with a 3.1.1 connack
+            let properties = match buf.is_empty() {
+                true => Properties::default(),
+                false => Properties::read(buf)?,
+            };
+
+            context.set_connack(reason_code, properties);
+            Packet::Connack(Connack { session_present: flags & 0x01 == 1, code: connect_return_code(reason_code) })
+        }
+        3 => Packet::Publish(read_publish(header, buf, context)?),
+        4 => read_ack(buf, context, Packet::Puback)?,
+        5 => read_ack(buf, context, Packet::Pubrec)?,
+        6 => read_ack(buf, context, Packet::Pubrel)?,
+        7 => read_ack(buf, context, Packet::Pubcomp)?,
+        9 => {
+            let pkid = PacketIdentifier(read_u16(buf)?);
+            Properties::read(buf)?;
+            let mut return_codes = Vec::with_capacity(buf.len());
+            for &code in buf.iter() {
+                let code = match is_failure(code) {
+                    true => SubscribeReturnCodes::Failure,
+                    false => SubscribeReturnCodes::Success(QoS::from_u8(code).map_err(|_| malformed("Invalid granted qos"))?),
+                };
+
+                return_codes.push(code);
+            }
+
+            Packet::Suback(Suback { pkid, return_codes })
+        }
+        11 => {
+            let pkid = PacketIdentifier(read_u16(buf)?);
+            Properties::read(buf)?;
+            if buf.iter().any(|&code| is_failure(code)) {
+                warn!("Unsubscribe failed for some filters. Pkid = {:?}, Reason codes = {:?}", pkid, buf);
+            }
+
+            Packet::Unsuback(pkid)
+        }
+        12 => Packet::Pingreq,
+        13 => Packet::Pingresp,
+        14 => {
+            let reason_code = match buf.is_empty() {
+                true => 0,
+                false => read_u8(buf)?,
+            };
+            let properties = match buf.is_empty() {
+                true => Properties::default(),
+                false => Properties::read(buf)?,
+            };
+
+            error!("Broker disconnected. Reason code = {:#04x}, Reason = {:?}", reason_code, properties.reason_string);
+            let message = format!("Broker disconnected. Reason code = {:#04x}", reason_code);
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, message));
+        }
+        packet_type => {
+            error!("Unsupported mqtt 5 packet. Type = {}", packet_type);
+            return Err(malformed("Unsupported packet type"));
+        }
+    };
+
+    Ok(packet)
+}
+
+fn read_publish(header: u8, buf: &mut &[u8], context: &Context) -> io::Result<Publish> {
+    let qos = QoS::from_u8((header >> 1) & 0x03).map_err(|_| malformed("Invalid qos"))?;
+    let topic_name = read_string(buf)?;
+    let pkid = match qos {
+        QoS::AtMostOnce => None,
+        _ => Some(PacketIdentifier(read_u16(buf)?)),
+    };
+
+    let properties = Properties::read(buf)?;
+    let payload = Arc::new(buf.to_vec());
+    if !properties.is_empty() {
+        context.set_publish_properties(&payload, properties);
+    }
+
+    Ok(Publish { dup: header & 0x08 != 0, qos, retain: header & 0x01 != 0, topic_name, pkid, payload })
+}
+
+/// Ack with the packet id in `buf`. Reason codes and reason strings of puback, pubrec
+/// and pubcomp are saved in the context unless the ack is a plain success
+fn read_ack(buf: &mut &[u8], context: &Context, ack: fn(PacketIdentifier) -> Packet) -> io::Result<Packet> {
+    let packet = ack(PacketIdentifier(read_u16(buf)?));
+    // success without properties leaves out the reason code
+    if buf.is_empty() {
+        return Ok(packet);
+    }
+
+    let reason_code = read_u8(buf)?;
+    let properties = match buf.is_empty() {
+        true => Properties::default(),
+        false => Properties::read(buf)?,
+    };
+
+    if reason_code != 0 || properties.reason_string.is_some() {
+        context.set_ack(&packet, reason_code, properties.reason_string);
+    }
+
+    Ok(packet)
+}
+
+/// Return code of mqtt311's connack for a connack reason code. Return codes of mqtt 3.1.1
+/// (of brokers which don't support mqtt 5) are kept
+fn connect_return_code(reason_code: u8) -> ConnectReturnCode {
+    match reason_code {
+        0x00 => ConnectReturnCode::Accepted,
+        0x01 | 0x84 => ConnectReturnCode::RefusedProtocolVersion,
+        0x02 | 0x85 => ConnectReturnCode::RefusedIdentifierRejected,
+        0x04 | 0x86 => ConnectReturnCode::BadUsernamePassword,
+        0x05 | 0x87 => ConnectReturnCode::NotAuthorized,
+        _ => ConnectReturnCode::ServerUnavailable,
+    }
+}
+
+/// Writes `packet` as per mqtt 5 with the properties of the context. Acks are written as
+/// plain successes and disconnects as normal disconnections
+pub(crate) fn write_packet(packet: &Packet, context: &Context, buf: &mut BytesMut) -> io::Result<()> {
+    let mut body = Vec::new();
+    let mut payload: &[u8] = &[];
+    let header = match packet {
+        Packet::Connect(connect) => {
+            write_connect(&mut body, connect, None, &context.connect_properties())?;
+            0x10
+        }
+        Packet::Publish(publish) => {
+            write_string(&mut body, &publish.topic_name)?;
+            if publish.qos != QoS::AtMostOnce {
+                let pkid = publish.pkid.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Publish without packet id"))?;
+                body.extend_from_slice(&pkid.0.to_be_bytes());
+            }
+
+            context.publish_properties(publish).unwrap_or_default().write(&mut body)?;
+            payload = &publish.payload[..];
+            0x30 | publish.retain as u8 | publish.qos.to_u8() << 1 | (publish.dup as u8) << 3
+        }
+        Packet::Puback(pkid) => {
+            body.extend_from_slice(&pkid.0.to_be_bytes());
+            0x40
+        }
+        Packet::Pubrec(pkid) => {
+            body.extend_from_slice(&pkid.0.to_be_bytes());
+            0x50
+        }
+        Packet::Pubrel(pkid) => {
+            body.extend_from_slice(&pkid.0.to_be_bytes());
+            0x62
+        }
+        Packet::Pubcomp(pkid) => {
+            body.extend_from_slice(&pkid.0.to_be_bytes());
+            0x70
+        }
+        Packet::Subscribe(subscribe) => {
+            body.extend_from_slice(&subscribe.pkid.0.to_be_bytes());
+            Properties::default().write(&mut body)?;
+            for topic in subscribe.topics.iter() {
+                write_string(&mut body, &topic.topic_path)?;
+                // subscription options other than the qos are left at their defaults
+                body.push(topic.qos.to_u8());
+            }
+
+            0x82
+        }
+        Packet::Unsubscribe(unsubscribe) => {
+            body.extend_from_slice(&unsubscribe.pkid.0.to_be_bytes());
+            Properties::default().write(&mut body)?;
+            for topic in unsubscribe.topics.iter() {
+                write_string(&mut body, topic)?;
+            }
+
+            0xA2
+        }
+        Packet::Pingreq => 0xC0,
+        Packet::Pingresp => 0xD0,
+        Packet::Disconnect => 0xE0,
+        packet => {
+            error!("Unsupported mqtt 5 packet = {:?}", packet);
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Unsupported mqtt 5 packet"));
+        }
+    };
+
+    let mut fixed_header = vec![header];
+    write_varint(&mut fixed_header, body.len() + payload.len())?;
+    buf.reserve(fixed_header.len() + body.len() + payload.len());
+    buf.put_slice(&fixed_header);
+    buf.put_slice(&body);
+    buf.put_slice(payload);
+    Ok(())
+}
+
+/// Encodes a connect with a binary password as per mqtt 5. See `codec::encode_connect`
+pub(crate) fn encode_connect(connect: &Connect, password: &[u8], context: &Context) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    write_connect(&mut body, connect, Some(password), &context.connect_properties())?;
+
+    let mut packet = vec![0x10];
+    write_varint(&mut packet, body.len())?;
+    packet.extend_from_slice(&body);
+    Ok(packet)
+}
+
+/// Variable header and payload of a connect. `password` replaces the password of the
+/// connect. `clean_session` is the clean start flag of mqtt 5. Sessions which aren't clean
+/// don't expire (like in mqtt 3.1.1) unless the properties set a session expiry interval
+fn write_connect(body: &mut Vec<u8>, connect: &Connect, password: Option<&[u8]>, properties: &Properties) -> io::Result<()> {
+    let password = password.or_else(|| connect.password.as_ref().map(|password| password.as_bytes()));
+    write_string(body, "MQTT")?;
+    body.push(5);
+
+    let mut flags = 0;
+    if connect.clean_session {
+        flags |= 0x02;
+    }
+    if let Some(will) = connect.last_will.as_ref() {
+        flags |= 0x04 | will.qos.to_u8() << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    if connect.username.is_some() {
+        flags |= 0x80;
+    }
+
+    body.push(flags);
+    body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+
+    let mut properties = properties.clone();
+    if !connect.clean_session && properties.session_expiry_interval.is_none() {
+        properties.session_expiry_interval = Some(u32::max_value());
+    }
+    properties.write(body)?;
+
+    write_string(body, &connect.client_id)?;
+    if let Some(will) = connect.last_will.as_ref() {
+        Properties::default().write(body)?;
+        write_string(body, &will.topic)?;
+        write_binary(body, will.message.as_bytes())?;
+    }
+    if let Some(username) = connect.username.as_ref() {
+        write_string(body, username)?;
+    }
+    if let Some(password) = password {
+        write_binary(body, password)?;
+    }
+
+    Ok(())
+}
+
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(malformed("Packet is shorter than its fields"));
+    }
+
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+fn read_u8(buf: &mut &[u8]) -> io::Result<u8> {
+    Ok(take(buf, 1)?[0])
+}
+
+fn read_u16(buf: &mut &[u8]) -> io::Result<u16> {
+    let bytes = take(buf, 2)?;
+    Ok((u16::from(bytes[0]) << 8) | u16::from(bytes[1]))
+}
+
+fn read_u32(buf: &mut &[u8]) -> io::Result<u32> {
+    let bytes = take(buf, 4)?;
+    Ok(bytes.iter().fold(0, |value, byte| (value << 8) | u32::from(*byte)))
+}
+
+fn read_binary<'a>(buf: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = read_u16(buf)?;
+    take(buf, usize::from(len))
+}
+
+fn read_string(buf: &mut &[u8]) -> io::Result<String> {
+    let bytes = read_binary(buf)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| malformed("String isn't utf8"))
+}
+
+fn read_varint(buf: &mut &[u8]) -> io::Result<usize> {
+    let mut value = 0;
+    for i in 0..4 {
+        let byte = read_u8(buf)?;
+        value |= usize::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(malformed("Malformed variable byte integer"))
+}
+
+fn write_binary(out: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > 65535 {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Field longer than 65535 bytes"));
+    }
+
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn write_string(out: &mut Vec<u8>, string: &str) -> io::Result<()> {
+    write_binary(out, string.as_bytes())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) -> io::Result<()> {
+    if value > MAX_REMAINING_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "Packet too long"));
+    }
+
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+
+        out.push(byte);
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Context, Properties};
+    use crate::codec::MqttCodec;
+    use bytes::BytesMut;
+    use mqtt311::{Connack, Connect, ConnectReturnCode, Packet, PacketIdentifier, Protocol, Publish, QoS};
+    use std::sync::Arc;
+    use tokio::codec::{Decoder, Encoder};
+
+    fn codec(context: &Arc<Context>) -> MqttCodec {
+        let mut codec = MqttCodec::new();
+        codec.set_v5(context.clone());
+        codec
+    }
+
+    #[test]
+    fn properties_should_be_written_and_read_back() {
+        let properties = Properties {
+            message_expiry_interval: Some(60),
+            content_type: Some("application/json".to_owned()),
+            correlation_data: Some(vec![1, 2, 3]),
+            reason_string: Some("ok".to_owned()),
+            receive_maximum: Some(10),
+            user_properties: vec![("key".to_owned(), "value".to_owned())],
+            ..Properties::default()
+        };
+
+        let mut buf = Vec::new();
+        properties.write(&mut buf).unwrap();
+        let mut slice = &buf[..];
+        assert_eq!(Properties::read(&mut slice).unwrap(), properties);
+        assert!(slice.is_empty());
+    }
+
+    #[test]
+    fn publish_properties_should_travel_with_the_payload() {
+        let (sender, receiver) = (Arc::new(Context::default()), Arc::new(Context::default()));
+        let publish = Publish {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic_name: "hello/world".to_owned(),
+            pkid: Some(PacketIdentifier(10)),
+            payload: Arc::new(vec![1, 2, 3]),
+        };
+
+        let properties = Properties { message_expiry_interval: Some(30), ..Properties::default() };
+        sender.set_publish_properties(&publish.payload, properties.clone());
+
+        let mut buf = BytesMut::new();
+        codec(&sender).encode(Packet::Publish(publish.clone()), &mut buf).unwrap();
+        let decoded = match codec(&receiver).decode(&mut buf).unwrap() {
+            Some(Packet::Publish(decoded)) => decoded,
+            packet => panic!("Expecting a publish. Found = {:?}", packet),
+        };
+
+        assert_eq!(decoded, publish);
+        assert_eq!(receiver.publish_properties(&decoded), Some(properties));
+
+        // a different payload with the same bytes doesn't have the properties
+        let copy = Publish { payload: Arc::new(vec![1, 2, 3]), ..decoded };
+        assert_eq!(receiver.publish_properties(&copy), None);
+    }
+
+    #[test]
+    fn connack_and_ack_reason_codes_should_be_kept_in_the_context() {
+        let context = Arc::new(Context::default());
+
+        // not authorized with a reason string
+        let mut buf = BytesMut::from(vec![0x20, 0x08, 0x00, 0x87, 0x05, 0x1F, 0x00, 0x02, b'n', b'o']);
+        let connack = Connack { session_present: false, code: ConnectReturnCode::NotAuthorized };
+        assert_eq!(codec(&context).decode(&mut buf).unwrap(), Some(Packet::Connack(connack)));
+        let (reason_code, properties) = context.connack().unwrap();
+        assert_eq!((reason_code, properties.reason_string), (0x87, Some("no".to_owned())));
+
+        // quota exceeded puback and a plain puback
+        let mut buf = BytesMut::from(vec![0x40, 0x03, 0x00, 0x01, 0x97, 0x40, 0x02, 0x00, 0x02]);
+        assert_eq!(codec(&context).decode(&mut buf).unwrap(), Some(Packet::Puback(PacketIdentifier(1))));
+        assert_eq!(codec(&context).decode(&mut buf).unwrap(), Some(Packet::Puback(PacketIdentifier(2))));
+        assert_eq!(context.take_ack(&Packet::Puback(PacketIdentifier(1))), Some((0x97, None)));
+        assert_eq!(context.take_ack(&Packet::Puback(PacketIdentifier(2))), None);
+    }
+
+    #[test]
+    fn connect_of_a_persistent_session_should_never_expire() {
+        let connect = Connect {
+            protocol: Protocol::MQTT(5),
+            keep_alive: 10,
+            client_id: "id".to_owned(),
+            clean_session: false,
+            last_will: None,
+            username: None,
+            password: None,
+        };
+
+        let mut buf = BytesMut::new();
+        codec(&Arc::new(Context::default())).encode(Packet::Connect(connect), &mut buf).unwrap();
+
+        // name, level 5, flags, keep alive, session expiry property, client id
+        let expected = [0x10, 0x14, 0, 4, b'M', b'Q', b'T', b'T', 5, 0, 0, 10, 5, 0x11, 0xff, 0xff, 0xff, 0xff, 0, 2, b'i', b'd'];
+        assert_eq!(&buf[..], &expected[..]);
+    }
+}
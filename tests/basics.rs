@@ -0,0 +1,482 @@
+use rumqtt::testutils::MockBroker;
+use rumqtt::{
+    ClientError, ConnectError, ConnectionInfo, ErrorSummary, MqttClient, MqttOptions, Notification, Packet, PublishError, QoS,
+    ReconnectOptions, WaitError,
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+fn options(id: &str, broker: &MockBroker) -> MqttOptions {
+    MqttOptions::new(id, "127.0.0.1", broker.port()).set_reconnect_opts(ReconnectOptions::Always(1))
+}
+
+fn publishes(packets: &[Packet]) -> usize {
+    packets.iter().filter(|packet| match packet {
+        Packet::Publish(_) => true,
+        _ => false,
+    }).count()
+}
+
+#[test]
+fn acked_publish_should_reach_the_broker() {
+    let broker = MockBroker::start().unwrap();
+    let (mut client, _notifications) = MqttClient::start(options("basics-ack", &broker)).unwrap();
+
+    client.publish_and_wait_ack("hello/world", QoS::AtLeastOnce, vec![1, 2, 3], Duration::from_secs(5)).unwrap();
+    client.publish_and_wait_ack("hello/world", QoS::ExactlyOnce, vec![1, 2, 3], Duration::from_secs(5)).unwrap();
+    assert_eq!(publishes(&broker.received()), 2);
+}
+
+#[test]
+fn refused_connection_should_fail_the_start() {
+    let broker = MockBroker::start().unwrap();
+    broker.refuse_connect(5);
+
+    let mqttoptions = options("basics-refused", &broker).set_reconnect_opts(ReconnectOptions::Never);
+    match MqttClient::start(mqttoptions) {
+        Err(ConnectError::MqttConnectionRefused(5)) => (),
+        o => panic!("Expecting connection refusal. Found = {:?}", o.map(|_| ())),
+    }
+}
+
+#[test]
+fn unacked_publish_should_be_replayed_after_reconnection() {
+    let broker = MockBroker::start().unwrap();
+    broker.set_session_present(true);
+    // connect and the publish
+    broker.drop_connection_after(2);
+
+    let mqttoptions = options("basics-replay", &broker).set_clean_session(false);
+    let (mut client, _notifications) = MqttClient::start(mqttoptions).unwrap();
+    client.publish_and_wait_ack("hello/world", QoS::AtLeastOnce, vec![1, 2, 3], Duration::from_secs(10)).unwrap();
+
+    assert_eq!(broker.connections(), 2);
+    assert_eq!(publishes(&broker.received()), 2);
+}
+
+#[test]
+fn late_ack_should_time_out_the_publish() {
+    let broker = MockBroker::start().unwrap();
+    broker.delay_acks(Duration::from_secs(2));
+
+    let (mut client, _notifications) = MqttClient::start(options("basics-late-ack", &broker)).unwrap();
+    match client.publish_and_wait_ack("hello/world", QoS::AtLeastOnce, vec![1, 2, 3], Duration::from_millis(200)) {
+        Err(PublishError::Timeout) => (),
+        o => panic!("Expecting publish timeout. Found = {:?}", o),
+    }
+}
+
+#[test]
+fn publishes_of_subscribed_topics_should_be_notified() {
+    let broker = MockBroker::start().unwrap();
+    let (mut client, notifications) = MqttClient::start(options("basics-subscribe", &broker)).unwrap();
+
+    client.subscribe("hello/+", QoS::AtLeastOnce).unwrap();
+    client.publish("hello/world", QoS::AtMostOnce, false, vec![1, 2, 3]).unwrap();
+
+    loop {
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Publish(publish)) => {
+                assert_eq!(publish.topic_name, "hello/world");
+                break;
+            }
+            Ok(_) => continue,
+            Err(e) => panic!("Expecting publish notification. Error = {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn idle_connection_should_be_pinged() {
+    let broker = MockBroker::start().unwrap();
+    let mqttoptions = options("basics-ping", &broker).set_keep_alive(1);
+    let (_client, _notifications) = MqttClient::start(mqttoptions).unwrap();
+
+    let pinged = broker.wait_until(Duration::from_secs(5), |packets| packets.iter().any(|packet| *packet == Packet::Pingreq));
+    assert!(pinged);
+    assert_eq!(broker.connections(), 1);
+}
+
+#[test]
+fn throttled_publishes_should_not_delay_pings() {
+    let broker = MockBroker::start().unwrap();
+    let mqttoptions = options("basics-throttle", &broker)
+        .set_keep_alive(1)
+        .set_throttle(10.0)
+        .set_request_channel_capacity(10_000);
+    let (mut client, _notifications) = MqttClient::start(mqttoptions).unwrap();
+
+    for i in 0..10_000 {
+        client.publish("hello/world", QoS::AtMostOnce, false, vec![0; i % 100]).unwrap();
+    }
+
+    // broker never talks to a client which only sends qos0 publishes. a ping is due
+    // after a keep alive while the publishes take 1000 seconds
+    let pinged = broker.wait_until(Duration::from_secs(3), |packets| packets.iter().any(|packet| *packet == Packet::Pingreq));
+    assert!(pinged);
+    assert!(publishes(&broker.received()) < 50);
+}
+
+#[test]
+fn full_request_channel_should_push_back() {
+    let broker = MockBroker::start().unwrap();
+    let mqttoptions = options("basics-request-capacity", &broker).set_request_channel_capacity(1);
+
+    // eventloop isn't run. nothing is taken out of the request channel
+    let (mut client, _notifications, _eventloop) = MqttClient::new(mqttoptions);
+    client.try_publish("hello/world", QoS::AtMostOnce, false, vec![1]).unwrap();
+    client.try_publish("hello/world", QoS::AtMostOnce, false, vec![2]).unwrap();
+    match client.try_publish("hello/world", QoS::AtMostOnce, false, vec![3]) {
+        Err(ClientError::RequestChannelFull(publish)) => assert_eq!(*publish.payload, vec![3]),
+        o => panic!("Expecting full request channel. Found = {:?}", o),
+    }
+
+    let mqttoptions = options("basics-request-capacity", &broker).set_request_channel_capacity(1000);
+    let (mut client, _notifications, _eventloop) = MqttClient::new(mqttoptions);
+    for i in 0..1000u32 {
+        client.try_publish("hello/world", QoS::AtMostOnce, false, i.to_be_bytes().to_vec()).unwrap();
+    }
+}
+
+#[test]
+fn burst_within_notification_capacity_should_not_be_dropped() {
+    let broker = MockBroker::start().unwrap();
+    let mqttoptions = options("basics-notification-capacity", &broker).set_notification_channel_capacity(1000);
+    let (mut client, notifications) = MqttClient::start(mqttoptions).unwrap();
+
+    client.subscribe("hello/world", QoS::AtMostOnce).unwrap();
+    for i in 0..500u32 {
+        client.publish("hello/world", QoS::AtMostOnce, false, i.to_be_bytes().to_vec()).unwrap();
+    }
+
+    // notifications are read only after the burst is echoed back
+    assert!(broker.wait_until(Duration::from_secs(10), |packets| publishes(packets) == 500));
+
+    let mut received = 0;
+    while received < 500 {
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Publish(_)) => received += 1,
+            Ok(_) => continue,
+            Err(e) => panic!("Expecting publish notification. Received = {}, Error = {:?}", received, e),
+        }
+    }
+
+    assert_eq!(notifications.dropped_count(), 0);
+}
+
+#[test]
+fn wait_for_connected_should_return_the_connection_or_why_it_failed() {
+    let broker = MockBroker::start().unwrap();
+    let (client, _notifications) = MqttClient::start(options("basics-wait", &broker)).unwrap();
+
+    let info = client.wait_for_connected(Duration::from_secs(5)).unwrap();
+    assert_eq!(info, ConnectionInfo { broker: ("127.0.0.1".to_owned(), broker.port()), session_present: false, tls: None });
+
+    client.clone().stop(Duration::from_secs(5)).unwrap();
+    assert_eq!(client.wait_for_connected(Duration::from_secs(5)), Err(WaitError::EventloopTerminated));
+
+    // eventloop which never runs
+    let (client, _notifications, _eventloop) = MqttClient::new(options("basics-wait-timeout", &broker));
+    assert_eq!(client.wait_for_connected(Duration::from_millis(100)), Err(WaitError::Timeout));
+}
+
+#[test]
+fn slow_consumer_should_pause_the_network_without_losing_publishes() {
+    let broker = MockBroker::start().unwrap();
+    let mqttoptions = options("basics-backpressure", &broker)
+        .set_keep_alive(1)
+        .set_notification_channel_capacity(2)
+        .set_inbound_backpressure(true);
+    let (mut client, notifications) = MqttClient::start(mqttoptions).unwrap();
+
+    client.subscribe("hello/world", QoS::AtLeastOnce).unwrap();
+    for i in 0..50u8 {
+        client.publish("hello/world", QoS::AtMostOnce, false, vec![i]).unwrap();
+    }
+
+    // consumer is away for a few keep alives. pings keep the connection up meanwhile
+    thread::sleep(Duration::from_secs(3));
+
+    let mut payloads = Vec::new();
+    while payloads.len() < 50 {
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Publish(publish)) => payloads.push(publish.payload[0]),
+            Ok(_) => continue,
+            Err(e) => panic!("Expecting publish notification. Error = {:?}", e),
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(payloads, (0..50).collect::<Vec<u8>>());
+    assert_eq!(notifications.dropped_count(), 0);
+    assert_eq!(broker.connections(), 1);
+}
+
+#[test]
+fn async_start_should_queue_requests_till_connected() {
+    let broker = MockBroker::start().unwrap();
+    let (mut client, notifications) = MqttClient::start_async(options("basics-async", &broker)).unwrap();
+
+    client.publish("hello/world", QoS::AtLeastOnce, false, vec![1, 2, 3]).unwrap();
+    match notifications.recv_timeout(Duration::from_secs(5)) {
+        Ok(Notification::Connected(_)) => (),
+        n => panic!("Expecting connected notification. Found = {:?}", n),
+    }
+
+    assert!(broker.wait_until(Duration::from_secs(5), |packets| publishes(packets) == 1));
+}
+
+#[test]
+fn failed_async_start_should_be_notified() {
+    let broker = MockBroker::start().unwrap();
+    broker.refuse_connect(3);
+
+    let mqttoptions = options("basics-async-refused", &broker).set_reconnect_opts(ReconnectOptions::Never);
+    let (mut client, notifications) = MqttClient::start_async(mqttoptions).unwrap();
+    match notifications.recv_timeout(Duration::from_secs(5)) {
+        Ok(Notification::ConnectFailed(ErrorSummary::Refused(3))) => (),
+        n => panic!("Expecting connection failure. Found = {:?}", n),
+    }
+
+    assert_eq!(client.wait_for_connected(Duration::from_secs(5)), Err(WaitError::EventloopTerminated));
+    match client.publish("hello/world", QoS::AtLeastOnce, false, vec![1, 2, 3]) {
+        Err(ClientError::EventloopTerminated) => (),
+        o => panic!("Expecting terminated eventloop. Found = {:?}", o),
+    }
+}
+
+#[test]
+fn clones_should_publish_concurrently_from_threads() {
+    fn assert_send<T: Send + Clone>(_: &T) {}
+
+    let broker = MockBroker::start().unwrap();
+    let (client, _notifications) = MqttClient::start(options("basics-threads", &broker)).unwrap();
+    assert_send(&client);
+
+    let publishers: Vec<_> = (0..8)
+        .map(|i| {
+            let mut client = client.clone();
+            thread::spawn(move || {
+                for j in 0..50 {
+                    let topic = format!("sensors/{}", i);
+                    client.publish(topic, QoS::AtLeastOnce, false, vec![j]).unwrap();
+                }
+            })
+        })
+        .collect();
+
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+
+    assert!(broker.wait_until(Duration::from_secs(10), |packets| publishes(packets) == 400));
+}
+
+#[cfg(unix)]
+#[test]
+fn custom_transport_should_be_connected_again_on_reconnections() {
+    use futures::future;
+    use rumqtt::AsyncStream;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::reactor::Handle;
+
+    let dials = Arc::new(AtomicUsize::new(0));
+    let count = dials.clone();
+    let mqttoptions = MqttOptions::new("basics-custom", "unreachable.invalid", 1883)
+        .set_reconnect_opts(ReconnectOptions::Always(1))
+        .set_custom_transport(move || {
+            let dial = count.fetch_add(1, Ordering::SeqCst);
+            let (stream, mut broker) = UnixStream::pair().unwrap();
+
+            // fake broker at the other end of the pipe. first connection is closed after the connack
+            thread::spawn(move || {
+                let mut buf = [0; 1024];
+                let _ = broker.read(&mut buf);
+                broker.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+                while dial > 0 {
+                    match broker.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => (),
+                    }
+                }
+            });
+
+            let stream = tokio::net::UnixStream::from_std(stream, &Handle::default())
+                .map(|stream| Box::new(stream) as Box<dyn AsyncStream>)
+                .map_err(ConnectError::Io);
+            Box::new(future::result(stream))
+        });
+
+    let (_client, notifications) = MqttClient::start(mqttoptions).unwrap();
+    let mut connections = 0;
+    while connections < 2 {
+        match notifications.recv_timeout(Duration::from_secs(5)) {
+            Ok(Notification::Connected(_)) => connections += 1,
+            Ok(_) => (),
+            Err(e) => panic!("Expecting a reconnection. Error = {:?}", e),
+        }
+    }
+
+    assert_eq!(dials.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn in_memory_transport_should_frame_partial_packets_and_write_exact_bytes() {
+    use rumqtt::testutils;
+
+    let timeout = Duration::from_secs(5);
+    let mqttoptions = MqttOptions::new("basics-duplex", "localhost", 1883).set_reconnect_opts(ReconnectOptions::Always(1));
+    let (mqttoptions, transport) = testutils::in_memory_transport(mqttoptions);
+    let (mut client, notifications) = MqttClient::start_async(mqttoptions).unwrap();
+
+    let mut broker = transport.accept(timeout).unwrap();
+    match broker.next_packet(timeout).unwrap() {
+        Some(Packet::Connect(connect)) => assert_eq!(connect.client_id, "basics-duplex"),
+        packet => panic!("Expecting connect. Found = {:?}", packet),
+    }
+
+    // connack split across reads
+    broker.write_all(&[0x20]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    broker.write_all(&[0x02, 0x00, 0x00]).unwrap();
+    match notifications.recv_timeout(timeout) {
+        Ok(Notification::Connected(ConnectionInfo { session_present: false, tls: None, .. })) => (),
+        n => panic!("Expecting connected notification. Found = {:?}", n),
+    }
+
+    client.publish("a/b", QoS::AtMostOnce, false, vec![1]).unwrap();
+    assert_eq!(broker.read_exact(8, timeout).unwrap(), vec![0x30, 0x06, 0x00, 0x03, b'a', b'/', b'b', 1]);
+
+    // closed pipe is a lost connection. reconnection opens a new one
+    broker.close();
+    assert!(transport.accept(timeout).is_some());
+}
+
+#[test]
+fn slow_broker_should_not_delay_pings_behind_a_publish_backlog() {
+    use rumqtt::testutils;
+
+    let timeout = Duration::from_secs(5);
+    let mqttoptions = MqttOptions::new("basics-slow-broker", "localhost", 1883)
+        .set_keep_alive(1)
+        .set_request_channel_capacity(1000);
+    let (mqttoptions, transport) = testutils::bounded_in_memory_transport(mqttoptions, 2048);
+    let (mut client, notifications) = MqttClient::start_async(mqttoptions).unwrap();
+
+    let mut broker = transport.accept(timeout).unwrap();
+    match broker.next_packet(timeout).unwrap() {
+        Some(Packet::Connect(_)) => broker.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(),
+        packet => panic!("Expecting connect. Found = {:?}", packet),
+    }
+
+    assert!(notifications.recv_timeout(timeout).is_ok());
+    for _ in 0..300 {
+        client.publish("hello/world", QoS::AtMostOnce, false, vec![0; 1000]).unwrap();
+    }
+
+    // broker reads 20 packets a second. the backlog takes 15 seconds to drain while a
+    // ping is due every second (qos0 publishes aren't answered)
+    let (mut pings, mut publishes) = (0, 0);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(4) {
+        match broker.next_packet(timeout).unwrap() {
+            Some(Packet::Pingreq) => {
+                pings += 1;
+                broker.send(Packet::Pingresp).unwrap();
+            }
+            Some(Packet::Publish(_)) => publishes += 1,
+            packet => panic!("Expecting publish or ping. Found = {:?}", packet),
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(pings >= 2, "pings = {}", pings);
+    assert!(publishes < 100, "publishes = {}", publishes);
+}
+
+#[test]
+fn batches_should_not_be_interleaved_with_other_publishes() {
+    let broker = MockBroker::start().unwrap();
+    let (client, _notifications) = MqttClient::start(options("basics-batch", &broker)).unwrap();
+
+    let publishers: Vec<_> = (0..4)
+        .map(|i| {
+            let mut client = client.clone();
+            thread::spawn(move || {
+                let batch = (0..50u8).map(|j| (format!("batch/{}", i), QoS::AtLeastOnce, vec![j])).collect();
+                client.publish_batch(batch).unwrap();
+            })
+        })
+        .collect();
+
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+
+    assert!(broker.wait_until(Duration::from_secs(10), |packets| publishes(packets) == 200));
+    let publishes: Vec<_> = broker
+        .received()
+        .into_iter()
+        .filter_map(|packet| match packet {
+            Packet::Publish(publish) => Some(publish),
+            _ => None,
+        })
+        .collect();
+
+    // every batch arrives in order as one run. no packet ids are in use before, so the
+    // ids of a batch are consecutive
+    for run in publishes.chunks(50) {
+        assert!(run.iter().all(|publish| publish.topic_name == run[0].topic_name));
+        for (j, publish) in run.iter().enumerate() {
+            assert_eq!(publish.payload[0], j as u8);
+            assert_eq!(publish.pkid.map(|pkid| pkid.0), run[0].pkid.map(|pkid| pkid.0 + j as u16));
+        }
+    }
+}
+
+#[test]
+fn client_should_recover_after_the_broker_goes_down_mid_session() {
+    let broker = MockBroker::start().unwrap();
+    // connect and the first publish
+    broker.drop_connection_after(2);
+
+    let (mut client, notifications) = MqttClient::start(options("basics-outage", &broker)).unwrap();
+    let wait_for = |expected: &dyn Fn(&Notification) -> bool| loop {
+        match notifications.recv_timeout(Duration::from_secs(10)) {
+            Ok(ref n) if expected(n) => return,
+            Ok(_) => continue,
+            Err(e) => panic!("Notification missing. Error = {:?}", e),
+        }
+    };
+
+    // broker goes down. the connection is cut and reconnections are refused
+    broker.refuse_connect(3);
+    client.publish("hello/world", QoS::AtLeastOnce, false, vec![1]).unwrap();
+    wait_for(&|n| match n {
+        Notification::Disconnected(_) => true,
+        _ => false,
+    });
+    wait_for(&|n| match n {
+        Notification::Reconnecting { error: Some(_), .. } => true,
+        _ => false,
+    });
+
+    // and comes back
+    broker.accept_connect();
+    wait_for(&|n| match n {
+        Notification::Connected(_) => true,
+        _ => false,
+    });
+
+    client.publish_and_wait_ack("hello/world", QoS::AtLeastOnce, vec![2], Duration::from_secs(10)).unwrap();
+    assert!(broker.connections() >= 3);
+}